@@ -0,0 +1,83 @@
+/// LoRa modulation and packet settings that determine the time on air.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoRaParams {
+    /// Spreading factor, 6-12.
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    /// Coding rate denominator, 5-8 for 4/5 to 4/8.
+    pub coding_rate: u8,
+    pub preamble_len: u16,
+    pub explicit_header: bool,
+    pub crc: bool,
+}
+
+impl LoRaParams {
+    /// Settings used by LoRaWAN uplinks: 4/5 coding rate, 8 symbol preamble,
+    /// explicit header and payload CRC.
+    pub const fn lorawan(spreading_factor: u8, bandwidth_hz: u32) -> Self {
+        Self {
+            spreading_factor,
+            bandwidth_hz,
+            coding_rate: 5,
+            preamble_len: 8,
+            explicit_header: true,
+            crc: true,
+        }
+    }
+
+    /// Settings of the P2P channel: 4/5 coding rate, 12 symbol preamble,
+    /// explicit header and payload CRC at SF11 and 500 kHz.
+    pub const fn p2p() -> Self {
+        Self {
+            spreading_factor: 11,
+            bandwidth_hz: 500_000,
+            coding_rate: 5,
+            preamble_len: 12,
+            explicit_header: true,
+            crc: true,
+        }
+    }
+
+    /// Duration of one symbol, in microseconds.
+    pub fn symbol_us(&self) -> u32 {
+        ((1u64 << self.spreading_factor) * 1_000_000 / self.bandwidth_hz as u64) as u32
+    }
+
+    /// Whether low data rate optimization is needed, which the radio requires
+    /// when a symbol lasts 16 ms or more.
+    pub fn low_data_rate_optimize(&self) -> bool {
+        self.symbol_us() >= 16_000
+    }
+
+    /// Number of symbols of the header and payload, following the Semtech
+    /// SX1276 datasheet.
+    pub fn payload_symbols(&self, payload_len: usize) -> u32 {
+        let sf = self.spreading_factor as i64;
+        let de = self.low_data_rate_optimize() as i64;
+        let numerator = 8 * payload_len as i64 - 4 * sf
+            + 28
+            + 16 * self.crc as i64
+            - 20 * (!self.explicit_header) as i64;
+        let denominator = 4 * (sf - 2 * de);
+        let blocks = if numerator > 0 {
+            (numerator + denominator - 1) / denominator
+        } else {
+            0
+        };
+        8 + (blocks * self.coding_rate as i64) as u32
+    }
+
+    /// Time on air of a packet carrying `payload_len` bytes, in microseconds.
+    pub fn time_on_air_us(&self, payload_len: usize) -> u32 {
+        let symbol_us = self.symbol_us();
+        // The preamble is followed by 4.25 symbols of sync word.
+        let preamble_us = (4 * self.preamble_len as u32 + 17) * symbol_us / 4;
+        preamble_us + self.payload_symbols(payload_len) * symbol_us
+    }
+
+    /// Time on air of a packet carrying `payload_len` bytes, in milliseconds,
+    /// rounded up.
+    pub fn time_on_air_ms(&self, payload_len: usize) -> u32 {
+        self.time_on_air_us(payload_len).div_ceil(1000)
+    }
+}
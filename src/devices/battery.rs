@@ -0,0 +1,43 @@
+use embassy_time::{Duration, Timer};
+use esp_hal::{
+    analog::adc::{Adc, AdcConfig, Attenuation},
+    gpio::{GpioPin, Output},
+    peripherals::ADC1,
+};
+
+use crate::devices::p2p_neighbors;
+
+/// Time between two readings of the battery.
+const READ_INTERVAL: Duration = Duration::from_secs(60);
+/// Voltage of a full-scale reading at 11 dB attenuation, in mV.
+const ADC_FULL_SCALE_MV: u32 = 3300;
+const ADC_MAX: u32 = 4095;
+/// The battery reaches GPIO37 through a 220k/100k divider.
+const DIVIDER_NUMERATOR: u32 = 320;
+const DIVIDER_DENOMINATOR: u32 = 100;
+
+/// Reads the battery every [`READ_INTERVAL`] and sets the level the node
+/// reports in its beacons.
+///
+/// `adc_ctrl` is GPIO21, held low for the task to connect the divider to
+/// `pin`.
+#[embassy_executor::task]
+pub async fn task_battery(adc: ADC1, pin: GpioPin<37>, _adc_ctrl: Output<'static>) {
+    esp_println::println!("[Battery] Starting battery task");
+    let mut config = AdcConfig::new();
+    let mut pin = config.enable_pin(pin, Attenuation::_11dB);
+    let mut adc = Adc::new(adc, config);
+    loop {
+        let raw = loop {
+            match adc.read_oneshot(&mut pin) {
+                Ok(raw) => break raw as u32,
+                Err(_) => Timer::after(Duration::from_millis(1)).await,
+            }
+        };
+        let mv = raw * ADC_FULL_SCALE_MV / ADC_MAX * DIVIDER_NUMERATOR / DIVIDER_DENOMINATOR;
+        let percent = p2p_neighbors::percent_from_mv(mv);
+        esp_println::println!("[Battery] {} mV, {}%", mv, percent);
+        p2p_neighbors::set_battery_percent(Some(percent));
+        Timer::after(READ_INTERVAL).await;
+    }
+}
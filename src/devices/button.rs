@@ -16,6 +16,14 @@ pub struct Button<'d> {
 }
 
 static BUTTON_SIGNAL: Signal<CriticalSectionRawMutex, ButtonState> = Signal::new();
+/// Signalled when a long press ends. Unlike the button state, it is not
+/// overwritten when the button is released, so a task can take it later.
+pub static LONG_PRESS_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Signalled instead of [`LONG_PRESS_SIGNAL`] when the button was held for
+/// [`HOLD_DURATION`].
+pub static HOLD_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Press after which the release signals [`HOLD_SIGNAL`].
+pub const HOLD_DURATION: Duration = Duration::from_secs(5);
 
 impl<'d> Button<'d> {
     pub fn new(pin: Input<'d>) -> Self {
@@ -27,6 +35,15 @@ impl<'d> Button<'d> {
     }
 
     pub async fn update(&mut self, state: ButtonState) {
+        // A long press is only told apart from a hold once released.
+        if matches!(state, ButtonState::Released) && matches!(self.state, ButtonState::LongPressed)
+        {
+            if self.press_duration >= HOLD_DURATION {
+                HOLD_SIGNAL.signal(());
+            } else {
+                LONG_PRESS_SIGNAL.signal(());
+            }
+        }
         self.state = state;
         BUTTON_SIGNAL.signal(state);
     }
@@ -0,0 +1,530 @@
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+
+use super::{crc::crc16_ccitt, lorawan_config::Region};
+
+/// Interval between two beacons, in seconds.
+pub const BEACON_PERIOD_S: u32 = 128;
+/// Time reserved at the start of a beacon period for the beacon itself, in
+/// milliseconds.
+pub const BEACON_RESERVED_MS: u32 = 2_120;
+/// Length of a ping slot, in milliseconds.
+pub const PING_SLOT_MS: u32 = 30;
+/// Number of ping slots in a beacon window.
+pub const BEACON_WINDOW_SLOTS: u32 = 4_096;
+/// Largest ping slot periodicity, one slot every 128 seconds.
+pub const MAX_PERIODICITY: u8 = 7;
+/// How long ping slots keep being served without beacons before the device
+/// falls back to class A, in seconds.
+pub const BEACONLESS_TIMEOUT_S: u32 = 2 * 60 * 60;
+/// Worst case drift of the device clock, in parts per million, used to widen
+/// the receive windows while no beacon is heard.
+const CLOCK_DRIFT_PPM: u32 = 20;
+
+const TIME_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+const GW_SPECIFIC_LEN: usize = 7;
+
+#[derive(Debug, PartialEq)]
+pub enum BeaconError {
+    Length(usize),
+    TimeCrc,
+}
+
+impl core::fmt::Display for BeaconError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BeaconError::Length(len) => write!(f, "Invalid beacon length {}", len),
+            BeaconError::TimeCrc => write!(f, "Beacon time CRC mismatch"),
+        }
+    }
+}
+
+/// Sizes of the reserved fields of a region's beacon, which set where the
+/// common fields sit in the frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeaconLayout {
+    pub rfu1_len: usize,
+    pub rfu2_len: usize,
+}
+
+impl BeaconLayout {
+    pub fn for_region(region: Region) -> Self {
+        let (rfu1_len, rfu2_len) = match region {
+            Region::US915 | Region::AU915 => (5, 3),
+            Region::IN865 => (1, 3),
+            _ => (2, 0),
+        };
+        Self { rfu1_len, rfu2_len }
+    }
+
+    /// Size of the beacon payload, in bytes.
+    pub fn payload_len(&self) -> usize {
+        self.rfu1_len + TIME_LEN + CRC_LEN + GW_SPECIFIC_LEN + self.rfu2_len + CRC_LEN
+    }
+}
+
+/// A received class B beacon.
+///
+/// | Field      | Size | Content                                        |
+/// |------------|------|------------------------------------------------|
+/// | RFU        | 1-5  | Region specific, see [`BeaconLayout`]          |
+/// | Time       | 4    | GPS seconds of the beacon, modulo 2^32 (LE)    |
+/// | CRC        | 2    | CRC-16 of RFU and Time (LE)                    |
+/// | GwSpecific | 7    | InfoDesc and Info, see [`Beacon::gateway`]     |
+/// | RFU        | 0-3  | Region specific                                |
+/// | CRC        | 2    | CRC-16 of GwSpecific and RFU (LE)              |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beacon {
+    /// GPS time of the start of the beacon period, in seconds.
+    pub time: u32,
+    /// Content of the gateway specific field, valid when its CRC matched.
+    pub gateway: Option<GatewayInfo>,
+}
+
+impl Beacon {
+    /// Parses a beacon payload laid out as `layout`.
+    ///
+    /// The gateway specific part has its own CRC so the time of a beacon
+    /// whose second half was corrupted can still be used.
+    ///
+    /// # Errors
+    ///
+    /// * `Length` - If the payload is not the size of the region's beacon.
+    /// * `TimeCrc` - If the first CRC does not match.
+    pub fn parse(payload: &[u8], layout: BeaconLayout) -> Result<Self, BeaconError> {
+        if payload.len() != layout.payload_len() {
+            return Err(BeaconError::Length(payload.len()));
+        }
+        let (common, gateway) = payload.split_at(layout.rfu1_len + TIME_LEN + CRC_LEN);
+        if !crc_matches(common) {
+            return Err(BeaconError::TimeCrc);
+        }
+        let time = &common[layout.rfu1_len..layout.rfu1_len + TIME_LEN];
+        let time = u32::from_le_bytes([time[0], time[1], time[2], time[3]]);
+        let gateway = crc_matches(gateway).then(|| {
+            let mut info = [0u8; 6];
+            info.copy_from_slice(&gateway[1..GW_SPECIFIC_LEN]);
+            GatewayInfo {
+                info_desc: gateway[0],
+                info,
+            }
+        });
+        Ok(Self { time, gateway })
+    }
+}
+
+/// Whether the CRC in the last two bytes of `bytes` matches the rest.
+fn crc_matches(bytes: &[u8]) -> bool {
+    let (data, crc) = bytes.split_at(bytes.len() - CRC_LEN);
+    crc16_ccitt(data) == u16::from_le_bytes([crc[0], crc[1]])
+}
+
+/// Gateway specific field of a beacon.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GatewayInfo {
+    /// 0-2 for the coordinates of the first, second or third antenna,
+    /// 128-255 for network specific content.
+    pub info_desc: u8,
+    pub info: [u8; 6],
+}
+
+impl GatewayInfo {
+    /// Latitude and longitude of the gateway antenna, in degrees, if the
+    /// field carries coordinates.
+    pub fn coordinates(&self) -> Option<(f64, f64)> {
+        if self.info_desc > 2 {
+            return None;
+        }
+        let i24 = |b: &[u8]| (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as f64;
+        let scale = (1u32 << 23) as f64;
+        Some((
+            i24(&self.info[0..3]) * 90.0 / scale,
+            i24(&self.info[3..6]) * 180.0 / scale,
+        ))
+    }
+}
+
+/// GPS time of the first beacon after `gps_time_s`.
+pub fn next_beacon_time(gps_time_s: u32) -> u32 {
+    (gps_time_s / BEACON_PERIOD_S + 1).wrapping_mul(BEACON_PERIOD_S)
+}
+
+/// Ping slots of one device in the beacon window that starts at a beacon.
+///
+/// A device with periodicity `p` opens `2^(7 - p)` slots per window, evenly
+/// spaced `2^(5 + p)` slots apart after a pseudo-random offset that the
+/// network computes the same way from the beacon time and the DevAddr.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PingSlots {
+    beacon_time: u32,
+    offset: u32,
+    period: u32,
+}
+
+impl PingSlots {
+    /// `periodicity` is clamped to [`MAX_PERIODICITY`].
+    pub fn new(beacon_time: u32, dev_addr: u32, periodicity: u8) -> Self {
+        let period = 1 << (5 + periodicity.min(MAX_PERIODICITY) as u32);
+        Self {
+            beacon_time,
+            offset: ping_offset(beacon_time, dev_addr, period),
+            period,
+        }
+    }
+
+    /// Number of slots between two ping slots.
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// Number of ping slots in the window.
+    pub fn count(&self) -> u32 {
+        BEACON_WINDOW_SLOTS / self.period
+    }
+
+    /// Slot number of the first ping slot, below [`PingSlots::period`].
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Start of ping slot `n`, in milliseconds after the beacon.
+    pub fn slot_ms(&self, n: u32) -> u32 {
+        BEACON_RESERVED_MS + (self.offset + n * self.period) * PING_SLOT_MS
+    }
+
+    /// Index of the first ping slot starting at or after `elapsed_ms` since the
+    /// beacon, or `None` if the window has no slot left.
+    pub fn next_slot(&self, elapsed_ms: u32) -> Option<u32> {
+        (0..self.count()).find(|n| self.slot_ms(*n) >= elapsed_ms)
+    }
+
+    pub fn beacon_time(&self) -> u32 {
+        self.beacon_time
+    }
+}
+
+/// Slot number of the first ping slot of a beacon window.
+///
+/// `Rand = aes128_encrypt(0^16, BeaconTime | DevAddr | 0^8)`, and the offset
+/// is `(Rand[0] + Rand[1] * 256) mod period`, as defined by the LoRaWAN
+/// specification.
+pub fn ping_offset(beacon_time: u32, dev_addr: u32, period: u32) -> u32 {
+    let mut block = [0u8; 16];
+    block[0..4].copy_from_slice(&beacon_time.to_le_bytes());
+    block[4..8].copy_from_slice(&dev_addr.to_le_bytes());
+    let cipher = Aes128::new(&GenericArray::from([0u8; 16]));
+    cipher.encrypt_block(GenericArray::from_mut_slice(&mut block));
+    (block[0] as u32 + block[1] as u32 * 256) % period
+}
+
+/// Beacon acquisition state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BeaconState {
+    /// No beacon received yet; the receiver must listen for a whole period.
+    Searching,
+    /// The last expected beacon was received.
+    Locked { beacon_time: u32 },
+    /// Beacons were missed; ping slots are kept with widened windows until
+    /// [`BEACONLESS_TIMEOUT_S`] after the last beacon.
+    BeaconLess { beacon_time: u32, missed: u32 },
+    /// No beacon for too long, the device must fall back to class A.
+    Lost,
+}
+
+/// Tracks beacons to keep the ping slots aligned with the network.
+///
+/// Class B also needs MAC support that `lorawan-device` does not provide
+/// yet: uplinks must carry the ClassB bit, the ping slot periodicity is
+/// negotiated with `PingSlotInfoReq`, and beacons are received with an
+/// implicit header that `LorawanRadio` cannot be configured for. Until it
+/// does, the device rejects the switch to class B.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeaconTracker {
+    state: BeaconState,
+}
+
+impl Default for BeaconTracker {
+    fn default() -> Self {
+        Self {
+            state: BeaconState::Searching,
+        }
+    }
+}
+
+impl BeaconTracker {
+    pub fn state(&self) -> BeaconState {
+        self.state
+    }
+
+    /// Whether ping slots are served, that is the device operates in class B.
+    pub fn is_class_b(&self) -> bool {
+        matches!(
+            self.state,
+            BeaconState::Locked { .. } | BeaconState::BeaconLess { .. }
+        )
+    }
+
+    /// Accounts for a beacon received at its expected time.
+    pub fn beacon_received(&mut self, beacon: &Beacon) {
+        self.state = BeaconState::Locked {
+            beacon_time: beacon.time,
+        };
+    }
+
+    /// Accounts for a beacon that was expected but not received.
+    ///
+    /// Returns `true` when the device has to fall back to class A.
+    pub fn beacon_missed(&mut self) -> bool {
+        self.state = match self.state {
+            BeaconState::Locked { beacon_time } => BeaconState::BeaconLess {
+                beacon_time,
+                missed: 1,
+            },
+            BeaconState::BeaconLess {
+                beacon_time,
+                missed,
+            } if (missed + 1) * BEACON_PERIOD_S < BEACONLESS_TIMEOUT_S => BeaconState::BeaconLess {
+                beacon_time,
+                missed: missed + 1,
+            },
+            BeaconState::BeaconLess { .. } | BeaconState::Lost => BeaconState::Lost,
+            BeaconState::Searching => BeaconState::Searching,
+        };
+        self.state == BeaconState::Lost
+    }
+
+    /// Starts acquiring beacons again, after a fallback to class A.
+    pub fn restart(&mut self) {
+        self.state = BeaconState::Searching;
+    }
+
+    /// GPS time of the next expected beacon, if beacons are tracked.
+    pub fn next_beacon_time(&self) -> Option<u32> {
+        match self.state {
+            BeaconState::Locked { beacon_time } => Some(beacon_time + BEACON_PERIOD_S),
+            BeaconState::BeaconLess {
+                beacon_time,
+                missed,
+            } => Some(beacon_time + (missed + 1) * BEACON_PERIOD_S),
+            BeaconState::Searching | BeaconState::Lost => None,
+        }
+    }
+
+    /// Time the receive windows are opened early and closed late to absorb
+    /// the clock drift since the last beacon, in milliseconds.
+    pub fn window_widening_ms(&self) -> u32 {
+        match self.state {
+            BeaconState::BeaconLess { missed, .. } => {
+                (missed + 1) * BEACON_PERIOD_S * CLOCK_DRIFT_PPM / 1_000
+            }
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Beacon of the example in the LoRaWAN specification, for a region
+    /// with 2 RFU bytes: time 0xCC020000, coordinates of the first antenna.
+    const EXAMPLE: [u8; 17] = [
+        0x00, 0x00, 0x00, 0x00, 0x02, 0xCC, 0xA2, 0x7E, 0x00, 0x01, 0x20, 0x00, 0x00, 0x81, 0x03,
+        0xDE, 0x55,
+    ];
+
+    /// Beacon laid out as `layout` with valid CRCs.
+    fn beacon(
+        layout: BeaconLayout,
+        time: u32,
+        gateway: [u8; GW_SPECIFIC_LEN],
+    ) -> std::vec::Vec<u8> {
+        let mut payload = std::vec![0u8; layout.rfu1_len];
+        payload.extend_from_slice(&time.to_le_bytes());
+        payload.extend_from_slice(&crc16_ccitt(&payload).to_le_bytes());
+        let start = payload.len();
+        payload.extend_from_slice(&gateway);
+        payload.resize(start + GW_SPECIFIC_LEN + layout.rfu2_len, 0);
+        let crc = crc16_ccitt(&payload[start..]);
+        payload.extend_from_slice(&crc.to_le_bytes());
+        payload
+    }
+
+    #[test]
+    fn parses_example_beacon() {
+        let layout = BeaconLayout::for_region(Region::EU868);
+        assert_eq!(layout.payload_len(), EXAMPLE.len());
+        let beacon = Beacon::parse(&EXAMPLE, layout).unwrap();
+        assert_eq!(beacon.time, 0xCC02_0000);
+        let gateway = beacon.gateway.unwrap();
+        assert_eq!(gateway.info_desc, 0);
+        assert_eq!(gateway.info, [0x01, 0x20, 0x00, 0x00, 0x81, 0x03]);
+        let (latitude, longitude) = gateway.coordinates().unwrap();
+        assert!((latitude - 0x2001 as f64 * 90.0 / (1 << 23) as f64).abs() < 1e-12);
+        assert!((longitude - 0x03_8100 as f64 * 180.0 / (1 << 23) as f64).abs() < 1e-12);
+    }
+
+    #[test]
+    fn checks_beacon_crcs() {
+        let layout = BeaconLayout::for_region(Region::EU868);
+        // A corrupted gateway part leaves the time usable.
+        let mut payload = EXAMPLE;
+        payload[10] ^= 0x01;
+        let beacon = Beacon::parse(&payload, layout).unwrap();
+        assert_eq!((beacon.time, beacon.gateway), (0xCC02_0000, None));
+
+        let mut payload = EXAMPLE;
+        payload[4] ^= 0x01;
+        assert_eq!(Beacon::parse(&payload, layout), Err(BeaconError::TimeCrc));
+        assert_eq!(
+            Beacon::parse(&EXAMPLE[..16], layout),
+            Err(BeaconError::Length(16))
+        );
+        assert_eq!(
+            Beacon::parse(&EXAMPLE, BeaconLayout::for_region(Region::US915)),
+            Err(BeaconError::Length(17))
+        );
+    }
+
+    #[test]
+    fn parses_regional_layouts() {
+        let gateway = [0x80, 1, 2, 3, 4, 5, 6];
+        for (region, len) in [
+            (Region::US915, 23),
+            (Region::AU915, 23),
+            (Region::IN865, 19),
+        ] {
+            let layout = BeaconLayout::for_region(region);
+            let payload = beacon(layout, 1_300_000_000, gateway);
+            assert_eq!(payload.len(), len);
+            let beacon = Beacon::parse(&payload, layout).unwrap();
+            assert_eq!(beacon.time, 1_300_000_000);
+            let info = beacon.gateway.unwrap();
+            assert_eq!(info.info_desc, 0x80);
+            // Network specific content carries no coordinates.
+            assert_eq!(info.coordinates(), None);
+        }
+    }
+
+    #[test]
+    fn decodes_negative_coordinates() {
+        let info = GatewayInfo {
+            info_desc: 2,
+            info: [0x00, 0x00, 0xC0, 0x00, 0x00, 0x80],
+        };
+        assert_eq!(info.coordinates(), Some((-45.0, -180.0)));
+    }
+
+    #[test]
+    fn beacons_every_128_seconds() {
+        assert_eq!(next_beacon_time(0), 128);
+        assert_eq!(next_beacon_time(127), 128);
+        assert_eq!(next_beacon_time(128), 256);
+        assert_eq!(next_beacon_time(1_300_000_001), 1_300_000_128);
+    }
+
+    #[test]
+    fn ping_offsets_follow_the_specification() {
+        // Rand is the block AES-128 encrypts with the zero key; for the zero
+        // block it is 66 E9 4B D4 ...
+        assert_eq!(ping_offset(0, 0, 4096), (0x66 + 0xE9 * 256) % 4096);
+        assert_eq!(ping_offset(0x4B0E_3A00, 0x2601_1BDA, 4096), 3692);
+        assert_eq!(ping_offset(0x4B0E_3A00, 0x2601_1BDA, 32), 12);
+        assert_eq!(ping_offset(0x4D7C_6D00, 0x0102_0304, 32), 22);
+        // Each beacon moves the slots.
+        assert_ne!(
+            ping_offset(0x4B0E_3A00, 0x2601_1BDA, 4096),
+            ping_offset(0x4B0E_3A80, 0x2601_1BDA, 4096)
+        );
+    }
+
+    #[test]
+    fn ping_slots_fill_the_window() {
+        let slots = PingSlots::new(0x4B0E_3A00, 0x2601_1BDA, 0);
+        assert_eq!(
+            (slots.period(), slots.count(), slots.offset()),
+            (32, 128, 12)
+        );
+        assert_eq!(slots.beacon_time(), 0x4B0E_3A00);
+        assert_eq!(slots.slot_ms(0), BEACON_RESERVED_MS + 12 * PING_SLOT_MS);
+        assert_eq!(slots.slot_ms(1), BEACON_RESERVED_MS + 44 * PING_SLOT_MS);
+        assert_eq!(slots.next_slot(0), Some(0));
+        assert_eq!(slots.next_slot(slots.slot_ms(0)), Some(0));
+        assert_eq!(slots.next_slot(slots.slot_ms(0) + 1), Some(1));
+        assert_eq!(slots.next_slot(slots.slot_ms(127)), Some(127));
+        assert_eq!(slots.next_slot(slots.slot_ms(127) + 1), None);
+
+        let slots = PingSlots::new(0x4B0E_3A00, 0x2601_1BDA, 7);
+        assert_eq!(
+            (slots.period(), slots.count(), slots.offset()),
+            (4096, 1, 3692)
+        );
+        assert_eq!(PingSlots::new(0x4B0E_3A00, 0x2601_1BDA, 9), slots);
+
+        // Every slot ends before the guard time ahead of the next beacon.
+        for periodicity in 0..=MAX_PERIODICITY {
+            for beacon_time in (0..64).map(|n| n * BEACON_PERIOD_S) {
+                let slots = PingSlots::new(beacon_time, 0x2601_1BDA, periodicity);
+                assert!(slots.offset() < slots.period());
+                let end_ms = slots.slot_ms(slots.count() - 1) + PING_SLOT_MS;
+                assert!(end_ms <= BEACON_PERIOD_S * 1000 - 3_000);
+            }
+        }
+    }
+
+    #[test]
+    fn tracks_beacons_until_lost() {
+        let mut tracker = BeaconTracker::default();
+        assert_eq!(tracker.state(), BeaconState::Searching);
+        assert!(!tracker.beacon_missed());
+        assert!(!tracker.is_class_b());
+        assert_eq!(tracker.next_beacon_time(), None);
+
+        let beacon = Beacon {
+            time: 1_280,
+            gateway: None,
+        };
+        tracker.beacon_received(&beacon);
+        assert!(tracker.is_class_b());
+        assert_eq!(tracker.next_beacon_time(), Some(1_408));
+        assert_eq!(tracker.window_widening_ms(), 0);
+
+        assert!(!tracker.beacon_missed());
+        assert_eq!(
+            tracker.state(),
+            BeaconState::BeaconLess {
+                beacon_time: 1_280,
+                missed: 1
+            }
+        );
+        assert!(tracker.is_class_b());
+        assert_eq!(tracker.next_beacon_time(), Some(1_536));
+        assert_eq!(tracker.window_widening_ms(), 2 * 128 * 20 / 1000);
+
+        // A beacon heard again locks back on.
+        tracker.beacon_received(&Beacon {
+            time: 1_536,
+            gateway: None,
+        });
+        assert_eq!(tracker.state(), BeaconState::Locked { beacon_time: 1_536 });
+
+        // Ping slots are served for two hours without beacons.
+        let mut missed = 0;
+        while !tracker.beacon_missed() {
+            missed += 1;
+            assert!(tracker.is_class_b());
+        }
+        assert_eq!(missed, BEACONLESS_TIMEOUT_S / BEACON_PERIOD_S);
+        assert_eq!(tracker.state(), BeaconState::Lost);
+        assert!(!tracker.is_class_b());
+        assert_eq!(tracker.next_beacon_time(), None);
+        assert!(tracker.beacon_missed());
+
+        tracker.restart();
+        assert_eq!(tracker.state(), BeaconState::Searching);
+    }
+}
@@ -0,0 +1,218 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Unix time of the GPS epoch, 1980-01-06 00:00:00 UTC.
+pub const GPS_EPOCH_UNIX_S: u32 = 315_964_800;
+/// Leap seconds GPS time is ahead of UTC, unchanged since 2017.
+pub const GPS_LEAP_S: u32 = 18;
+
+/// Where the system time was last set from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeSource {
+    Gps,
+    Network,
+    /// The time beacon of a P2P coordinator, see `p2p_tdma`.
+    Peer,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sync {
+    /// GPS time in milliseconds minus the uptime.
+    offset_ms: i64,
+    source: TimeSource,
+}
+
+/// System time, `None` until the GPS receiver or the network set it.
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<Option<Sync>>> = Mutex::new(Cell::new(None));
+
+/// Sets the system time to `gps_ms` milliseconds since the GPS epoch at
+/// uptime `at`.
+pub fn set_gps_time(gps_ms: u64, at: Instant, source: TimeSource) {
+    let offset_ms = gps_ms as i64 - at.as_millis() as i64;
+    CLOCK.lock(|clock| clock.set(Some(Sync { offset_ms, source })));
+}
+
+/// Shifts the time given by [`device_time`] by `correction_s`, as answered by
+/// the network.
+pub fn correct(correction_s: i32) {
+    CLOCK.lock(|clock| {
+        let offset_ms = clock.get().map_or(0, |sync| sync.offset_ms);
+        clock.set(Some(Sync {
+            offset_ms: offset_ms + correction_s as i64 * 1000,
+            source: TimeSource::Network,
+        }));
+    });
+}
+
+pub fn source() -> Option<TimeSource> {
+    CLOCK.lock(|clock| clock.get()).map(|sync| sync.source)
+}
+
+/// Milliseconds since the GPS epoch at uptime `at`.
+pub fn gps_time_ms_at(at: Instant) -> Option<u64> {
+    let sync = CLOCK.lock(|clock| clock.get())?;
+    u64::try_from(at.as_millis() as i64 + sync.offset_ms).ok()
+}
+
+/// Seconds since the GPS epoch.
+pub fn gps_time() -> Option<u32> {
+    gps_time_ms_at(Instant::now()).map(|ms| (ms / 1000) as u32)
+}
+
+/// Seconds since the Unix epoch, in UTC.
+pub fn unix_time() -> Option<u32> {
+    gps_time().map(gps_to_unix)
+}
+
+/// Time the device reports to the network: GPS time once it is known, and
+/// the uptime before that, which the network corrects.
+pub fn device_time() -> u32 {
+    gps_time().unwrap_or(Instant::now().as_secs() as u32)
+}
+
+pub fn gps_to_unix(gps_s: u32) -> u32 {
+    gps_s
+        .wrapping_add(GPS_EPOCH_UNIX_S)
+        .wrapping_sub(GPS_LEAP_S)
+}
+
+pub fn unix_to_gps(unix_s: u32) -> u32 {
+    unix_s
+        .wrapping_sub(GPS_EPOCH_UNIX_S)
+        .wrapping_add(GPS_LEAP_S)
+}
+
+/// Milliseconds since the GPS epoch of a UTC date and time of day.
+pub fn utc_to_gps_ms(year: i32, month: u8, day: u8, ms_of_day: u32) -> u64 {
+    let days = days_from_civil(year, month, day) as i64;
+    let unix_ms = days * 86_400_000 + ms_of_day as i64;
+    let gps_ms = unix_ms - (GPS_EPOCH_UNIX_S as i64 - GPS_LEAP_S as i64) * 1000;
+    gps_ms.max(0) as u64
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar.
+pub fn days_from_civil(year: i32, month: u8, day: u8) -> i32 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i32;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i32 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Date of a number of days since 1970-01-01, as `(year, month, day)`.
+pub fn civil_from_days(days: i32) -> (i32, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + (month <= 2) as i32;
+    (year, month, day)
+}
+
+/// A point in time, displayed in UTC when the system time is known and as
+/// the uptime otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timestamp {
+    Unix(u32),
+    Uptime(u64),
+}
+
+/// The current time, for logs.
+pub fn timestamp() -> Timestamp {
+    match unix_time() {
+        Some(unix_s) => Timestamp::Unix(unix_s),
+        None => Timestamp::Uptime(Instant::now().as_millis()),
+    }
+}
+
+impl core::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match *self {
+            Timestamp::Unix(unix_s) => {
+                let (year, month, day) = civil_from_days((unix_s / 86_400) as i32);
+                let seconds = unix_s % 86_400;
+                write!(
+                    f,
+                    "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                    year,
+                    month,
+                    day,
+                    seconds / 3600,
+                    seconds / 60 % 60,
+                    seconds % 60
+                )
+            }
+            Timestamp::Uptime(ms) => write!(f, "+{}.{:03}s", ms / 1000, ms % 1000),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(1980, 1, 6), 3657);
+        assert_eq!(days_from_civil(2000, 2, 29), 11_016);
+        assert_eq!(days_from_civil(2024, 1, 1), 19_723);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+    }
+
+    #[test]
+    fn round_trips_days() {
+        for days in (-800_000..800_000).step_by(37) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn converts_utc_to_gps() {
+        // GPS time is ahead of UTC by the leap seconds since its epoch.
+        assert_eq!(utc_to_gps_ms(1980, 1, 6, 0), GPS_LEAP_S as u64 * 1000);
+        assert_eq!(
+            utc_to_gps_ms(2024, 1, 1, 12 * 3_600_000 + 500),
+            1_388_145_618_500
+        );
+        // Dates before the GPS epoch are clamped to it.
+        assert_eq!(utc_to_gps_ms(1970, 1, 1, 0), 0);
+    }
+
+    #[test]
+    fn converts_gps_and_unix() {
+        assert_eq!(unix_to_gps(1_704_067_200), 1_388_102_418);
+        assert_eq!(gps_to_unix(1_388_102_418), 1_704_067_200);
+        assert_eq!(gps_to_unix(GPS_LEAP_S), GPS_EPOCH_UNIX_S);
+        for unix_s in [GPS_EPOCH_UNIX_S, 1_704_067_200, u32::MAX - 100] {
+            assert_eq!(gps_to_unix(unix_to_gps(unix_s)), unix_s);
+        }
+    }
+
+    #[test]
+    fn displays_timestamps() {
+        assert_eq!(
+            std::format!("{}", Timestamp::Unix(1_704_067_261)),
+            "2024-01-01T00:01:01Z"
+        );
+        assert_eq!(
+            std::format!("{}", Timestamp::Unix(951_868_799)),
+            "2000-02-29T23:59:59Z"
+        );
+        assert_eq!(std::format!("{}", Timestamp::Uptime(12_034)), "+12.034s");
+    }
+}
@@ -0,0 +1,380 @@
+use super::downlink::{push_answer, Answer, CommandError};
+
+/// FPort of the LoRaWAN Application Layer Clock Synchronization package
+/// (TS003).
+pub const CLOCK_SYNC_PORT: u8 = 202;
+/// Length of an `AppTimeReq` uplink.
+pub const APP_TIME_REQ_LEN: usize = 6;
+/// Periodicity of the time requests until the network sets one, about 18
+/// hours.
+pub const DEFAULT_PERIODICITY: u8 = 9;
+/// Delay before asking again when a request went unanswered.
+pub const RETRY_INTERVAL_S: u32 = 600;
+
+const PACKAGE_IDENTIFIER: u8 = 1;
+const PACKAGE_VERSION: u8 = 1;
+const ANS_REQUIRED: u8 = 0x10;
+
+mod cid {
+    pub const PACKAGE_VERSION: u8 = 0x00;
+    pub const APP_TIME: u8 = 0x01;
+    pub const DEVICE_APP_TIME_PERIODICITY: u8 = 0x02;
+    pub const FORCE_DEVICE_RESYNC: u8 = 0x03;
+}
+
+/// Command of the clock synchronization package.
+///
+/// | CID    | Request                       | Arguments                      |
+/// |--------|-------------------------------|--------------------------------|
+/// | `0x00` | PackageVersionReq             | -                              |
+/// | `0x01` | AppTimeAns                    | correction (i32), token (3:0)  |
+/// | `0x02` | DeviceAppTimePeriodicityReq   | periodicity (3:0)              |
+/// | `0x03` | ForceDeviceResyncReq          | transmissions (2:0)            |
+///
+/// Multi-byte values are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockCommand {
+    PackageVersion,
+    AppTime { correction_s: i32, token: u8 },
+    Periodicity(u8),
+    ForceResync(u8),
+}
+
+impl ClockCommand {
+    /// Parses the command at the start of `bytes`, returning it with the
+    /// number of bytes it used.
+    ///
+    /// # Errors
+    ///
+    /// * `Empty` - If `bytes` is empty.
+    /// * `UnknownOpcode` - If the CID is not defined.
+    /// * `Truncated` - If the payload ends before the command's arguments.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), CommandError> {
+        let (&id, args) = bytes.split_first().ok_or(CommandError::Empty)?;
+        let arg = |len: usize| args.get(..len).ok_or(CommandError::Truncated(id));
+        let (command, len) = match id {
+            cid::PACKAGE_VERSION => (ClockCommand::PackageVersion, 0),
+            cid::APP_TIME => {
+                let a = arg(5)?;
+                let command = ClockCommand::AppTime {
+                    correction_s: i32::from_le_bytes([a[0], a[1], a[2], a[3]]),
+                    token: a[4] & 0x0F,
+                };
+                (command, 5)
+            }
+            cid::DEVICE_APP_TIME_PERIODICITY => (ClockCommand::Periodicity(arg(1)?[0] & 0x0F), 1),
+            cid::FORCE_DEVICE_RESYNC => (ClockCommand::ForceResync(arg(1)?[0] & 0x07), 1),
+            _ => return Err(CommandError::UnknownOpcode(id)),
+        };
+        Ok((command, 1 + len))
+    }
+}
+
+/// Seconds between time requests for a periodicity value.
+pub fn period_s(periodicity: u8) -> u32 {
+    128 << periodicity.min(15)
+}
+
+/// Writes `device_time` into the `AppTimeReq` and
+/// `DeviceAppTimePeriodicityAns` of an uplink `payload` of the package.
+///
+/// Uplinks wait in the queue and may be sent again, so the time is stamped
+/// right before each transmission; the network's correction is only right
+/// for the time the uplink went out.
+///
+/// | CID    | Uplink                        | Arguments                      |
+/// |--------|-------------------------------|--------------------------------|
+/// | `0x00` | PackageVersionAns             | identifier, version            |
+/// | `0x01` | AppTimeReq                    | DeviceTime (u32), param        |
+/// | `0x02` | DeviceAppTimePeriodicityAns   | status, time (u32)             |
+pub fn stamp(payload: &mut [u8], device_time: u32) {
+    let mut start = 0;
+    while let Some(&id) = payload.get(start) {
+        let (time_at, len) = match id {
+            cid::PACKAGE_VERSION => (None, 3),
+            cid::APP_TIME => (Some(1), 6),
+            cid::DEVICE_APP_TIME_PERIODICITY => (Some(2), 6),
+            _ => return,
+        };
+        let Some(command) = payload.get_mut(start..start + len) else {
+            return;
+        };
+        if let Some(at) = time_at {
+            command[at..at + 4].copy_from_slice(&device_time.to_le_bytes());
+        }
+        start += len;
+    }
+}
+
+/// Clock synchronization package (TS003 v1.0.0).
+///
+/// The device asks for the time with `AppTimeReq` once at start, then every
+/// period; the network answers with the correction to apply. Times are in
+/// seconds since the GPS epoch and `now_s` is the uptime used to schedule
+/// requests.
+pub struct ClockSync {
+    token: u8,
+    periodicity: u8,
+    /// Requests left to send on `ForceDeviceResyncReq`.
+    forced: u8,
+    next_request_s: u32,
+    synced: bool,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self {
+            token: 0,
+            periodicity: DEFAULT_PERIODICITY,
+            forced: 0,
+            next_request_s: 0,
+            synced: false,
+        }
+    }
+}
+
+impl ClockSync {
+    /// Whether an `AppTimeAns` was applied since start.
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    pub fn period_s(&self) -> u32 {
+        period_s(self.periodicity)
+    }
+
+    /// Builds the `AppTimeReq` to send at uptime `now_s`, if one is due.
+    ///
+    /// `device_time` is the device's idea of the GPS time, stamped again
+    /// with [`stamp`] when the request goes out; the network answers even
+    /// without a correction until the clock was synchronized.
+    /// Without `periodic` only the requests forced by the network are sent,
+    /// for devices that get the time elsewhere.
+    pub fn poll(
+        &mut self,
+        now_s: u32,
+        device_time: u32,
+        periodic: bool,
+    ) -> Option<[u8; APP_TIME_REQ_LEN]> {
+        if self.forced > 0 {
+            self.forced -= 1;
+        } else if periodic && now_s >= self.next_request_s {
+            // Retried soon until answered, then every period.
+            let delay = if self.synced {
+                self.period_s()
+            } else {
+                RETRY_INTERVAL_S
+            };
+            self.next_request_s = now_s.saturating_add(delay);
+        } else {
+            return None;
+        }
+        let time = device_time.to_le_bytes();
+        let param = self.token | if self.synced { 0 } else { ANS_REQUIRED };
+        Some([cid::APP_TIME, time[0], time[1], time[2], time[3], param])
+    }
+
+    /// Handles a downlink of the package at uptime `now_s`, appending the
+    /// answers to `answer`.
+    ///
+    /// Returns the time correction to apply, in seconds.
+    pub fn handle(
+        &mut self,
+        payload: &[u8],
+        now_s: u32,
+        device_time: u32,
+        answer: &mut Answer,
+    ) -> Option<i32> {
+        let mut correction = None;
+        let mut bytes = payload;
+        while !bytes.is_empty() {
+            let command = match ClockCommand::parse(bytes) {
+                Ok((command, len)) => {
+                    bytes = &bytes[len..];
+                    command
+                }
+                Err(e) => {
+                    esp_println::println!("[CLOCK] {}", e);
+                    break;
+                }
+            };
+            match command {
+                ClockCommand::PackageVersion => {
+                    push_answer(
+                        answer,
+                        &[cid::PACKAGE_VERSION, PACKAGE_IDENTIFIER, PACKAGE_VERSION],
+                    );
+                }
+                // Answers to earlier requests are stale.
+                ClockCommand::AppTime { token, .. } if token != self.token => {}
+                ClockCommand::AppTime { correction_s, .. } => {
+                    self.token = (self.token + 1) & 0x0F;
+                    self.synced = true;
+                    self.next_request_s = now_s.saturating_add(self.period_s());
+                    correction = Some(correction_s);
+                }
+                ClockCommand::Periodicity(periodicity) => {
+                    self.periodicity = periodicity;
+                    self.next_request_s = now_s.saturating_add(self.period_s());
+                    let time = device_time.to_le_bytes();
+                    push_answer(
+                        answer,
+                        &[
+                            cid::DEVICE_APP_TIME_PERIODICITY,
+                            0,
+                            time[0],
+                            time[1],
+                            time[2],
+                            time[3],
+                        ],
+                    );
+                }
+                ClockCommand::ForceResync(transmissions) => self.forced = transmissions,
+            }
+        }
+        correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `AppTimeAns` with `correction_s` and `token`.
+    fn app_time_ans(correction_s: i32, token: u8) -> [u8; 6] {
+        let c = correction_s.to_le_bytes();
+        [cid::APP_TIME, c[0], c[1], c[2], c[3], token]
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            ClockCommand::parse(&[0x01, 0xFB, 0xFF, 0xFF, 0xFF, 0x13]),
+            Ok((
+                ClockCommand::AppTime {
+                    correction_s: -5,
+                    token: 3
+                },
+                6
+            ))
+        );
+        assert_eq!(
+            ClockCommand::parse(&[0x02, 0xF4]),
+            Ok((ClockCommand::Periodicity(4), 2))
+        );
+        assert_eq!(
+            ClockCommand::parse(&[0x03, 0x0F]),
+            Ok((ClockCommand::ForceResync(7), 2))
+        );
+        assert_eq!(
+            ClockCommand::parse(&[0x01, 0, 0]),
+            Err(CommandError::Truncated(1))
+        );
+        assert_eq!(
+            ClockCommand::parse(&[0x04]),
+            Err(CommandError::UnknownOpcode(4))
+        );
+    }
+
+    #[test]
+    fn requests_until_answered() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.poll(0, 100, true), Some([0x01, 100, 0, 0, 0, 0x10]));
+        assert_eq!(sync.poll(RETRY_INTERVAL_S - 1, 0, true), None);
+        // Unanswered requests are retried with the same token.
+        assert_eq!(
+            sync.poll(RETRY_INTERVAL_S, 200, true),
+            Some([0x01, 200, 0, 0, 0, 0x10])
+        );
+        assert!(!sync.is_synced());
+    }
+
+    #[test]
+    fn matches_tokens() {
+        let mut sync = ClockSync::default();
+        let mut answer = Answer::new();
+        sync.poll(0, 100, true);
+        // An answer to an earlier request is stale.
+        assert_eq!(sync.handle(&app_time_ans(7, 3), 10, 0, &mut answer), None);
+        assert!(!sync.is_synced());
+        assert_eq!(
+            sync.handle(&app_time_ans(-5, 0), 10, 0, &mut answer),
+            Some(-5)
+        );
+        assert!(sync.is_synced());
+        // The token moved on, the same answer again is stale.
+        assert_eq!(sync.handle(&app_time_ans(-5, 0), 11, 0, &mut answer), None);
+        assert!(answer.is_empty());
+
+        // Once synchronized no answer is required and the next token is used.
+        let request = sync.poll(10 + sync.period_s(), 300, true);
+        assert_eq!(request, Some([0x01, 0x2C, 0x01, 0, 0, 0x01]));
+        assert_eq!(
+            sync.handle(&app_time_ans(1, 1), 10, 0, &mut answer),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn tokens_wrap() {
+        let mut sync = ClockSync::default();
+        let mut answer = Answer::new();
+        for token in 0..16 {
+            assert_eq!(
+                sync.handle(&app_time_ans(0, token), 0, 0, &mut answer),
+                Some(0)
+            );
+        }
+        assert_eq!(sync.poll(0, 0, false), None);
+        sync.handle(&[cid::FORCE_DEVICE_RESYNC, 1], 0, 0, &mut answer);
+        assert_eq!(sync.poll(0, 0, false), Some([0x01, 0, 0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn sets_periodicity() {
+        let mut sync = ClockSync::default();
+        let mut answer = Answer::new();
+        assert_eq!(sync.period_s(), 128 << DEFAULT_PERIODICITY);
+        let requests = [cid::PACKAGE_VERSION, cid::DEVICE_APP_TIME_PERIODICITY, 0x02];
+        sync.handle(&requests, 50, 0x0102_0304, &mut answer);
+        assert_eq!(
+            &answer[..],
+            &[0x00, 1, 1, 0x02, 0x00, 0x04, 0x03, 0x02, 0x01]
+        );
+        assert_eq!(sync.period_s(), 512);
+        assert_eq!(sync.poll(50 + 511, 0, true), None);
+        assert!(sync.poll(50 + 512, 0, true).is_some());
+        assert_eq!(period_s(15), 128 << 15);
+    }
+
+    #[test]
+    fn forces_resync() {
+        let mut sync = ClockSync::default();
+        let mut answer = Answer::new();
+        sync.poll(0, 0, true);
+        sync.handle(&[cid::FORCE_DEVICE_RESYNC, 0x02], 0, 0, &mut answer);
+        assert!(answer.is_empty());
+        // Forced requests go out even when periodic ones are not wanted.
+        assert!(sync.poll(1, 0, false).is_some());
+        assert!(sync.poll(2, 0, false).is_some());
+        assert_eq!(sync.poll(3, 0, false), None);
+    }
+
+    #[test]
+    fn stamps_device_time() {
+        let mut payload = [
+            0x00, 1, 1, // PackageVersionAns
+            0x01, 0, 0, 0, 0, 0x12, // AppTimeReq
+            0x02, 0x00, 0, 0, 0, 0, // DeviceAppTimePeriodicityAns
+        ];
+        stamp(&mut payload, 0x0A0B_0C0D);
+        assert_eq!(
+            payload,
+            [0x00, 1, 1, 0x01, 0x0D, 0x0C, 0x0B, 0x0A, 0x12, 0x02, 0x00, 0x0D, 0x0C, 0x0B, 0x0A]
+        );
+        // A truncated command is left as is.
+        let mut payload = [0x01, 0, 0];
+        stamp(&mut payload, 0x0A0B_0C0D);
+        assert_eq!(payload, [0x01, 0, 0]);
+    }
+}
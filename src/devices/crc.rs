@@ -0,0 +1,55 @@
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320) lookup table.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Continues a CRC-32 computation from a previous value.
+///
+/// The semantics match zlib's `crc32(seed, data)`: passing `0` as the seed
+/// computes the standard CRC-32 of `data`, and passing the result of a previous
+/// call continues the checksum over concatenated data.
+pub fn crc32_update(seed: u32, data: &[u8]) -> u32 {
+    let mut crc = !seed;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Computes the standard CRC-32 of `data`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Computes the CRC-16/CCITT of `data` (polynomial 0x1021, initial value 0,
+/// not reflected), as used by LoRaWAN class B beacons and the P2P frames.
+pub fn crc16_ccitt(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -12,6 +12,11 @@ use heapless::String;
 use qrcodegen_no_heap::{QrCode, QrCodeEcc, Version};
 use ssd1306::{mode::DisplayConfigAsync, size::DisplaySize128x64, I2CDisplayInterface};
 
+use super::{
+    link_status::{self, LinkStatus},
+    p2p_pairing::{self, PairingStatus},
+};
+
 pub static DISPLAY_SIGNAL: Signal<CriticalSectionRawMutex, String<64>> = Signal::new();
 
 #[derive(Debug)]
@@ -26,7 +31,6 @@ type Display<'a> = ssd1306::Ssd1306Async<
     ssd1306::mode::BufferedGraphicsModeAsync<DisplaySize128x64>,
 >;
 
-
 fn show_qr(
     display: &mut Display,
     qr: &QrCode,
@@ -59,19 +63,38 @@ fn show_qr(
     Ok(())
 }
 
+/// Formats a value of the link table, "--" while it is not known.
+fn link_value<T: core::fmt::Display>(value: Option<T>, unit: &str) -> String<12> {
+    let mut msg = String::new();
+    let _ = match value {
+        Some(value) => core::fmt::write(&mut msg, core::format_args!("{}{}", value, unit)),
+        None => core::fmt::write(&mut msg, core::format_args!("--")),
+    };
+    msg
+}
+
 async fn show_table<'a>(
     display: &mut Display<'a>,
     text_style: MonoTextStyleBuilder<'a, BinaryColor>,
-    freq: &str,
-    snr: &str,
-    rssi: &str,
+    status: &LinkStatus,
 ) {
     const START_X: i32 = 50;
     const START_Y: i32 = 5;
     const LINE_SPACING: i32 = 12;
 
-    let labels = ["MHz:", "SNR:", "RSSI:"];
-    let values = [freq, snr, rssi];
+    // P2P nodes count their neighbours in place of the gateways.
+    let (nodes_label, nodes) = match status.peers {
+        Some(peers) => ("Peer:", Some(peers)),
+        None => ("GW:", status.gateways),
+    };
+    let labels = ["MHz:", "SNR:", "RSSI:", "Marg:", nodes_label];
+    let values = [
+        link_value(status.frequency_hz.map(|hz| hz as f32 / 1_000_000.0), ""),
+        link_value(status.snr_db, " dB"),
+        link_value(status.rssi_dbm, " dBm"),
+        link_value(status.margin_db, " dB"),
+        link_value(nodes, ""),
+    ];
 
     // 1. Create a single reusable String buffer
     let mut msg = heapless::String::<64>::new();
@@ -81,7 +104,7 @@ async fn show_table<'a>(
         msg.clear();
 
         // 3. Improved error handling
-        if let Err(e) = core::fmt::write(&mut msg, core::format_args!("{} {}", label, value)) {
+        if let Err(e) = core::fmt::write(&mut msg, core::format_args!("{} {:<7}", label, value)) {
             esp_println::println!("[OLED] Format error (line {}): {:?}", i, e);
             continue;
         }
@@ -104,6 +127,84 @@ async fn show_table<'a>(
     }
 }
 
+/// Shows the progress of pairing in place of the link table.
+async fn show_pairing<'a>(
+    display: &mut Display<'a>,
+    text_style: MonoTextStyleBuilder<'a, BinaryColor>,
+    status: &PairingStatus,
+) {
+    const START_X: i32 = 50;
+    const START_Y: i32 = 5;
+    const LINE_SPACING: i32 = 12;
+
+    let mut lines: [String<16>; 5] = Default::default();
+    let mut line = |i: usize, args: core::fmt::Arguments| {
+        let _ = core::fmt::write(&mut lines[i], args);
+    };
+    match *status {
+        PairingStatus::Idle => (),
+        PairingStatus::Searching => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("Searching"));
+            line(3, core::format_args!("Hold: cancel"));
+        }
+        PairingStatus::Exchanging { peer } => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("Node {:04X}", peer));
+            line(3, core::format_args!("Hold: cancel"));
+        }
+        PairingStatus::Verifying { peer, code } => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("Node {:04X}", peer));
+            line(
+                2,
+                core::format_args!("Code {:03} {:03}", code / 1000, code % 1000),
+            );
+            line(3, core::format_args!("Hold: accept"));
+        }
+        PairingStatus::Confirming { peer } => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("Node {:04X}", peer));
+            line(2, core::format_args!("Waiting peer"));
+            line(3, core::format_args!("Hold: cancel"));
+        }
+        PairingStatus::Paired { peer } => {
+            line(0, core::format_args!("Paired"));
+            line(1, core::format_args!("Node {:04X}", peer));
+        }
+        PairingStatus::TimedOut => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("timed out"));
+        }
+        PairingStatus::Cancelled => {
+            line(0, core::format_args!("Pairing"));
+            line(1, core::format_args!("cancelled"));
+        }
+    }
+
+    let mut msg = heapless::String::<64>::new();
+    for (i, line) in lines.iter().enumerate() {
+        msg.clear();
+        // Padded to overwrite the link table.
+        if let Err(e) = core::fmt::write(&mut msg, core::format_args!("{:<13}", line)) {
+            esp_println::println!("[OLED] Format error (line {}): {:?}", i, e);
+            continue;
+        }
+        let y_pos = START_Y + (i as i32 * LINE_SPACING);
+        match Text::with_baseline(
+            &msg,
+            Point::new(START_X, y_pos),
+            text_style.build(),
+            Baseline::Top,
+        )
+        .draw(display)
+        {
+            Ok(_) => (),
+            Err(e) => esp_println::println!("[OLED] Draw failed (line {}): {:?}", i, e),
+        }
+    }
+}
+
 #[embassy_executor::task]
 pub async fn display(i2c: I2c<'static, Async>, mut reset: Output<'static>) {
     esp_println::println!("[OLED] Starting display task");
@@ -127,7 +228,9 @@ pub async fn display(i2c: I2c<'static, Async>, mut reset: Output<'static>) {
 
     let text_style = MonoTextStyleBuilder::new()
         .font(&FONT_6X9)
-        .text_color(BinaryColor::On);
+        .text_color(BinaryColor::On)
+        // Redrawn values overwrite the previous ones.
+        .background_color(BinaryColor::Off);
 
     let qr_style = PrimitiveStyleBuilder::new()
         .fill_color(BinaryColor::On)
@@ -136,9 +239,6 @@ pub async fn display(i2c: I2c<'static, Async>, mut reset: Output<'static>) {
     let mut out_buffer = [0u8; Version::MAX.buffer_len()];
     let mut temp_buffer = [0u8; Version::MAX.buffer_len()];
 
-    let freq = "868.1";
-    let snr = "10 dB";
-    let rssi = "-98 dBm";
     loop {
         match DISPLAY_SIGNAL.try_take() {
             Some(value) => {
@@ -153,7 +253,12 @@ pub async fn display(i2c: I2c<'static, Async>, mut reset: Output<'static>) {
             None => (),
         };
 
-        show_table(&mut display, text_style, freq, snr, rssi).await;
+        match p2p_pairing::pairing_status() {
+            PairingStatus::Idle => {
+                show_table(&mut display, text_style, &link_status::link_status()).await
+            }
+            status => show_pairing(&mut display, text_style, &status).await,
+        }
         match display.flush().await {
             Ok(()) => (),
             // Err(e) => esp_println::println!("[OLED] Display flush error: {:#?}", e),
@@ -0,0 +1,487 @@
+use super::lorawan_config::DeviceClass;
+
+/// FPort carrying remote commands and their acknowledgements.
+pub const COMMAND_PORT: u8 = 10;
+/// Maximum number of handlers a [`Dispatcher`] can hold.
+pub const MAX_HANDLERS: usize = 8;
+/// Maximum size of the acknowledgements sent in one uplink.
+pub const MAX_ACK_LEN: usize = 32;
+/// Size of an acknowledgement without data.
+const ACK_LEN: usize = 2;
+
+mod opcode {
+    pub const SET_UPLINK_INTERVAL: u8 = 0x01;
+    pub const SET_LED: u8 = 0x02;
+    pub const REBOOT: u8 = 0x03;
+    pub const REQUEST_STATUS: u8 = 0x04;
+    pub const SET_DATA_RATE: u8 = 0x05;
+    pub const SET_CLASS: u8 = 0x06;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Empty,
+    UnknownOpcode(u8),
+    Truncated(u8),
+    InvalidArgument(u8),
+}
+
+impl core::fmt::Display for CommandError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "Empty command"),
+            CommandError::UnknownOpcode(op) => write!(f, "Unknown command {:#04x}", op),
+            CommandError::Truncated(op) => write!(f, "Command {:#04x} is truncated", op),
+            CommandError::InvalidArgument(op) => {
+                write!(f, "Invalid argument for command {:#04x}", op)
+            }
+        }
+    }
+}
+
+impl CommandError {
+    /// Opcode of the command that failed to parse, 0 for an empty payload.
+    pub fn opcode(&self) -> u8 {
+        match self {
+            CommandError::Empty => 0,
+            CommandError::UnknownOpcode(op)
+            | CommandError::Truncated(op)
+            | CommandError::InvalidArgument(op) => *op,
+        }
+    }
+
+    pub fn status(&self) -> AckStatus {
+        match self {
+            CommandError::UnknownOpcode(_) => AckStatus::UnknownCommand,
+            CommandError::Empty | CommandError::Truncated(_) => AckStatus::Truncated,
+            CommandError::InvalidArgument(_) => AckStatus::InvalidArgument,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DispatchError {
+    InvalidPort(u8),
+    PortTaken(u8),
+    Full,
+}
+
+impl core::fmt::Display for DispatchError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            DispatchError::InvalidPort(port) => write!(f, "Port {} is reserved", port),
+            DispatchError::PortTaken(port) => write!(f, "Port {} already has a handler", port),
+            DispatchError::Full => write!(f, "Too many handlers"),
+        }
+    }
+}
+
+/// Remote command received on [`COMMAND_PORT`].
+///
+/// A downlink carries one or more commands back to back, each an opcode
+/// followed by its arguments:
+///
+/// | Opcode | Arguments                 | Command                  |
+/// |--------|---------------------------|--------------------------|
+/// | `0x01` | interval in seconds (u16) | Set the uplink interval  |
+/// | `0x02` | 0 = off, 1 = on           | Set the LED              |
+/// | `0x03` | -                         | Reboot after the ack     |
+/// | `0x04` | -                         | Report the device status |
+/// | `0x05` | data rate                 | Change the data rate     |
+/// | `0x06` | 0 = A, 1 = B, 2 = C       | Switch the device class  |
+///
+/// Multi-byte values are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    SetUplinkInterval(u16),
+    SetLed(bool),
+    Reboot,
+    RequestStatus,
+    SetDataRate(u8),
+    SetClass(DeviceClass),
+}
+
+impl Command {
+    pub fn opcode(&self) -> u8 {
+        match self {
+            Command::SetUplinkInterval(_) => opcode::SET_UPLINK_INTERVAL,
+            Command::SetLed(_) => opcode::SET_LED,
+            Command::Reboot => opcode::REBOOT,
+            Command::RequestStatus => opcode::REQUEST_STATUS,
+            Command::SetDataRate(_) => opcode::SET_DATA_RATE,
+            Command::SetClass(_) => opcode::SET_CLASS,
+        }
+    }
+
+    /// Parses the command at the start of `bytes`, returning it with the
+    /// number of bytes it used.
+    ///
+    /// # Errors
+    ///
+    /// * `Empty` - If `bytes` is empty.
+    /// * `UnknownOpcode` - If the opcode is not defined.
+    /// * `Truncated` - If the payload ends before the command's arguments.
+    /// * `InvalidArgument` - If an argument is out of range.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), CommandError> {
+        let (&op, args) = bytes.split_first().ok_or(CommandError::Empty)?;
+        let arg = |len: usize| args.get(..len).ok_or(CommandError::Truncated(op));
+        let command = match op {
+            opcode::SET_UPLINK_INTERVAL => {
+                let value = arg(2)?;
+                let seconds = u16::from_be_bytes([value[0], value[1]]);
+                if seconds == 0 {
+                    return Err(CommandError::InvalidArgument(op));
+                }
+                Command::SetUplinkInterval(seconds)
+            }
+            opcode::SET_LED => match arg(1)?[0] {
+                0 => Command::SetLed(false),
+                1 => Command::SetLed(true),
+                _ => return Err(CommandError::InvalidArgument(op)),
+            },
+            opcode::REBOOT => Command::Reboot,
+            opcode::REQUEST_STATUS => Command::RequestStatus,
+            opcode::SET_DATA_RATE => Command::SetDataRate(arg(1)?[0]),
+            opcode::SET_CLASS => match DeviceClass::from_u8(arg(1)?[0]) {
+                Some(class) => Command::SetClass(class),
+                None => return Err(CommandError::InvalidArgument(op)),
+            },
+            _ => return Err(CommandError::UnknownOpcode(op)),
+        };
+        Ok((command, 1 + command.args_len()))
+    }
+
+    fn args_len(&self) -> usize {
+        match self {
+            Command::SetUplinkInterval(_) => 2,
+            Command::SetLed(_) | Command::SetDataRate(_) | Command::SetClass(_) => 1,
+            Command::Reboot | Command::RequestStatus => 0,
+        }
+    }
+}
+
+/// Iterator over the commands of a downlink payload.
+///
+/// Parsing stops after the first error, as the length of an unknown or
+/// malformed command, and so the start of the next one, is unknown.
+pub struct Commands<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Commands<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl Iterator for Commands<'_> {
+    type Item = Result<Command, CommandError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        match Command::parse(self.bytes) {
+            Ok((command, len)) => {
+                self.bytes = &self.bytes[len..];
+                Some(Ok(command))
+            }
+            Err(e) => {
+                self.bytes = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Outcome of a command, reported in its acknowledgement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AckStatus {
+    Ok = 0,
+    UnknownCommand = 1,
+    Truncated = 2,
+    InvalidArgument = 3,
+    Rejected = 4,
+    /// Marks that acknowledgements were dropped, see [`Acks`].
+    Overflow = 5,
+}
+
+/// Acknowledgements waiting for the next uplink.
+///
+/// Each acknowledgement is the opcode of the command, its [`AckStatus`] and
+/// any data the command returns. Acknowledgements that do not fit are
+/// dropped; the uplink then ends with opcode 0 and [`AckStatus::Overflow`],
+/// for which room is always kept, so the sender knows to query again.
+#[derive(Debug, Default)]
+pub struct Acks {
+    bytes: heapless::Vec<u8, MAX_ACK_LEN>,
+    overflowed: bool,
+}
+
+impl Acks {
+    /// Queues an acknowledgement, returning `false` if it did not fit.
+    ///
+    /// Once one did not fit, the later ones are dropped too, so the overflow
+    /// marker stays last.
+    pub fn push(&mut self, opcode: u8, status: AckStatus, data: &[u8]) -> bool {
+        if self.overflowed {
+            return false;
+        }
+        if self.bytes.len() + ACK_LEN + data.len() > MAX_ACK_LEN - ACK_LEN {
+            self.overflowed = true;
+            // Cannot fail, room for the marker is kept.
+            let _ = self
+                .bytes
+                .extend_from_slice(&[0, AckStatus::Overflow as u8]);
+            return false;
+        }
+        // Cannot fail, the length was checked above.
+        let _ = self.bytes.extend_from_slice(&[opcode, status as u8]);
+        let _ = self.bytes.extend_from_slice(data);
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Whether acknowledgements were dropped since the last
+    /// [`Acks::clear`].
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Drops the queued acknowledgements once they were sent.
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.overflowed = false;
+    }
+}
+
+/// Answers of an application layer package, sent on the package's port.
+pub type Answer = heapless::Vec<u8, MAX_ACK_LEN>;
+
+/// Appends `bytes` to `answer`, returning `false` and dropping them if they
+/// do not fit.
+pub fn push_answer(answer: &mut Answer, bytes: &[u8]) -> bool {
+    answer.len() + bytes.len() <= answer.capacity() && answer.extend_from_slice(bytes).is_ok()
+}
+
+/// Handler for the downlinks of one FPort.
+pub type Handler<C> = fn(&mut C, &[u8], &mut Acks);
+
+/// Routes downlinks to the handler registered for their FPort.
+pub struct Dispatcher<C> {
+    handlers: heapless::Vec<(u8, Handler<C>), MAX_HANDLERS>,
+}
+
+impl<C> Default for Dispatcher<C> {
+    fn default() -> Self {
+        Self {
+            handlers: heapless::Vec::new(),
+        }
+    }
+}
+
+impl<C> Dispatcher<C> {
+    /// Registers `handler` for the application port `port`.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidPort` - If the port is 0 (MAC commands) or reserved (224-255).
+    /// * `PortTaken` - If the port already has a handler.
+    /// * `Full` - If [`MAX_HANDLERS`] handlers are registered.
+    pub fn register(&mut self, port: u8, handler: Handler<C>) -> Result<(), DispatchError> {
+        if !(1..=223).contains(&port) {
+            return Err(DispatchError::InvalidPort(port));
+        }
+        if self.handlers.iter().any(|(p, _)| *p == port) {
+            return Err(DispatchError::PortTaken(port));
+        }
+        self.handlers
+            .push((port, handler))
+            .map_err(|_| DispatchError::Full)
+    }
+
+    /// Passes a downlink to the handler of its port, returning `false` if the
+    /// port has none.
+    pub fn dispatch(&self, context: &mut C, port: u8, payload: &[u8], acks: &mut Acks) -> bool {
+        match self.handlers.iter().find(|(p, _)| *p == port) {
+            Some((_, handler)) => {
+                handler(context, payload, acks);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> std::vec::Vec<Result<Command, CommandError>> {
+        Commands::new(bytes).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse(&[]), []);
+        assert_eq!(
+            parse(&[0x01, 0x00, 0x3C, 0x02, 0x01, 0x03, 0x04, 0x05, 0x03, 0x06, 0x02]),
+            [
+                Ok(Command::SetUplinkInterval(60)),
+                Ok(Command::SetLed(true)),
+                Ok(Command::Reboot),
+                Ok(Command::RequestStatus),
+                Ok(Command::SetDataRate(3)),
+                Ok(Command::SetClass(DeviceClass::C)),
+            ]
+        );
+        assert_eq!(
+            Command::parse(&[0x02, 0x00, 0xFF]),
+            Ok((Command::SetLed(false), 2))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert_eq!(Command::parse(&[]), Err(CommandError::Empty));
+        assert_eq!(parse(&[0x00]), [Err(CommandError::UnknownOpcode(0x00))]);
+        assert_eq!(
+            parse(&[0x07, 0x01]),
+            [Err(CommandError::UnknownOpcode(0x07))]
+        );
+        assert_eq!(parse(&[0x01]), [Err(CommandError::Truncated(0x01))]);
+        assert_eq!(parse(&[0x01, 0x00]), [Err(CommandError::Truncated(0x01))]);
+        assert_eq!(parse(&[0x02]), [Err(CommandError::Truncated(0x02))]);
+        assert_eq!(parse(&[0x05]), [Err(CommandError::Truncated(0x05))]);
+        assert_eq!(parse(&[0x06]), [Err(CommandError::Truncated(0x06))]);
+        assert_eq!(
+            parse(&[0x01, 0x00, 0x00]),
+            [Err(CommandError::InvalidArgument(0x01))]
+        );
+        assert_eq!(
+            parse(&[0x02, 0x02]),
+            [Err(CommandError::InvalidArgument(0x02))]
+        );
+        assert_eq!(
+            parse(&[0x06, 0x03]),
+            [Err(CommandError::InvalidArgument(0x06))]
+        );
+
+        // Parsing stops at the first error, the commands before it stand.
+        assert_eq!(
+            parse(&[0x04, 0x99, 0x03, 0x03]),
+            [
+                Ok(Command::RequestStatus),
+                Err(CommandError::UnknownOpcode(0x99))
+            ]
+        );
+        assert_eq!(
+            parse(&[0x03, 0x01, 0x00]),
+            [Ok(Command::Reboot), Err(CommandError::Truncated(0x01))]
+        );
+    }
+
+    #[test]
+    fn errors_map_to_ack_status() {
+        assert_eq!(CommandError::Empty.opcode(), 0);
+        assert_eq!(CommandError::Empty.status(), AckStatus::Truncated);
+        assert_eq!(
+            CommandError::UnknownOpcode(9).status(),
+            AckStatus::UnknownCommand
+        );
+        assert_eq!(CommandError::Truncated(1).opcode(), 1);
+        assert_eq!(CommandError::Truncated(1).status(), AckStatus::Truncated);
+        assert_eq!(
+            CommandError::InvalidArgument(2).status(),
+            AckStatus::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn never_panics_on_random_payloads() {
+        let mut seed = 1u32;
+        for _ in 0..100_000 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let len = (seed >> 28) as usize;
+            // Opcodes and arguments mostly in the defined ranges.
+            let bytes: std::vec::Vec<u8> =
+                (0..len).map(|i| (seed >> (i % 4 * 8)) as u8 % 8).collect();
+            let commands = parse(&bytes);
+            // At most the last item is an error.
+            let errors = commands.iter().filter(|command| command.is_err()).count();
+            assert!(errors <= 1);
+            assert!(errors == 0 || commands.last().unwrap().is_err());
+            let used: usize = commands
+                .iter()
+                .flatten()
+                .map(|command| 1 + command.args_len())
+                .sum();
+            assert!(used <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn overflow_is_marked_in_the_acks() {
+        let mut acks = Acks::default();
+        assert!(acks.push(0x04, AckStatus::Ok, &[0xAA; 12]));
+        for _ in 0..8 {
+            assert!(acks.push(0x02, AckStatus::Ok, &[]));
+        }
+        assert!(!acks.overflowed());
+        assert_eq!(acks.as_slice().len(), MAX_ACK_LEN - ACK_LEN);
+
+        assert!(!acks.push(0x02, AckStatus::Ok, &[]));
+        assert!(acks.overflowed());
+        // A shorter one after the overflow is dropped too.
+        assert!(!acks.push(0x03, AckStatus::Ok, &[]));
+        let bytes = acks.as_slice();
+        assert_eq!(bytes.len(), MAX_ACK_LEN);
+        assert_eq!(&bytes[..2], &[0x04, AckStatus::Ok as u8]);
+        assert_eq!(&bytes[MAX_ACK_LEN - 2..], &[0, AckStatus::Overflow as u8]);
+
+        acks.clear();
+        assert!(acks.is_empty() && !acks.overflowed());
+        assert!(acks.push(0x05, AckStatus::Rejected, &[]));
+        assert_eq!(acks.as_slice(), &[0x05, AckStatus::Rejected as u8]);
+    }
+
+    #[test]
+    fn dispatches_by_port() {
+        fn count(calls: &mut u32, payload: &[u8], _: &mut Acks) {
+            *calls += payload.len() as u32;
+        }
+        let mut dispatcher: Dispatcher<u32> = Dispatcher::default();
+        assert_eq!(
+            dispatcher.register(0, count),
+            Err(DispatchError::InvalidPort(0))
+        );
+        assert_eq!(
+            dispatcher.register(224, count),
+            Err(DispatchError::InvalidPort(224))
+        );
+        dispatcher.register(10, count).unwrap();
+        assert_eq!(
+            dispatcher.register(10, count),
+            Err(DispatchError::PortTaken(10))
+        );
+        for port in 11..11 + MAX_HANDLERS as u8 - 1 {
+            dispatcher.register(port, count).unwrap();
+        }
+        assert_eq!(dispatcher.register(100, count), Err(DispatchError::Full));
+
+        let (mut calls, mut acks) = (0, Acks::default());
+        assert!(dispatcher.dispatch(&mut calls, 10, &[1, 2, 3], &mut acks));
+        assert_eq!(calls, 3);
+        assert!(!dispatcher.dispatch(&mut calls, 100, &[1], &mut acks));
+        assert_eq!(calls, 3);
+    }
+}
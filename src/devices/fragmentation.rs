@@ -0,0 +1,817 @@
+use embedded_storage::Storage;
+
+use super::downlink::{push_answer, Answer, CommandError};
+
+/// FPort of the LoRaWAN Fragmented Data Block Transport package (TS004).
+pub const FRAGMENTATION_PORT: u8 = 201;
+/// Largest number of fragments of a data block.
+pub const MAX_FRAGMENTS: usize = 8_192;
+/// Largest number of lost fragments that forward error correction can
+/// recover; bounds the size of the decoding matrix.
+pub const MAX_REDUNDANCY: usize = 128;
+/// Largest fragment, the biggest downlink payload less the fragment header.
+pub const MAX_FRAGMENT_LEN: usize = 239;
+
+const PACKAGE_IDENTIFIER: u8 = 3;
+const PACKAGE_VERSION: u8 = 1;
+/// The only fragmentation algorithm defined by the package.
+const FRAG_ALGO_PARITY: u8 = 0;
+
+mod cid {
+    pub const PACKAGE_VERSION: u8 = 0x00;
+    pub const FRAG_SESSION_STATUS: u8 = 0x01;
+    pub const FRAG_SESSION_SETUP: u8 = 0x02;
+    pub const FRAG_SESSION_DELETE: u8 = 0x03;
+    pub const DATA_FRAGMENT: u8 = 0x08;
+}
+
+mod setup_status {
+    pub const ENCODING_UNSUPPORTED: u8 = 0x01;
+    pub const NOT_ENOUGH_MEMORY: u8 = 0x02;
+    pub const INDEX_NOT_SUPPORTED: u8 = 0x04;
+}
+
+const DELETE_NO_SESSION: u8 = 0x04;
+const STATUS_NOT_ENOUGH_MATRIX_MEMORY: u8 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub enum FragError {
+    Storage,
+    Length(usize),
+    Index(u16),
+}
+
+impl core::fmt::Display for FragError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FragError::Storage => write!(f, "Failed to access storage"),
+            FragError::Length(len) => write!(f, "Fragment of {} bytes has the wrong size", len),
+            FragError::Index(n) => write!(f, "Fragment {} is out of range", n),
+        }
+    }
+}
+
+/// Parameters of a fragmentation session, from `FragSessionSetupReq`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragSessionSetup {
+    /// Session slot, 0-3.
+    pub index: u8,
+    /// Multicast groups allowed to carry fragments; unicast always is.
+    pub mc_group_mask: u8,
+    /// Number of uncoded fragments of the data block.
+    pub nb_frag: u16,
+    pub frag_size: u8,
+    /// Answers to multicast requests are delayed by a random time of up to
+    /// `2^(block_ack_delay + 4)` seconds.
+    pub block_ack_delay: u8,
+    pub frag_algo: u8,
+    /// Bytes appended to the data block to fill the last fragment.
+    pub padding: u8,
+    /// Application defined description of the data block.
+    pub descriptor: u32,
+}
+
+impl FragSessionSetup {
+    /// Size of the data block, without padding.
+    pub fn data_len(&self) -> usize {
+        (self.nb_frag as usize * self.frag_size as usize).saturating_sub(self.padding as usize)
+    }
+}
+
+/// Command of the fragmentation package.
+///
+/// | CID    | Request               | Arguments                          |
+/// |--------|-----------------------|------------------------------------|
+/// | `0x00` | PackageVersionReq     | -                                  |
+/// | `0x01` | FragSessionStatusReq  | participants (bit 0), index (2:1)  |
+/// | `0x02` | FragSessionSetupReq   | see [`FragSessionSetup`], 10 bytes |
+/// | `0x03` | FragSessionDeleteReq  | index (1:0)                        |
+/// | `0x08` | DataFragment          | index (15:14), N (13:0), payload   |
+///
+/// Multi-byte values are little-endian. A data fragment takes the rest of
+/// the payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FragCommand<'a> {
+    PackageVersion,
+    SessionStatus {
+        participants: bool,
+        index: u8,
+    },
+    SessionSetup(FragSessionSetup),
+    SessionDelete {
+        index: u8,
+    },
+    DataFragment {
+        index: u8,
+        n: u16,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> FragCommand<'a> {
+    /// Parses the command at the start of `bytes`, returning it with the
+    /// number of bytes it used.
+    ///
+    /// # Errors
+    ///
+    /// * `Empty` - If `bytes` is empty.
+    /// * `UnknownOpcode` - If the CID is not defined.
+    /// * `Truncated` - If the payload ends before the command's arguments.
+    pub fn parse(bytes: &'a [u8]) -> Result<(Self, usize), CommandError> {
+        let (&id, args) = bytes.split_first().ok_or(CommandError::Empty)?;
+        let arg = |len: usize| args.get(..len).ok_or(CommandError::Truncated(id));
+        let (command, len) = match id {
+            cid::PACKAGE_VERSION => (FragCommand::PackageVersion, 0),
+            cid::FRAG_SESSION_STATUS => {
+                let param = arg(1)?[0];
+                let command = FragCommand::SessionStatus {
+                    participants: param & 0x01 != 0,
+                    index: (param >> 1) & 0x03,
+                };
+                (command, 1)
+            }
+            cid::FRAG_SESSION_SETUP => {
+                let a = arg(10)?;
+                let setup = FragSessionSetup {
+                    index: (a[0] >> 4) & 0x03,
+                    mc_group_mask: a[0] & 0x0F,
+                    nb_frag: u16::from_le_bytes([a[1], a[2]]),
+                    frag_size: a[3],
+                    block_ack_delay: (a[4] >> 3) & 0x07,
+                    frag_algo: a[4] & 0x07,
+                    padding: a[5],
+                    descriptor: u32::from_le_bytes([a[6], a[7], a[8], a[9]]),
+                };
+                (FragCommand::SessionSetup(setup), 10)
+            }
+            cid::FRAG_SESSION_DELETE => {
+                let index = arg(1)?[0] & 0x03;
+                (FragCommand::SessionDelete { index }, 1)
+            }
+            cid::DATA_FRAGMENT => {
+                let a = arg(2)?;
+                let index_and_n = u16::from_le_bytes([a[0], a[1]]);
+                let command = FragCommand::DataFragment {
+                    index: (index_and_n >> 14) as u8,
+                    n: index_and_n & 0x3FFF,
+                    payload: &args[2..],
+                };
+                (command, args.len())
+            }
+            _ => return Err(CommandError::UnknownOpcode(id)),
+        };
+        Ok((command, 1 + len))
+    }
+}
+
+/// Iterator over the commands of a fragmentation downlink.
+pub struct FragCommands<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> FragCommands<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+}
+
+impl<'a> Iterator for FragCommands<'a> {
+    type Item = Result<FragCommand<'a>, CommandError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.is_empty() {
+            return None;
+        }
+        match FragCommand::parse(self.bytes) {
+            Ok((command, len)) => {
+                self.bytes = &self.bytes[len..];
+                Some(Ok(command))
+            }
+            Err(e) => {
+                self.bytes = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+const FRAG_WORDS: usize = MAX_FRAGMENTS / 32;
+const ROW_WORDS: usize = MAX_REDUNDANCY / 32;
+
+fn bit(set: &[u32], i: usize) -> bool {
+    set[i / 32] & (1 << (i % 32)) != 0
+}
+
+fn set_bit(set: &mut [u32], i: usize) {
+    set[i / 32] |= 1 << (i % 32);
+}
+
+/// Indexes of the set bits of `set`, in increasing order.
+fn set_bits(set: &[u32]) -> impl Iterator<Item = usize> + '_ {
+    set.iter().enumerate().flat_map(|(w, word)| {
+        (0..32)
+            .filter(move |b| word & (1 << b) != 0)
+            .map(move |b| w * 32 + b)
+    })
+}
+
+fn first_bit(set: &[u32]) -> Option<usize> {
+    let (w, word) = set.iter().enumerate().find(|(_, word)| **word != 0)?;
+    Some(w * 32 + word.trailing_zeros() as usize)
+}
+
+fn xor(data: &mut [u8], other: &[u8]) {
+    data.iter_mut().zip(other).for_each(|(a, b)| *a ^= b);
+}
+
+/// Pseudo-random sequence generator of the parity matrix.
+fn prbs23(x: u32) -> u32 {
+    let b0 = x & 1;
+    let b1 = (x & 0x20) >> 5;
+    (x >> 1) + ((b0 ^ b1) << 22)
+}
+
+/// Fills `row` with line `n` (from 1) of the parity check matrix of a block
+/// of `m` fragments: the uncoded fragments XORed into coded fragment `n`.
+pub fn parity_row(n: u32, m: u32, row: &mut [u32]) {
+    row.fill(0);
+    let m_temp = if m.is_power_of_two() { 1 } else { 0 };
+    let mut x = 1 + 1001 * n;
+    for _ in 0..m / 2 {
+        let mut r = 1 << 16;
+        while r >= m {
+            x = prbs23(x);
+            r = x % (m + m_temp);
+        }
+        set_bit(row, r as usize);
+    }
+}
+
+/// Progress of the reconstruction of a data block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeState {
+    /// Receiving uncoded fragments.
+    Receiving,
+    /// Coded fragments are combined to recover the lost ones.
+    Decoding,
+    /// More fragments were lost than the decoder can recover.
+    TooManyMissing,
+    Complete,
+}
+
+/// Reassembles a data block from uncoded and coded fragments.
+///
+/// Fragments `1..=nb_frag` are the data block itself and are written to
+/// `storage` at `(n - 1) * frag_size`. Later fragments are XORs of uncoded
+/// ones, given by [`parity_row`]. Each coded fragment is reduced by the
+/// fragments already known and stored, in row echelon form over the lost
+/// ones, in the flash slot of the lost fragment it pivots on. Once there
+/// are as many independent rows as lost fragments, back substitution
+/// writes every lost fragment to its slot.
+pub struct FragDecoder {
+    nb_frag: u16,
+    frag_size: u8,
+    state: DecodeState,
+    nb_received: u16,
+    /// Uncoded fragments whose data is in their slot.
+    known: [u32; FRAG_WORDS],
+    /// Scratch space for a parity matrix line.
+    row: [u32; FRAG_WORDS],
+    /// Lost fragments, the columns of the decoding matrix.
+    missing: heapless::Vec<u16, MAX_REDUNDANCY>,
+    matrix: [[u32; ROW_WORDS]; MAX_REDUNDANCY],
+    pivots: [u32; ROW_WORDS],
+    rank: usize,
+}
+
+impl Default for FragDecoder {
+    fn default() -> Self {
+        Self {
+            nb_frag: 0,
+            frag_size: 0,
+            state: DecodeState::Receiving,
+            nb_received: 0,
+            known: [0; FRAG_WORDS],
+            row: [0; FRAG_WORDS],
+            missing: heapless::Vec::new(),
+            matrix: [[0; ROW_WORDS]; MAX_REDUNDANCY],
+            pivots: [0; ROW_WORDS],
+            rank: 0,
+        }
+    }
+}
+
+impl FragDecoder {
+    /// Prepares the decoder for a block of `nb_frag` fragments of
+    /// `frag_size` bytes.
+    pub fn reset(&mut self, nb_frag: u16, frag_size: u8) {
+        self.nb_frag = nb_frag.min(MAX_FRAGMENTS as u16);
+        self.frag_size = frag_size;
+        self.state = DecodeState::Receiving;
+        self.nb_received = 0;
+        self.known = [0; FRAG_WORDS];
+        self.missing.clear();
+        self.pivots = [0; ROW_WORDS];
+        self.rank = 0;
+    }
+
+    pub fn state(&self) -> DecodeState {
+        self.state
+    }
+
+    /// Number of fragments received, coded or not.
+    pub fn nb_received(&self) -> u16 {
+        self.nb_received
+    }
+
+    /// Number of fragments still needed to rebuild the block.
+    pub fn missing(&self) -> usize {
+        match self.state {
+            DecodeState::Complete => 0,
+            DecodeState::Decoding => self.missing.len() - self.rank,
+            DecodeState::Receiving | DecodeState::TooManyMissing => {
+                let known: u32 = self.known.iter().map(|w| w.count_ones()).sum();
+                self.nb_frag as usize - known as usize
+            }
+        }
+    }
+
+    fn offset(&self, fragment: usize) -> u32 {
+        (fragment * self.frag_size as usize) as u32
+    }
+
+    fn read<S: Storage>(
+        &self,
+        storage: &mut S,
+        fragment: usize,
+        data: &mut [u8],
+    ) -> Result<(), FragError> {
+        storage
+            .read(self.offset(fragment), data)
+            .map_err(|_| FragError::Storage)
+    }
+
+    fn write<S: Storage>(
+        &self,
+        storage: &mut S,
+        fragment: usize,
+        data: &[u8],
+    ) -> Result<(), FragError> {
+        storage
+            .write(self.offset(fragment), data)
+            .map_err(|_| FragError::Storage)
+    }
+
+    /// Processes fragment `n`, counting from 1, and returns the new state.
+    pub fn push<S: Storage>(
+        &mut self,
+        storage: &mut S,
+        n: u16,
+        payload: &[u8],
+    ) -> Result<DecodeState, FragError> {
+        if payload.len() != self.frag_size as usize {
+            return Err(FragError::Length(payload.len()));
+        }
+        if n == 0 {
+            return Err(FragError::Index(n));
+        }
+        if self.state == DecodeState::Complete {
+            return Ok(self.state);
+        }
+        self.nb_received = self.nb_received.saturating_add(1);
+
+        let nb_frag = self.nb_frag as usize;
+        if n as usize <= nb_frag {
+            let fragment = n as usize - 1;
+            if bit(&self.known, fragment) {
+                return Ok(self.state);
+            }
+            if self.state != DecodeState::Decoding {
+                self.write(storage, fragment, payload)?;
+                set_bit(&mut self.known, fragment);
+                if self.missing() == 0 {
+                    self.state = DecodeState::Complete;
+                }
+                return Ok(self.state);
+            }
+            // Once decoding started the slots of lost fragments may hold
+            // coded rows, so the fragment joins the matrix as a unit row.
+            self.row.fill(0);
+            set_bit(&mut self.row, fragment);
+        } else {
+            if self.state != DecodeState::Decoding {
+                self.start_decoding();
+                if self.state == DecodeState::TooManyMissing {
+                    return Ok(self.state);
+                }
+            }
+            parity_row((n as usize - nb_frag) as u32, nb_frag as u32, &mut self.row);
+        }
+        self.insert(storage, payload)?;
+        if self.rank == self.missing.len() {
+            self.solve(storage)?;
+        }
+        Ok(self.state)
+    }
+
+    /// Fixes the columns of the decoding matrix, the fragments lost so far.
+    fn start_decoding(&mut self) {
+        self.missing.clear();
+        self.pivots = [0; ROW_WORDS];
+        self.rank = 0;
+        for fragment in 0..self.nb_frag as usize {
+            if !bit(&self.known, fragment) && self.missing.push(fragment as u16).is_err() {
+                self.state = DecodeState::TooManyMissing;
+                return;
+            }
+        }
+        self.state = DecodeState::Decoding;
+    }
+
+    /// Reduces the row in `self.row` and adds it to the matrix if it is
+    /// independent of the rows already there.
+    fn insert<S: Storage>(&mut self, storage: &mut S, payload: &[u8]) -> Result<(), FragError> {
+        let len = self.frag_size as usize;
+        let mut data = [0u8; MAX_FRAGMENT_LEN];
+        let mut other = [0u8; MAX_FRAGMENT_LEN];
+        data[..len].copy_from_slice(payload);
+
+        let mut columns = [0u32; ROW_WORDS];
+        for fragment in set_bits(&self.row).take_while(|f| *f < self.nb_frag as usize) {
+            if bit(&self.known, fragment) {
+                self.read(storage, fragment, &mut other[..len])?;
+                xor(&mut data[..len], &other[..len]);
+            } else if let Ok(column) = self.missing.binary_search(&(fragment as u16)) {
+                set_bit(&mut columns, column);
+            }
+        }
+
+        while let Some(pivot) = first_bit(&columns) {
+            let fragment = self.missing[pivot] as usize;
+            if !bit(&self.pivots, pivot) {
+                self.matrix[pivot] = columns;
+                set_bit(&mut self.pivots, pivot);
+                self.rank += 1;
+                return self.write(storage, fragment, &data[..len]);
+            }
+            xor_words(&mut columns, &self.matrix[pivot]);
+            self.read(storage, fragment, &mut other[..len])?;
+            xor(&mut data[..len], &other[..len]);
+        }
+        // The row only combined fragments that were already known.
+        Ok(())
+    }
+
+    /// Rebuilds the lost fragments by back substitution, last pivot first.
+    fn solve<S: Storage>(&mut self, storage: &mut S) -> Result<(), FragError> {
+        let len = self.frag_size as usize;
+        let mut data = [0u8; MAX_FRAGMENT_LEN];
+        let mut other = [0u8; MAX_FRAGMENT_LEN];
+        for pivot in (0..self.missing.len()).rev() {
+            let fragment = self.missing[pivot] as usize;
+            self.read(storage, fragment, &mut data[..len])?;
+            for column in set_bits(&self.matrix[pivot]).filter(|c| *c > pivot) {
+                self.read(storage, self.missing[column] as usize, &mut other[..len])?;
+                xor(&mut data[..len], &other[..len]);
+            }
+            self.write(storage, fragment, &data[..len])?;
+        }
+        for fragment in self.missing.iter() {
+            set_bit(&mut self.known, *fragment as usize);
+        }
+        self.state = DecodeState::Complete;
+        Ok(())
+    }
+}
+
+fn xor_words(row: &mut [u32], other: &[u32]) {
+    row.iter_mut().zip(other).for_each(|(a, b)| *a ^= b);
+}
+
+/// Fragmented Data Block Transport package (TS004 v1.0.0) with a single
+/// session, reassembling the data block into `storage`.
+pub struct Fragmentation<S> {
+    storage: S,
+    session: Option<FragSessionSetup>,
+    decoder: FragDecoder,
+}
+
+impl<S: Storage> Fragmentation<S> {
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            session: None,
+            decoder: FragDecoder::default(),
+        }
+    }
+
+    pub fn session(&self) -> Option<&FragSessionSetup> {
+        self.session.as_ref()
+    }
+
+    pub fn decoder(&self) -> &FragDecoder {
+        &self.decoder
+    }
+
+    /// Storage holding the reassembled data block.
+    pub fn storage(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Handles a downlink of the package, appending the answers to `answer`.
+    ///
+    /// Returns the session once its data block is complete; this happens
+    /// once per session.
+    pub fn handle(&mut self, payload: &[u8], answer: &mut Answer) -> Option<FragSessionSetup> {
+        let mut completed = None;
+        for command in FragCommands::new(payload) {
+            let command = match command {
+                Ok(command) => command,
+                Err(e) => {
+                    esp_println::println!("[FUOTA] {}", e);
+                    break;
+                }
+            };
+            match command {
+                FragCommand::PackageVersion => {
+                    push_answer(
+                        answer,
+                        &[cid::PACKAGE_VERSION, PACKAGE_IDENTIFIER, PACKAGE_VERSION],
+                    );
+                }
+                FragCommand::SessionStatus {
+                    participants,
+                    index,
+                } => self.status(participants, index, answer),
+                FragCommand::SessionSetup(setup) => {
+                    let status = self.setup(setup);
+                    push_answer(
+                        answer,
+                        &[cid::FRAG_SESSION_SETUP, (setup.index << 6) | status],
+                    );
+                }
+                FragCommand::SessionDelete { index } => {
+                    let status = match self.session {
+                        Some(session) if session.index == index => {
+                            self.session = None;
+                            index
+                        }
+                        _ => index | DELETE_NO_SESSION,
+                    };
+                    push_answer(answer, &[cid::FRAG_SESSION_DELETE, status]);
+                }
+                FragCommand::DataFragment { index, n, payload } => {
+                    if self.fragment(index, n, payload) {
+                        completed = self.session;
+                    }
+                }
+            }
+        }
+        completed
+    }
+
+    fn setup(&mut self, setup: FragSessionSetup) -> u8 {
+        let mut status = 0;
+        if setup.frag_algo != FRAG_ALGO_PARITY {
+            status |= setup_status::ENCODING_UNSUPPORTED;
+        }
+        let fits = setup.nb_frag as usize * setup.frag_size as usize <= self.storage.capacity();
+        if setup.nb_frag as usize > MAX_FRAGMENTS
+            || setup.frag_size as usize > MAX_FRAGMENT_LEN
+            || setup.frag_size == 0
+            || !fits
+        {
+            status |= setup_status::NOT_ENOUGH_MEMORY;
+        }
+        if matches!(self.session, Some(session) if session.index != setup.index) {
+            status |= setup_status::INDEX_NOT_SUPPORTED;
+        }
+        if status == 0 {
+            esp_println::println!(
+                "[FUOTA] Session {}: {} fragments of {} bytes",
+                setup.index,
+                setup.nb_frag,
+                setup.frag_size
+            );
+            self.session = Some(setup);
+            self.decoder.reset(setup.nb_frag, setup.frag_size);
+        }
+        status
+    }
+
+    fn status(&self, participants: bool, index: u8, answer: &mut Answer) {
+        let Some(session) = self.session.filter(|session| session.index == index) else {
+            return;
+        };
+        let complete = self.decoder.state() == DecodeState::Complete;
+        // Without the participants flag only devices still missing
+        // fragments answer.
+        if complete && !participants {
+            return;
+        }
+        let received = ((session.index as u16) << 14) | (self.decoder.nb_received() & 0x3FFF);
+        let received = received.to_le_bytes();
+        let missing = self.decoder.missing().min(u8::MAX as usize) as u8;
+        let status = match self.decoder.state() {
+            DecodeState::TooManyMissing => STATUS_NOT_ENOUGH_MATRIX_MEMORY,
+            _ => 0,
+        };
+        push_answer(
+            answer,
+            &[
+                cid::FRAG_SESSION_STATUS,
+                received[0],
+                received[1],
+                missing,
+                status,
+            ],
+        );
+    }
+
+    /// Returns `true` when the fragment completes the data block.
+    fn fragment(&mut self, index: u8, n: u16, payload: &[u8]) -> bool {
+        let Some(session) = self.session.filter(|session| session.index == index) else {
+            return false;
+        };
+        if self.decoder.state() == DecodeState::Complete {
+            return false;
+        }
+        match self.decoder.push(&mut self.storage, n, payload) {
+            Ok(DecodeState::Complete) => {
+                esp_println::println!("[FUOTA] Session {} complete", session.index);
+                true
+            }
+            Ok(_) => false,
+            Err(e) => {
+                esp_println::println!("[FUOTA] Fragment {}: {}", n, e);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{test_storage::MemStorage, test_util::randoms};
+
+    fn block(len: usize, seed: u32) -> std::vec::Vec<u8> {
+        randoms(seed).take(len).map(|r| r as u8).collect()
+    }
+
+    fn uncoded(block: &[u8], frag_size: usize, n: usize) -> &[u8] {
+        &block[(n - 1) * frag_size..n * frag_size]
+    }
+
+    /// Coded fragment `n` (from 1) of `block`, as the server sends it.
+    fn coded(block: &[u8], frag_size: usize, n: usize) -> std::vec::Vec<u8> {
+        let nb_frag = block.len() / frag_size;
+        let mut row = [0u32; FRAG_WORDS];
+        parity_row(n as u32, nb_frag as u32, &mut row);
+        let mut fragment = std::vec![0u8; frag_size];
+        for j in set_bits(&row).take_while(|j| *j < nb_frag) {
+            xor(&mut fragment, uncoded(block, frag_size, j + 1));
+        }
+        fragment
+    }
+
+    fn data_fragment(index: u8, n: u16, payload: &[u8]) -> std::vec::Vec<u8> {
+        let header = ((index as u16) << 14 | n).to_le_bytes();
+        let mut bytes = std::vec![cid::DATA_FRAGMENT, header[0], header[1]];
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    #[test]
+    fn recovers_random_losses_from_parity() {
+        for (seed, nb_frag, frag_size, loss) in [
+            (1, 200, 20, 10),
+            (7, 500, 50, 20),
+            (99, 64, 8, 30),
+            (5, 33, 3, 50),
+        ] {
+            let data = block(nb_frag * frag_size, seed);
+            let mut storage = MemStorage::new(data.len());
+            let mut decoder = std::boxed::Box::<FragDecoder>::default();
+            decoder.reset(nb_frag as u16, frag_size as u8);
+            let mut randoms = randoms(seed);
+            for n in 1..=nb_frag {
+                if randoms.next().unwrap() % 100 >= loss {
+                    let fragment = uncoded(&data, frag_size, n);
+                    decoder.push(&mut storage, n as u16, fragment).unwrap();
+                }
+            }
+            let mut n = nb_frag + 1;
+            while decoder.state() != DecodeState::Complete {
+                assert!(n < 5 * nb_frag, "seed {} did not converge", seed);
+                if randoms.next().unwrap() % 100 >= loss {
+                    let fragment = coded(&data, frag_size, n - nb_frag);
+                    decoder.push(&mut storage, n as u16, &fragment).unwrap();
+                }
+                // A late uncoded fragment joins the matrix as a unit row.
+                if randoms.next().unwrap() % 100 < 10 {
+                    let late = randoms.next().unwrap() as usize % nb_frag + 1;
+                    let fragment = uncoded(&data, frag_size, late);
+                    decoder.push(&mut storage, late as u16, fragment).unwrap();
+                }
+                n += 1;
+            }
+            assert_eq!(storage.data, data, "seed {}", seed);
+            assert_eq!(decoder.missing(), 0);
+        }
+    }
+
+    #[test]
+    fn reports_too_many_missing() {
+        let (nb_frag, frag_size) = (400, 4);
+        let data = block(nb_frag * frag_size, 3);
+        let mut storage = MemStorage::new(data.len());
+        let mut decoder = std::boxed::Box::<FragDecoder>::default();
+        decoder.reset(nb_frag as u16, frag_size as u8);
+        for n in (2..=nb_frag).step_by(2) {
+            let fragment = uncoded(&data, frag_size, n);
+            decoder.push(&mut storage, n as u16, fragment).unwrap();
+        }
+        let first = coded(&data, frag_size, 1);
+        let state = decoder.push(&mut storage, nb_frag as u16 + 1, &first);
+        assert_eq!(state, Ok(DecodeState::TooManyMissing));
+        assert_eq!(decoder.missing(), 200);
+
+        // Late uncoded fragments bring the loss within reach of the matrix.
+        for n in (1..300).step_by(2) {
+            let fragment = uncoded(&data, frag_size, n);
+            decoder.push(&mut storage, n as u16, fragment).unwrap();
+        }
+        let mut n = nb_frag + 2;
+        while decoder.state() != DecodeState::Complete {
+            let fragment = coded(&data, frag_size, n - nb_frag);
+            decoder.push(&mut storage, n as u16, &fragment).unwrap();
+            n += 1;
+        }
+        assert_eq!(storage.data, data);
+        assert_eq!(
+            decoder.push(&mut storage, 1, &[0; 3]),
+            Err(FragError::Length(3))
+        );
+    }
+
+    #[test]
+    fn answers_session_commands() {
+        let (nb_frag, frag_size) = (20, 10);
+        let data = block(nb_frag * frag_size, 11);
+        let mut fragmentation = std::boxed::Box::new(Fragmentation::new(MemStorage::new(4096)));
+        let mut answer = Answer::new();
+
+        // PackageVersionReq and FragSessionSetupReq for session 1 of 20
+        // fragments of 10 bytes, padded by 3.
+        let setup = [0x00, 0x02, 0x11, 20, 0, 10, 0x08, 3, 1, 2, 3, 4];
+        assert_eq!(fragmentation.handle(&setup, &mut answer), None);
+        assert_eq!(&answer[..], &[0x00, 3, 1, 0x02, 0x40]);
+        let session = *fragmentation.session().unwrap();
+        assert_eq!(session.mc_group_mask, 1);
+        assert_eq!(session.block_ack_delay, 1);
+        assert_eq!(session.descriptor, 0x0403_0201);
+        assert_eq!(session.data_len(), 197);
+
+        // Another index, an unknown algorithm and a block too large.
+        answer.clear();
+        let setup = [0x02, 0x20, 0xFF, 0xFF, 250, 0x01, 0, 0, 0, 0, 0];
+        fragmentation.handle(&setup, &mut answer);
+        assert_eq!(&answer[..], &[0x02, 0x80 | 0x07]);
+
+        for n in (1..=nb_frag).filter(|n| *n != 3 && *n != 7) {
+            let bytes = data_fragment(1, n as u16, uncoded(&data, frag_size, n));
+            assert_eq!(fragmentation.handle(&bytes, &mut answer), None);
+        }
+        // FragSessionStatusAns: 18 received, 2 missing.
+        answer.clear();
+        fragmentation.handle(&[0x01, 0x02], &mut answer);
+        assert_eq!(&answer[..], &[0x01, 18, 0x40, 2, 0]);
+
+        let mut completed = None;
+        for n in nb_frag + 1..nb_frag + 10 {
+            let bytes = data_fragment(1, n as u16, &coded(&data, frag_size, n - nb_frag));
+            if let Some(session) = fragmentation.handle(&bytes, &mut answer) {
+                assert!(completed.is_none());
+                completed = Some(session);
+            }
+        }
+        assert_eq!(completed, Some(session));
+        assert_eq!(&fragmentation.storage().data[..data.len()], &data[..]);
+
+        // Three coded fragments recovered both lost ones. Complete devices
+        // only answer when all participants are asked.
+        answer.clear();
+        fragmentation.handle(&[0x01, 0x02], &mut answer);
+        assert!(answer.is_empty());
+        fragmentation.handle(&[0x01, 0x03], &mut answer);
+        assert_eq!(&answer[..], &[0x01, 21, 0x40, 0, 0]);
+
+        // FragSessionDeleteReq for session 1, then for the missing session.
+        answer.clear();
+        fragmentation.handle(&[0x03, 0x01, 0x03, 0x01], &mut answer);
+        assert_eq!(&answer[..], &[0x03, 0x01, 0x03, 0x05]);
+        assert!(fragmentation.session().is_none());
+    }
+}
@@ -1,12 +1,40 @@
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pipe::Pipe};
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pipe::Pipe,
+};
+use embassy_time::Instant;
 use esp_hal::{
     uart::{UartRx, UartTx},
     Async,
 };
-use nmea0183::{ParseResult, Parser};
+use nmea0183::{ParseResult, Parser, GGA, RMC};
+
+use super::{
+    clock::{self, TimeSource},
+    payload::Fix,
+};
 
 static DATAPIPE_UART: Pipe<CriticalSectionRawMutex, UART_BUF_SIZE> = Pipe::new();
 const UART_BUF_SIZE: usize = 4048;
+/// Last position reported by the receiver, `None` while it has no fix.
+static GPS_FIX: Mutex<CriticalSectionRawMutex, Cell<Option<Fix>>> = Mutex::new(Cell::new(None));
+
+/// Returns the last position reported by the receiver.
+pub fn latest_fix() -> Option<Fix> {
+    GPS_FIX.lock(|fix| fix.get())
+}
+
+fn to_fix(gga: &GGA) -> Fix {
+    Fix {
+        latitude: gga.latitude.as_f64(),
+        longitude: gga.longitude.as_f64(),
+        altitude: gga.altitude.meters,
+        satellites: gga.sat_in_use,
+        hdop: gga.hdop,
+    }
+}
 
 #[embassy_executor::task]
 pub async fn uart_writer(mut tx: UartTx<'static, Async>) {
@@ -45,6 +73,15 @@ pub async fn uart_writer(mut tx: UartTx<'static, Async>) {
     }
 }
 
+/// GPS time of a RMC sentence, in milliseconds.
+fn to_gps_ms(rmc: &RMC) -> u64 {
+    let date = &rmc.datetime.date;
+    let time = &rmc.datetime.time;
+    let ms_of_day = (time.hours as u32 * 3600 + time.minutes as u32 * 60) * 1000
+        + (time.seconds * 1000.0) as u32;
+    clock::utc_to_gps_ms(date.year as i32, date.month, date.day, ms_of_day)
+}
+
 fn handle_parsed_result(result: Result<ParseResult, &str>) {
     match result {
         Ok(ParseResult::GGA(Some(gga))) => {
@@ -55,6 +92,12 @@ fn handle_parsed_result(result: Result<ParseResult, &str>) {
                 gga.longitude,
                 gga.altitude
             );
+            GPS_FIX.lock(|fix| fix.set(Some(to_fix(&gga))));
+        }
+        Ok(ParseResult::GGA(None)) => GPS_FIX.lock(|fix| fix.set(None)),
+        // Only a valid RMC sentence carries the date.
+        Ok(ParseResult::RMC(Some(rmc))) => {
+            clock::set_gps_time(to_gps_ms(&rmc), Instant::now(), TimeSource::Gps);
         }
         Ok(_) => {}
         Err(_) => {}
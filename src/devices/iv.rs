@@ -1,9 +1,68 @@
+use core::cell::Cell;
+
 use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
 use embassy_time::{with_timeout, Duration};
-use embedded_hal::digital::OutputPin;
+use embedded_hal::digital::{InputPin, OutputPin};
 use embedded_hal_async::digital::Wait;
 use lora_phy::{mod_params::RadioError, mod_traits::InterfaceVariant};
 
+/// Levels of the DIO pins once a wait for an SX1276 interrupt ended; what
+/// they mean depends on the mode of the radio.
+///
+/// | Pin  | Tx     | Rx        | CAD         |
+/// |------|--------|-----------|-------------|
+/// | DIO0 | TxDone | RxDone    | CadDone     |
+/// | DIO1 | -      | RxTimeout | CadDetected |
+///
+/// Both pins may be high, as CadDetected rises along with CadDone.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Irq {
+    pub dio0: bool,
+    pub dio1: bool,
+}
+
+impl Irq {
+    /// Neither pin rose in time; the driver polls the IRQ flags anyway.
+    pub fn timed_out(&self) -> bool {
+        !self.dio0 && !self.dio1
+    }
+}
+
+/// The event that ended the last wait of an [`InterfaceSx1276`].
+///
+/// The radio driver owns the interface and only learns that a wait ended,
+/// so the interface shares the event with the callers that need to tell
+/// them apart, such as channel activity detection.
+pub struct IrqEvent(Mutex<CriticalSectionRawMutex, Cell<Option<Irq>>>);
+
+impl IrqEvent {
+    pub const fn new() -> Self {
+        Self(Mutex::new(Cell::new(None)))
+    }
+
+    /// The event of the last wait, `None` if there was none since
+    /// [`IrqEvent::clear`].
+    pub fn last(&self) -> Option<Irq> {
+        self.0.lock(|irq| irq.get())
+    }
+
+    /// Forgets the last event, before an operation whose events are awaited.
+    pub fn clear(&self) {
+        self.0.lock(|irq| irq.set(None));
+    }
+
+    fn record(&self, event: Irq) {
+        self.0.lock(|irq| irq.set(Some(event)));
+    }
+}
+
+impl Default for IrqEvent {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// SX1276 Interface with RX/TX switching
 pub struct InterfaceSx1276<CTRL, WAIT> {
     dio0: WAIT,
@@ -11,20 +70,23 @@ pub struct InterfaceSx1276<CTRL, WAIT> {
     reset: CTRL,
     rf_switch_rx: Option<CTRL>,
     rf_switch_tx: Option<CTRL>,
+    irq: &'static IrqEvent,
 }
 
 impl<CTRL, WAIT> InterfaceSx1276<CTRL, WAIT>
 where
     CTRL: OutputPin,
-    WAIT: Wait,
+    WAIT: Wait + InputPin,
 {
-    /// Create a new SX1276 interface
+    /// Create a new SX1276 interface, recording the events of its waits in
+    /// `irq`
     pub fn new(
         dio0: WAIT,
         dio1: WAIT,
         reset: CTRL,
         rf_switch_rx: Option<CTRL>,
         rf_switch_tx: Option<CTRL>,
+        irq: &'static IrqEvent,
     ) -> Result<Self, RadioError> {
         Ok(Self {
             dio0,
@@ -32,36 +94,38 @@ where
             reset,
             rf_switch_rx,
             rf_switch_tx,
+            irq,
         })
     }
 }
 
 impl<CTRL, WAIT> InterfaceVariant for InterfaceSx1276<CTRL, WAIT>
 where
-    WAIT: Wait,
+    WAIT: Wait + InputPin,
     CTRL: OutputPin,
 {
-    /// Wait for an interrupt from DIO0 or DIO1
+    /// Wait for an interrupt from DIO0 or DIO1, recording the levels of both
+    /// once woken, see [`IrqEvent`].
     async fn await_irq(&mut self) -> Result<(), RadioError> {
-        match with_timeout(
+        let woken = with_timeout(
             Duration::from_millis(100),
             select(self.dio0.wait_for_high(), self.dio1.wait_for_high()),
         )
-        .await
-        {
+        .await;
+        // The wait polls DIO0 first, so DIO1 is read even when DIO0 woke it.
+        let event = Irq {
+            dio0: self.dio0.is_high().map_err(|_| RadioError::Irq)?,
+            dio1: self.dio1.is_high().map_err(|_| RadioError::Irq)?,
+        };
+        self.irq.record(event);
+        match woken {
             Ok(Either::First(value)) => {
                 defmt::info!("DIO0 interrupt triggered");
-                match value {
-                    Ok(()) => Ok(()),
-                    Err(_) => Err(RadioError::Irq),
-                }
+                value.map_err(|_| RadioError::Irq)
             }
             Ok(Either::Second(value)) => {
                 defmt::info!("DIO1 interrupt triggered");
-                match value {
-                    Ok(()) => Ok(()),
-                    Err(_) => Err(RadioError::Irq),
-                }
+                value.map_err(|_| RadioError::Irq)
             }
             Err(_) => Ok(()),
         }
@@ -0,0 +1,334 @@
+use super::lorawan_config::Region;
+
+/// Size of a Join-request PHY payload, in bytes.
+pub const JOIN_REQUEST_LEN: usize = 23;
+
+const HOUR_MS: u64 = 60 * 60 * 1000;
+/// Join attempts made at one data rate before stepping down to the next.
+const ATTEMPTS_PER_DATA_RATE: u32 = 2;
+/// Upper bound of the random delay before the first attempt after a reset,
+/// so devices powered up together do not join in lockstep.
+const FIRST_JITTER_MS: u32 = 5_000;
+/// Delay after the first failed attempt, doubled after each further one.
+const BASE_DELAY_MS: u64 = 8_000;
+const MAX_DELAY_MS: u64 = 30 * 60 * 1000;
+
+/// Aggregated Join-request airtime allowed by the LoRaWAN specification
+/// (section 7, retransmission back-off), in microseconds.
+const FIRST_HOUR_BUDGET_US: u64 = 36_000_000;
+const NEXT_TEN_HOURS_BUDGET_US: u64 = 36_000_000;
+const DAILY_BUDGET_US: u64 = 8_700_000;
+
+/// A scheduled Join-request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JoinAttempt {
+    /// Time of the transmission, in milliseconds since reset.
+    pub at_ms: u64,
+    pub data_rate: u8,
+    pub airtime_us: u32,
+}
+
+/// Duty-cycle window that an instant falls in.
+struct Window {
+    id: u64,
+    end_ms: u64,
+    budget_us: u64,
+}
+
+impl Window {
+    /// The first hour after reset and the following 10 hours each have their
+    /// own budget; after that the budget applies per 24 hours.
+    fn at(ms: u64) -> Self {
+        if ms < HOUR_MS {
+            Self {
+                id: 0,
+                end_ms: HOUR_MS,
+                budget_us: FIRST_HOUR_BUDGET_US,
+            }
+        } else if ms < 11 * HOUR_MS {
+            Self {
+                id: 1,
+                end_ms: 11 * HOUR_MS,
+                budget_us: NEXT_TEN_HOURS_BUDGET_US,
+            }
+        } else {
+            let day = (ms - 11 * HOUR_MS) / (24 * HOUR_MS);
+            Self {
+                id: 2 + day,
+                end_ms: 11 * HOUR_MS + (day + 1) * 24 * HOUR_MS,
+                budget_us: DAILY_BUDGET_US,
+            }
+        }
+    }
+}
+
+/// Schedules OTAA Join-requests.
+///
+/// Attempts back off exponentially with random jitter, step the data rate
+/// down from the configured one to DR0 and back, and are pushed out whenever
+/// the aggregated airtime limits of the current window would be exceeded.
+/// The schedule never ends: once a window's budget is spent the next attempt
+/// is placed at the start of the following window.
+///
+/// All times are in milliseconds since reset, as the limits are defined
+/// relative to power-up.
+pub struct JoinBackoff {
+    region: Region,
+    max_data_rate: u8,
+    attempts: u32,
+    window: u64,
+    used_us: u64,
+}
+
+impl JoinBackoff {
+    /// Creates a schedule starting at `data_rate`, clamped to the region's
+    /// highest data rate.
+    pub fn new(region: Region, data_rate: u8) -> Self {
+        Self {
+            region,
+            max_data_rate: data_rate.min(region.max_data_rate()),
+            attempts: 0,
+            window: 0,
+            used_us: 0,
+        }
+    }
+
+    /// Number of attempts recorded so far.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Data rate of attempt number `attempt`.
+    pub fn data_rate(&self, attempt: u32) -> u8 {
+        let steps = self.max_data_rate as u32 + 1;
+        self.max_data_rate - ((attempt / ATTEMPTS_PER_DATA_RATE) % steps) as u8
+    }
+
+    /// Delay from the previous attempt to attempt number `attempt`, before
+    /// jitter.
+    pub fn delay_ms(attempt: u32) -> u64 {
+        match attempt {
+            0 => 0,
+            n => BASE_DELAY_MS
+                .saturating_mul(1 << (n - 1).min(16))
+                .min(MAX_DELAY_MS),
+        }
+    }
+
+    /// Airtime already spent in `window`.
+    fn used_in(&self, window: &Window) -> u64 {
+        if window.id == self.window {
+            self.used_us
+        } else {
+            0
+        }
+    }
+
+    /// Earliest time from `from_ms` at which `airtime_us` fits in the budget.
+    pub fn earliest(&self, from_ms: u64, airtime_us: u32) -> u64 {
+        let mut at_ms = from_ms;
+        loop {
+            let window = Window::at(at_ms);
+            if self.used_in(&window) + airtime_us as u64 <= window.budget_us {
+                return at_ms;
+            }
+            at_ms = window.end_ms;
+        }
+    }
+
+    /// Schedules the next attempt.
+    ///
+    /// `random` is any random value; it is reduced to a jitter of up to half
+    /// the back-off delay.
+    pub fn next_attempt(&self, now_ms: u64, random: u32) -> JoinAttempt {
+        let data_rate = self.data_rate(self.attempts);
+        let airtime_us = self
+            .region
+            .uplink_params(data_rate)
+            .map(|params| params.time_on_air_us(JOIN_REQUEST_LEN))
+            .unwrap_or(0);
+
+        let delay_ms = Self::delay_ms(self.attempts);
+        let jitter_ms = match self.attempts {
+            0 => random % FIRST_JITTER_MS,
+            _ => (random as u64 % (delay_ms / 2 + 1)) as u32,
+        };
+        let at_ms = self.earliest(now_ms + delay_ms + jitter_ms as u64, airtime_us);
+        JoinAttempt {
+            at_ms,
+            data_rate,
+            airtime_us,
+        }
+    }
+
+    /// Accounts for a transmitted attempt.
+    pub fn record(&mut self, attempt: &JoinAttempt) {
+        let window = Window::at(attempt.at_ms);
+        self.used_us = self.used_in(&window) + attempt.airtime_us as u64;
+        self.window = window.id;
+        self.attempts += 1;
+    }
+
+    /// Starts over from the first attempt, to join again after a session was
+    /// lost. The airtime already spent keeps counting against the budget of
+    /// its window.
+    pub fn restart(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_util::randoms;
+
+    /// Schedules and records attempts until `until_ms`, each started as soon
+    /// as the previous one failed.
+    fn run(region: Region, data_rate: u8, until_ms: u64) -> std::vec::Vec<JoinAttempt> {
+        let mut backoff = JoinBackoff::new(region, data_rate);
+        let mut attempts = std::vec::Vec::new();
+        let mut now_ms = 0;
+        for random in randoms(7) {
+            let attempt = backoff.next_attempt(now_ms, random);
+            if attempt.at_ms >= until_ms {
+                break;
+            }
+            assert!(attempt.at_ms >= now_ms);
+            backoff.record(&attempt);
+            attempts.push(attempt);
+            // Transmission and both receive windows.
+            now_ms = attempt.at_ms + attempt.airtime_us as u64 / 1000 + 7_000;
+        }
+        attempts
+    }
+
+    fn airtime_us(attempts: &[JoinAttempt], from_ms: u64, to_ms: u64) -> u64 {
+        attempts
+            .iter()
+            .filter(|attempt| attempt.at_ms >= from_ms && attempt.at_ms < to_ms)
+            .map(|attempt| attempt.airtime_us as u64)
+            .sum()
+    }
+
+    #[test]
+    fn delays_grow_exponentially() {
+        assert_eq!(JoinBackoff::delay_ms(0), 0);
+        assert_eq!(JoinBackoff::delay_ms(1), BASE_DELAY_MS);
+        for attempt in 2..9 {
+            assert_eq!(
+                JoinBackoff::delay_ms(attempt),
+                2 * JoinBackoff::delay_ms(attempt - 1)
+            );
+        }
+        assert_eq!(JoinBackoff::delay_ms(9), MAX_DELAY_MS);
+        assert_eq!(JoinBackoff::delay_ms(u32::MAX), MAX_DELAY_MS);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let mut backoff = JoinBackoff::new(Region::US915, 3);
+        let now_ms = 1_000;
+        for random in randoms(1).take(1_000).chain([0, u32::MAX]) {
+            let at_ms = backoff.next_attempt(now_ms, random).at_ms;
+            assert!((now_ms..now_ms + FIRST_JITTER_MS as u64).contains(&at_ms));
+        }
+        for attempt in 1..12 {
+            backoff.record(&backoff.next_attempt(0, 0));
+            let delay_ms = JoinBackoff::delay_ms(attempt);
+            assert_eq!(backoff.next_attempt(now_ms, 0).at_ms, now_ms + delay_ms);
+            for random in randoms(attempt).take(1_000).chain([u32::MAX]) {
+                let at_ms = backoff.next_attempt(now_ms, random).at_ms;
+                assert!(at_ms >= now_ms + delay_ms);
+                assert!(at_ms <= now_ms + delay_ms + delay_ms / 2);
+            }
+        }
+    }
+
+    #[test]
+    fn data_rate_steps_down_and_back() {
+        let backoff = JoinBackoff::new(Region::EU868, 5);
+        let rates: std::vec::Vec<u8> = (0..14).map(|n| backoff.data_rate(n)).collect();
+        assert_eq!(rates, [5, 5, 4, 4, 3, 3, 2, 2, 1, 1, 0, 0, 5, 5]);
+
+        // The configured data rate is clamped to the region's highest.
+        let backoff = JoinBackoff::new(Region::US915, 6);
+        let rates: std::vec::Vec<u8> = (0..10).map(|n| backoff.data_rate(n)).collect();
+        assert_eq!(rates, [4, 4, 3, 3, 2, 2, 1, 1, 0, 0]);
+
+        let mut backoff = JoinBackoff::new(Region::AU915, 0);
+        for _ in 0..4 {
+            let attempt = backoff.next_attempt(0, 0);
+            assert_eq!(attempt.data_rate, 0);
+            backoff.record(&attempt);
+        }
+        assert_eq!(backoff.attempts(), 4);
+    }
+
+    #[test]
+    fn airtime_stays_within_budgets() {
+        const DAY_MS: u64 = 24 * HOUR_MS;
+        for region in [Region::EU868, Region::US915, Region::AU915] {
+            let attempts = run(region, 5, 11 * HOUR_MS + 10 * DAY_MS);
+            assert!(airtime_us(&attempts, 0, HOUR_MS) <= FIRST_HOUR_BUDGET_US);
+            assert!(airtime_us(&attempts, HOUR_MS, 11 * HOUR_MS) <= NEXT_TEN_HOURS_BUDGET_US);
+            for day in 0..10 {
+                let start_ms = 11 * HOUR_MS + day * DAY_MS;
+                assert!(airtime_us(&attempts, start_ms, start_ms + DAY_MS) <= DAILY_BUDGET_US);
+                // The schedule goes on every day.
+                assert!(airtime_us(&attempts, start_ms, start_ms + DAY_MS) > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn spent_budget_defers_to_next_window() {
+        let mut backoff = JoinBackoff::new(Region::EU868, 0);
+        let airtime_us = backoff.next_attempt(0, 0).airtime_us;
+        let fits = (FIRST_HOUR_BUDGET_US / airtime_us as u64) as u32;
+        for n in 0..fits {
+            backoff.record(&JoinAttempt {
+                at_ms: n as u64 * 1_000,
+                data_rate: 0,
+                airtime_us,
+            });
+        }
+        assert_eq!(backoff.earliest(fits as u64 * 1_000, airtime_us), HOUR_MS);
+        // Airtime of the first hour does not count in the next window.
+        assert_eq!(backoff.earliest(HOUR_MS + 1, airtime_us), HOUR_MS + 1);
+        let attempt = backoff.next_attempt(HOUR_MS - 10_000, 0);
+        assert!(attempt.at_ms >= HOUR_MS);
+
+        // Once a day's budget is spent, the next attempt waits for the next
+        // day.
+        let day_ms = 11 * HOUR_MS + 24 * HOUR_MS;
+        let mut backoff = JoinBackoff::new(Region::EU868, 0);
+        for n in 0..DAILY_BUDGET_US / airtime_us as u64 {
+            backoff.record(&JoinAttempt {
+                at_ms: 11 * HOUR_MS + n * 1_000,
+                data_rate: 0,
+                airtime_us,
+            });
+        }
+        assert_eq!(backoff.earliest(12 * HOUR_MS, airtime_us), day_ms);
+    }
+
+    #[test]
+    fn restart_keeps_spent_airtime() {
+        let mut backoff = JoinBackoff::new(Region::EU868, 5);
+        for _ in 0..5 {
+            backoff.record(&backoff.next_attempt(0, 0));
+        }
+        backoff.record(&JoinAttempt {
+            at_ms: 10 * 60 * 1000,
+            data_rate: 0,
+            airtime_us: FIRST_HOUR_BUDGET_US as u32,
+        });
+
+        backoff.restart();
+        assert_eq!(backoff.attempts(), 0);
+        let attempt = backoff.next_attempt(20 * 60 * 1000, 0);
+        assert_eq!(attempt.data_rate, 5);
+        // The first hour's budget stays spent.
+        assert_eq!(attempt.at_ms, HOUR_MS);
+    }
+}
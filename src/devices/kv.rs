@@ -0,0 +1,733 @@
+use embedded_storage::Storage;
+
+use super::crc::{crc32, crc32_update};
+
+/// Size of a store sector; matches the ESP32 flash erase size.
+pub const SECTOR_SIZE: usize = 4096;
+/// Maximum length of a key, in bytes.
+pub const MAX_KEY_LEN: usize = 32;
+/// Maximum length of a value, in bytes.
+pub const MAX_VALUE_LEN: usize = 256;
+
+const SECTOR_MAGIC: [u8; 4] = *b"KVS1";
+const SECTOR_HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 8;
+const MAX_RECORD_LEN: usize = RECORD_HEADER_LEN + MAX_KEY_LEN + MAX_VALUE_LEN + 3;
+
+const KIND_VALUE: u8 = 0x01;
+const KIND_TOMBSTONE: u8 = 0x02;
+
+/// Image of an erased sector, written in a single call so a [`Storage`]
+/// implementation only has to erase the sector once.
+pub static ERASED_SECTOR: [u8; SECTOR_SIZE] = [0xFF; SECTOR_SIZE];
+
+#[derive(Debug, PartialEq)]
+pub enum KvError {
+    Storage,
+    TooFewSectors,
+    KeyTooLong,
+    ValueTooLong,
+    Full,
+    BufferTooSmall,
+    InvalidValue,
+}
+
+impl core::fmt::Display for KvError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            KvError::Storage => write!(f, "Failed to access storage"),
+            KvError::TooFewSectors => write!(f, "Store needs at least two sectors"),
+            KvError::KeyTooLong => write!(f, "Key is too long"),
+            KvError::ValueTooLong => write!(f, "Value is too long"),
+            KvError::Full => write!(f, "Store is full"),
+            KvError::BufferTooSmall => write!(f, "Buffer too small for value"),
+            KvError::InvalidValue => write!(f, "Stored value has an unexpected format"),
+        }
+    }
+}
+
+/// A value that can be stored in a [`KvStore`].
+pub trait KvValue: Sized {
+    /// Encodes the value into `buffer`, returning the number of bytes used.
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError>;
+    /// Decodes a value previously written by [`KvValue::encode`].
+    fn decode(bytes: &[u8]) -> Result<Self, KvError>;
+}
+
+macro_rules! impl_kv_int {
+    ($($t:ty),*) => {
+        $(
+            impl KvValue for $t {
+                fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError> {
+                    let bytes = self.to_le_bytes();
+                    buffer
+                        .get_mut(..bytes.len())
+                        .ok_or(KvError::BufferTooSmall)?
+                        .copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+
+                fn decode(bytes: &[u8]) -> Result<Self, KvError> {
+                    let bytes = bytes.try_into().map_err(|_| KvError::InvalidValue)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+impl_kv_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl KvValue for bool {
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError> {
+        (*self as u8).encode(buffer)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, KvError> {
+        match u8::decode(bytes)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(KvError::InvalidValue),
+        }
+    }
+}
+
+impl<const N: usize> KvValue for [u8; N] {
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError> {
+        buffer
+            .get_mut(..N)
+            .ok_or(KvError::BufferTooSmall)?
+            .copy_from_slice(self);
+        Ok(N)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, KvError> {
+        bytes.try_into().map_err(|_| KvError::InvalidValue)
+    }
+}
+
+impl<const N: usize> KvValue for heapless::Vec<u8, N> {
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError> {
+        buffer
+            .get_mut(..self.len())
+            .ok_or(KvError::BufferTooSmall)?
+            .copy_from_slice(self);
+        Ok(self.len())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, KvError> {
+        heapless::Vec::from_slice(bytes).map_err(|_| KvError::InvalidValue)
+    }
+}
+
+impl<const N: usize> KvValue for heapless::String<N> {
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, KvError> {
+        buffer
+            .get_mut(..self.len())
+            .ok_or(KvError::BufferTooSmall)?
+            .copy_from_slice(self.as_bytes());
+        Ok(self.len())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, KvError> {
+        let value = core::str::from_utf8(bytes).map_err(|_| KvError::InvalidValue)?;
+        value.try_into().map_err(|_| KvError::InvalidValue)
+    }
+}
+
+/// Header of a record as stored in flash.
+struct Record {
+    kind: u8,
+    key_len: usize,
+    value_len: usize,
+    crc: u32,
+}
+
+impl Record {
+    /// Total length in flash, padded to a 4-byte boundary.
+    fn stored_len(&self) -> usize {
+        (RECORD_HEADER_LEN + self.key_len + self.value_len + 3) & !3
+    }
+}
+
+/// Log-structured, wear-leveled key/value store.
+///
+/// The storage is split into [`SECTOR_SIZE`] sectors used as a ring. Only the
+/// sector with the highest sequence number is active, and every `set` appends
+/// a CRC-protected record to it, so the latest record for a key wins. When the
+/// active sector is full, the live records are copied into the next sector of
+/// the ring and its header is written last: until then the previous sector is
+/// still the valid one, so a power loss at any point leaves either the old or
+/// the new state, never a mix of both.
+///
+/// Sector layout:
+///
+/// | Offset | Size | Field                     |
+/// |--------|------|---------------------------|
+/// | 0      | 4    | Magic (`KVS1`)            |
+/// | 4      | 4    | Sequence number (LE)      |
+/// | 8      | 4    | CRC-32 of bytes 0..8 (LE) |
+/// | 12     | 4    | Reserved (`0xFF`)         |
+/// | 16     | ...  | Records                   |
+///
+/// Record layout, padded with `0xFF` to a 4-byte boundary:
+///
+/// | Offset | Size | Field                                 |
+/// |--------|------|---------------------------------------|
+/// | 0      | 1    | Kind (value or tombstone)             |
+/// | 1      | 1    | Key length                            |
+/// | 2      | 2    | Value length (LE)                     |
+/// | 4      | 4    | CRC-32 of kind, lengths, key, value   |
+/// | 8      | ...  | Key followed by value                 |
+pub struct KvStore<S> {
+    storage: S,
+    sectors: u32,
+    active: u32,
+    sequence: u32,
+    /// End of the valid records in the active sector.
+    end: usize,
+    /// Whether the bytes at `end` were left programmed by an interrupted write.
+    torn: bool,
+}
+
+impl<S: Storage> KvStore<S> {
+    /// Mounts the store, formatting it if no valid sector is found.
+    ///
+    /// A record left half-written by a power loss is ignored, and the next
+    /// write compacts the sector so the torn bytes are never reused.
+    pub fn mount(storage: S) -> Result<Self, KvError> {
+        let sectors = (storage.capacity() / SECTOR_SIZE) as u32;
+        if sectors < 2 {
+            return Err(KvError::TooFewSectors);
+        }
+
+        let mut store = Self {
+            storage,
+            sectors,
+            active: 0,
+            sequence: 0,
+            end: SECTOR_HEADER_LEN,
+            torn: false,
+        };
+
+        let mut found = false;
+        for sector in 0..sectors {
+            if let Some(sequence) = store.read_sector_header(sector)? {
+                if !found || sequence > store.sequence {
+                    store.active = sector;
+                    store.sequence = sequence;
+                    found = true;
+                }
+            }
+        }
+
+        if !found {
+            store.format_sector(0, 1)?;
+            return Ok(store);
+        }
+
+        store.scan()?;
+        Ok(store)
+    }
+
+    /// Releases the underlying storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Reads the raw value of `key` into `buffer`.
+    ///
+    /// Returns the value length, or `None` if the key is not set.
+    pub fn get_raw(&mut self, key: &str, buffer: &mut [u8]) -> Result<Option<usize>, KvError> {
+        let Some((offset, record)) = self.find(key)? else {
+            return Ok(None);
+        };
+        if record.kind == KIND_TOMBSTONE {
+            return Ok(None);
+        }
+        let value = buffer
+            .get_mut(..record.value_len)
+            .ok_or(KvError::BufferTooSmall)?;
+        self.read(
+            self.active,
+            offset + RECORD_HEADER_LEN + record.key_len,
+            value,
+        )?;
+        Ok(Some(record.value_len))
+    }
+
+    /// Stores the raw `value` of `key`.
+    pub fn set_raw(&mut self, key: &str, value: &[u8]) -> Result<(), KvError> {
+        self.append(KIND_VALUE, key, value)
+    }
+
+    /// Reads and decodes the value of `key`.
+    pub fn get<T: KvValue>(&mut self, key: &str) -> Result<Option<T>, KvError> {
+        let mut buffer = [0u8; MAX_VALUE_LEN];
+        match self.get_raw(key, &mut buffer)? {
+            Some(len) => T::decode(&buffer[..len]).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Encodes and stores the value of `key`.
+    pub fn set<T: KvValue>(&mut self, key: &str, value: &T) -> Result<(), KvError> {
+        let mut buffer = [0u8; MAX_VALUE_LEN];
+        let len = value.encode(&mut buffer)?;
+        self.set_raw(key, &buffer[..len])
+    }
+
+    /// Removes `key` from the store.
+    pub fn remove(&mut self, key: &str) -> Result<(), KvError> {
+        match self.find(key)? {
+            Some((_, record)) if record.kind == KIND_VALUE => self.append(KIND_TOMBSTONE, key, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bytes left in the active sector.
+    pub fn free(&self) -> usize {
+        if self.torn {
+            return 0;
+        }
+        SECTOR_SIZE - self.end
+    }
+
+    /// Copies the live records into the next sector and makes it active.
+    pub fn collect_garbage(&mut self) -> Result<(), KvError> {
+        let target = (self.active + 1) % self.sectors;
+        self.write(target, 0, &ERASED_SECTOR)?;
+
+        let mut target_offset = SECTOR_HEADER_LEN;
+        let mut offset = SECTOR_HEADER_LEN;
+        let mut buffer = [0u8; MAX_RECORD_LEN];
+        while offset < self.end {
+            let Some(record) = self.read_header(self.active, offset)? else {
+                break;
+            };
+            let len = record.stored_len();
+            self.read(self.active, offset, &mut buffer[..len])?;
+            let key = &buffer[RECORD_HEADER_LEN..RECORD_HEADER_LEN + record.key_len];
+
+            // Only the last record of a key is live, and tombstones have
+            // nothing left to hide once the older records are dropped.
+            if record.kind == KIND_VALUE && !self.superseded(offset + len, key)? {
+                self.write(target, target_offset, &buffer[..len])?;
+                target_offset += len;
+            }
+            offset += len;
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        self.write_sector_header(target, sequence)?;
+        self.active = target;
+        self.sequence = sequence;
+        self.end = target_offset;
+        self.torn = false;
+        Ok(())
+    }
+
+    fn append(&mut self, kind: u8, key: &str, value: &[u8]) -> Result<(), KvError> {
+        if key.len() > MAX_KEY_LEN {
+            return Err(KvError::KeyTooLong);
+        }
+        if value.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLong);
+        }
+
+        let len = (RECORD_HEADER_LEN + key.len() + value.len() + 3) & !3;
+        if len > self.free() {
+            self.collect_garbage()?;
+            if len > self.free() {
+                return Err(KvError::Full);
+            }
+        }
+
+        let mut buffer = [0xFFu8; MAX_RECORD_LEN];
+        buffer[0] = kind;
+        buffer[1] = key.len() as u8;
+        buffer[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        let key_end = RECORD_HEADER_LEN + key.len();
+        buffer[RECORD_HEADER_LEN..key_end].copy_from_slice(key.as_bytes());
+        buffer[key_end..key_end + value.len()].copy_from_slice(value);
+        let crc = record_crc(
+            &buffer[..4],
+            &buffer[RECORD_HEADER_LEN..key_end + value.len()],
+        );
+        buffer[4..8].copy_from_slice(&crc.to_le_bytes());
+
+        if let Err(e) = self.write(self.active, self.end, &buffer[..len]) {
+            // Part of the record may have been programmed already.
+            self.torn = true;
+            return Err(e);
+        }
+        self.end += len;
+        Ok(())
+    }
+
+    /// Finds the latest record of `key` in the active sector.
+    fn find(&mut self, key: &str) -> Result<Option<(usize, Record)>, KvError> {
+        let mut found = None;
+        let mut offset = SECTOR_HEADER_LEN;
+        while offset < self.end {
+            let Some(record) = self.read_header(self.active, offset)? else {
+                break;
+            };
+            let len = record.stored_len();
+            if self.key_matches(offset, &record, key.as_bytes())? {
+                found = Some((offset, record));
+            }
+            offset += len;
+        }
+        Ok(found)
+    }
+
+    /// Whether a record for `key` exists between `offset` and the end of the
+    /// active sector.
+    fn superseded(&mut self, mut offset: usize, key: &[u8]) -> Result<bool, KvError> {
+        while offset < self.end {
+            let Some(record) = self.read_header(self.active, offset)? else {
+                break;
+            };
+            if self.key_matches(offset, &record, key)? {
+                return Ok(true);
+            }
+            offset += record.stored_len();
+        }
+        Ok(false)
+    }
+
+    fn key_matches(&mut self, offset: usize, record: &Record, key: &[u8]) -> Result<bool, KvError> {
+        if record.key_len != key.len() {
+            return Ok(false);
+        }
+        let mut key_buf = [0u8; MAX_KEY_LEN];
+        self.read(
+            self.active,
+            offset + RECORD_HEADER_LEN,
+            &mut key_buf[..record.key_len],
+        )?;
+        Ok(&key_buf[..record.key_len] == key)
+    }
+
+    /// Finds the end of the valid records in the active sector, checking
+    /// the CRC of every record on the way.
+    fn scan(&mut self) -> Result<(), KvError> {
+        let mut offset = SECTOR_HEADER_LEN;
+        while offset + RECORD_HEADER_LEN <= SECTOR_SIZE {
+            match self.read_record(self.active, offset)? {
+                Some(record) => offset += record.stored_len(),
+                None => {
+                    let mut header = [0u8; RECORD_HEADER_LEN];
+                    self.read(self.active, offset, &mut header)?;
+                    self.torn = header.iter().any(|b| *b != 0xFF);
+                    break;
+                }
+            }
+        }
+        self.end = offset;
+        Ok(())
+    }
+
+    /// Reads the header at `offset`, returning `None` if it does not describe
+    /// a well-formed record.
+    fn read_header(&mut self, sector: u32, offset: usize) -> Result<Option<Record>, KvError> {
+        if offset + RECORD_HEADER_LEN > SECTOR_SIZE {
+            return Ok(None);
+        }
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        self.read(sector, offset, &mut header)?;
+
+        let record = Record {
+            kind: header[0],
+            key_len: header[1] as usize,
+            value_len: u16::from_le_bytes([header[2], header[3]]) as usize,
+            crc: u32::from_le_bytes([header[4], header[5], header[6], header[7]]),
+        };
+        if !matches!(record.kind, KIND_VALUE | KIND_TOMBSTONE)
+            || record.key_len > MAX_KEY_LEN
+            || record.value_len > MAX_VALUE_LEN
+            || offset + record.stored_len() > SECTOR_SIZE
+        {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Reads and validates the record at `offset`.
+    ///
+    /// Returns `None` for erased flash or a record that fails its CRC.
+    fn read_record(&mut self, sector: u32, offset: usize) -> Result<Option<Record>, KvError> {
+        let Some(record) = self.read_header(sector, offset)? else {
+            return Ok(None);
+        };
+        let mut data = [0u8; MAX_KEY_LEN + MAX_VALUE_LEN];
+        let data = &mut data[..record.key_len + record.value_len];
+        self.read(sector, offset + RECORD_HEADER_LEN, data)?;
+        let header = [
+            record.kind,
+            record.key_len as u8,
+            record.value_len as u8,
+            (record.value_len >> 8) as u8,
+        ];
+        if record_crc(&header, data) != record.crc {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    fn read_sector_header(&mut self, sector: u32) -> Result<Option<u32>, KvError> {
+        let mut header = [0u8; SECTOR_HEADER_LEN];
+        self.read(sector, 0, &mut header)?;
+        if header[0..4] != SECTOR_MAGIC {
+            return Ok(None);
+        }
+        let stored = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if crc32(&header[..8]) != stored {
+            return Ok(None);
+        }
+        Ok(Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ])))
+    }
+
+    fn write_sector_header(&mut self, sector: u32, sequence: u32) -> Result<(), KvError> {
+        let mut header = [0xFFu8; SECTOR_HEADER_LEN];
+        header[0..4].copy_from_slice(&SECTOR_MAGIC);
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        let crc = crc32(&header[..8]);
+        header[8..12].copy_from_slice(&crc.to_le_bytes());
+        self.write(sector, 0, &header)
+    }
+
+    fn format_sector(&mut self, sector: u32, sequence: u32) -> Result<(), KvError> {
+        self.write(sector, 0, &ERASED_SECTOR)?;
+        self.write_sector_header(sector, sequence)?;
+        self.active = sector;
+        self.sequence = sequence;
+        self.end = SECTOR_HEADER_LEN;
+        self.torn = false;
+        Ok(())
+    }
+
+    fn read(&mut self, sector: u32, offset: usize, bytes: &mut [u8]) -> Result<(), KvError> {
+        // NOR flash drivers only accept word-aligned reads, so whole words are
+        // read into a scratch buffer and the requested bytes copied out.
+        let address = sector as usize * SECTOR_SIZE + offset;
+        let mut scratch = [0u8; 32];
+        let mut done = 0;
+        while done < bytes.len() {
+            let at = address + done;
+            let skip = at & 3;
+            let len = ((bytes.len() - done + skip + 3) & !3).min(scratch.len());
+            self.storage
+                .read((at - skip) as u32, &mut scratch[..len])
+                .map_err(|_| KvError::Storage)?;
+            let copied = (len - skip).min(bytes.len() - done);
+            bytes[done..done + copied].copy_from_slice(&scratch[skip..skip + copied]);
+            done += copied;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u32, offset: usize, bytes: &[u8]) -> Result<(), KvError> {
+        let address = sector * SECTOR_SIZE as u32 + offset as u32;
+        self.storage
+            .write(address, bytes)
+            .map_err(|_| KvError::Storage)
+    }
+}
+
+fn record_crc(header: &[u8], data: &[u8]) -> u32 {
+    crc32_update(crc32(header), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_storage::MemStorage;
+
+    fn mount(sectors: usize) -> KvStore<MemStorage> {
+        KvStore::mount(MemStorage::new(sectors * SECTOR_SIZE)).unwrap()
+    }
+
+    fn remount(store: KvStore<MemStorage>) -> KvStore<MemStorage> {
+        let mut storage = store.into_inner();
+        storage.restore();
+        KvStore::mount(storage).unwrap()
+    }
+
+    /// Bytes a `u32` value of `key` takes in a sector.
+    fn record_len(key: &str) -> usize {
+        (RECORD_HEADER_LEN + key.len() + 4 + 3) & !3
+    }
+
+    #[test]
+    fn values_survive_remount() {
+        let mut store = mount(2);
+        store.set("a", &5u32).unwrap();
+        store.set("flag", &true).unwrap();
+        store
+            .set("name", &heapless::String::<16>::try_from("node").unwrap())
+            .unwrap();
+        store.set("a", &6u32).unwrap();
+        store.set("gone", &1u8).unwrap();
+        store.remove("gone").unwrap();
+
+        let mut store = remount(store);
+        assert_eq!(store.get::<u32>("a"), Ok(Some(6)));
+        assert_eq!(store.get::<bool>("flag"), Ok(Some(true)));
+        assert_eq!(
+            store.get::<heapless::String<16>>("name").unwrap().unwrap(),
+            "node"
+        );
+        assert_eq!(store.get::<u8>("gone"), Ok(None));
+        assert_eq!(store.get::<u16>("a"), Err(KvError::InvalidValue));
+        assert_eq!(store.get::<u32>("missing"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let mut store = mount(2);
+        assert_eq!(
+            store.set("k".repeat(MAX_KEY_LEN + 1).as_str(), &1u8),
+            Err(KvError::KeyTooLong)
+        );
+        assert_eq!(
+            store.set_raw("k", &[0; MAX_VALUE_LEN + 1]),
+            Err(KvError::ValueTooLong)
+        );
+        store.set_raw("k", &[1; 8]).unwrap();
+        assert_eq!(
+            store.get_raw("k", &mut [0; 4]),
+            Err(KvError::BufferTooSmall)
+        );
+        assert!(matches!(
+            KvStore::mount(MemStorage::new(SECTOR_SIZE)),
+            Err(KvError::TooFewSectors)
+        ));
+    }
+
+    #[test]
+    fn garbage_collection_keeps_live_values() {
+        let mut store = mount(3);
+        store.set("keep", &77u64).unwrap();
+        store.set("drop", &1u8).unwrap();
+        store.remove("drop").unwrap();
+        // Several times the size of a sector, so the ring wraps around.
+        for i in 0..2000u32 {
+            store.set("counter", &i).unwrap();
+        }
+        let mut store = remount(store);
+        assert_eq!(store.get::<u64>("keep"), Ok(Some(77)));
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(1999)));
+        assert_eq!(store.get::<u8>("drop"), Ok(None));
+    }
+
+    #[test]
+    fn torn_record_is_skipped() {
+        let mut store = mount(2);
+        store.set("counter", &1u32).unwrap();
+        let end = store.end;
+
+        // The power fails half way through the next record.
+        let mut storage = store.into_inner();
+        storage.cut_after(record_len("counter") / 2);
+        let mut store = KvStore::mount(storage).unwrap();
+        assert!(store.set("counter", &2u32).is_err());
+
+        let mut store = remount(store);
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(1)));
+        assert!(store.torn);
+        assert_eq!(store.end, end);
+        assert_eq!(store.free(), 0);
+
+        // The next write compacts the sector instead of appending after the
+        // torn bytes.
+        store.set("counter", &3u32).unwrap();
+        assert!(!store.torn);
+        let mut store = remount(store);
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(3)));
+        assert!(!store.torn);
+    }
+
+    #[test]
+    fn power_cut_during_gc_keeps_old_sector() {
+        let mut store = mount(2);
+        store.set("keep", &77u64).unwrap();
+        while store.free() >= record_len("counter") {
+            store.set("counter", &5u32).unwrap();
+        }
+        let active = store.active;
+        let target = ((active + 1) % store.sectors) as usize * SECTOR_SIZE;
+        let storage = store.into_inner();
+
+        // The next write starts a collection: the target sector is erased
+        // and the two live records are copied, then the power fails before
+        // its header is written.
+        let copied = record_len("keep") + 4 + record_len("counter");
+        let mut cut = storage;
+        cut.cut_after(SECTOR_SIZE + copied);
+        let mut store = KvStore::mount(cut).unwrap();
+        assert!(store.set("counter", &6u32).is_err());
+
+        let mut storage = store.into_inner();
+        storage.restore();
+        assert!(storage.data[target..target + SECTOR_HEADER_LEN]
+            .iter()
+            .all(|b| *b == 0xFF));
+        assert!(
+            storage.data[target + SECTOR_HEADER_LEN..target + SECTOR_HEADER_LEN + copied]
+                .iter()
+                .any(|b| *b != 0xFF)
+        );
+
+        let mut store = KvStore::mount(storage).unwrap();
+        assert_eq!(store.active, active);
+        assert_eq!(store.get::<u64>("keep"), Ok(Some(77)));
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(5)));
+
+        // The collection is redone by the next write.
+        store.set("counter", &7u32).unwrap();
+        assert_ne!(store.active, active);
+        let mut store = remount(store);
+        assert_eq!(store.get::<u64>("keep"), Ok(Some(77)));
+        assert_eq!(store.get::<u32>("counter"), Ok(Some(7)));
+    }
+
+    #[test]
+    fn power_cut_anywhere_keeps_old_or_new_value() {
+        for cut in (0..3 * SECTOR_SIZE).step_by(7) {
+            let mut store = mount(2);
+            store.set("keep", &77u64).unwrap();
+            let mut storage = store.into_inner();
+            storage.cut_after(cut);
+            let mut store = KvStore::mount(storage).unwrap();
+            let mut last = None;
+            for i in 0..1000u32 {
+                if store.set("counter", &i).is_err() {
+                    break;
+                }
+                last = Some(i);
+            }
+
+            let mut store = remount(store);
+            assert_eq!(store.get::<u64>("keep"), Ok(Some(77)), "cut at {}", cut);
+            let value = store.get::<u32>("counter").unwrap();
+            let expected = last.map_or(0, |last| last + 1);
+            assert!(
+                value == last || value == Some(expected),
+                "cut at {}: {:?} after {:?}",
+                cut,
+                value,
+                last
+            );
+
+            store.set("counter", &4242u32).unwrap();
+            let mut store = remount(store);
+            assert_eq!(store.get::<u32>("counter"), Ok(Some(4242)));
+            assert_eq!(store.get::<u64>("keep"), Ok(Some(77)));
+        }
+    }
+}
@@ -12,7 +12,7 @@ pub struct Led<'d> {
     state: LedState,
 }
 
-static LED_SIGNAL: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
+pub static LED_SIGNAL: Signal<CriticalSectionRawMutex, LedState> = Signal::new();
 
 impl<'d> Led<'d> {
     pub fn new(pin: Output<'d>) -> Self {
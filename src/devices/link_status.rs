@@ -0,0 +1,564 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use heapless::Vec;
+use lora_modulation::{Bandwidth, BaseBandModulationParams, SpreadingFactor};
+use lorawan_device::async_device::radio::{
+    PhyRxTx, RxConfig, RxMode, RxQuality, RxStatus, Timings, TxConfig,
+};
+
+use super::{
+    downlink::CommandError,
+    multicast,
+    rx_windows::{self, Window},
+};
+
+/// FPort whose payload holds MAC commands, encrypted with the NwkSKey.
+pub const MAC_PORT: u8 = 0;
+/// Largest LoRaWAN frame.
+pub const MAX_FRAME_LEN: usize = 255;
+/// Seconds between link checks.
+pub const LINK_CHECK_INTERVAL_S: u64 = 3600;
+/// `LinkCheckReq` followed by `DeviceTimeReq`, both without arguments.
+pub const LINK_CHECK_REQUEST: [u8; 2] = [cid::LINK_CHECK, cid::DEVICE_TIME];
+/// Link checks in a row the network may leave unanswered before the session
+/// is given up.
+pub const LINK_CHECK_LIMIT: u8 = 3;
+/// Uplinks without any downlink after which a link check is sent early,
+/// `ADR_ACK_LIMIT` of the regional parameters.
+pub const ADR_ACK_LIMIT: u32 = 64;
+/// Further uplinks without any downlink after which the session is given
+/// up, `ADR_ACK_DELAY` of the regional parameters.
+pub const ADR_ACK_DELAY: u32 = 32;
+
+mod cid {
+    pub const LINK_CHECK: u8 = 0x02;
+    pub const DEVICE_TIME: u8 = 0x0D;
+}
+
+/// Quality of the LoRaWAN or P2P link, shared with the display.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkStatus {
+    /// Demodulation margin of the last `LinkCheckAns`, in dB above the
+    /// demodulation floor.
+    pub margin_db: Option<u8>,
+    /// Gateways that received the last `LinkCheckReq`.
+    pub gateways: Option<u8>,
+    /// Frequency, RSSI and SNR of the last downlink.
+    pub frequency_hz: Option<u32>,
+    pub rssi_dbm: Option<i16>,
+    pub snr_db: Option<i8>,
+    /// End of the last uplink transmission, the instant `DeviceTimeAns`
+    /// refers to.
+    pub uplink_end: Option<Instant>,
+    /// Neighbours of a P2P node, which take the place of the gateways.
+    pub peers: Option<u8>,
+}
+
+static LINK_STATUS: Mutex<CriticalSectionRawMutex, Cell<LinkStatus>> =
+    Mutex::new(Cell::new(LinkStatus {
+        margin_db: None,
+        gateways: None,
+        frequency_hz: None,
+        rssi_dbm: None,
+        snr_db: None,
+        uplink_end: None,
+        peers: None,
+    }));
+
+/// Last frame the radio received, for what the stack does not hand over.
+static LAST_FRAME: Mutex<CriticalSectionRawMutex, RefCell<Vec<u8, MAX_FRAME_LEN>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+pub fn link_status() -> LinkStatus {
+    LINK_STATUS.lock(|status| status.get())
+}
+
+fn update(f: impl FnOnce(&mut LinkStatus)) {
+    LINK_STATUS.lock(|status| {
+        let mut value = status.get();
+        f(&mut value);
+        status.set(value);
+    });
+}
+
+/// Answer to a link diagnostics MAC command.
+///
+/// | CID    | Answer          | Arguments                                  |
+/// |--------|-----------------|--------------------------------------------|
+/// | `0x02` | LinkCheckAns    | margin (dB), gateway count                 |
+/// | `0x0D` | DeviceTimeAns   | GPS seconds (u32), fractional (1/256 s)    |
+///
+/// Multi-byte values are little-endian.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacAnswer {
+    LinkCheck { margin_db: u8, gateways: u8 },
+    DeviceTime { gps_s: u32, fraction: u8 },
+}
+
+impl MacAnswer {
+    /// Parses the MAC command at the start of `bytes`, returning the answer,
+    /// if it is one of the diagnostics answers, with the number of bytes the
+    /// command used.
+    ///
+    /// Other downlink MAC commands of LoRaWAN 1.0.x are skipped, the stack
+    /// handles them.
+    ///
+    /// # Errors
+    ///
+    /// * `Empty` - If `bytes` is empty.
+    /// * `UnknownOpcode` - If the CID is not defined.
+    /// * `Truncated` - If the payload ends before the command's arguments.
+    pub fn parse(bytes: &[u8]) -> Result<(Option<Self>, usize), CommandError> {
+        let (&id, args) = bytes.split_first().ok_or(CommandError::Empty)?;
+        let len = match id {
+            cid::LINK_CHECK => 2,
+            cid::DEVICE_TIME => 5,
+            // LinkADRReq, RXParamSetupReq, DlChannelReq
+            0x03 | 0x05 | 0x0A => 4,
+            // DutyCycleReq, RXTimingSetupReq, TxParamSetupReq
+            0x04 | 0x08 | 0x09 => 1,
+            // DevStatusReq
+            0x06 => 0,
+            // NewChannelReq
+            0x07 => 5,
+            _ => return Err(CommandError::UnknownOpcode(id)),
+        };
+        let a = args.get(..len).ok_or(CommandError::Truncated(id))?;
+        let answer = match id {
+            cid::LINK_CHECK => Some(MacAnswer::LinkCheck {
+                margin_db: a[0],
+                gateways: a[1],
+            }),
+            cid::DEVICE_TIME => Some(MacAnswer::DeviceTime {
+                gps_s: u32::from_le_bytes([a[0], a[1], a[2], a[3]]),
+                fraction: a[4],
+            }),
+            _ => None,
+        };
+        Ok((answer, 1 + len))
+    }
+}
+
+/// Handles the MAC commands of a downlink on [`MAC_PORT`], storing the link
+/// check results.
+///
+/// Returns the GPS time of `DeviceTimeAns` in milliseconds, with the instant
+/// it refers to.
+pub fn handle(payload: &[u8]) -> Option<(u64, Instant)> {
+    let mut time = None;
+    let mut bytes = payload;
+    while !bytes.is_empty() {
+        let answer = match MacAnswer::parse(bytes) {
+            Ok((answer, len)) => {
+                bytes = &bytes[len..];
+                answer
+            }
+            Err(e) => {
+                esp_println::println!("[LINK] {}", e);
+                break;
+            }
+        };
+        match answer {
+            Some(MacAnswer::LinkCheck {
+                margin_db,
+                gateways,
+            }) => {
+                esp_println::println!("[LINK] Margin {} dB, {} gateways", margin_db, gateways);
+                update(|status| {
+                    status.margin_db = Some(margin_db);
+                    status.gateways = Some(gateways);
+                });
+            }
+            Some(MacAnswer::DeviceTime { gps_s, fraction }) => {
+                let gps_ms = gps_s as u64 * 1000 + fraction as u64 * 1000 / 256;
+                let at = link_status().uplink_end.unwrap_or(Instant::now());
+                time = Some((gps_ms, at));
+            }
+            None => {}
+        }
+    }
+    time
+}
+
+/// Demodulation margin of a frame received at `snr_db` with spreading factor
+/// `sf`, in dB above the floor of the SX127x: -7.5 dB at SF7, 2.5 dB lower
+/// for each step up.
+pub fn demodulation_margin(sf: u8, snr_db: i16) -> u8 {
+    let floor_x10 = -75 - 25 * (sf.clamp(7, 12) as i16 - 7);
+    ((snr_db * 10 - floor_x10) / 10).clamp(0, u8::MAX as i16) as u8
+}
+
+/// Records a P2P frame received on `frequency_hz` with spreading factor `sf`.
+///
+/// The margin is that of the frame, as `LinkCheckAns` gives it for uplinks.
+pub fn p2p_received(frequency_hz: u32, sf: u8, rssi_dbm: i16, snr_db: i16) {
+    update(|status| {
+        status.frequency_hz = Some(frequency_hz);
+        status.rssi_dbm = Some(rssi_dbm);
+        status.snr_db = Some(snr_db.clamp(i8::MIN as i16, i8::MAX as i16) as i8);
+        status.margin_db = Some(demodulation_margin(sf, snr_db));
+    });
+}
+
+/// Records the number of neighbours of a P2P node.
+pub fn set_peers(peers: usize) {
+    update(|status| status.peers = Some(peers.min(u8::MAX as usize) as u8));
+}
+
+/// Takes the last frame the radio received.
+///
+/// After the stack accepted a join accept or a downlink, this is that frame.
+pub fn take_frame() -> Option<Vec<u8, MAX_FRAME_LEN>> {
+    LAST_FRAME.lock(|frame| {
+        let frame = frame.take();
+        (!frame.is_empty()).then_some(frame)
+    })
+}
+
+/// MAC commands in the FOpts of the data downlink `frame`, if its frame
+/// counter is `fcnt`.
+///
+/// | Offset | Size | Field                                  |
+/// |--------|------|----------------------------------------|
+/// | 0      | 1    | MHDR, data down in bits 7-5            |
+/// | 1      | 4    | DevAddr                                |
+/// | 5      | 1    | FCtrl, FOpts length in bits 3-0        |
+/// | 6      | 2    | FCnt (LE), low 16 bits                 |
+/// | 8      | 0-15 | FOpts                                  |
+///
+/// The FOpts of LoRaWAN 1.0.x are not encrypted.
+pub fn frame_options(frame: &[u8], fcnt: u32) -> Option<&[u8]> {
+    // Unconfirmed and confirmed data down.
+    if !matches!(frame.first()? >> 5, 3 | 5) {
+        return None;
+    }
+    let fhdr = frame.get(1..8)?;
+    if u16::from_le_bytes([fhdr[5], fhdr[6]]) != fcnt as u16 {
+        return None;
+    }
+    let end = 8 + (fhdr[4] & 0x0F) as usize;
+    // The MIC follows.
+    if frame.len() < end + 4 {
+        return None;
+    }
+    Some(&frame[8..end])
+}
+
+/// Counts the uplinks the network left unanswered, to tell when a session
+/// is lost and the device must join again.
+///
+/// After [`ADR_ACK_LIMIT`] uplinks without a downlink the network is asked
+/// for a link check; the session is lost after [`ADR_ACK_DELAY`] more, or
+/// after [`LINK_CHECK_LIMIT`] link checks in a row went unanswered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkLoss {
+    silent_uplinks: u32,
+    unanswered_checks: u8,
+}
+
+impl LinkLoss {
+    /// Records an uplink that got no downlink, `link_check` if it carried a
+    /// `LinkCheckReq`.
+    pub fn unanswered(&mut self, link_check: bool) {
+        self.silent_uplinks = self.silent_uplinks.saturating_add(1);
+        if link_check {
+            self.unanswered_checks = self.unanswered_checks.saturating_add(1);
+        }
+    }
+
+    /// Records a downlink, which proves the session still works.
+    pub fn answered(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Whether a link check is due to find out if the network still hears
+    /// the device.
+    pub fn needs_check(&self) -> bool {
+        self.silent_uplinks == ADR_ACK_LIMIT
+    }
+
+    /// Whether the session must be given up.
+    pub fn is_lost(&self) -> bool {
+        self.unanswered_checks >= LINK_CHECK_LIMIT
+            || self.silent_uplinks >= ADR_ACK_LIMIT + ADR_ACK_DELAY
+    }
+}
+
+/// Radio of the LoRaWAN stack that records the quality of the downlinks it
+/// receives in the [`LinkStatus`], and keeps the last frame for
+/// [`take_frame`].
+///
+/// It also opens the receive windows of a session restored from flash with
+/// the settings the network configured, see [`rx_windows::restore`], and
+/// listens on the channel of a multicast session in class C, see
+/// [`multicast::listen`].
+pub struct MonitoredRadio<R> {
+    radio: R,
+    frequency_hz: u32,
+    /// Data rate of the last uplink, with the receive windows set up since.
+    uplink_dr: Option<u8>,
+    windows: u8,
+}
+
+impl<R> MonitoredRadio<R> {
+    pub fn new(radio: R) -> Self {
+        Self {
+            radio,
+            frequency_hz: 0,
+            uplink_dr: None,
+            windows: 0,
+        }
+    }
+
+    fn received(&self, frame: &[u8], quality: &RxQuality) {
+        let (frequency_hz, rssi, snr) = (self.frequency_hz, quality.rssi(), quality.snr());
+        update(|status| {
+            status.frequency_hz = Some(frequency_hz);
+            status.rssi_dbm = Some(rssi);
+            status.snr_db = Some(snr);
+        });
+        LAST_FRAME.lock(|last| {
+            let mut last = last.borrow_mut();
+            last.clear();
+            // Cannot fail, the radio receives at most 255 bytes.
+            let _ = last.extend_from_slice(&frame[..frame.len().min(MAX_FRAME_LEN)]);
+        });
+        multicast::offer(frame);
+    }
+
+    /// Replaces the frequency and modulation the stack set up for a window
+    /// with those of the restored session.
+    fn restore_window(&mut self, config: &mut RxConfig) {
+        let Some((region, settings)) = rx_windows::restored() else {
+            return;
+        };
+        let window = match config.mode {
+            RxMode::Single { .. } if self.windows == 0 => Window::Rx1,
+            RxMode::Single { .. } | RxMode::Continuous => Window::Rx2,
+        };
+        self.windows = self.windows.saturating_add(1);
+        let Some(uplink_dr) = self.uplink_dr else {
+            return;
+        };
+        let Some((frequency, params)) =
+            settings.window(region, window, uplink_dr, config.rf.frequency)
+        else {
+            return;
+        };
+        if let (Some(sf), Some(bw)) = (
+            spreading_factor(params.spreading_factor),
+            bandwidth(params.bandwidth_hz),
+        ) {
+            config.rf.frequency = frequency;
+            config.rf.bb = BaseBandModulationParams::new(sf, bw, config.rf.bb.cr);
+        }
+    }
+
+    /// Replaces the channel class C listens on between uplinks with that of
+    /// the multicast session.
+    fn multicast_window(config: &mut RxConfig) {
+        let Some(session) = multicast::listening() else {
+            return;
+        };
+        if !matches!(config.mode, RxMode::Continuous) {
+            return;
+        }
+        if let (Some(sf), Some(bw)) = (
+            spreading_factor(session.params.spreading_factor),
+            bandwidth(session.params.bandwidth_hz),
+        ) {
+            config.rf.frequency = session.frequency_hz;
+            config.rf.bb = BaseBandModulationParams::new(sf, bw, config.rf.bb.cr);
+        }
+    }
+}
+
+fn spreading_factor(sf: u8) -> Option<SpreadingFactor> {
+    Some(match sf {
+        7 => SpreadingFactor::_7,
+        8 => SpreadingFactor::_8,
+        9 => SpreadingFactor::_9,
+        10 => SpreadingFactor::_10,
+        11 => SpreadingFactor::_11,
+        12 => SpreadingFactor::_12,
+        _ => return None,
+    })
+}
+
+fn bandwidth(hz: u32) -> Option<Bandwidth> {
+    Some(match hz {
+        125_000 => Bandwidth::_125KHz,
+        250_000 => Bandwidth::_250KHz,
+        500_000 => Bandwidth::_500KHz,
+        _ => return None,
+    })
+}
+
+/// Spreading factor and bandwidth in Hz of `bb`, for the LoRaWAN data rates.
+fn lora_params(bb: &BaseBandModulationParams) -> Option<(u8, u32)> {
+    let sf = match bb.sf {
+        SpreadingFactor::_7 => 7,
+        SpreadingFactor::_8 => 8,
+        SpreadingFactor::_9 => 9,
+        SpreadingFactor::_10 => 10,
+        SpreadingFactor::_11 => 11,
+        SpreadingFactor::_12 => 12,
+        _ => return None,
+    };
+    let bw = match bb.bw {
+        Bandwidth::_125KHz => 125_000,
+        Bandwidth::_250KHz => 250_000,
+        Bandwidth::_500KHz => 500_000,
+        _ => return None,
+    };
+    Some((sf, bw))
+}
+
+impl<R: PhyRxTx> PhyRxTx for MonitoredRadio<R> {
+    type PhyError = R::PhyError;
+
+    const ANTENNA_GAIN: i8 = R::ANTENNA_GAIN;
+    const MAX_RADIO_POWER: u8 = R::MAX_RADIO_POWER;
+
+    async fn tx(&mut self, config: TxConfig, buf: &[u8]) -> Result<u32, Self::PhyError> {
+        self.windows = 0;
+        self.uplink_dr = rx_windows::restored().and_then(|(region, _)| {
+            let (sf, bw) = lora_params(&config.rf.bb)?;
+            region.uplink_data_rate(sf, bw)
+        });
+        let result = self.radio.tx(config, buf).await;
+        if result.is_ok() {
+            let end = Instant::now();
+            update(|status| status.uplink_end = Some(end));
+        }
+        result
+    }
+
+    async fn setup_rx(&mut self, mut config: RxConfig) -> Result<(), Self::PhyError> {
+        self.restore_window(&mut config);
+        Self::multicast_window(&mut config);
+        self.frequency_hz = config.rf.frequency;
+        self.radio.setup_rx(config).await
+    }
+
+    async fn rx_continuous(
+        &mut self,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, RxQuality), Self::PhyError> {
+        let result = self.radio.rx_continuous(rx_buf).await;
+        if let Ok((len, quality)) = &result {
+            self.received(&rx_buf[..*len], quality);
+        }
+        result
+    }
+
+    async fn rx_single(&mut self, buf: &mut [u8]) -> Result<RxStatus, Self::PhyError> {
+        let result = self.radio.rx_single(buf).await;
+        if let Ok(RxStatus::Rx(len, quality)) = &result {
+            self.received(&buf[..*len], quality);
+        }
+        result
+    }
+
+    async fn low_power(&mut self) -> Result<(), Self::PhyError> {
+        self.radio.low_power().await
+    }
+}
+
+impl<R: Timings> Timings for MonitoredRadio<R> {
+    /// Opens the windows of a restored session after its RX delay.
+    fn get_rx_window_offset_ms(&self) -> i32 {
+        let shift = rx_windows::restored().map_or(0, |(_, settings)| settings.delay_shift_ms());
+        self.radio.get_rx_window_offset_ms() + shift
+    }
+
+    fn get_rx_window_duration_ms(&self) -> u32 {
+        self.radio.get_rx_window_duration_ms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unconfirmed data down with FCnt 0x0102 and `fopts`, without payload.
+    fn downlink(fopts: &[u8]) -> std::vec::Vec<u8> {
+        let mut frame = std::vec![0x60, 0x34, 0x12, 0x0B, 0x26, fopts.len() as u8, 0x02, 0x01];
+        frame.extend_from_slice(fopts);
+        frame.extend_from_slice(&[0xAA; 4]);
+        frame
+    }
+
+    #[test]
+    fn reads_frame_options() {
+        let frame = downlink(&[0x02, 12, 3]);
+        assert_eq!(frame_options(&frame, 0x0102), Some(&[0x02, 12, 3][..]));
+        assert_eq!(frame_options(&frame, 0x1_0102), Some(&[0x02, 12, 3][..]));
+        assert_eq!(frame_options(&downlink(&[]), 0x0102), Some(&[][..]));
+
+        assert_eq!(frame_options(&frame, 0x0103), None);
+        assert_eq!(frame_options(&frame[..frame.len() - 1], 0x0102), None);
+        let mut uplink = frame.clone();
+        uplink[0] = 0x40;
+        assert_eq!(frame_options(&uplink, 0x0102), None);
+        assert_eq!(frame_options(&[0x20; 17], 0x0102), None);
+        assert_eq!(frame_options(&[], 0), None);
+    }
+
+    #[test]
+    fn handles_link_check_answers() {
+        // LinkCheckAns, LinkADRReq and DeviceTimeAns, as FOpts carry them.
+        let options = [
+            0x02, 12, 3, 0x03, 0x50, 0xFF, 0x00, 0x01, 0x0D, 0x10, 0, 0, 0, 128,
+        ];
+        let (gps_ms, _) = handle(&options).unwrap();
+        assert_eq!(gps_ms, 16_500);
+        let status = link_status();
+        assert_eq!((status.margin_db, status.gateways), (Some(12), Some(3)));
+
+        assert_eq!(handle(&[0x02, 12]), None);
+        assert_eq!(handle(&[0x06, 0x02, 20, 1]), None);
+        assert_eq!(link_status().margin_db, Some(20));
+    }
+
+    #[test]
+    fn gives_up_unanswered_sessions() {
+        let mut loss = LinkLoss::default();
+        for _ in 1..ADR_ACK_LIMIT {
+            loss.unanswered(false);
+            assert!(!loss.needs_check());
+        }
+        loss.unanswered(false);
+        assert!(loss.needs_check());
+        for _ in 0..ADR_ACK_DELAY - 1 {
+            loss.unanswered(false);
+            assert!(!loss.is_lost());
+        }
+        loss.unanswered(false);
+        assert!(!loss.needs_check());
+        assert!(loss.is_lost());
+
+        loss.answered();
+        assert_eq!(loss, LinkLoss::default());
+        for _ in 1..LINK_CHECK_LIMIT {
+            loss.unanswered(true);
+        }
+        assert!(!loss.is_lost());
+        loss.answered();
+        for _ in 0..LINK_CHECK_LIMIT {
+            loss.unanswered(true);
+        }
+        assert!(loss.is_lost());
+    }
+
+    #[test]
+    fn computes_demodulation_margins() {
+        assert_eq!(demodulation_margin(7, -7), 0);
+        assert_eq!(demodulation_margin(7, 3), 10);
+        assert_eq!(demodulation_margin(11, -10), 7);
+        assert_eq!(demodulation_margin(12, -25), 0);
+        assert_eq!(demodulation_margin(12, 10), 30);
+    }
+}
@@ -1,11 +1,15 @@
-use embedded_storage::Storage;
 use esp_hal::gpio::{Input, Output};
 use lora_phy::{
     sx127x::{Sx1276, Sx127x},
     LoRa,
 };
 
-use super::{iv::InterfaceSx1276, types::BusSpi};
+use super::{
+    iv::{InterfaceSx1276, IrqEvent},
+    rx_windows::RxSettings,
+    session::SessionRecord,
+    types::{BusSpi, MutexSettings},
+};
 
 #[derive(Debug)]
 pub enum LoraTaskError {
@@ -23,7 +27,12 @@ pub struct LoRaRadio<'d> {
     pub application_eui: [u8; 8],
     pub application_session_key: [u8; 16],
     pub network_session_key: [u8; 16],
-    pub storage: esp_storage::FlashStorage,
+    pub fcnt_up: u32,
+    pub fcnt_down: u32,
+    pub rx_settings: Option<RxSettings>,
+    pub settings: &'static MutexSettings,
+    /// Event of the last wait for a radio interrupt.
+    pub irq: &'static IrqEvent,
 }
 
 impl<'d> LoRaRadio<'d> {
@@ -32,7 +41,8 @@ impl<'d> LoRaRadio<'d> {
         reset: Output<'d>,
         dio0: Input<'d>,
         dio1: Input<'d>,
-        storage: esp_storage::FlashStorage,
+        irq: &'static IrqEvent,
+        settings: &'static MutexSettings,
     ) -> Result<Self, LoraTaskError> {
         let config = lora_phy::sx127x::Config {
             chip: Sx1276,
@@ -40,7 +50,7 @@ impl<'d> LoRaRadio<'d> {
             tx_boost: false,
             rx_boost: false,
         };
-        let iv = InterfaceSx1276::new(dio0, dio1, reset, None, None)
+        let iv = InterfaceSx1276::new(dio0, dio1, reset, None, None, irq)
             .map_err(|_| LoraTaskError::InitFailed)?;
         let driver_lora = Sx127x::new(spi, iv, config);
         let lora = LoRa::new(driver_lora, true, embassy_time::Delay)
@@ -55,21 +65,54 @@ impl<'d> LoRaRadio<'d> {
             application_eui: [0; 8],
             application_session_key: [0; 16],
             network_session_key: [0; 16],
-            storage,
+            fcnt_up: 0,
+            fcnt_down: 0,
+            rx_settings: None,
+            settings,
+            irq,
         })
     }
 
-    pub fn save(&mut self) {
-        let mut buffer = [0; 40];
-        buffer[..4].copy_from_slice(&self.device_addr);
-        buffer[4..12].copy_from_slice(&self.device_eui);
-        buffer[12..20].copy_from_slice(&self.application_eui);
-        buffer[20..28].copy_from_slice(&self.application_session_key);
-        buffer[28..36].copy_from_slice(&self.network_session_key);
-        buffer[36..40].copy_from_slice(&self.device_nonce.to_le_bytes());
-        match self.storage.write(0, &buffer) {
+    /// Snapshot of the session fields as a persistable record.
+    pub fn session(&self) -> SessionRecord {
+        SessionRecord {
+            device_addr: self.device_addr,
+            device_eui: self.device_eui,
+            application_eui: self.application_eui,
+            application_session_key: self.application_session_key,
+            network_session_key: self.network_session_key,
+            device_nonce: self.device_nonce,
+            fcnt_up: self.fcnt_up,
+            fcnt_down: self.fcnt_down,
+            rx_settings: self.rx_settings,
+        }
+    }
+
+    fn apply_session(&mut self, record: SessionRecord) {
+        self.device_addr = record.device_addr;
+        self.device_eui = record.device_eui;
+        self.application_eui = record.application_eui;
+        self.application_session_key = record.application_session_key;
+        self.network_session_key = record.network_session_key;
+        self.device_nonce = record.device_nonce;
+        self.fcnt_up = record.fcnt_up;
+        self.fcnt_down = record.fcnt_down;
+        self.rx_settings = record.rx_settings;
+    }
+
+    /// Persists the session fields to the settings store.
+    pub async fn save(&mut self) {
+        let mut settings = self.settings.lock().await;
+        match self.session().write(&mut *settings) {
             Ok(()) => (),
-            Err(e) => esp_println::println!("[LoRa] Error writing to flash: {:#?}", e),
+            Err(e) => esp_println::println!("[LoRa] Error writing to flash: {}", e),
         };
     }
+
+    /// Restores the session fields from the settings store, see
+    /// [`SessionRecord::load`].
+    pub async fn load(&mut self) {
+        let record = SessionRecord::load(&mut *self.settings.lock().await);
+        self.apply_session(record);
+    }
 }
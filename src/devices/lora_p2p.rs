@@ -1,11 +1,49 @@
-use crate::devices::lora::LoRaRadio;
-use embassy_time::{Duration, Timer};
+use crate::devices::{
+    airtime::LoRaParams,
+    button::{HOLD_SIGNAL, LONG_PRESS_SIGNAL},
+    clock::{self, TimeSource},
+    gps, link_status,
+    lora::LoRaRadio,
+    p2p_arq::{Arq, ArqConfig, ArqError, Delivered, P2pRadio},
+    p2p_cad::{self, CadDecision, CadStats, ListenBeforeTalk},
+    p2p_crypto::{self, SecureError, SecureRadio, SECURITY_LEN},
+    p2p_fragment::{self, FragmentConfig, FragmentError, Reassembler},
+    p2p_frame::{
+        self, Frame, MessageType, NodeId, BROADCAST, CRC_LEN, HEADER_LEN, MAX_FRAME_LEN,
+        MAX_PAYLOAD_LEN,
+    },
+    p2p_mesh::{self, Flood, MeshRole, MESH_HEADER_LEN},
+    p2p_neighbors::{self, capabilities, Beacon, BEACON_INTERVAL_MS, BEACON_JITTER_MS},
+    p2p_pairing,
+    p2p_routing::{self, encode_unicast, RouteHeader, Router, DEFAULT_HOP_LIMIT, ROUTE_HEADER_LEN},
+    p2p_tdma::{self, Tdma, TdmaRole},
+    payload::{DeviceStatus, Telemetry, COMPACT_FIX_LEN},
+    types::{MutexNvs, SettingsStorage},
+};
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{efuse::Efuse, rng::Rng};
 use lora_phy::{
-    mod_params::{Bandwidth, CodingRate, ModulationParams, PacketParams, SpreadingFactor},
+    mod_params::{
+        Bandwidth, CodingRate, ModulationParams, PacketParams, PacketStatus, SpreadingFactor,
+    },
     RxMode,
 };
 
 const PREAMBLE_LENGTH: u16 = 12;
+/// Time spent listening between two telemetry broadcasts.
+const RECEIVE_WINDOW: Duration = Duration::from_secs(6);
+/// Pause after a radio error before the loop carries on.
+const ERROR_BACKOFF: Duration = Duration::from_secs(1);
+/// Largest message reassembled from fragments, and the number reassembled
+/// at a time.
+const MAX_MESSAGE_LEN: usize = 2048;
+const REASSEMBLY_BUFFERS: usize = 2;
+/// Time between two neighbour reports to the sink.
+const NEIGHBOR_REPORT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The LoRa radio with the frames secured by the network key.
+type Link = SecureRadio<'static, P2pLink, SettingsStorage>;
 
 #[derive(Debug)]
 pub enum P2PErrors {
@@ -13,6 +51,7 @@ pub enum P2PErrors {
     Tx,
     PrepareForRx,
     Rx,
+    ChannelBusy,
 }
 
 impl core::fmt::Display for P2PErrors {
@@ -23,91 +62,809 @@ impl core::fmt::Display for P2PErrors {
             P2PErrors::Tx => write!(f, "Transmission failed"),
             P2PErrors::PrepareForRx => write!(f, "Failed to prepare for reception"),
             P2PErrors::Rx => write!(f, "Reception failed"),
+            P2PErrors::ChannelBusy => write!(f, "Channel busy, frame not sent"),
+        }
+    }
+}
+
+/// LoRa radio with the P2P modulation, the radio of the [`Arq`] link.
+pub struct P2pLink {
+    lora: LoRaRadio<'static>,
+    modulation: ModulationParams,
+    tx_params: PacketParams,
+    rx_params: PacketParams,
+    /// RSSI and SNR of the last frame received.
+    rssi: Option<i16>,
+    snr: Option<i16>,
+    lbt: ListenBeforeTalk,
+}
+
+impl P2pLink {
+    /// Configures `lora` for P2P on `frequency` Hz; `seed` starts the random
+    /// backoff of listen-before-talk.
+    pub fn new(mut lora: LoRaRadio<'static>, frequency: u32, seed: u32) -> Result<Self, P2PErrors> {
+        let modulation = match lora.radio.create_modulation_params(
+            SpreadingFactor::_11,
+            Bandwidth::_500KHz,
+            CodingRate::_4_5,
+            frequency,
+        ) {
+            Ok(params) => params,
+            Err(err) => {
+                esp_println::println!("[LoRa P2P] Failed to create modulation params: {:?}", err);
+                return Err(P2PErrors::PrepareForTx);
+            }
+        };
+
+        let tx_params = match lora.radio.create_tx_packet_params(
+            PREAMBLE_LENGTH,
+            false,
+            true,
+            false,
+            &modulation,
+        ) {
+            Ok(params) => params,
+            Err(err) => {
+                esp_println::println!("[LoRa P2P] Failed to create tx packet params: {:?}", err);
+                return Err(P2PErrors::PrepareForTx);
+            }
+        };
+
+        let rx_params = match lora.radio.create_rx_packet_params(
+            PREAMBLE_LENGTH,
+            false,
+            MAX_FRAME_LEN as u8,
+            true,
+            false,
+            &modulation,
+        ) {
+            Ok(params) => params,
+            Err(err) => {
+                esp_println::println!("[LoRa P2P] Failed to create rx packet params: {:?}", err);
+                return Err(P2PErrors::PrepareForRx);
+            }
+        };
+
+        Ok(Self {
+            lora,
+            modulation,
+            tx_params,
+            rx_params,
+            rssi: None,
+            snr: None,
+            lbt: ListenBeforeTalk::new(Default::default(), seed),
+        })
+    }
+
+    /// Channel activity detections made before transmitting.
+    pub fn cad_stats(&self) -> CadStats {
+        self.lbt.stats()
+    }
+
+    /// Senses the channel with a channel activity detection, deciding what
+    /// follows with the listen-before-talk policy, see [`p2p_cad`].
+    async fn sense_channel(&mut self) -> CadDecision {
+        self.lora.irq.clear();
+        let detected = match self.lora.radio.prepare_for_cad(&self.modulation).await {
+            Ok(()) => self.lora.radio.cad(&self.modulation).await,
+            Err(err) => Err(err),
+        };
+        match detected {
+            Ok(detected) => {
+                // CadDetected raises DIO1 along with CadDone on DIO0.
+                let irq = self.lora.irq.last().unwrap_or_default();
+                self.lbt.detected(detected || irq.dio1, irq.timed_out())
+            }
+            Err(err) => {
+                esp_println::println!("[LoRa P2P] Channel activity detection failed: {:?}", err);
+                self.lbt.failed()
+            }
         }
     }
 }
 
+impl P2pRadio for P2pLink {
+    type Error = P2PErrors;
+
+    /// Holds the frame until the slot of this node when it follows a TDMA
+    /// schedule, see [`p2p_tdma`], then listens before talking, backing off
+    /// while another node transmits, see [`p2p_cad`]. Acknowledgements follow
+    /// the frame in its slot and time beacons start theirs, so they are sent
+    /// at once.
+    ///
+    /// # Errors
+    ///
+    /// * `ChannelBusy` - If the channel stayed busy and the frame was dropped.
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), P2PErrors> {
+        let kind = Frame::decode(frame).map(|frame| frame.header.kind);
+        if !matches!(kind, Ok(MessageType::Ack | MessageType::Tdma)) {
+            let airtime_ms = LoRaParams::p2p().time_on_air_ms(frame.len());
+            self.lbt.start();
+            loop {
+                // A backoff may end after the slot, so it is checked again.
+                let now_ms = Instant::now().as_millis();
+                let at_ms = p2p_tdma::transmit_at(airtime_ms, now_ms);
+                if at_ms > now_ms {
+                    Timer::at(Instant::from_millis(at_ms)).await;
+                }
+                match self.sense_channel().await {
+                    CadDecision::Transmit => break,
+                    CadDecision::Backoff(delay_ms) => {
+                        esp_println::println!(
+                            "[LoRa P2P] Channel busy, backing off {} ms",
+                            delay_ms
+                        );
+                        Timer::after(Duration::from_millis(delay_ms as u64)).await;
+                    }
+                    CadDecision::Drop => {
+                        esp_println::println!("[LoRa P2P] Channel busy, dropped frame");
+                        return Err(P2PErrors::ChannelBusy);
+                    }
+                }
+            }
+        }
+        p2p_tx_msg(&mut self.lora, &mut self.tx_params, &self.modulation, frame).await
+    }
+
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, P2PErrors> {
+        let rx = p2p_rx_msg(
+            &mut self.lora,
+            buffer,
+            &mut self.rx_params,
+            &self.modulation,
+        );
+        let result = select(rx, Timer::after(timeout)).await;
+        match result {
+            Either::First(rx) => rx.map(|(len, status)| {
+                self.rssi = Some(status.rssi);
+                self.snr = Some(status.snr);
+                Some(len as usize)
+            }),
+            Either::Second(()) => {
+                // Stop listening, the next transmission must not wait for it.
+                if let Err(err) = self.lora.radio.enter_standby().await {
+                    esp_println::println!("[LoRa P2P] Failed to enter standby: {:?}", err);
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    fn rssi(&self) -> Option<i16> {
+        self.rssi
+    }
+
+    fn snr(&self) -> Option<i16> {
+        self.snr
+    }
+}
+
 /// Starts a loop that sends and receives LoRa P2P messages with the given LoRa radio.
 ///
-/// The function will first create modulation and packet parameters for transmission and
-/// reception. It will then enter a loop that sleeps for 6 seconds, receives a message,
-/// sleeps for 1 second, sends a message, sleeps for 1 second, and then repeats.
+/// The function will first configure the radio for P2P. It will then enter a loop that
+/// broadcasts the telemetry and listens for [`RECEIVE_WINDOW`] before the next broadcast.
+///
+/// Messages are framed with [`p2p_frame`] and addressed by the node ID derived from the
+/// factory MAC address; frames for other nodes are ignored. Frames that request it are
+/// acknowledged by the [`Arq`] link. Frames are secured with the network key provisioned in
+/// NVS, see [`p2p_crypto`], and the number of frames rejected is printed after each receive
+/// window.
+///
+/// Telemetry is flooded through the mesh, see [`p2p_mesh`], so it reaches the nodes out of
+/// range too; the messages of other nodes are rebroadcast after a delay, during the receive
+/// window. A node set up as a relay only rebroadcasts.
+///
+/// A beacon announcing the node is broadcast every [`BEACON_INTERVAL_MS`], and the nodes heard
+/// are kept in the neighbour table, see [`p2p_neighbors`].
+///
+/// Routes to the other nodes are learnt from the advertisements of the neighbours, see
+/// [`p2p_routing`], and the `Unicast` messages for other nodes are forwarded along them. When a
+/// sink node is set, the telemetry is sent to it along its route, or flooded to it while there
+/// is none. Every [`NEIGHBOR_REPORT_INTERVAL`] the neighbour table is sent to a sink in range,
+/// in fragments, see [`p2p_fragment`]; the sink prints the reports it reassembles.
 ///
-/// If any step fails, an error message will be printed to the console and the function will
-/// exit.
+/// In a TDMA network, see [`p2p_tdma`], the coordinator broadcasts the schedule at the start of
+/// each superframe and gives a slot to each node it hears; the frames of a member wait for its
+/// slot, the node listening meanwhile, and the telemetry is sent once a slot.
+///
+/// Before each frame the channel is sensed with channel activity detection, see [`p2p_cad`];
+/// while another node transmits the frame is held back, and the detections are printed after
+/// each receive window in which the channel was found busy.
+///
+/// A long press of the button starts pairing with another node, see [`p2p_pairing`], and
+/// holding it for [`HOLD_DURATION`](crate::devices::button::HOLD_DURATION) unpairs the node
+/// from every peer; both are noticed at the end of the current receive window.
+///
+/// Radio errors are printed to the console and the loop carries on after
+/// [`ERROR_BACKOFF`]; only a missing network key or a radio that cannot be configured ends
+/// the task.
 #[embassy_executor::task]
-pub async fn task_lora_p2p(mut lora: LoRaRadio<'static>) {
-    esp_println::println!("[LoRa] Starting LoRa P2P ...");
+pub async fn task_lora_p2p(lora: LoRaRadio<'static>, mut rng: Rng, nvs: &'static MutexNvs) {
+    let mac = Efuse::read_base_mac_address();
+    let node = p2p_frame::node_id(&mac);
+    esp_println::println!("[LoRa] Starting LoRa P2P as node {:04X} ...", node);
+    let Some(key) = p2p_crypto::load_network_key(&mut *nvs.lock().await) else {
+        esp_println::println!("[LoRa P2P] Not transmitting until a network key is provisioned");
+        return;
+    };
     let frequency: u32 = 904_000_000;
-    let modulation = match lora.radio.create_modulation_params(
-        SpreadingFactor::_11,
-        Bandwidth::_500KHz,
-        CodingRate::_4_5,
-        frequency,
-    ) {
-        Ok(params) => params,
+    let settings = lora.settings;
+    let link = match P2pLink::new(lora, frequency, rng.random()) {
+        Ok(link) => link,
         Err(err) => {
-            esp_println::println!("[LoRa P2P] Failed to create modulation params: {:?}", err);
+            esp_println::println!("[LoRa P2P] Failed to configure radio: {}", err);
             return;
         }
     };
-
-    let mut tx_params =
-        match lora
-            .radio
-            .create_tx_packet_params(PREAMBLE_LENGTH, false, true, false, &modulation)
-        {
-            Ok(params) => params,
-            Err(err) => {
-                esp_println::println!("[LoRa P2P] Failed to create tx packet params: {:?}", err);
-                return;
-            }
-        };
-
-    let mut rx = [0u8; 255];
-    let mut rx_params = match lora.radio.create_rx_packet_params(
-        PREAMBLE_LENGTH,
-        false,
-        rx.len() as u8,
-        true,
-        false,
-        &modulation,
-    ) {
-        Ok(params) => params,
+    let link = match SecureRadio::new(link, mac, key, settings).await {
+        Ok(link) => link,
         Err(err) => {
-            esp_println::println!("[LoRa P2P] Failed to create rx packet params: {:?}", err);
+            esp_println::println!("[LoRa P2P] Failed to resume the frame counter: {}", err);
             return;
         }
     };
+    // Nodes back off differently as their addresses differ.
+    let seed = u32::from_le_bytes([mac[2], mac[3], mac[4], mac[5]]);
+    let mut arq = Arq::new(link, node, ArqConfig::default(), seed);
+    let mut flood = Flood::new(node, p2p_mesh::load_config(settings).await, rng.random());
+    if flood.config().role == MeshRole::Relay {
+        esp_println::println!("[LoRa P2P] Relaying only");
+    }
+    let mut router = Router::new(node, rng.random(), Instant::now().as_millis());
+    let sink = p2p_routing::load_sink(settings).await;
+    let tdma_role = p2p_tdma::load_role(settings).await;
+    let tdma = Tdma::new(
+        node,
+        tdma_role,
+        LoRaParams::p2p(),
+        Instant::now().as_millis(),
+    );
+    p2p_tdma::with_tdma(|shared| *shared = tdma);
+    match tdma_role {
+        TdmaRole::Coordinator => esp_println::println!("[LoRa P2P] Coordinating TDMA slots"),
+        TdmaRole::Member => esp_println::println!("[LoRa P2P] Following TDMA slots"),
+        TdmaRole::Off => {}
+    }
 
+    let mut rx = [0u8; MAX_PAYLOAD_LEN];
+    let mut reassembler = Reassembler::<MAX_MESSAGE_LEN, REASSEMBLY_BUFFERS>::default();
+    let mut rejected = 0;
+    let mut channel_busy = 0;
+    let mut next_beacon = Instant::now();
+    let mut next_report = Instant::now() + NEIGHBOR_REPORT_INTERVAL;
+    // Reports count up from a random ID, so a reboot rarely repeats one the
+    // sink still holds.
+    let mut report_id = rng.random() as u8;
     loop {
-        match p2p_tx_msg(&mut lora, &mut tx_params, &modulation).await {
-            Ok(()) => {}
-            Err(err) => {
-                esp_println::println!("[LoRa P2P] Failed to send message: {:?}", err);
-                return;
+        if LONG_PRESS_SIGNAL.try_take().is_some() {
+            pair_with_peer(&mut arq, &mut rng).await;
+        }
+        if HOLD_SIGNAL.try_take().is_some() {
+            unpair_all(&mut arq).await;
+        }
+        send_tdma_beacon(&mut arq).await;
+        // Outside the slot of this node, listen until it starts.
+        let slot_ms = next_slot_ms(Instant::now().as_millis());
+        let sending = slot_ms <= Instant::now().as_millis();
+        if sending && Instant::now() >= next_beacon {
+            if let Err(err) = send_beacon(&mut arq, &flood).await {
+                esp_println::println!("[LoRa P2P] Failed to send beacon: {}", err);
             }
+            let delay_ms = BEACON_INTERVAL_MS + rng.random() as u64 % BEACON_JITTER_MS;
+            next_beacon = Instant::now() + Duration::from_millis(delay_ms);
         }
-        Timer::after(Duration::from_secs(1)).await;
-        rx = [0u8; 255];
-        match p2p_rx_msg(&mut lora, &mut rx, &mut rx_params, &modulation).await {
-            Ok(rx_len) => {
-                // Remove this erroneous line from your original code:
-                for i in 0..rx_len {
-                    esp_println::print!("0x{:02X} ", rx[i as usize]);
+        if sending && flood.config().role == MeshRole::Node {
+            esp_println::println!("[LoRa P2P] Sending...");
+            match send_telemetry(&mut arq, &mut flood, &mut router, sink).await {
+                Ok(seq) => esp_println::println!("[LoRa P2P] Message #{} sent", seq),
+                Err(err) => {
+                    esp_println::println!("[LoRa P2P] Failed to send message: {}", err);
+                    Timer::after(ERROR_BACKOFF).await;
+                    continue;
                 }
-                esp_println::print!("\n");
             }
-            Err(err) => {
-                esp_println::println!("[LoRa P2P] Failed to receive message before tx: {:?}", err);
-                continue;
+            // The fragments of a report would overrun a TDMA slot.
+            if tdma_role == TdmaRole::Off && Instant::now() >= next_report {
+                // Fragments are not routed, so only a sink in range gets one.
+                if let Some(sink) = sink.filter(|sink| router.next_hop(*sink) == Some(*sink)) {
+                    match send_neighbor_report(&mut arq, sink, report_id).await {
+                        Ok(()) => {
+                            esp_println::println!("[LoRa P2P] Neighbours sent to {:04X}", sink)
+                        }
+                        Err(err) => {
+                            esp_println::println!("[LoRa P2P] Failed to send neighbours: {}", err)
+                        }
+                    }
+                    report_id = report_id.wrapping_add(1);
+                }
+                next_report = Instant::now() + NEIGHBOR_REPORT_INTERVAL;
+            }
+        }
+        let window_end = if sending {
+            Instant::now() + RECEIVE_WINDOW
+        } else {
+            Instant::from_millis(slot_ms)
+        };
+        while Instant::now() < window_end {
+            let now_ms = Instant::now().as_millis();
+            // Rebroadcasts and advertisements wait for the slot too.
+            let slot_ms = next_slot_ms(now_ms);
+            let mut wake_ms = window_end.as_millis();
+            for due_ms in [flood.next_due_ms(), Some(router.next_advert_ms())]
+                .into_iter()
+                .flatten()
+            {
+                wake_ms = wake_ms.min(due_ms.max(slot_ms));
+            }
+            if let Some(due_ms) = p2p_tdma::with_tdma(|tdma| tdma.next_beacon_ms()) {
+                wake_ms = wake_ms.min(due_ms);
+            }
+            let timeout = Instant::from_millis(wake_ms).saturating_duration_since(Instant::now());
+            let result = arq.receive(&mut rx, timeout).await;
+            let received_ms = Instant::now().as_millis();
+            if let Ok(Some(received)) = &result {
+                let (rssi, snr) = (arq.radio().rssi(), arq.radio().snr());
+                p2p_neighbors::with_neighbors(|table| {
+                    if table.get(received.source).is_none() {
+                        esp_println::println!("[LoRa P2P] New neighbour {:04X}", received.source);
+                    }
+                    table.heard(received.source, rssi, snr, received_ms);
+                    link_status::set_peers(table.len());
+                });
+                if let (Some(rssi), Some(snr)) = (rssi, snr) {
+                    let sf = LoRaParams::p2p().spreading_factor;
+                    link_status::p2p_received(frequency, sf, rssi, snr);
+                }
+                p2p_tdma::with_tdma(|tdma| {
+                    tdma.assign(received.source);
+                });
+            }
+            match result {
+                Ok(Some(received)) if received.kind == MessageType::Fragment => {
+                    let payload = &rx[..received.len];
+                    let now_ms = Instant::now().as_millis();
+                    match p2p_fragment::receive_fragment(
+                        &mut arq,
+                        &mut reassembler,
+                        &received,
+                        payload,
+                        now_ms,
+                    )
+                    .await
+                    {
+                        Ok(Some((message_id, _))) => print_neighbor_report(
+                            received.source,
+                            reassembler
+                                .message(received.source, message_id)
+                                .unwrap_or(&[]),
+                        ),
+                        Ok(None) => {}
+                        Err(err) => esp_println::println!("[LoRa P2P] Dropped fragment: {}", err),
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Mesh => {
+                    let payload = &rx[..received.len];
+                    let snr = arq.radio().snr();
+                    let now_ms = Instant::now().as_millis();
+                    match flood.handle(payload, snr, now_ms, rng.random()) {
+                        Ok(Some(header)) => {
+                            esp_println::println!(
+                                "[LoRa P2P] Node {:04X} message {} via {:04X}, {} hops",
+                                header.origin,
+                                header.id,
+                                received.source,
+                                header.hops
+                            );
+                            print_telemetry(header.origin, header.id, &payload[MESH_HEADER_LEN..]);
+                        }
+                        Ok(None) => {}
+                        Err(err) => esp_println::println!("[LoRa P2P] Dropped mesh frame: {}", err),
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Beacon => {
+                    match Beacon::decode(&rx[..received.len]) {
+                        Ok(beacon) => p2p_neighbors::with_neighbors(|table| {
+                            table.beacon(received.source, &beacon)
+                        }),
+                        Err(err) => esp_println::println!("[LoRa P2P] Dropped beacon: {}", err),
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Routes => {
+                    // The average of the neighbour, so one faded frame does not change the routes.
+                    let snr = p2p_neighbors::neighbor(received.source).and_then(|n| n.snr_db());
+                    let now_ms = Instant::now().as_millis();
+                    if let Err(err) =
+                        router.handle(received.source, snr, &rx[..received.len], now_ms)
+                    {
+                        esp_println::println!("[LoRa P2P] Dropped routes: {}", err);
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Unicast => {
+                    let payload = &rx[..received.len];
+                    match RouteHeader::decode(payload) {
+                        Ok(header) if header.destination == node => {
+                            esp_println::println!(
+                                "[LoRa P2P] Node {:04X} message via {:04X}, {} hops",
+                                header.origin,
+                                received.source,
+                                header.hops + 1
+                            );
+                            print_telemetry(
+                                header.origin,
+                                received.seq,
+                                &payload[ROUTE_HEADER_LEN..],
+                            );
+                        }
+                        Ok(header) => {
+                            let message = &payload[ROUTE_HEADER_LEN..];
+                            forward_unicast(&mut arq, &mut router, &header, message).await
+                        }
+                        Err(err) => esp_println::println!("[LoRa P2P] Dropped unicast: {}", err),
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Tdma => {
+                    let frame_len = HEADER_LEN + received.len + SECURITY_LEN + CRC_LEN;
+                    let synced = p2p_tdma::with_tdma(|tdma| {
+                        tdma.handle(&rx[..received.len], frame_len, received_ms)
+                            .map(|beacon| (beacon, tdma.schedule().map(|s| s.start_ms)))
+                    });
+                    match synced {
+                        Ok((beacon, Some(start_ms))) => {
+                            // The clock of this node follows the coordinator's, unless
+                            // the GPS receiver or the network set it.
+                            if let (Some(gps_ms), None | Some(TimeSource::Peer)) =
+                                (beacon.gps_ms, clock::source())
+                            {
+                                let at = Instant::from_millis(start_ms);
+                                clock::set_gps_time(gps_ms, at, TimeSource::Peer);
+                            }
+                        }
+                        Ok((_, None)) => {}
+                        Err(err) => {
+                            esp_println::println!("[LoRa P2P] Dropped TDMA beacon: {}", err)
+                        }
+                    }
+                }
+                Ok(Some(received)) if received.kind == MessageType::Pairing => {
+                    handle_unpair(&mut arq, received.source, &rx[..received.len]).await
+                }
+                Ok(Some(received)) if received.kind == MessageType::Data => {
+                    print_telemetry(received.source, received.seq, &rx[..received.len])
+                }
+                Ok(Some(_)) | Ok(None) => {}
+                Err(err) => {
+                    esp_println::println!("[LoRa P2P] Failed to receive message: {}", err);
+                    Timer::after(ERROR_BACKOFF).await;
+                }
+            }
+            send_tdma_beacon(&mut arq).await;
+            if next_slot_ms(Instant::now().as_millis()) <= Instant::now().as_millis() {
+                rebroadcast_due(&mut arq, &mut flood).await;
+                advertise_routes(&mut arq, &mut router, &mut rng).await;
+            }
+        }
+        let stats = arq.radio().stats();
+        if stats.rejected() != rejected {
+            rejected = stats.rejected();
+            esp_println::println!(
+                "[LoRa P2P] Frames accepted: {}, rejected: {} malformed, {} unsecured, {} forged, {} replayed",
+                stats.accepted,
+                stats.malformed,
+                stats.unsecured,
+                stats.forged,
+                stats.replayed
+            );
+        }
+        let cad = arq.radio().radio().cad_stats();
+        if cad.busy != channel_busy {
+            channel_busy = cad.busy;
+            esp_println::println!(
+                "[LoRa P2P] Channel checks: {} clear, {} busy, {} failed, {} polled; {} backoffs for {} ms, gave up {} times",
+                cad.clear,
+                cad.busy,
+                cad.failed,
+                cad.polled,
+                cad.backoffs,
+                cad.backoff_ms,
+                cad.gave_up
+            );
+        }
+        let now_ms = Instant::now().as_millis();
+        p2p_neighbors::with_neighbors(|table| {
+            table.expire(now_ms, |neighbor| {
+                esp_println::println!("[LoRa P2P] Lost neighbour {:04X}", neighbor.node);
+                router.link_lost(neighbor.node, now_ms);
+                p2p_tdma::with_tdma(|tdma| tdma.release(neighbor.node));
+            });
+            link_status::set_peers(table.len());
+        });
+        router.expire(now_ms);
+        p2p_pairing::clear_status();
+    }
+}
+
+/// Pairs with another node in pairing mode and stores the agreed link key.
+async fn pair_with_peer(arq: &mut Arq<Link>, rng: &mut Rng) {
+    let mut secret = [0u8; 32];
+    for chunk in secret.chunks_mut(4) {
+        chunk.copy_from_slice(&rng.random().to_le_bytes());
+    }
+    esp_println::println!("[LoRa P2P] Pairing...");
+    match p2p_pairing::pair(arq, secret).await {
+        Ok(Some((peer, key))) => match arq.radio().store_link_key(peer, key).await {
+            Ok(()) => esp_println::println!("[LoRa P2P] Paired with node {:04X}", peer),
+            Err(err) => esp_println::println!("[LoRa P2P] Failed to store the link key: {}", err),
+        },
+        Ok(None) => esp_println::println!(
+            "[LoRa P2P] Pairing ended: {:?}",
+            p2p_pairing::pairing_status()
+        ),
+        Err(err) => esp_println::println!("[LoRa P2P] Pairing failed: {}", err),
+    }
+}
+
+/// Forgets the link keys agreed with every paired node, telling each one.
+async fn unpair_all(arq: &mut Arq<Link>) {
+    let paired = match arq.radio().paired().await {
+        Ok(paired) => paired,
+        Err(err) => {
+            esp_println::println!("[LoRa P2P] Failed to read the paired nodes: {}", err);
+            return;
+        }
+    };
+    for peer in paired {
+        if let Ok(Some(key)) = arq.radio().link_key(peer).await {
+            if let Err(err) = p2p_pairing::send_unpair(arq, peer, &key).await {
+                esp_println::println!("[LoRa P2P] Node {:04X} was not told: {}", peer, err);
             }
         }
-        Timer::after(Duration::from_secs(1)).await;
+        match arq.radio().forget_link_key(peer).await {
+            Ok(()) => esp_println::println!("[LoRa P2P] Unpaired from node {:04X}", peer),
+            Err(err) => esp_println::println!("[LoRa P2P] Failed to unpair: {}", err),
+        }
     }
 }
 
-/// Sends a message over LoRa in P2P mode.
+/// Forgets the link key agreed with `source` when `payload` is its unpair
+/// message; other pairing messages are left to pairing mode.
+async fn handle_unpair(arq: &mut Arq<Link>, source: NodeId, payload: &[u8]) {
+    let Ok(message) = p2p_pairing::Message::decode(payload) else {
+        return;
+    };
+    let Ok(Some(key)) = arq.radio().link_key(source).await else {
+        return;
+    };
+    if !p2p_pairing::is_unpair(&message, source, arq.node(), &key) {
+        return;
+    }
+    match arq.radio().forget_link_key(source).await {
+        Ok(()) => esp_println::println!("[LoRa P2P] Node {:04X} unpaired", source),
+        Err(err) => esp_println::println!("[LoRa P2P] Failed to unpair: {}", err),
+    }
+}
+
+/// Prints the telemetry `source` sent in its message `seq`.
+fn print_telemetry(source: NodeId, seq: u16, payload: &[u8]) {
+    match Telemetry::decode_compact(payload) {
+        Ok(reading) => {
+            esp_println::println!("[LoRa P2P] Node {:04X} #{}: {:?}", source, seq, reading)
+        }
+        Err(err) => esp_println::println!("[LoRa P2P] Node {:04X} #{}: {}", source, seq, err),
+    }
+}
+
+/// Prints the neighbour report `source` sent, the only message nodes send in
+/// fragments.
+fn print_neighbor_report(source: NodeId, message: &[u8]) {
+    match core::str::from_utf8(message) {
+        Ok(report) => {
+            esp_println::println!("[LoRa P2P] Node {:04X} neighbours: {}", source, report)
+        }
+        Err(_) => esp_println::println!(
+            "[LoRa P2P] Node {:04X} sent {} bytes that are no report",
+            source,
+            message.len()
+        ),
+    }
+}
+
+/// Sends the neighbour table of this node to `sink` as message `message_id`,
+/// in the JSON of HTTP reports.
+async fn send_neighbor_report(
+    arq: &mut Arq<Link>,
+    sink: NodeId,
+    message_id: u8,
+) -> Result<(), FragmentError<SecureError<P2PErrors>>> {
+    let mut report = heapless::String::<MAX_MESSAGE_LEN>::new();
+    let now_ms = Instant::now().as_millis();
+    // Cannot fail, a full table is shorter than a message.
+    let _ = p2p_neighbors::with_neighbors(|table| table.write_json(&mut report, now_ms));
+    p2p_fragment::send_message(
+        arq,
+        sink,
+        message_id,
+        report.as_bytes(),
+        &FragmentConfig::default(),
+    )
+    .await
+}
+
+/// Announces this node to the ones in range.
+async fn send_beacon(
+    arq: &mut Arq<Link>,
+    flood: &Flood,
+) -> Result<u16, ArqError<SecureError<P2PErrors>>> {
+    let mut bits = 0;
+    if flood.config().role == MeshRole::Relay {
+        bits |= capabilities::RELAY;
+    }
+    if gps::latest_fix().is_some() {
+        bits |= capabilities::GPS;
+    }
+    let beacon = Beacon {
+        capabilities: bits,
+        battery_percent: p2p_neighbors::battery_percent(),
+    };
+    arq.send_frame(MessageType::Beacon, BROADCAST, &beacon.encode(), 0)
+        .await
+}
+
+/// Sends the rebroadcasts whose delay ended.
+async fn rebroadcast_due(arq: &mut Arq<Link>, flood: &mut Flood) {
+    let mut frame = [0u8; MAX_PAYLOAD_LEN];
+    while let Some(len) = flood.due(Instant::now().as_millis(), &mut frame) {
+        if let Err(err) = arq
+            .send_frame(MessageType::Mesh, BROADCAST, &frame[..len], 0)
+            .await
+        {
+            esp_println::println!("[LoRa P2P] Failed to rebroadcast: {}", err);
+        }
+    }
+}
+
+/// When this node may next start a frame of any length: at once, unless it
+/// follows a TDMA schedule.
+fn next_slot_ms(now_ms: u64) -> u64 {
+    let airtime_ms = LoRaParams::p2p().time_on_air_ms(MAX_FRAME_LEN);
+    p2p_tdma::transmit_at(airtime_ms, now_ms)
+}
+
+/// Broadcasts the TDMA schedule when this node coordinates one and the next
+/// superframe is due, starting it.
+async fn send_tdma_beacon(arq: &mut Arq<Link>) {
+    let now = Instant::now();
+    let gps_ms = clock::gps_time_ms_at(now);
+    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+    let Some(len) = p2p_tdma::with_tdma(|tdma| tdma.beacon(now.as_millis(), gps_ms, &mut payload))
+    else {
+        return;
+    };
+    if let Err(err) = arq
+        .send_frame(MessageType::Tdma, BROADCAST, &payload[..len], 0)
+        .await
+    {
+        esp_println::println!("[LoRa P2P] Failed to send TDMA beacon: {}", err);
+    }
+}
+
+/// Broadcasts the route advertisement, if one is due.
+async fn advertise_routes(arq: &mut Arq<Link>, router: &mut Router, rng: &mut Rng) {
+    let mut frame = [0u8; MAX_PAYLOAD_LEN];
+    let Some(len) = router.advertise(Instant::now().as_millis(), rng.random(), &mut frame) else {
+        return;
+    };
+    if let Err(err) = arq
+        .send_frame(MessageType::Routes, BROADCAST, &frame[..len], 0)
+        .await
+    {
+        esp_println::println!("[LoRa P2P] Failed to advertise routes: {}", err);
+    }
+}
+
+/// Sends a `Unicast` payload to `next_hop`, breaking the routes through it
+/// when it does not acknowledge the frame.
+async fn send_unicast(
+    arq: &mut Arq<Link>,
+    router: &mut Router,
+    next_hop: NodeId,
+    payload: &[u8],
+) -> Result<Delivered, ArqError<SecureError<P2PErrors>>> {
+    let result = arq
+        .send_reliable_frame(MessageType::Unicast, next_hop, payload)
+        .await;
+    if let Err(ArqError::Timeout { .. }) = result {
+        esp_println::println!("[LoRa P2P] Route error: {:04X} does not answer", next_hop);
+        router.link_lost(next_hop, Instant::now().as_millis());
+    }
+    result
+}
+
+/// Forwards a `Unicast` message for another node to the next hop of its
+/// route; it is dropped if there is none or no hops are left.
+async fn forward_unicast(
+    arq: &mut Arq<Link>,
+    router: &mut Router,
+    header: &RouteHeader,
+    message: &[u8],
+) {
+    let Some(forwarded) = header.forwarded() else {
+        esp_println::println!(
+            "[LoRa P2P] Dropped message from {:04X} to {:04X}: no hops left",
+            header.origin,
+            header.destination
+        );
+        return;
+    };
+    let Some(next_hop) = router.next_hop(header.destination) else {
+        esp_println::println!(
+            "[LoRa P2P] Dropped message from {:04X}: no route to {:04X}",
+            header.origin,
+            header.destination
+        );
+        return;
+    };
+    let mut frame = [0u8; MAX_PAYLOAD_LEN];
+    // Cannot fail, the message came in a frame of the same size.
+    let len = encode_unicast(&forwarded, message, &mut frame).unwrap_or(0);
+    if let Err(err) = send_unicast(arq, router, next_hop, &frame[..len]).await {
+        esp_println::println!("[LoRa P2P] Failed to forward message: {}", err);
+    }
+}
+
+/// Sends the latest telemetry, returning the frame's sequence number: along
+/// the route to `sink` if there is one, otherwise flooded through the mesh
+/// to `sink`, or to every node without one.
+async fn send_telemetry(
+    arq: &mut Arq<Link>,
+    flood: &mut Flood,
+    router: &mut Router,
+    sink: Option<NodeId>,
+) -> Result<u16, ArqError<SecureError<P2PErrors>>> {
+    let reading = Telemetry {
+        fix: gps::latest_fix(),
+        status: DeviceStatus {
+            uptime_s: Instant::now().as_secs() as u32,
+            data_rate: 0,
+            unix_time: clock::unix_time(),
+        },
+    };
+    let mut payload = [0u8; COMPACT_FIX_LEN];
+    // Cannot fail, the buffer fits a reading with a fix.
+    let len = reading.encode_compact(&mut payload).unwrap_or(0);
+    let payload = &payload[..len];
+    let destination = sink.unwrap_or(BROADCAST);
+    let mut message = [0u8; MAX_PAYLOAD_LEN];
+    if let Some(next_hop) = sink.and_then(|sink| router.next_hop(sink)) {
+        let header = RouteHeader {
+            origin: router.node(),
+            destination,
+            hops_left: DEFAULT_HOP_LIMIT,
+            hops: 0,
+        };
+        // Cannot fail either, a reading is shorter than a routed message.
+        let len = encode_unicast(&header, payload, &mut message).unwrap_or(0);
+        match send_unicast(arq, router, next_hop, &message[..len]).await {
+            Ok(delivered) => return Ok(delivered.seq),
+            Err(err) => esp_println::println!(
+                "[LoRa P2P] Failed to route to {:04X}, flooding: {}",
+                destination,
+                err
+            ),
+        }
+    }
+    // Cannot fail either, a reading is shorter than a mesh message.
+    let len = flood
+        .originate(destination, payload, &mut message)
+        .unwrap_or(0);
+    arq.send_frame(MessageType::Mesh, BROADCAST, &message[..len], 0)
+        .await
+}
+
+/// Sends a frame over LoRa in P2P mode.
 ///
 /// The method will first prepare the radio for transmission and then send the message.
 /// If any step fails, an error will be returned.
@@ -123,18 +880,12 @@ async fn p2p_tx_msg(
     lora: &mut LoRaRadio<'static>,
     tx_params: &mut PacketParams,
     modulation: &ModulationParams,
+    tx: &[u8],
 ) -> Result<(), P2PErrors> {
-    esp_println::println!("[LoRa P2P] Sending...");
-
-    let mut tx: [u8; 52] = [0; 52];
-    for i in 0..tx.len() {
-        tx[i as usize] = i as u8;
-    }
-
     // Add timeout for prepare_for_tx
     match lora
         .radio
-        .prepare_for_tx(&modulation, tx_params, 20, &tx)
+        .prepare_for_tx(&modulation, tx_params, 20, tx)
         .await
     {
         Ok(()) => esp_println::println!("[LoRa P2P] Prepared for tx"),
@@ -169,7 +920,8 @@ async fn p2p_tx_msg(
 ///
 /// # Returns
 ///
-/// * `Ok(u8)` - The length of the received message if successful.
+/// * `Ok((u8, PacketStatus))` - The length of the received message and its RSSI and SNR if
+///   successful.
 /// * `Err(P2PErrors)` - An error if the preparation for receiving or the reception itself fails.
 ///
 /// # Errors
@@ -183,12 +935,12 @@ async fn p2p_rx_msg(
     rx: &mut [u8],
     rx_params: &mut PacketParams,
     modulation: &ModulationParams,
-) -> Result<u8, P2PErrors> {
+) -> Result<(u8, PacketStatus), P2PErrors> {
     esp_println::println!("[LoRa P2P] Receiving...");
 
     match lora
         .radio
-        .prepare_for_rx(RxMode::Single(512), &modulation, &rx_params)
+        .prepare_for_rx(RxMode::Continuous, &modulation, &rx_params)
         .await
     {
         Ok(()) => esp_println::println!("[LoRa P2P] Prepared for rx"),
@@ -214,7 +966,7 @@ async fn p2p_rx_msg(
     );
 
     if status.rssi < 50 {
-        return Ok(rx_len);
+        return Ok((rx_len, status));
     }
 
     for i in 0..rx_len {
@@ -227,5 +979,5 @@ async fn p2p_rx_msg(
         core::format_args!("rssi: {} snr: {}", status.rssi, status.snr),
     )
     .map_err(|_| P2PErrors::Rx)?;
-    return Ok(rx_len);
+    return Ok((rx_len, status));
 }
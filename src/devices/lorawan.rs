@@ -1,101 +1,898 @@
-use embassy_time::{Duration, Timer};
+use core::cell::Cell;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::rng::Rng;
 use lora_phy::lorawan_radio::LorawanRadio;
 use lorawan_device::{
-    async_device::{Device, EmbassyTimer},
+    async_device::{Device, EmbassyTimer, ListenResponse, SendResponse},
     default_crypto::DefaultFactory as Crypto,
-    region, AppEui, AppKey, AppSKey, DevAddr, DevEui, NwkSKey,
+    mac::Session,
+    region, AppEui, AppKey, AppSKey, DevAddr, DevEui, JoinMode, NwkSKey,
 };
+use rand_core::RngCore;
 
-use super::lora::LoRaRadio;
+use super::{
+    clock::{self, TimeSource},
+    clock_sync::{self, ClockSync, CLOCK_SYNC_PORT},
+    downlink::{AckStatus, Acks, Answer, Command, Commands, Dispatcher, Handler, COMMAND_PORT},
+    fragmentation::FRAGMENTATION_PORT,
+    gps,
+    join_backoff::JoinBackoff,
+    led::{LedState, LED_SIGNAL},
+    link_status::{
+        self, LinkLoss, MonitoredRadio, LINK_CHECK_INTERVAL_S, LINK_CHECK_REQUEST, MAC_PORT,
+    },
+    lora::LoRaRadio,
+    lorawan_config::{Activation, DeviceClass, LoRaWanConfig, Region},
+    multicast::{self, Multicast, MULTICAST_FRAME, MULTICAST_PORT},
+    payload::{DeviceStatus, Telemetry},
+    rx_windows::{self, RxSettings},
+    session::SessionRecord,
+    types::{Firmware, MutexSettings},
+    uplink_queue::{OverflowPolicy, Priority, QueueError, Uplink, UplinkQueue, MAX_UPLINK_LEN},
+};
 const _MAX_TX_POWER: u8 = 20;
+const DEFAULT_UPLINK_INTERVAL_S: u16 = 10;
+const MIN_UPLINK_INTERVAL_S: u16 = 10;
+/// Shortest spacing between uplinks while a backlog is drained.
+const BACKLOG_INTERVAL_MS: u64 = 2_000;
+/// LoRaWAN frame overhead around the application payload: MHDR, FHDR
+/// without options, FPort and MIC.
+const FRAME_OVERHEAD_LEN: usize = 13;
+
+/// Builds the regional parameters, including the join bias for regions with
+/// subbands.
+fn region_configuration(config: &LoRaWanConfig) -> region::Configuration {
+    let subband = config.subband.and_then(subband);
+    match config.region {
+        Region::US915 => {
+            let mut us915 = region::US915::new();
+            if let Some(subband) = subband {
+                us915.set_join_bias(subband);
+            }
+            us915.into()
+        }
+        Region::AU915 => {
+            let mut au915 = region::AU915::new();
+            if let Some(subband) = subband {
+                au915.set_join_bias(subband);
+            }
+            au915.into()
+        }
+        Region::EU868 => region::Configuration::new(region::Region::EU868),
+        Region::AS923_1 => region::Configuration::new(region::Region::AS923_1),
+        Region::AS923_2 => region::Configuration::new(region::Region::AS923_2),
+        Region::AS923_3 => region::Configuration::new(region::Region::AS923_3),
+        Region::AS923_4 => region::Configuration::new(region::Region::AS923_4),
+        Region::IN865 => region::Configuration::new(region::Region::IN865),
+        Region::EU433 => region::Configuration::new(region::Region::EU433),
+    }
+}
+
+fn subband(subband: u8) -> Option<region::Subband> {
+    Some(match subband {
+        1 => region::Subband::_1,
+        2 => region::Subband::_2,
+        3 => region::Subband::_3,
+        4 => region::Subband::_4,
+        5 => region::Subband::_5,
+        6 => region::Subband::_6,
+        7 => region::Subband::_7,
+        8 => region::Subband::_8,
+        _ => return None,
+    })
+}
+
+fn data_rate(dr: u8) -> region::DR {
+    match dr {
+        0 => region::DR::_0,
+        1 => region::DR::_1,
+        2 => region::DR::_2,
+        3 => region::DR::_3,
+        4 => region::DR::_4,
+        5 => region::DR::_5,
+        _ => region::DR::_6,
+    }
+}
+
+fn join_mode(activation: &Activation) -> JoinMode {
+    match activation {
+        Activation::Otaa {
+            deveui,
+            appeui,
+            appkey,
+        } => JoinMode::OTAA {
+            deveui: DevEui::from(*deveui),
+            appeui: AppEui::from(*appeui),
+            appkey: AppKey::from(*appkey),
+        },
+        Activation::Abp {
+            devaddr,
+            nwkskey,
+            appskey,
+        } => JoinMode::ABP {
+            devaddr: DevAddr::from(*devaddr),
+            nwkskey: NwkSKey::from(*nwkskey),
+            appskey: AppSKey::from(*appskey),
+        },
+    }
+}
+
+/// Whether a stored session was negotiated for the provisioned credentials.
+///
+/// A device re-provisioned with other keys must join again instead of
+/// resuming the session of its previous identity.
+fn session_matches(record: &SessionRecord, activation: &Activation) -> bool {
+    if !record.is_joined() {
+        return false;
+    }
+    match activation {
+        Activation::Otaa { deveui, appeui, .. } => {
+            record.device_eui == *deveui && record.application_eui == *appeui
+        }
+        Activation::Abp {
+            devaddr,
+            nwkskey,
+            appskey,
+        } => {
+            record.device_addr == *devaddr
+                && record.network_session_key == *nwkskey
+                && record.application_session_key == *appskey
+        }
+    }
+}
+
+/// Copies the keys and frame counters of the stack's session into `record`.
+fn update_record(record: &mut SessionRecord, session: &Session, activation: &Activation) {
+    if let Activation::Otaa { deveui, appeui, .. } = activation {
+        record.device_eui = *deveui;
+        record.application_eui = *appeui;
+    }
+    record.device_addr.copy_from_slice(session.devaddr.as_ref());
+    record.network_session_key = session.nwkskey.inner().0;
+    record.application_session_key = session.appskey.inner().0;
+    record.fcnt_up = session.fcnt_up;
+    record.fcnt_down = session.fcnt_down;
+}
+
+fn restore_session(record: &SessionRecord) -> Session {
+    let mut session = Session::new(
+        NwkSKey::from(record.network_session_key),
+        AppSKey::from(record.application_session_key),
+        DevAddr::from(record.device_addr),
+    );
+    session.fcnt_up = record.fcnt_up;
+    session.fcnt_down = record.fcnt_down;
+    session
+}
+
+/// DevNonce of the join request being sent, see [`NonceRng`].
+static JOIN_NONCE: Mutex<CriticalSectionRawMutex, Cell<Option<u16>>> = Mutex::new(Cell::new(None));
+
+/// Random number generator of the stack that hands it the DevNonce of the
+/// [`SessionRecord`].
+///
+/// The stack draws the DevNonce of a join request as the low 16 bits of a
+/// random number and cannot be given one. While a nonce is armed, every
+/// number drawn carries it in those bits, so the nonces count up across
+/// reboots instead of repeating at random, which the network rejects.
+#[derive(Clone)]
+struct NonceRng(Rng);
+
+impl NonceRng {
+    /// Makes the next join request use `nonce`, until [`NonceRng::disarm`].
+    fn arm(nonce: u16) {
+        JOIN_NONCE.lock(|armed| armed.set(Some(nonce)));
+    }
+
+    fn disarm() {
+        JOIN_NONCE.lock(|armed| armed.set(None));
+    }
+}
+
+impl RngCore for NonceRng {
+    fn next_u32(&mut self) -> u32 {
+        let random = self.0.random();
+        match JOIN_NONCE.lock(|armed| armed.get()) {
+            Some(nonce) => (random & 0xFFFF_0000) | nonce as u32,
+            None => random,
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_u32(self)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Records the receive window settings the MAC `commands` of a downlink
+/// configure, returning whether they changed and must be saved.
+fn update_rx_settings(record: &mut SessionRecord, region: Region, commands: &[u8]) -> bool {
+    let mut rx = record
+        .rx_settings
+        .unwrap_or_else(|| RxSettings::defaults(region));
+    if !rx.apply(commands) {
+        return false;
+    }
+    esp_println::println!("[LoRa WAN] Receive windows changed: {:?}", rx);
+    record.rx_settings = Some(rx);
+    if rx_windows::restored().is_some() {
+        rx_windows::restore(region, rx);
+    }
+    true
+}
+
+/// Handles the MAC commands in the FOpts of the downlink the stack accepted
+/// with frame counter `fcnt`, which the stack does not hand over.
+///
+/// Returns whether the session changed and must be saved.
+fn handle_frame_options(record: &mut SessionRecord, region: Region, fcnt: u32) -> bool {
+    let Some(frame) = link_status::take_frame() else {
+        return false;
+    };
+    let Some(options) = link_status::frame_options(&frame, fcnt) else {
+        return false;
+    };
+    if !options.is_empty() {
+        handle_link_check(options);
+    }
+    update_rx_settings(record, region, options)
+}
+
+async fn save_session(settings: &MutexSettings, record: &SessionRecord) {
+    if let Err(e) = record.write(&mut *settings.lock().await) {
+        esp_println::println!("[LoRa WAN] Failed to save session: {}", e);
+    }
+}
+
+/// Settings changed by remote commands, applied by the uplink loop.
+struct RemoteState {
+    region: Region,
+    uplink_interval_s: u16,
+    data_rate: u8,
+    fcnt_up: u32,
+    class: DeviceClass,
+    reboot: bool,
+    /// Receives firmware updates, `None` when the flash has no OTA slots.
+    firmware: Option<&'static mut Firmware>,
+    fragmentation_answer: Answer,
+    multicast: Multicast,
+    multicast_answer: Answer,
+    clock_sync: ClockSync,
+    clock_answer: Answer,
+}
+
+impl RemoteState {
+    /// Whether a package has answers waiting to be queued.
+    fn answers_pending(&self) -> bool {
+        !self.fragmentation_answer.is_empty()
+            || !self.multicast_answer.is_empty()
+            || !self.clock_answer.is_empty()
+    }
+}
+
+/// Handles the commands received on [`COMMAND_PORT`].
+fn handle_commands(state: &mut RemoteState, payload: &[u8], acks: &mut Acks) {
+    for command in Commands::new(payload) {
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                esp_println::println!("[LoRa WAN] {}", e);
+                acks.push(e.opcode(), e.status(), &[]);
+                continue;
+            }
+        };
+        esp_println::println!("[LoRa WAN] Command {:?}", command);
+
+        let status = match command {
+            Command::SetUplinkInterval(seconds) if seconds < MIN_UPLINK_INTERVAL_S => {
+                AckStatus::Rejected
+            }
+            Command::SetUplinkInterval(seconds) => {
+                state.uplink_interval_s = seconds;
+                AckStatus::Ok
+            }
+            Command::SetLed(on) => {
+                LED_SIGNAL.signal(if on { LedState::On } else { LedState::Off });
+                AckStatus::Ok
+            }
+            Command::Reboot => {
+                state.reboot = true;
+                AckStatus::Ok
+            }
+            Command::RequestStatus => {
+                let mut status = [0u8; 12];
+                status[0..2].copy_from_slice(&state.uplink_interval_s.to_be_bytes());
+                status[2] = state.data_rate;
+                status[3..7].copy_from_slice(&state.fcnt_up.to_be_bytes());
+                let uptime_s = Instant::now().as_secs() as u32;
+                status[7..11].copy_from_slice(&uptime_s.to_be_bytes());
+                status[11] = state.class as u8;
+                acks.push(command.opcode(), AckStatus::Ok, &status);
+                continue;
+            }
+            Command::SetDataRate(dr) if dr > state.region.max_data_rate() => AckStatus::Rejected,
+            Command::SetDataRate(dr) => {
+                state.data_rate = dr;
+                AckStatus::Ok
+            }
+            // The stack cannot operate in class B yet, see `class_b::BeaconTracker`.
+            Command::SetClass(DeviceClass::B) => AckStatus::Rejected,
+            Command::SetClass(class) => {
+                state.class = class;
+                AckStatus::Ok
+            }
+        };
+        acks.push(command.opcode(), status, &[]);
+    }
+}
+
+/// Handles the Fragmented Data Block Transport package on
+/// [`FRAGMENTATION_PORT`], installing the firmware update once its data block
+/// is complete.
+fn handle_fragmentation(state: &mut RemoteState, payload: &[u8], _acks: &mut Acks) {
+    let Some(firmware) = state.firmware.as_deref_mut() else {
+        return;
+    };
+    let answer = &mut state.fragmentation_answer;
+    let Some(session) = firmware.fragmentation.handle(payload, answer) else {
+        return;
+    };
+    match firmware.install(&session) {
+        Ok(len) => {
+            esp_println::println!("[FUOTA] Installed image of {} bytes", len);
+            state.reboot = true;
+        }
+        Err(e) => esp_println::println!("[FUOTA] Update rejected: {}", e),
+    }
+}
+
+/// Handles the Remote Multicast Setup package on [`MULTICAST_PORT`].
+fn handle_multicast(state: &mut RemoteState, payload: &[u8], _acks: &mut Acks) {
+    let uptime_s = Instant::now().as_secs() as u32;
+    let answer = &mut state.multicast_answer;
+    state
+        .multicast
+        .handle(payload, clock::gps_time(), uptime_s, answer);
+}
+
+/// Handles the Clock Synchronization package on [`CLOCK_SYNC_PORT`],
+/// correcting the system time.
+fn handle_clock_sync(state: &mut RemoteState, payload: &[u8], _acks: &mut Acks) {
+    let now_s = Instant::now().as_secs() as u32;
+    let answer = &mut state.clock_answer;
+    if let Some(correction) = state
+        .clock_sync
+        .handle(payload, now_s, clock::device_time(), answer)
+    {
+        clock::correct(correction);
+        esp_println::println!(
+            "[CLOCK] Corrected by {} s to {}",
+            correction,
+            clock::timestamp()
+        );
+    }
+}
+
+/// Handles the MAC commands on [`MAC_PORT`] or in FOpts that answer the link
+/// checks.
+///
+/// The network time of `DeviceTimeAns` sets the clock unless the GPS
+/// receiver does.
+fn handle_link_check(payload: &[u8]) {
+    if let Some((gps_ms, at)) = link_status::handle(payload) {
+        if clock::source() != Some(TimeSource::Gps) {
+            clock::set_gps_time(gps_ms, at, TimeSource::Network);
+            esp_println::println!("[CLOCK] Network time {}", clock::timestamp());
+        }
+    }
+}
+
+/// Passes a downlink to the handler of its port.
+fn handle_downlink(
+    dispatcher: &Dispatcher<RemoteState>,
+    state: &mut RemoteState,
+    acks: &mut Acks,
+    port: u8,
+    payload: &[u8],
+) {
+    esp_println::println!("[LoRa WAN] Downlink on port {}: {:?}", port, payload);
+    // MAC commands are not application data, the dispatcher refuses port 0.
+    if port == MAC_PORT {
+        handle_link_check(payload);
+    } else if !dispatcher.dispatch(state, port, payload, acks) {
+        esp_println::println!("[LoRa WAN] No handler for port {}", port);
+    }
+}
+
+/// Queues `uplink`, keeping the stored copy of the queue in sync when
+/// `store` is set.
+async fn enqueue(queue: &mut UplinkQueue, uplink: Uplink, store: Option<&MutexSettings>) {
+    let port = uplink.port;
+    let dropped = match queue.push(uplink) {
+        Ok(dropped) => dropped,
+        Err(e) => {
+            esp_println::println!("[LoRa WAN] Uplink on port {} dropped: {}", port, e);
+            return;
+        }
+    };
+    if let Some(dropped) = &dropped {
+        esp_println::println!(
+            "[LoRa WAN] Queue full, dropped uplink on port {}",
+            dropped.port
+        );
+    }
+    let Some(settings) = store else {
+        return;
+    };
+    let mut settings = settings.lock().await;
+    // The new uplink may reuse the slot of the dropped one, so it is stored
+    // after the dropped one is removed.
+    if let Some(Err(e)) = dropped.map(|dropped| dropped.remove(&mut *settings)) {
+        esp_println::println!("[LoRa WAN] Failed to remove queued uplink: {}", e);
+    }
+    let stored = queue.iter().max_by_key(|queued| queued.seq());
+    if let Some(Err(e)) = stored.map(|queued| queued.write(&mut *settings)) {
+        esp_println::println!("[LoRa WAN] Failed to store queued uplink: {}", e);
+    }
+}
+
+/// Removes the stored copy of an uplink that left the queue.
+async fn forget(uplink: &Uplink, store: Option<&MutexSettings>) {
+    if let Some(settings) = store {
+        if let Err(e) = uplink.remove(&mut *settings.lock().await) {
+            esp_println::println!("[LoRa WAN] Failed to remove queued uplink: {}", e);
+        }
+    }
+}
 
-// OTAA Credentials
-const _DEFAULT_DEVEUI: [u8; 8] = [0x4d, 0x89, 0x64, 0x17, 0xe7, 0x49, 0x2c, 0xbb];
-const _DEFAULT_APPEUI: [u8; 8] = [0x82, 0xc5, 0x4b, 0x04, 0x0d, 0x29, 0x70, 0xa9];
-const _DEFAULT_APPKEY: [u8; 16] = [
-    0x72, 0xad, 0x47, 0x7e, 0xca, 0xe4, 0xa7, 0x80, 0xb5, 0xae, 0x93, 0xbf, 0xac, 0x7a, 0x04, 0xbb,
-];
-
-// ABP Credentials
-const _DEFAULT_DEVADDR: [u8; 4] = [0xD2, 0xFC, 0x8B, 0xC8];
-const _DEFAULT_NWKSKEY: [u8; 16] = [
-    0x17, 0x2A, 0xE2, 0xDC, 0xD3, 0xA3, 0xC6, 0xE2, 0x39, 0xE4, 0xDC, 0x73, 0x00, 0x23, 0xF0, 0x7E,
-];
-const _DEFAULT_APPSKEY: [u8; 16] = [
-    0xAA, 0x70, 0x08, 0x19, 0xFE, 0x52, 0x8C, 0x91, 0x6B, 0xEF, 0x1D, 0xDE, 0x04, 0x55, 0x1F, 0x95,
-];
+/// Time to wait after an uplink of `len` bytes before the next one of a
+/// backlog, so the region's duty cycle is respected.
+fn backlog_delay(region: Region, dr: u8, len: usize) -> Duration {
+    let airtime_ms = region
+        .uplink_params(dr)
+        .map(|params| params.time_on_air_ms(len + FRAME_OVERHEAD_LEN))
+        .unwrap_or(0) as u64;
+    let off_ms = match region.duty_cycle() {
+        Some(n) => airtime_ms * (n as u64 - 1),
+        None => 0,
+    };
+    Duration::from_millis(off_ms.max(BACKLOG_INTERVAL_MS))
+}
 
 #[embassy_executor::task]
-pub async fn task_lorawan(mut lora: LoRaRadio<'static>, rng: Rng) {
-    esp_println::println!("[LoRa WAN] Activating LoRaWAN network using OTAA ...");
+pub async fn task_lorawan(
+    mut lora: LoRaRadio<'static>,
+    mut rng: Rng,
+    config: Option<LoRaWanConfig>,
+    firmware: Option<&'static mut Firmware>,
+) {
+    let Some(config) = config else {
+        esp_println::println!("[LoRa WAN] Not joining until keys are provisioned");
+        return;
+    };
+    esp_println::println!(
+        "[LoRa WAN] Activating LoRaWAN network in {:?} ...",
+        config.region
+    );
     lora.radio.init().await.unwrap();
-    lora.device_nonce = u16::from(lora.device_nonce).wrapping_add(1);
+    // The radio is moved into the stack below, keep what is needed to persist
+    // the session.
+    let settings = lora.settings;
+    let mut record = lora.session();
     // Convert the P2P radio into a LoRaWAN radio
     let radio: LorawanRadio<_, _, _MAX_TX_POWER> = lora.radio.into();
-    let data = [0xAB, 0xCD, 0xEF];
-    let mut au915 = region::AU915::new();
-    au915.set_join_bias(region::Subband::_1);
+    // Record the link quality of the downlinks for the display.
+    let radio = MonitoredRadio::new(radio);
     // Create the LoRaWAN device
-    let mut device: Device<_, Crypto, _, _> =
-        Device::new(au915.into(), radio, EmbassyTimer::new(), rng);
+    let mut device: Device<_, Crypto, _, _> = Device::new(
+        region_configuration(&config),
+        radio,
+        EmbassyTimer::new(),
+        NonceRng(rng.clone()),
+    );
+    device.set_datarate(data_rate(config.data_rate));
 
-    esp_println::println!("[LoRa WAN] Activating device...");
+    let mut joined = session_matches(&record, &config.activation);
+    if joined {
+        record.resume();
+        device.set_session(restore_session(&record));
+        // The stack starts with the receive windows of the region.
+        if let Some(rx) = record.rx_settings {
+            rx_windows::restore(config.region, rx);
+            esp_println::println!("[LoRa WAN] Receive windows restored: {:?}", rx);
+        }
+        // Checkpoint the skipped-ahead counter before the first uplink.
+        save_session(settings, &record).await;
+        esp_println::println!(
+            "[LoRa WAN] Session restored, FCntUp {} FCntDown {}",
+            record.fcnt_up,
+            record.fcnt_down
+        );
+    }
+    let join_mode = join_mode(&config.activation);
+    let mut backoff = JoinBackoff::new(config.region, config.data_rate);
 
-    // Create the ABP join mode with the credentials.
-    let join_mode = lorawan_device::JoinMode::OTAA {
-        appeui: AppEui::from(_DEFAULT_APPEUI),
-        appkey: AppKey::from(_DEFAULT_APPKEY),
-        deveui: DevEui::from(_DEFAULT_DEVEUI),
+    let mut dispatcher = Dispatcher::default();
+    let handlers: [(u8, Handler<RemoteState>); 4] = [
+        (COMMAND_PORT, handle_commands),
+        (FRAGMENTATION_PORT, handle_fragmentation),
+        (MULTICAST_PORT, handle_multicast),
+        (CLOCK_SYNC_PORT, handle_clock_sync),
+    ];
+    for (port, handler) in handlers {
+        if let Err(e) = dispatcher.register(port, handler) {
+            esp_println::println!("[LoRa WAN] Failed to register handler: {}", e);
+        }
+    }
+    let mut state = RemoteState {
+        region: config.region,
+        uplink_interval_s: DEFAULT_UPLINK_INTERVAL_S,
+        data_rate: config.data_rate,
+        fcnt_up: record.fcnt_up,
+        class: match config.class {
+            DeviceClass::B => {
+                esp_println::println!("[LoRa WAN] Class B is not supported, using class A");
+                DeviceClass::A
+            }
+            class => class,
+        },
+        reboot: false,
+        firmware,
+        fragmentation_answer: Answer::new(),
+        multicast: Multicast::new(config.gen_app_key.as_ref(), config.region),
+        multicast_answer: Answer::new(),
+        clock_sync: ClockSync::default(),
+        clock_answer: Answer::new(),
     };
-    
-
-    // let join_mode = lorawan_device::JoinMode::ABP {
-    //     devaddr: DevAddr::from(_DEFAULT_DEVADDR),
-    //     nwkskey: NwkSKey::from(_DEFAULT_NWKSKEY),
-    //     appskey: AppSKey::from(_DEFAULT_APPSKEY),
-    // };
-
-    loop {
-        // In ABP, join() will not perform an over-the-air join but will instead configure the device.
-        match device.join(&join_mode).await {
-            Ok(join_response) => match join_response {
-                lorawan_device::async_device::JoinResponse::JoinSuccess => {
-                    esp_println::println!("[LoRa WAN] Joined network.");
-                    break;
+    let mut acks = Acks::default();
+    let mut telemetry = [0u8; MAX_UPLINK_LEN];
+    let store = config.persist_queue.then_some(settings);
+    let mut queue = match store {
+        Some(settings) => {
+            let mut settings = settings.lock().await;
+            match UplinkQueue::read(&mut *settings, OverflowPolicy::Coalesce) {
+                Ok(queue) => queue,
+                // The invalid records are removed, reading again restores the
+                // valid ones.
+                Err(QueueError::InvalidRecord(slot)) => {
+                    esp_println::println!("[LoRa WAN] Dropped invalid queued uplink {}", slot);
+                    UplinkQueue::read(&mut *settings, OverflowPolicy::Coalesce)
+                        .unwrap_or_else(|_| UplinkQueue::new(OverflowPolicy::Coalesce))
                 }
-                lorawan_device::async_device::JoinResponse::NoJoinAccept => {
-                    esp_println::println!("[LoRa WAN] Rejoined network.");
+                Err(e) => {
+                    esp_println::println!("[LoRa WAN] Failed to restore uplink queue: {}", e);
+                    UplinkQueue::new(OverflowPolicy::Coalesce)
                 }
-            },
-            Err(err) => {
-                esp_println::println!("[LoRa WAN] OTAA activation failed: {:?}", err);
-                return;
             }
         }
-        Timer::after(Duration::from_millis(1000)).await;
+        None => UplinkQueue::new(OverflowPolicy::Coalesce),
+    };
+    if !queue.is_empty() {
+        esp_println::println!("[LoRa WAN] Restored {} queued uplinks", queue.len());
     }
+    let mut next_reading = Instant::now();
+    let mut next_link_check = Instant::now();
+    let mut class = DeviceClass::A;
+    // End of the off time the duty cycle requires after the last uplink.
+    let mut quiet_until = Instant::now();
+    let mut link_loss = LinkLoss::default();
 
     // Now send uplink messages in a loop.
     loop {
-        esp_println::println!("[LoRa WAN] Sending uplink to Everynet...");
-        // The payload here is a sample byte array; replace with your application data.
-        match device.send(&data, 2, false).await {
-            Ok(lorawan_device::async_device::SendResponse::DownlinkReceived(_)) => {
-                esp_println::println!("[LoRa WAN] Everynet downlink received!");
+        if !joined {
+            esp_println::println!("[LoRa WAN] Activating device...");
+            // Nothing of a lost session carries over but the DevNonce, which
+            // must keep counting up.
+            record = SessionRecord {
+                device_nonce: record.device_nonce,
+                ..Default::default()
+            };
+            rx_windows::clear();
+            device.disable_class_c();
+            class = DeviceClass::A;
+            link_loss = LinkLoss::default();
+            backoff.restart();
+            // The last uplink's off time applies to the join requests too.
+            Timer::at(quiet_until).await;
+            loop {
+                let attempt = backoff.next_attempt(Instant::now().as_millis(), rng.random());
+                Timer::at(Instant::from_millis(attempt.at_ms)).await;
+                backoff.record(&attempt);
+                device.set_datarate(data_rate(attempt.data_rate));
+                // Save the nonce before it goes on air, so a reboot during the
+                // join does not send it again.
+                record.device_nonce = record.device_nonce.wrapping_add(1);
+                save_session(settings, &record).await;
+                esp_println::println!(
+                    "[LoRa WAN] Join attempt {} at DR{} with DevNonce {}",
+                    backoff.attempts(),
+                    attempt.data_rate,
+                    record.device_nonce
+                );
+
+                // In ABP, join() will not perform an over-the-air join but
+                // will instead configure the device.
+                NonceRng::arm(record.device_nonce);
+                let result = device.join(&join_mode).await;
+                NonceRng::disarm();
+                match result {
+                    Ok(lorawan_device::async_device::JoinResponse::JoinSuccess) => {
+                        esp_println::println!("[LoRa WAN] Joined network.");
+                        break;
+                    }
+                    Ok(lorawan_device::async_device::JoinResponse::NoJoinAccept) => {
+                        esp_println::println!("[LoRa WAN] No join accept received.");
+                    }
+                    Err(err) => {
+                        esp_println::println!("[LoRa WAN] Activation failed: {:?}", err);
+                    }
+                }
+            }
+            device.set_datarate(data_rate(config.data_rate));
+
+            if let Some(session) = device.get_session() {
+                update_record(&mut record, session, &config.activation);
+            }
+            // The join accept configures the receive windows, which the stack
+            // applies itself for this session.
+            record.rx_settings = match &config.activation {
+                Activation::Otaa { appkey, .. } => link_status::take_frame()
+                    .and_then(|frame| RxSettings::from_join_accept(config.region, appkey, &frame)),
+                Activation::Abp { .. } => None,
+            };
+            save_session(settings, &record).await;
+            state.fcnt_up = record.fcnt_up;
+            joined = true;
+        }
+
+        if Instant::now() >= next_reading {
+            next_reading = Instant::now() + Duration::from_secs(state.uplink_interval_s as u64);
+            let budget = config
+                .region
+                .max_payload(state.data_rate)
+                .min(telemetry.len());
+            let reading = Telemetry {
+                fix: gps::latest_fix(),
+                status: DeviceStatus {
+                    uptime_s: Instant::now().as_secs() as u32,
+                    data_rate: state.data_rate,
+                    unix_time: clock::unix_time(),
+                },
+            };
+            match reading.encode(config.payload_format, &mut telemetry[..budget]) {
+                Ok(len) => {
+                    let payload = &telemetry[..len];
+                    match Uplink::new(config.port, Priority::Normal, config.confirmed, payload) {
+                        Ok(uplink) => enqueue(&mut queue, uplink, store).await,
+                        Err(e) => esp_println::println!("[LoRa WAN] {}", e),
+                    }
+                }
+                Err(e) => esp_println::println!("[LoRa WAN] Failed to encode telemetry: {}", e),
+            }
+        }
+
+        if Instant::now() >= next_link_check {
+            next_link_check = Instant::now() + Duration::from_secs(LINK_CHECK_INTERVAL_S);
+            // The stack cannot add MAC commands to FOpts, so they go as a
+            // port 0 payload. The network answers on port 0 or in FOpts.
+            match Uplink::new(MAC_PORT, Priority::Low, false, &LINK_CHECK_REQUEST) {
+                Ok(uplink) => enqueue(&mut queue, uplink, store).await,
+                Err(e) => esp_println::println!("[LoRa WAN] {}", e),
+            }
+        }
+
+        // A multicast session runs in class C, whatever the device's class.
+        let session = state.multicast.active(Instant::now().as_secs() as u32);
+        if session != multicast::listening() {
+            multicast::listen(session);
+        }
+        let wanted = if session.is_some() {
+            DeviceClass::C
+        } else {
+            state.class
+        };
+        if wanted != class {
+            match wanted {
+                DeviceClass::C => device.enable_class_c(),
+                _ => device.disable_class_c(),
+            }
+            class = wanted;
+            esp_println::println!("[LoRa WAN] Operating as class {:?}", class);
+        }
+        // Acknowledgements go out ahead of any queued telemetry.
+        if !acks.is_empty() {
+            if acks.overflowed() {
+                esp_println::println!("[LoRa WAN] Too many commands, acknowledgements dropped");
+            }
+            match Uplink::new(COMMAND_PORT, Priority::High, false, acks.as_slice()) {
+                Ok(uplink) => enqueue(&mut queue, uplink, store).await,
+                Err(e) => esp_println::println!("[LoRa WAN] {}", e),
+            }
+            acks.clear();
+        }
+        // The GPS receiver keeps the time better than the network can.
+        let periodic = clock::source() != Some(TimeSource::Gps);
+        let now_s = Instant::now().as_secs() as u32;
+        if let Some(request) = state.clock_sync.poll(now_s, clock::device_time(), periodic) {
+            // Cannot fail, the request is shorter than an answer.
+            let _ = state.clock_answer.extend_from_slice(&request);
+        }
+        let answers = [
+            (FRAGMENTATION_PORT, &mut state.fragmentation_answer),
+            (MULTICAST_PORT, &mut state.multicast_answer),
+            (CLOCK_SYNC_PORT, &mut state.clock_answer),
+        ];
+        for (port, answer) in answers.into_iter().filter(|(_, answer)| !answer.is_empty()) {
+            match Uplink::new(port, Priority::High, false, answer) {
+                Ok(uplink) => enqueue(&mut queue, uplink, store).await,
+                Err(e) => esp_println::println!("[LoRa WAN] {}", e),
+            }
+            answer.clear();
+        }
+
+        let mut delivered = false;
+        let mut rx_changed = false;
+        if let Some(uplink) = queue.peek() {
+            let (seq, port, len) = (uplink.seq(), uplink.port, uplink.payload.len());
+            device.set_datarate(data_rate(state.data_rate));
+            esp_println::println!(
+                "[LoRa WAN] {} Sending uplink on port {} ({} queued)...",
+                clock::timestamp(),
+                port,
+                queue.len()
+            );
+            let mut payload = uplink.payload.clone();
+            if port == CLOCK_SYNC_PORT {
+                clock_sync::stamp(&mut payload, clock::device_time());
+            }
+            let response = device.send(&payload, port, uplink.confirmed).await;
+            let left = match response {
+                Ok(SendResponse::DownlinkReceived(_)) | Ok(SendResponse::RxComplete) => {
+                    esp_println::println!("[LoRa WAN] Uplink sent successfully.");
+                    delivered = true;
+                    queue.remove(seq)
+                }
+                Ok(SendResponse::NoAck) => {
+                    esp_println::println!("[LoRa WAN] Uplink not acknowledged.");
+                    queue.fail(seq)
+                }
+                Ok(SendResponse::SessionExpired) => {
+                    esp_println::println!("[LoRa WAN] Session expired.");
+                    joined = false;
+                    queue.fail(seq)
+                }
+                Err(err) => {
+                    esp_println::println!("[LoRa WAN] Failed to send uplink: {:?}", err);
+                    queue.fail(seq)
+                }
+            };
+            if let Some(uplink) = left {
+                if !delivered {
+                    esp_println::println!("[LoRa WAN] Giving up on uplink on port {}", uplink.port);
+                }
+                forget(&uplink, store).await;
+            }
+            quiet_until = Instant::now() + backlog_delay(config.region, state.data_rate, len);
+            match response {
+                Ok(SendResponse::DownlinkReceived(_)) => link_loss.answered(),
+                // The link checks go alone on the MAC port.
+                Ok(SendResponse::RxComplete) | Ok(SendResponse::NoAck) => {
+                    link_loss.unanswered(port == MAC_PORT)
+                }
+                _ => {}
+            }
+            if let Ok(SendResponse::DownlinkReceived(fcnt)) = response {
+                rx_changed |= handle_frame_options(&mut record, config.region, fcnt);
                 while let Some(downlink) = device.take_downlink() {
-                    esp_println::println!("[LoRa WAN] Downlink Data: {:?}", downlink.data);
+                    let (port, payload) = (downlink.fport, &downlink.data);
+                    if port == MAC_PORT {
+                        rx_changed |= update_rx_settings(&mut record, config.region, payload);
+                    }
+                    handle_downlink(&dispatcher, &mut state, &mut acks, port, payload);
                 }
-                break;
             }
-            Ok(_) => {
-                esp_println::println!("[LoRa WAN] Uplink sent successfully.");
+        }
+
+        if link_loss.needs_check() {
+            next_link_check = Instant::now();
+        }
+        // A session the network no longer answers is given up, an ABP
+        // session cannot be renewed by joining.
+        if link_loss.is_lost() && matches!(config.activation, Activation::Otaa { .. }) {
+            esp_println::println!("[LoRa WAN] Network not answering, joining again");
+            joined = false;
+        }
+        if !joined {
+            continue;
+        }
+
+        // Reboot once the answers to the downlinks went out.
+        let rebooting = state.reboot
+            && acks.is_empty()
+            && !state.answers_pending()
+            && !queue.iter().any(|uplink| uplink.priority == Priority::High);
+        if let Some(session) = device.get_session() {
+            state.fcnt_up = session.fcnt_up;
+            if rebooting || rx_changed || record.needs_checkpoint(session.fcnt_up) {
+                update_record(&mut record, session, &config.activation);
+                save_session(settings, &record).await;
             }
-            Err(err) => {
-                esp_println::println!("[LoRa WAN] Failed to send uplink: {:?}", err);
+        }
+        if rebooting {
+            esp_println::println!("[LoRa WAN] Rebooting on remote command");
+            esp_hal::reset::software_reset();
+        }
+        // Drain a backlog as fast as the duty cycle allows once the network is
+        // reachable again; after a failure wait for the next reading.
+        let wake = if delivered && !queue.is_empty() {
+            quiet_until.min(next_reading)
+        } else {
+            next_reading
+        };
+        // Wake to start or end a multicast session.
+        let uptime_s = Instant::now().as_secs() as u32;
+        let wake = match state.multicast.next_change_s(uptime_s) {
+            Some(change_s) => wake.min(Instant::from_secs(change_s as u64)),
+            None => wake,
+        };
+        if class != DeviceClass::C {
+            Timer::at(wake).await;
+            continue;
+        }
+        // In class C the radio listens on RX2, or the channel of a multicast
+        // session, until the next uplink is due or until a command needs an
+        // answer.
+        while acks.is_empty() && !state.answers_pending() {
+            let listen = select3(device.rxc_listen(), Timer::at(wake), MULTICAST_FRAME.wait());
+            match listen.await {
+                Either3::First(Ok(ListenResponse::DownlinkReceived(fcnt))) => {
+                    link_loss.answered();
+                    let mut rx_changed = handle_frame_options(&mut record, config.region, fcnt);
+                    while let Some(downlink) = device.take_downlink() {
+                        let (port, payload) = (downlink.fport, &downlink.data);
+                        if port == MAC_PORT {
+                            rx_changed |= update_rx_settings(&mut record, config.region, payload);
+                        }
+                        handle_downlink(&dispatcher, &mut state, &mut acks, port, payload);
+                    }
+                    if rx_changed {
+                        if let Some(session) = device.get_session() {
+                            update_record(&mut record, session, &config.activation);
+                        }
+                        save_session(settings, &record).await;
+                    }
+                }
+                Either3::First(Ok(ListenResponse::SessionExpired)) => {
+                    esp_println::println!("[LoRa WAN] Session expired.");
+                    joined = false;
+                    break;
+                }
+                Either3::First(Err(err)) => {
+                    esp_println::println!("[LoRa WAN] Class C receive failed: {:?}", err);
+                    Timer::at(wake).await;
+                    break;
+                }
+                Either3::Second(()) => break,
+                Either3::Third(frame) => {
+                    if let Some((port, payload)) = state.multicast.receive(&frame) {
+                        handle_downlink(&dispatcher, &mut state, &mut acks, port, &payload);
+                    }
+                }
             }
         }
-        // Wait 10 seconds before sending the next uplink.
-        embassy_time::Timer::after(Duration::from_secs(10)).await;
+        if joined && (!acks.is_empty() || state.answers_pending()) {
+            Timer::at(quiet_until.min(wake)).await;
+        }
     }
 }
@@ -0,0 +1,799 @@
+use embedded_storage::Storage;
+
+use super::{
+    airtime::LoRaParams,
+    nvs::{Nvs, NvsError},
+    payload::PayloadFormat,
+};
+
+/// NVS namespace holding the factory-provisioned LoRaWAN configuration.
+pub const LORAWAN_NAMESPACE: &str = "lorawan";
+
+const MODE_KEY: &str = "mode";
+const DEVEUI_KEY: &str = "deveui";
+const APPEUI_KEY: &str = "appeui";
+const APPKEY_KEY: &str = "appkey";
+const DEVADDR_KEY: &str = "devaddr";
+const NWKSKEY_KEY: &str = "nwkskey";
+const APPSKEY_KEY: &str = "appskey";
+const REGION_KEY: &str = "region";
+const SUBBAND_KEY: &str = "subband";
+const DATA_RATE_KEY: &str = "dr";
+const PORT_KEY: &str = "port";
+const FORMAT_KEY: &str = "format";
+const CONFIRMED_KEY: &str = "confirmed";
+const PERSIST_QUEUE_KEY: &str = "persistq";
+const CLASS_KEY: &str = "class";
+const GENAPPKEY_KEY: &str = "genappkey";
+
+const MODE_OTAA: u8 = 0;
+const MODE_ABP: u8 = 1;
+/// Stored subband value meaning "no join bias".
+const SUBBAND_NONE: u8 = 0;
+
+const DEFAULT_REGION: Region = Region::AU915;
+const DEFAULT_SUBBAND: Option<u8> = Some(1);
+const DEFAULT_DATA_RATE: u8 = 2;
+const DEFAULT_PORT: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    Storage,
+    UnknownActivation(u8),
+    UnknownRegion(u8),
+    MissingKey(&'static str),
+    InvalidKey(&'static str),
+    InvalidSubband(u8),
+    InvalidDataRate(u8),
+    InvalidPort(u8),
+    UnknownFormat(u8),
+    UnknownClass(u8),
+}
+
+impl core::fmt::Display for ConfigError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::Storage => write!(f, "Failed to access storage"),
+            ConfigError::UnknownActivation(mode) => write!(f, "Unknown activation mode {}", mode),
+            ConfigError::UnknownRegion(region) => write!(f, "Unknown region {}", region),
+            ConfigError::MissingKey(key) => write!(f, "Missing key {}", key),
+            ConfigError::InvalidKey(key) => write!(f, "Invalid key {}", key),
+            ConfigError::InvalidSubband(subband) => {
+                write!(f, "Subband {} not valid for region", subband)
+            }
+            ConfigError::InvalidDataRate(dr) => {
+                write!(f, "Data rate DR{} not valid for region", dr)
+            }
+            ConfigError::InvalidPort(port) => write!(f, "Invalid application port {}", port),
+            ConfigError::UnknownFormat(format) => write!(f, "Unknown payload format {}", format),
+            ConfigError::UnknownClass(class) => write!(f, "Unknown device class {}", class),
+        }
+    }
+}
+
+/// Regional parameters supported by the firmware.
+///
+/// The discriminants are the values stored in NVS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    EU868 = 0,
+    US915 = 1,
+    AU915 = 2,
+    AS923_1 = 3,
+    AS923_2 = 4,
+    AS923_3 = 5,
+    AS923_4 = 6,
+    IN865 = 7,
+    EU433 = 8,
+}
+
+impl Region {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Region::EU868,
+            1 => Region::US915,
+            2 => Region::AU915,
+            3 => Region::AS923_1,
+            4 => Region::AS923_2,
+            5 => Region::AS923_3,
+            6 => Region::AS923_4,
+            7 => Region::IN865,
+            8 => Region::EU433,
+            _ => return None,
+        })
+    }
+
+    /// Highest LoRa uplink data rate of the region.
+    pub fn max_data_rate(&self) -> u8 {
+        match self {
+            Region::US915 => 4,
+            Region::IN865 => 5,
+            _ => 6,
+        }
+    }
+
+    /// Whether the region splits its channels into 8 subbands of 8 channels.
+    pub fn has_subbands(&self) -> bool {
+        matches!(self, Region::US915 | Region::AU915)
+    }
+
+    /// Maximum share of time a device may transmit, as `1 / n`, or `None`
+    /// where the region limits the dwell time per uplink instead.
+    pub fn duty_cycle(&self) -> Option<u32> {
+        match self {
+            Region::US915 | Region::AU915 | Region::IN865 => None,
+            _ => Some(100),
+        }
+    }
+
+    /// Largest application payload of an uplink at data rate `dr`, without
+    /// MAC commands piggybacked in the frame header.
+    pub fn max_payload(&self, dr: u8) -> usize {
+        match (self, dr) {
+            (Region::US915, 0) => 11,
+            (Region::US915, 1) => 53,
+            (Region::US915, 2) => 125,
+            (Region::US915, _) => 242,
+            (_, 0..=2) => 51,
+            (_, 3) => 115,
+            (_, _) => 222,
+        }
+    }
+
+    /// Modulation of an uplink at data rate `dr`, or `None` if the region does
+    /// not define it as a LoRa data rate.
+    pub fn uplink_params(&self, dr: u8) -> Option<LoRaParams> {
+        if dr > self.max_data_rate() {
+            return None;
+        }
+        let (spreading_factor, bandwidth_hz) = match (self, dr) {
+            (Region::US915, 4) => (8, 500_000),
+            (Region::US915, dr) => (10 - dr, 125_000),
+            (Region::AU915, 6) => (8, 500_000),
+            (_, 6) => (7, 250_000),
+            (_, dr) => (12 - dr, 125_000),
+        };
+        Some(LoRaParams::lorawan(spreading_factor, bandwidth_hz))
+    }
+
+    /// Uplink data rate whose modulation is `spreading_factor` and
+    /// `bandwidth_hz`.
+    pub fn uplink_data_rate(&self, spreading_factor: u8, bandwidth_hz: u32) -> Option<u8> {
+        (0..=self.max_data_rate()).find(|dr| {
+            self.uplink_params(*dr).is_some_and(|params| {
+                params.spreading_factor == spreading_factor && params.bandwidth_hz == bandwidth_hz
+            })
+        })
+    }
+
+    /// Modulation of a downlink at data rate `dr`, or `None` if the region
+    /// does not define it as a LoRa data rate.
+    pub fn downlink_params(&self, dr: u8) -> Option<LoRaParams> {
+        match self {
+            Region::US915 | Region::AU915 => match dr {
+                8..=13 => Some(LoRaParams::lorawan(20 - dr, 500_000)),
+                _ => None,
+            },
+            _ => self.uplink_params(dr),
+        }
+    }
+
+    /// Data rate of the RX1 window after an uplink at `uplink_dr`, lowered by
+    /// the RX1 data rate offset the network configured.
+    pub fn rx1_data_rate(&self, uplink_dr: u8, offset: u8) -> u8 {
+        match self {
+            Region::US915 => (10 + uplink_dr).saturating_sub(offset).clamp(8, 13),
+            Region::AU915 => (8 + uplink_dr).saturating_sub(offset).clamp(8, 13),
+            _ => uplink_dr.saturating_sub(offset),
+        }
+    }
+
+    /// Frequency in Hz and data rate of the RX2 window until the network
+    /// configures others.
+    pub fn rx2_default(&self) -> (u32, u8) {
+        match self {
+            Region::EU868 => (869_525_000, 0),
+            Region::US915 | Region::AU915 => (923_300_000, 8),
+            Region::AS923_1 => (923_200_000, 2),
+            Region::AS923_2 => (921_400_000, 2),
+            Region::AS923_3 => (916_600_000, 2),
+            Region::AS923_4 => (917_500_000, 2),
+            Region::IN865 => (866_550_000, 2),
+            Region::EU433 => (434_665_000, 0),
+        }
+    }
+}
+
+/// When the device listens for downlinks.
+///
+/// The discriminants are the values stored in NVS and carried by the
+/// class switch command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceClass {
+    /// Only in the two receive windows after each uplink.
+    A = 0,
+    /// Also in ping slots synchronised to network beacons. Not supported
+    /// yet: the device stays in class A, see `class_b::BeaconTracker`.
+    B = 1,
+    /// Continuously on RX2 between uplinks, for mains-powered devices.
+    C = 2,
+}
+
+impl DeviceClass {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => DeviceClass::A,
+            1 => DeviceClass::B,
+            2 => DeviceClass::C,
+            _ => return None,
+        })
+    }
+}
+
+/// How the device obtains its session keys.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Activation {
+    Otaa {
+        deveui: [u8; 8],
+        appeui: [u8; 8],
+        appkey: [u8; 16],
+    },
+    Abp {
+        devaddr: [u8; 4],
+        nwkskey: [u8; 16],
+        appskey: [u8; 16],
+    },
+}
+
+impl Activation {
+    /// Reads the provisioned keys from the `lorawan` NVS namespace.
+    ///
+    /// # Errors
+    ///
+    /// * `UnknownActivation` - If the mode is neither OTAA nor ABP.
+    /// * `MissingKey` - If a key of the mode is not provisioned.
+    /// * `InvalidKey` - If a key has the wrong length.
+    pub fn read<S: Storage>(nvs: &mut Nvs<S>) -> Result<Self, ConfigError> {
+        Ok(match read_u8(nvs, MODE_KEY)?.unwrap_or(MODE_OTAA) {
+            MODE_OTAA => Activation::Otaa {
+                deveui: read_key(nvs, DEVEUI_KEY)?,
+                appeui: read_key(nvs, APPEUI_KEY)?,
+                appkey: read_key(nvs, APPKEY_KEY)?,
+            },
+            MODE_ABP => Activation::Abp {
+                devaddr: read_key(nvs, DEVADDR_KEY)?,
+                nwkskey: read_key(nvs, NWKSKEY_KEY)?,
+                appskey: read_key(nvs, APPSKEY_KEY)?,
+            },
+            mode => return Err(ConfigError::UnknownActivation(mode)),
+        })
+    }
+
+    /// Checks that the keys can be used on air.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidKey` - If a DevEUI or a key is all zeros.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let keys: [(&'static str, &[u8]); 2] = match self {
+            Activation::Otaa { deveui, appkey, .. } => [(DEVEUI_KEY, deveui), (APPKEY_KEY, appkey)],
+            Activation::Abp {
+                nwkskey, appskey, ..
+            } => [(NWKSKEY_KEY, nwkskey), (APPSKEY_KEY, appskey)],
+        };
+        match keys.iter().find(|(_, key)| key.iter().all(|b| *b == 0)) {
+            Some((name, _)) => Err(ConfigError::InvalidKey(name)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Per-device LoRaWAN configuration.
+///
+/// Every field is stored under its own key in the `lorawan` NVS namespace:
+///
+/// | Key         | Type | Value                                    |
+/// |-------------|------|------------------------------------------|
+/// | `mode`      | u8   | 0 = OTAA, 1 = ABP (OTAA when missing)    |
+/// | `deveui`    | blob | 8 bytes (OTAA)                           |
+/// | `appeui`    | blob | 8 bytes (OTAA)                           |
+/// | `appkey`    | blob | 16 bytes (OTAA)                          |
+/// | `devaddr`   | blob | 4 bytes (ABP)                            |
+/// | `nwkskey`   | blob | 16 bytes (ABP)                           |
+/// | `appskey`   | blob | 16 bytes (ABP)                           |
+/// | `region`    | u8   | See [`Region`]                           |
+/// | `subband`   | u8   | 1-8, or 0 for no join bias               |
+/// | `dr`        | u8   | Uplink data rate                         |
+/// | `port`      | u8   | Application port, 1-223                  |
+/// | `format`    | u8   | Telemetry encoding, 0 = LPP, 1 = compact |
+/// | `confirmed` | u8   | 1 to send telemetry as confirmed uplinks |
+/// | `persistq`  | u8   | 1 to keep queued uplinks across reboots  |
+/// | `class`     | u8   | Class at boot, see [`DeviceClass`]       |
+/// | `genappkey` | blob | 16 bytes, multicast root key (optional)  |
+///
+/// Keys are mandatory for the selected activation mode; the other fields
+/// fall back to their defaults, one by one, when they are not provisioned
+/// or cannot be used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoRaWanConfig {
+    pub activation: Activation,
+    pub region: Region,
+    pub subband: Option<u8>,
+    pub data_rate: u8,
+    pub port: u8,
+    pub payload_format: PayloadFormat,
+    /// Whether telemetry uplinks request an acknowledgement, so uplinks lost
+    /// in a coverage gap are detected and sent again.
+    ///
+    /// Off by default, as acknowledgements cost downlink airtime. Without
+    /// them the stack cannot tell a lost uplink from a delivered one, so
+    /// telemetry leaves the uplink queue as soon as it is transmitted: a
+    /// device that must keep its readings through coverage gaps, such as one
+    /// on a vehicle, needs this set.
+    pub confirmed: bool,
+    /// Whether uplinks waiting for coverage are stored in flash. Only useful
+    /// with [`LoRaWanConfig::confirmed`], as unconfirmed telemetry does not
+    /// wait in the queue.
+    pub persist_queue: bool,
+    pub class: DeviceClass,
+    /// Root key of the multicast group keys, see
+    /// [`Multicast`](super::multicast::Multicast); without it multicast groups
+    /// are refused.
+    pub gen_app_key: Option<[u8; 16]>,
+}
+
+impl LoRaWanConfig {
+    /// Configuration activated by `activation`, with the defaults of the
+    /// other fields.
+    pub fn new(activation: Activation) -> Self {
+        Self {
+            activation,
+            region: DEFAULT_REGION,
+            subband: DEFAULT_SUBBAND,
+            data_rate: DEFAULT_DATA_RATE,
+            port: DEFAULT_PORT,
+            payload_format: PayloadFormat::default(),
+            confirmed: false,
+            persist_queue: false,
+            class: DeviceClass::A,
+            gen_app_key: None,
+        }
+    }
+
+    /// Checks that the configuration can be used on air.
+    ///
+    /// # Errors
+    ///
+    /// * `InvalidKey` - If a DevEUI or a key is all zeros.
+    /// * `InvalidSubband` - If a subband is set outside 1-8, or for a region without subbands.
+    /// * `InvalidDataRate` - If the data rate is above the region's maximum.
+    /// * `InvalidPort` - If the port is 0 or in the reserved range 224-255.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.activation.validate()?;
+        check_subband(self.region, self.subband)?;
+        check_data_rate(self.region, self.data_rate)?;
+        check_port(self.port)
+    }
+
+    /// Reads and validates the configuration from the `lorawan` NVS
+    /// namespace, failing on the first field that cannot be used.
+    pub fn read<S: Storage>(nvs: &mut Nvs<S>) -> Result<Self, ConfigError> {
+        let mut config = Self::new(Activation::read(nvs)?);
+        config.activation.validate()?;
+        config.read_fields(nvs, Err)?;
+        Ok(config)
+    }
+
+    /// Reads the fields other than the activation over their defaults,
+    /// passing the errors of the ones that cannot be used to `invalid`,
+    /// which fails the read or keeps the default.
+    fn read_fields<S: Storage>(
+        &mut self,
+        nvs: &mut Nvs<S>,
+        mut invalid: impl FnMut(ConfigError) -> Result<(), ConfigError>,
+    ) -> Result<(), ConfigError> {
+        match read_u8(nvs, REGION_KEY) {
+            Ok(None) => {}
+            Ok(Some(value)) => match Region::from_u8(value) {
+                Some(region) => self.region = region,
+                None => invalid(ConfigError::UnknownRegion(value))?,
+            },
+            Err(e) => invalid(e)?,
+        }
+        // The default subband only applies to the default region.
+        if self.region != DEFAULT_REGION {
+            self.subband = None;
+        }
+        match read_u8(nvs, SUBBAND_KEY) {
+            Ok(None) => {}
+            Ok(Some(value)) => {
+                let subband = (value != SUBBAND_NONE).then_some(value);
+                match check_subband(self.region, subband) {
+                    Ok(()) => self.subband = subband,
+                    Err(e) => invalid(e)?,
+                }
+            }
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, DATA_RATE_KEY) {
+            Ok(None) => {}
+            Ok(Some(dr)) => match check_data_rate(self.region, dr) {
+                Ok(()) => self.data_rate = dr,
+                Err(e) => invalid(e)?,
+            },
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, PORT_KEY) {
+            Ok(None) => {}
+            Ok(Some(port)) => match check_port(port) {
+                Ok(()) => self.port = port,
+                Err(e) => invalid(e)?,
+            },
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, FORMAT_KEY) {
+            Ok(None) | Ok(Some(0)) => self.payload_format = PayloadFormat::Lpp,
+            Ok(Some(1)) => self.payload_format = PayloadFormat::Compact,
+            Ok(Some(format)) => invalid(ConfigError::UnknownFormat(format))?,
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, CONFIRMED_KEY) {
+            Ok(value) => self.confirmed = value.map_or(self.confirmed, |v| v != 0),
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, PERSIST_QUEUE_KEY) {
+            Ok(value) => self.persist_queue = value.map_or(self.persist_queue, |v| v != 0),
+            Err(e) => invalid(e)?,
+        }
+        match read_u8(nvs, CLASS_KEY) {
+            Ok(None) => {}
+            Ok(Some(value)) => match DeviceClass::from_u8(value) {
+                Some(class) => self.class = class,
+                None => invalid(ConfigError::UnknownClass(value))?,
+            },
+            Err(e) => invalid(e)?,
+        }
+        match read_key(nvs, GENAPPKEY_KEY) {
+            Ok(key) => self.gen_app_key = Some(key),
+            Err(ConfigError::MissingKey(_)) => {}
+            Err(e) => invalid(e)?,
+        }
+        Ok(())
+    }
+
+    /// Stores the configuration in the `lorawan` NVS namespace.
+    pub fn write<S: Storage>(&self, nvs: &mut Nvs<S>) -> Result<(), ConfigError> {
+        self.store(nvs).map_err(|_| ConfigError::Storage)
+    }
+
+    fn store<S: Storage>(&self, nvs: &mut Nvs<S>) -> Result<(), NvsError> {
+        match &self.activation {
+            Activation::Otaa {
+                deveui,
+                appeui,
+                appkey,
+            } => {
+                nvs.set_int(LORAWAN_NAMESPACE, MODE_KEY, MODE_OTAA)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, DEVEUI_KEY, deveui)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, APPEUI_KEY, appeui)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, APPKEY_KEY, appkey)?;
+            }
+            Activation::Abp {
+                devaddr,
+                nwkskey,
+                appskey,
+            } => {
+                nvs.set_int(LORAWAN_NAMESPACE, MODE_KEY, MODE_ABP)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, DEVADDR_KEY, devaddr)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, NWKSKEY_KEY, nwkskey)?;
+                nvs.set_blob(LORAWAN_NAMESPACE, APPSKEY_KEY, appskey)?;
+            }
+        }
+        let subband = self.subband.unwrap_or(SUBBAND_NONE);
+        nvs.set_int(LORAWAN_NAMESPACE, REGION_KEY, self.region as u8)?;
+        nvs.set_int(LORAWAN_NAMESPACE, SUBBAND_KEY, subband)?;
+        nvs.set_int(LORAWAN_NAMESPACE, DATA_RATE_KEY, self.data_rate)?;
+        nvs.set_int(LORAWAN_NAMESPACE, PORT_KEY, self.port)?;
+        let format: u8 = match self.payload_format {
+            PayloadFormat::Lpp => 0,
+            PayloadFormat::Compact => 1,
+        };
+        nvs.set_int(LORAWAN_NAMESPACE, FORMAT_KEY, format)?;
+        nvs.set_int(LORAWAN_NAMESPACE, CONFIRMED_KEY, self.confirmed as u8)?;
+        nvs.set_int(
+            LORAWAN_NAMESPACE,
+            PERSIST_QUEUE_KEY,
+            self.persist_queue as u8,
+        )?;
+        nvs.set_int(LORAWAN_NAMESPACE, CLASS_KEY, self.class as u8)?;
+        match &self.gen_app_key {
+            Some(key) => nvs.set_blob(LORAWAN_NAMESPACE, GENAPPKEY_KEY, key),
+            None => nvs.erase_key(LORAWAN_NAMESPACE, GENAPPKEY_KEY),
+        }
+    }
+
+    /// Loads the provisioned configuration, `None` if no usable keys are
+    /// provisioned: the device must not join then, as keys shared by every
+    /// build would make the devices collide on one DevEUI.
+    ///
+    /// A field that cannot be used is replaced by its default alone, keeping
+    /// the provisioned keys.
+    pub fn load<S: Storage>(nvs: &mut Nvs<S>) -> Option<Self> {
+        let activation = match Activation::read(nvs).and_then(|activation| {
+            activation.validate()?;
+            Ok(activation)
+        }) {
+            Ok(activation) => activation,
+            Err(e) => {
+                esp_println::println!("[LoRa WAN] {}, not joining without provisioned keys", e);
+                return None;
+            }
+        };
+        let mut config = Self::new(activation);
+        // Cannot fail, errors are only printed.
+        let _ = config.read_fields(nvs, |e| {
+            esp_println::println!("[LoRa WAN] {}, using the default", e);
+            Ok(())
+        });
+        if config.persist_queue && !config.confirmed {
+            esp_println::println!("[LoRa WAN] Unconfirmed telemetry is not kept for coverage");
+        }
+        esp_println::println!("[LoRa WAN] Using provisioned configuration");
+        Some(config)
+    }
+}
+
+fn check_subband(region: Region, subband: Option<u8>) -> Result<(), ConfigError> {
+    match subband {
+        Some(subband) if !region.has_subbands() || !(1..=8).contains(&subband) => {
+            Err(ConfigError::InvalidSubband(subband))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn check_data_rate(region: Region, dr: u8) -> Result<(), ConfigError> {
+    if dr > region.max_data_rate() {
+        return Err(ConfigError::InvalidDataRate(dr));
+    }
+    Ok(())
+}
+
+fn check_port(port: u8) -> Result<(), ConfigError> {
+    if !(1..=223).contains(&port) {
+        return Err(ConfigError::InvalidPort(port));
+    }
+    Ok(())
+}
+
+fn read_u8<S: Storage>(nvs: &mut Nvs<S>, key: &str) -> Result<Option<u8>, ConfigError> {
+    nvs.get_int(LORAWAN_NAMESPACE, key)
+        .map_err(|_| ConfigError::Storage)
+}
+
+/// Reads a mandatory blob that must be exactly `N` bytes long.
+fn read_key<S: Storage, const N: usize>(
+    nvs: &mut Nvs<S>,
+    key: &'static str,
+) -> Result<[u8; N], ConfigError> {
+    let mut value = [0u8; N];
+    match nvs.get_blob(LORAWAN_NAMESPACE, key, &mut value) {
+        Ok(Some(len)) if len == N => Ok(value),
+        Ok(Some(_)) | Err(NvsError::BufferTooSmall) => Err(ConfigError::InvalidKey(key)),
+        Ok(None) => Err(ConfigError::MissingKey(key)),
+        Err(_) => Err(ConfigError::Storage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{nvs::PAGE_SIZE, test_storage::MemStorage};
+
+    const DEVEUI: [u8; 8] = [0x70, 0xB3, 0xD5, 0x7E, 0xD0, 0x00, 0x12, 0x34];
+    const APPEUI: [u8; 8] = [0; 8];
+    const APPKEY: [u8; 16] = [0x2B; 16];
+
+    fn empty() -> Nvs<MemStorage> {
+        Nvs::new(MemStorage::new(3 * PAGE_SIZE)).unwrap()
+    }
+
+    fn otaa() -> LoRaWanConfig {
+        LoRaWanConfig::new(Activation::Otaa {
+            deveui: DEVEUI,
+            appeui: APPEUI,
+            appkey: APPKEY,
+        })
+    }
+
+    fn provisioned() -> Nvs<MemStorage> {
+        let mut nvs = empty();
+        nvs.set_blob(LORAWAN_NAMESPACE, DEVEUI_KEY, &DEVEUI)
+            .unwrap();
+        nvs.set_blob(LORAWAN_NAMESPACE, APPEUI_KEY, &APPEUI)
+            .unwrap();
+        nvs.set_blob(LORAWAN_NAMESPACE, APPKEY_KEY, &APPKEY)
+            .unwrap();
+        nvs
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let configs = [
+            LoRaWanConfig {
+                region: Region::EU868,
+                subband: None,
+                data_rate: 5,
+                port: 10,
+                payload_format: PayloadFormat::Compact,
+                confirmed: true,
+                persist_queue: true,
+                class: DeviceClass::C,
+                gen_app_key: Some([0x11; 16]),
+                ..otaa()
+            },
+            LoRaWanConfig::new(Activation::Abp {
+                devaddr: [0x26, 0x01, 0x1B, 0xDA],
+                nwkskey: [0x01; 16],
+                appskey: [0x02; 16],
+            }),
+        ];
+        let mut nvs = empty();
+        for config in configs {
+            config.write(&mut nvs).unwrap();
+            assert_eq!(LoRaWanConfig::read(&mut nvs), Ok(config.clone()));
+            assert_eq!(LoRaWanConfig::load(&mut nvs), Some(config));
+        }
+    }
+
+    #[test]
+    fn missing_fields_use_defaults() {
+        let mut nvs = provisioned();
+        assert_eq!(LoRaWanConfig::read(&mut nvs), Ok(otaa()));
+        assert_eq!(LoRaWanConfig::load(&mut nvs), Some(otaa()));
+    }
+
+    #[test]
+    fn refuses_missing_or_zero_keys() {
+        let mut nvs = empty();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::MissingKey(DEVEUI_KEY))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs), None);
+
+        let mut nvs = provisioned();
+        nvs.set_blob(LORAWAN_NAMESPACE, APPKEY_KEY, &[0; 16])
+            .unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::InvalidKey(APPKEY_KEY))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs), None);
+
+        let mut nvs = provisioned();
+        nvs.set_blob(LORAWAN_NAMESPACE, APPKEY_KEY, &[0x2B; 15])
+            .unwrap();
+        assert_eq!(LoRaWanConfig::load(&mut nvs), None);
+
+        let mut nvs = provisioned();
+        nvs.set_int(LORAWAN_NAMESPACE, MODE_KEY, MODE_ABP).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::MissingKey(DEVADDR_KEY))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs), None);
+
+        let mut nvs = provisioned();
+        nvs.set_int(LORAWAN_NAMESPACE, MODE_KEY, 7u8).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::UnknownActivation(7))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs), None);
+    }
+
+    #[test]
+    fn bad_fields_fall_back_one_by_one() {
+        let mut nvs = provisioned();
+        nvs.set_int(LORAWAN_NAMESPACE, REGION_KEY, 99u8).unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, DATA_RATE_KEY, 3u8).unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, PORT_KEY, 224u8).unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, CONFIRMED_KEY, 1u8).unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, CLASS_KEY, 9u8).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::UnknownRegion(99))
+        );
+        assert_eq!(
+            LoRaWanConfig::load(&mut nvs),
+            Some(LoRaWanConfig {
+                data_rate: 3,
+                confirmed: true,
+                ..otaa()
+            })
+        );
+
+        let mut nvs = provisioned();
+        nvs.set_int(LORAWAN_NAMESPACE, REGION_KEY, Region::EU868 as u8)
+            .unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, DATA_RATE_KEY, 9u8).unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, FORMAT_KEY, 5u8).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::InvalidDataRate(9))
+        );
+        assert_eq!(
+            LoRaWanConfig::load(&mut nvs),
+            Some(LoRaWanConfig {
+                region: Region::EU868,
+                subband: None,
+                ..otaa()
+            })
+        );
+    }
+
+    #[test]
+    fn subband_depends_on_region() {
+        let mut nvs = provisioned();
+        nvs.set_int(LORAWAN_NAMESPACE, SUBBAND_KEY, SUBBAND_NONE)
+            .unwrap();
+        assert_eq!(LoRaWanConfig::read(&mut nvs).unwrap().subband, None);
+        nvs.set_int(LORAWAN_NAMESPACE, SUBBAND_KEY, 2u8).unwrap();
+        assert_eq!(LoRaWanConfig::read(&mut nvs).unwrap().subband, Some(2));
+        nvs.set_int(LORAWAN_NAMESPACE, SUBBAND_KEY, 9u8).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::InvalidSubband(9))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs).unwrap().subband, Some(1));
+
+        // Regions without subbands reject one.
+        nvs.set_int(LORAWAN_NAMESPACE, REGION_KEY, Region::EU868 as u8)
+            .unwrap();
+        nvs.set_int(LORAWAN_NAMESPACE, SUBBAND_KEY, 2u8).unwrap();
+        assert_eq!(
+            LoRaWanConfig::read(&mut nvs),
+            Err(ConfigError::InvalidSubband(2))
+        );
+        assert_eq!(LoRaWanConfig::load(&mut nvs).unwrap().subband, None);
+    }
+
+    #[test]
+    fn validate_rejects_unusable_fields() {
+        assert_eq!(otaa().validate(), Ok(()));
+        let config = LoRaWanConfig {
+            activation: Activation::Otaa {
+                deveui: [0; 8],
+                appeui: APPEUI,
+                appkey: APPKEY,
+            },
+            ..otaa()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::InvalidKey(DEVEUI_KEY)));
+        let config = LoRaWanConfig {
+            activation: Activation::Abp {
+                devaddr: [1, 2, 3, 4],
+                nwkskey: [1; 16],
+                appskey: [0; 16],
+            },
+            ..otaa()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::InvalidKey(APPSKEY_KEY)));
+        let config = LoRaWanConfig {
+            subband: Some(0),
+            ..otaa()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::InvalidSubband(0)));
+        let config = LoRaWanConfig {
+            data_rate: 14,
+            ..otaa()
+        };
+        assert_eq!(config.validate(), Err(ConfigError::InvalidDataRate(14)));
+        for port in [0, 224, 255] {
+            let config = LoRaWanConfig { port, ..otaa() };
+            assert_eq!(config.validate(), Err(ConfigError::InvalidPort(port)));
+        }
+    }
+}
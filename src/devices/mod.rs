@@ -1,4 +1,6 @@
+pub mod battery;
 pub mod button;
+pub mod crc;
 pub mod display;
 pub mod gps;
 pub mod led;
@@ -6,5 +8,39 @@ pub mod lora;
 pub mod wifi;
 pub mod lorawan;
 pub mod lora_p2p;
+pub mod partition;
+pub mod session;
 pub mod types;
-pub mod iv;
\ No newline at end of file
+pub mod iv;
+pub mod kv;
+pub mod nvs;
+pub mod lorawan_config;
+pub mod airtime;
+pub mod join_backoff;
+pub mod downlink;
+pub mod payload;
+pub mod uplink_queue;
+pub mod class_b;
+pub mod fragmentation;
+pub mod multicast;
+pub mod ota;
+pub mod clock;
+pub mod clock_sync;
+pub mod link_status;
+pub mod p2p_frame;
+pub mod p2p_arq;
+pub mod p2p_fragment;
+pub mod p2p_crypto;
+pub mod p2p_pairing;
+pub mod p2p_mesh;
+#[cfg(test)]
+pub mod p2p_mesh_sim;
+pub mod p2p_neighbors;
+pub mod p2p_routing;
+pub mod p2p_tdma;
+pub mod p2p_cad;
+pub mod rx_windows;
+#[cfg(test)]
+pub mod test_storage;
+#[cfg(test)]
+pub mod test_util;
\ No newline at end of file
@@ -0,0 +1,723 @@
+use core::cell::Cell;
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use cmac::{Cmac, Mac};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use heapless::Vec;
+
+use super::{
+    airtime::LoRaParams,
+    downlink::{push_answer, Answer, CommandError},
+    link_status::{MAC_PORT, MAX_FRAME_LEN},
+    lorawan_config::Region,
+};
+
+/// FPort of the LoRaWAN Remote Multicast Setup package (TS005).
+pub const MULTICAST_PORT: u8 = 200;
+/// Number of multicast groups, the most the package can address.
+pub const MAX_GROUPS: usize = 4;
+
+const PACKAGE_IDENTIFIER: u8 = 2;
+const PACKAGE_VERSION: u8 = 1;
+
+mod cid {
+    pub const PACKAGE_VERSION: u8 = 0x00;
+    pub const MC_GROUP_STATUS: u8 = 0x01;
+    pub const MC_GROUP_SETUP: u8 = 0x02;
+    pub const MC_GROUP_DELETE: u8 = 0x03;
+    pub const MC_CLASS_C_SESSION: u8 = 0x04;
+    pub const MC_CLASS_B_SESSION: u8 = 0x05;
+}
+
+const SETUP_ID_ERROR: u8 = 0x04;
+const DELETE_GROUP_UNDEFINED: u8 = 0x04;
+const SESSION_DR_ERROR: u8 = 0x04;
+const SESSION_FREQ_ERROR: u8 = 0x08;
+const SESSION_GROUP_UNDEFINED: u8 = 0x10;
+
+/// MHDR of an unconfirmed data downlink, the only kind sent to a group.
+const UNCONFIRMED_DATA_DOWN: u8 = 0x60;
+/// MHDR, DevAddr, FCtrl, FCnt, FPort and MIC of a multicast frame, which
+/// carries no FOpts.
+const FRAME_OVERHEAD_LEN: usize = 13;
+
+/// A multicast group, with its session keys and frame counter window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct McGroup {
+    pub addr: u32,
+    pub app_s_key: [u8; 16],
+    pub nwk_s_key: [u8; 16],
+    /// Frame counters the group's frames are accepted for.
+    pub min_fcnt: u32,
+    pub max_fcnt: u32,
+    /// Frame counter of the last frame received, later frames must be above.
+    pub fcnt: Option<u32>,
+}
+
+/// A class C multicast session, from `McClassCSessionReq`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClassCSession {
+    pub group: u8,
+    /// Start of the session, in seconds since the GPS epoch.
+    pub session_time: u32,
+    pub timeout_s: u32,
+    pub frequency_hz: u32,
+    pub data_rate: u8,
+}
+
+/// Channel of the class C session the radio listens on, see [`listen`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct McListen {
+    pub addr: u32,
+    pub frequency_hz: u32,
+    pub params: LoRaParams,
+}
+
+/// Session accepted by `McClassCSessionReq`, in seconds of uptime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Scheduled {
+    session: ClassCSession,
+    start_s: u32,
+    end_s: u32,
+}
+
+/// Command of the remote multicast setup package.
+///
+/// | CID    | Request              | Arguments                                       |
+/// |--------|----------------------|-------------------------------------------------|
+/// | `0x00` | PackageVersionReq    | -                                               |
+/// | `0x01` | McGroupStatusReq     | group mask (3:0)                                |
+/// | `0x02` | McGroupSetupReq      | id, McAddr, McKey_encrypted, min/max FCount     |
+/// | `0x03` | McGroupDeleteReq     | id (1:0)                                        |
+/// | `0x04` | McClassCSessionReq   | id, SessionTime, timeout (3:0), frequency, DR   |
+/// | `0x05` | McClassBSessionReq   | id, SessionTime, timeout, frequency, DR         |
+///
+/// Multi-byte values are little-endian, frequencies are in units of 100 Hz.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum McCommand {
+    PackageVersion,
+    GroupStatus {
+        mask: u8,
+    },
+    GroupSetup {
+        id: u8,
+        addr: u32,
+        key_encrypted: [u8; 16],
+        min_fcnt: u32,
+        max_fcnt: u32,
+    },
+    GroupDelete {
+        id: u8,
+    },
+    ClassCSession(ClassCSession),
+    ClassBSession {
+        id: u8,
+    },
+}
+
+fn u32_le(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+impl McCommand {
+    /// Parses the command at the start of `bytes`, returning it with the
+    /// number of bytes it used.
+    ///
+    /// # Errors
+    ///
+    /// * `Empty` - If `bytes` is empty.
+    /// * `UnknownOpcode` - If the CID is not defined.
+    /// * `Truncated` - If the payload ends before the command's arguments.
+    pub fn parse(bytes: &[u8]) -> Result<(Self, usize), CommandError> {
+        let (&id, args) = bytes.split_first().ok_or(CommandError::Empty)?;
+        let arg = |len: usize| args.get(..len).ok_or(CommandError::Truncated(id));
+        let (command, len) = match id {
+            cid::PACKAGE_VERSION => (McCommand::PackageVersion, 0),
+            cid::MC_GROUP_STATUS => {
+                let mask = arg(1)?[0] & 0x0F;
+                (McCommand::GroupStatus { mask }, 1)
+            }
+            cid::MC_GROUP_SETUP => {
+                let a = arg(29)?;
+                let mut key_encrypted = [0u8; 16];
+                key_encrypted.copy_from_slice(&a[5..21]);
+                let command = McCommand::GroupSetup {
+                    id: a[0] & 0x03,
+                    addr: u32_le(&a[1..5]),
+                    key_encrypted,
+                    min_fcnt: u32_le(&a[21..25]),
+                    max_fcnt: u32_le(&a[25..29]),
+                };
+                (command, 29)
+            }
+            cid::MC_GROUP_DELETE => {
+                let id = arg(1)?[0] & 0x03;
+                (McCommand::GroupDelete { id }, 1)
+            }
+            cid::MC_CLASS_C_SESSION => {
+                let a = arg(10)?;
+                let session = ClassCSession {
+                    group: a[0] & 0x03,
+                    session_time: u32_le(&a[1..5]),
+                    timeout_s: 1 << (a[5] & 0x0F),
+                    frequency_hz: u32::from_le_bytes([a[6], a[7], a[8], 0]) * 100,
+                    data_rate: a[9],
+                };
+                (McCommand::ClassCSession(session), 10)
+            }
+            cid::MC_CLASS_B_SESSION => {
+                let id = arg(10)?[0] & 0x03;
+                (McCommand::ClassBSession { id }, 10)
+            }
+            _ => return Err(CommandError::UnknownOpcode(id)),
+        };
+        Ok((command, 1 + len))
+    }
+}
+
+fn encrypt(key: &[u8; 16], block: &[u8; 16]) -> [u8; 16] {
+    let mut block = GenericArray::from(*block);
+    Aes128::new(&GenericArray::from(*key)).encrypt_block(&mut block);
+    block.into()
+}
+
+/// `aes128_encrypt(key, prefix | addr | pad16)`.
+fn derive(key: &[u8; 16], prefix: u8, addr: u32) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0] = prefix;
+    block[1..5].copy_from_slice(&addr.to_le_bytes());
+    encrypt(key, &block)
+}
+
+/// Block of a downlink to `addr` with frame counter `fcnt`: `B0` of the MIC
+/// with `prefix` 0x49, or `Ai` of the payload keystream with 0x01.
+fn downlink_block(prefix: u8, addr: u32, fcnt: u32, last: u8) -> [u8; 16] {
+    let mut block = [0u8; 16];
+    block[0] = prefix;
+    // Direction, 1 for downlinks.
+    block[5] = 1;
+    block[6..10].copy_from_slice(&addr.to_le_bytes());
+    block[10..14].copy_from_slice(&fcnt.to_le_bytes());
+    block[15] = last;
+    block
+}
+
+impl McGroup {
+    /// Checks and decrypts a data downlink `frame` to the group, returning
+    /// its FPort and payload.
+    ///
+    /// Returns `None` if the frame is not for the group, its frame counter
+    /// is outside the window or not above the last one, or its MIC is
+    /// wrong.
+    ///
+    /// | Offset | Size | Field                                     |
+    /// |--------|------|-------------------------------------------|
+    /// | 0      | 1    | MHDR, unconfirmed data down               |
+    /// | 1      | 4    | DevAddr, the McAddr                       |
+    /// | 5      | 1    | FCtrl, without FOpts                      |
+    /// | 6      | 2    | FCnt, low 16 bits                         |
+    /// | 8      | 1    | FPort, not 0                              |
+    /// | 9      | n    | FRMPayload, encrypted with the McAppSKey  |
+    /// | 9 + n  | 4    | MIC, CMAC with the McNetSKey              |
+    pub fn receive(&mut self, frame: &[u8]) -> Option<(u8, Vec<u8, MAX_FRAME_LEN>)> {
+        if frame.len() < FRAME_OVERHEAD_LEN
+            || frame[0] != UNCONFIRMED_DATA_DOWN
+            || u32::from_le_bytes([frame[1], frame[2], frame[3], frame[4]]) != self.addr
+            || frame[5] & 0x0F != 0
+            || frame[8] == MAC_PORT
+        {
+            return None;
+        }
+        let fcnt = self.full_fcnt(u16::from_le_bytes([frame[6], frame[7]]))?;
+        let (message, mic) = frame.split_at(frame.len() - 4);
+        let mut cmac = <Cmac<Aes128> as KeyInit>::new(&GenericArray::from(self.nwk_s_key));
+        cmac.update(&downlink_block(0x49, self.addr, fcnt, message.len() as u8));
+        cmac.update(message);
+        if cmac.finalize().into_bytes()[..4] != *mic {
+            return None;
+        }
+        self.fcnt = Some(fcnt);
+
+        let mut payload = Vec::new();
+        for (i, chunk) in message[9..].chunks(16).enumerate() {
+            let block = downlink_block(0x01, self.addr, fcnt, i as u8 + 1);
+            let keystream = encrypt(&self.app_s_key, &block);
+            for (byte, key) in chunk.iter().zip(keystream) {
+                // Cannot fail, the payload is shorter than the frame.
+                let _ = payload.push(byte ^ key);
+            }
+        }
+        Some((frame[8], payload))
+    }
+
+    /// 32-bit frame counter of a frame with the low bits `fcnt`, the closest
+    /// one above the last frame received, if it is in the window.
+    fn full_fcnt(&self, fcnt: u16) -> Option<u32> {
+        let next = match self.fcnt {
+            Some(last) => last.checked_add(1)?,
+            None => self.min_fcnt,
+        };
+        let mut full = (next & 0xFFFF_0000) | fcnt as u32;
+        if full < next {
+            full = full.checked_add(0x1_0000)?;
+        }
+        (full <= self.max_fcnt).then_some(full)
+    }
+}
+
+/// Remote Multicast Setup package (TS005 v1.0.0) for a LoRaWAN 1.0.x device.
+///
+/// The group keys are recovered with the McKEKey derived from the GenAppKey;
+/// without one every group is refused with `IDerror`, and the server sends
+/// the fragments of a firmware update to the device address instead.
+///
+/// The stack only accepts frames sent to the device address, so the radio
+/// hands the frames of the group in session over through
+/// [`MULTICAST_FRAME`] and they are checked with [`Multicast::receive`].
+/// During a class C session the device listens on the session's channel
+/// instead of RX2, in class C whatever class it operates in, and misses the
+/// unicast downlinks of class C until the session times out. One session is
+/// kept at a time, a new one replaces it.
+///
+/// Class B sessions are refused, as the device cannot operate in class B.
+pub struct Multicast {
+    /// McKEKey, which recovers the group keys sent by the server.
+    ke_key: Option<[u8; 16]>,
+    groups: [Option<McGroup>; MAX_GROUPS],
+    region: Region,
+    session: Option<Scheduled>,
+}
+
+impl Multicast {
+    /// Derives the key encryption key from `gen_app_key`, for sessions in
+    /// `region`.
+    pub fn new(gen_app_key: Option<&[u8; 16]>, region: Region) -> Self {
+        let ke_key = gen_app_key.map(|key| {
+            let root_key = encrypt(key, &[0; 16]);
+            encrypt(&root_key, &[0; 16])
+        });
+        Self {
+            ke_key,
+            groups: Default::default(),
+            region,
+            session: None,
+        }
+    }
+
+    pub fn group(&self, id: u8) -> Option<&McGroup> {
+        self.groups.get(id as usize)?.as_ref()
+    }
+
+    /// Handles a downlink of the package, appending the answers to `answer`.
+    ///
+    /// `now` is the time in seconds since the GPS epoch, `None` while the
+    /// clock is not synchronized; sessions then start at once. `uptime_s`
+    /// is the time since boot the session is scheduled in.
+    pub fn handle(&mut self, payload: &[u8], now: Option<u32>, uptime_s: u32, answer: &mut Answer) {
+        let mut bytes = payload;
+        while !bytes.is_empty() {
+            let command = match McCommand::parse(bytes) {
+                Ok((command, len)) => {
+                    bytes = &bytes[len..];
+                    command
+                }
+                Err(e) => {
+                    esp_println::println!("[FUOTA] {}", e);
+                    break;
+                }
+            };
+            match command {
+                McCommand::PackageVersion => {
+                    push_answer(
+                        answer,
+                        &[cid::PACKAGE_VERSION, PACKAGE_IDENTIFIER, PACKAGE_VERSION],
+                    );
+                }
+                McCommand::GroupStatus { mask } => self.status(mask, answer),
+                McCommand::GroupSetup {
+                    id,
+                    addr,
+                    key_encrypted,
+                    min_fcnt,
+                    max_fcnt,
+                } => {
+                    let Some(ke_key) = &self.ke_key else {
+                        esp_println::println!(
+                            "[FUOTA] Multicast group {} at {:08X} refused, no GenAppKey",
+                            id,
+                            addr
+                        );
+                        push_answer(answer, &[cid::MC_GROUP_SETUP, id | SETUP_ID_ERROR]);
+                        continue;
+                    };
+                    // The server encrypts the key with aes128_decrypt so the
+                    // device only needs the encryption direction.
+                    let key = encrypt(ke_key, &key_encrypted);
+                    self.groups[id as usize] = Some(McGroup {
+                        addr,
+                        app_s_key: derive(&key, 0x01, addr),
+                        nwk_s_key: derive(&key, 0x02, addr),
+                        min_fcnt,
+                        max_fcnt,
+                        fcnt: None,
+                    });
+                    esp_println::println!("[FUOTA] Multicast group {} at {:08X}", id, addr);
+                    push_answer(answer, &[cid::MC_GROUP_SETUP, id]);
+                }
+                McCommand::GroupDelete { id } => {
+                    let status = match self.groups[id as usize].take() {
+                        Some(_) => id,
+                        None => id | DELETE_GROUP_UNDEFINED,
+                    };
+                    if self.session.is_some_and(|s| s.session.group == id) {
+                        self.session = None;
+                    }
+                    push_answer(answer, &[cid::MC_GROUP_DELETE, status]);
+                }
+                McCommand::ClassCSession(session) => {
+                    let mut status = session.group;
+                    if self.groups[session.group as usize].is_none() {
+                        status |= SESSION_GROUP_UNDEFINED;
+                    }
+                    if session.frequency_hz == 0 {
+                        status |= SESSION_FREQ_ERROR;
+                    }
+                    if self.region.downlink_params(session.data_rate).is_none() {
+                        status |= SESSION_DR_ERROR;
+                    }
+                    if status != session.group {
+                        push_answer(answer, &[cid::MC_CLASS_C_SESSION, status]);
+                        continue;
+                    }
+                    let time_to_start = time_to_start(session.session_time, now);
+                    let time = time_to_start.to_le_bytes();
+                    push_answer(
+                        answer,
+                        &[cid::MC_CLASS_C_SESSION, status, time[0], time[1], time[2]],
+                    );
+                    let start_s = uptime_s.saturating_add(time_to_start);
+                    self.session = Some(Scheduled {
+                        session,
+                        start_s,
+                        end_s: start_s.saturating_add(session.timeout_s),
+                    });
+                    esp_println::println!(
+                        "[FUOTA] Class C session for group {} in {} s",
+                        session.group,
+                        time_to_start
+                    );
+                }
+                // The device cannot listen in ping slots, so the session's
+                // channel is refused along with an undefined group.
+                McCommand::ClassBSession { id } => {
+                    let mut status = id | SESSION_FREQ_ERROR | SESSION_DR_ERROR;
+                    if self.groups[id as usize].is_none() {
+                        status |= SESSION_GROUP_UNDEFINED;
+                    }
+                    push_answer(answer, &[cid::MC_CLASS_B_SESSION, status]);
+                }
+            }
+        }
+    }
+
+    fn status(&self, mask: u8, answer: &mut Answer) {
+        let total = self.groups.iter().filter(|group| group.is_some()).count() as u8;
+        let defined = (0..MAX_GROUPS as u8)
+            .filter(|id| mask & (1 << id) != 0 && self.groups[*id as usize].is_some());
+        let answered = defined.clone().fold(0, |acc, id| acc | (1 << id));
+        push_answer(answer, &[cid::MC_GROUP_STATUS, (total << 4) | answered]);
+        for id in defined {
+            if let Some(group) = &self.groups[id as usize] {
+                let addr = group.addr.to_le_bytes();
+                push_answer(answer, &[id, addr[0], addr[1], addr[2], addr[3]]);
+            }
+        }
+    }
+
+    /// Channel the radio listens on at `uptime_s`, if a session is running;
+    /// a session that timed out is dropped.
+    pub fn active(&mut self, uptime_s: u32) -> Option<McListen> {
+        let scheduled = self.session?;
+        if uptime_s >= scheduled.end_s {
+            esp_println::println!("[FUOTA] Class C session ended");
+            self.session = None;
+            return None;
+        }
+        if uptime_s < scheduled.start_s {
+            return None;
+        }
+        let session = scheduled.session;
+        Some(McListen {
+            addr: self.group(session.group)?.addr,
+            frequency_hz: session.frequency_hz,
+            params: self.region.downlink_params(session.data_rate)?,
+        })
+    }
+
+    /// Uptime in seconds at which the session starts or ends next.
+    pub fn next_change_s(&self, uptime_s: u32) -> Option<u32> {
+        let scheduled = self.session?;
+        Some(if uptime_s < scheduled.start_s {
+            scheduled.start_s
+        } else {
+            scheduled.end_s
+        })
+    }
+
+    /// Checks and decrypts a `frame` to the group in session, returning its
+    /// FPort and payload.
+    pub fn receive(&mut self, frame: &[u8]) -> Option<(u8, Vec<u8, MAX_FRAME_LEN>)> {
+        let group = self.session?.session.group;
+        self.groups[group as usize].as_mut()?.receive(frame)
+    }
+}
+
+/// Seconds until a session starting at `session_time`, 0 if it already
+/// started or the time is not known.
+pub fn time_to_start(session_time: u32, now: Option<u32>) -> u32 {
+    now.map_or(0, |now| session_time.saturating_sub(now))
+        .min(0x00FF_FFFF)
+}
+
+/// Channel the radio listens on instead of RX2 during a class C session.
+static LISTENING: Mutex<CriticalSectionRawMutex, Cell<Option<McListen>>> =
+    Mutex::new(Cell::new(None));
+
+/// Frames the radio received for the group in session.
+pub static MULTICAST_FRAME: Signal<CriticalSectionRawMutex, Vec<u8, MAX_FRAME_LEN>> = Signal::new();
+
+/// Makes the radio listen on the channel of a class C session, or on RX2
+/// again with `None`.
+pub fn listen(session: Option<McListen>) {
+    LISTENING.lock(|listening| listening.set(session));
+}
+
+/// Channel of the class C session the radio listens on, if there is one.
+pub fn listening() -> Option<McListen> {
+    LISTENING.lock(|listening| listening.get())
+}
+
+/// Hands a `frame` the radio received over to [`MULTICAST_FRAME`] if it is
+/// a data downlink to the group in session.
+pub fn offer(frame: &[u8]) {
+    let Some(session) = listening() else {
+        return;
+    };
+    if frame.len() >= FRAME_OVERHEAD_LEN
+        && frame[0] == UNCONFIRMED_DATA_DOWN
+        && frame[1..5] == session.addr.to_le_bytes()
+    {
+        if let Ok(frame) = Vec::from_slice(frame) {
+            MULTICAST_FRAME.signal(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let mut setup = std::vec![0x02, 0x01, 0x78, 0x56, 0x34, 0x12];
+        setup.extend_from_slice(&[0xAB; 16]);
+        setup.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF, 0, 0]);
+        let command = McCommand::GroupSetup {
+            id: 1,
+            addr: 0x1234_5678,
+            key_encrypted: [0xAB; 16],
+            min_fcnt: 0,
+            max_fcnt: 0xFFFF,
+        };
+        assert_eq!(McCommand::parse(&setup), Ok((command, 30)));
+
+        let session = [0x04, 0x01, 100, 0, 0, 0, 0x05, 0x18, 0x4F, 0x84, 0x03];
+        let command = McCommand::ClassCSession(ClassCSession {
+            group: 1,
+            session_time: 100,
+            timeout_s: 32,
+            frequency_hz: 867_100_000,
+            data_rate: 3,
+        });
+        assert_eq!(McCommand::parse(&session), Ok((command, 11)));
+        assert_eq!(
+            McCommand::parse(&setup[..29]),
+            Err(CommandError::Truncated(2))
+        );
+        assert_eq!(
+            McCommand::parse(&[0x06]),
+            Err(CommandError::UnknownOpcode(6))
+        );
+    }
+
+    const GEN_APP_KEY: [u8; 16] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E,
+        0x0F,
+    ];
+    const ADDR: u32 = 0x0102_0304;
+
+    /// `McGroupSetupReq` of group 1 at [`ADDR`], for frame counters
+    /// 0x10000-0x1FFFF.
+    fn setup() -> std::vec::Vec<u8> {
+        let mut setup = std::vec![0x02, 0x01, 0x04, 0x03, 0x02, 0x01];
+        setup.extend(0x10..0x20u8);
+        setup.extend_from_slice(&[0, 0, 1, 0, 0xFF, 0xFF, 1, 0]);
+        setup
+    }
+
+    /// Frame to group 1 with FCnt 0x10005, carrying "hello" on port 201.
+    const FRAME: [u8; 18] = [
+        0x60, 0x04, 0x03, 0x02, 0x01, 0x00, 0x05, 0x00, 0xC9, 0x05, 0x85, 0xE8, 0x79, 0x95, 0x35,
+        0x57, 0xFC, 0x18,
+    ];
+
+    fn multicast() -> Multicast {
+        let mut multicast = Multicast::new(Some(&GEN_APP_KEY), Region::EU868);
+        let mut answer = Answer::new();
+        multicast.handle(&setup(), None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x02, 0x01]);
+        multicast
+    }
+
+    #[test]
+    fn derives_group_keys() {
+        let multicast = multicast();
+        assert_eq!(
+            multicast.ke_key,
+            Some([
+                0x2C, 0x57, 0x8F, 0x79, 0x27, 0xA9, 0x49, 0xD3, 0xB5, 0x11, 0xAE, 0x8F, 0xB6, 0x91,
+                0x45, 0xC6
+            ])
+        );
+        let group = multicast.group(1).unwrap();
+        assert_eq!(group.addr, ADDR);
+        assert_eq!(
+            group.app_s_key,
+            [
+                0xE4, 0x37, 0x97, 0x11, 0x62, 0x84, 0xDB, 0x62, 0x90, 0x17, 0xD5, 0x48, 0x27, 0x4F,
+                0x5A, 0xAE
+            ]
+        );
+        assert_eq!(
+            group.nwk_s_key,
+            [
+                0x2C, 0x25, 0x1F, 0x03, 0xD7, 0x1B, 0x63, 0x20, 0x95, 0xB1, 0x17, 0x81, 0xF0, 0x94,
+                0xCE, 0x2B
+            ]
+        );
+        assert_eq!((group.min_fcnt, group.max_fcnt), (0x1_0000, 0x1_FFFF));
+    }
+
+    #[test]
+    fn refuses_groups_without_gen_app_key() {
+        let mut multicast = Multicast::new(None, Region::EU868);
+        let mut answer = Answer::new();
+        multicast.handle(&setup(), None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x02, 0x05]);
+        assert!(multicast.group(1).is_none());
+    }
+
+    #[test]
+    fn status_and_delete_round_trip() {
+        let mut multicast = multicast();
+        let mut answer = Answer::new();
+        multicast.handle(&[0x01, 0x0F], None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x01, 0x12, 0x01, 0x04, 0x03, 0x02, 0x01]);
+
+        // A group outside the mask is counted but not listed.
+        answer.clear();
+        multicast.handle(&[0x01, 0x01], None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x01, 0x10]);
+
+        answer.clear();
+        multicast.handle(&[0x03, 0x01, 0x03, 0x01, 0x01, 0x0F], None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x03, 0x01, 0x03, 0x05, 0x01, 0x00]);
+        assert!(multicast.group(1).is_none());
+    }
+
+    #[test]
+    fn schedules_class_c_session() {
+        let mut multicast = multicast();
+        let mut answer = Answer::new();
+        // Group 1 at GPS time 1000 for 32 s on 869.525 MHz at DR3.
+        let session = [0x04, 0x01, 0xE8, 0x03, 0, 0, 0x05, 0xD2, 0xAD, 0x84, 0x03];
+        multicast.handle(&session, Some(900), 50, &mut answer);
+        assert_eq!(&answer[..], &[0x04, 0x01, 100, 0, 0]);
+
+        assert_eq!(multicast.next_change_s(50), Some(150));
+        assert_eq!(multicast.active(149), None);
+        let listen = McListen {
+            addr: ADDR,
+            frequency_hz: 869_525_000,
+            params: Region::EU868.downlink_params(3).unwrap(),
+        };
+        assert_eq!(multicast.active(150), Some(listen));
+        assert_eq!(multicast.next_change_s(150), Some(182));
+        assert_eq!(multicast.active(182), None);
+        assert_eq!(multicast.next_change_s(182), None);
+
+        // Deleting the group ends its session.
+        multicast.handle(&session, None, 200, &mut answer);
+        assert_eq!(multicast.active(200), Some(listen));
+        multicast.handle(&[0x03, 0x01], None, 200, &mut answer);
+        assert_eq!(multicast.active(200), None);
+    }
+
+    #[test]
+    fn refuses_sessions() {
+        let mut multicast = multicast();
+        let mut answer = Answer::new();
+        // Class C session of undefined group 2 at DR9, on frequency 0.
+        let class_c = [0x04, 0x02, 100, 0, 0, 0, 0x05, 0, 0, 0, 0x09];
+        // Class B sessions of group 1 and of undefined group 2.
+        let class_b = [0x05, 0x01, 100, 0, 0, 0, 0x05, 0xD2, 0xAD, 0x84, 0x03];
+        let mut undefined = class_b;
+        undefined[1] = 0x02;
+        let requests = [class_c, class_b, undefined].concat();
+        multicast.handle(&requests, None, 0, &mut answer);
+        assert_eq!(&answer[..], &[0x04, 0x1E, 0x05, 0x0D, 0x05, 0x1E]);
+        assert_eq!(multicast.next_change_s(0), None);
+    }
+
+    #[test]
+    fn receives_frames_in_fcnt_window() {
+        let mut group = multicast().group(1).unwrap().clone();
+        let (port, payload) = group.receive(&FRAME).unwrap();
+        assert_eq!((port, &payload[..]), (201, &b"hello"[..]));
+        assert_eq!(group.fcnt, Some(0x1_0005));
+        // A replay is below the next frame counter.
+        assert_eq!(group.receive(&FRAME), None);
+
+        let mut group = multicast().group(1).unwrap().clone();
+        let mut tampered = FRAME;
+        tampered[9] ^= 1;
+        assert_eq!(group.receive(&tampered), None);
+        let mut other = FRAME;
+        other[1] = 0x05;
+        assert_eq!(group.receive(&other), None);
+        assert_eq!(group.fcnt, None);
+
+        // The window ends before the frame.
+        group.max_fcnt = 0x1_0004;
+        assert_eq!(group.receive(&FRAME), None);
+    }
+
+    #[test]
+    fn extends_fcnt() {
+        let mut group = multicast().group(1).unwrap().clone();
+        assert_eq!(group.full_fcnt(5), Some(0x1_0005));
+        group.fcnt = Some(0x1_FFF0);
+        assert_eq!(group.full_fcnt(0xFFF1), Some(0x1_FFF1));
+        assert_eq!(group.full_fcnt(0x0001), None);
+        group.max_fcnt = u32::MAX;
+        assert_eq!(group.full_fcnt(0x0001), Some(0x2_0001));
+        group.fcnt = Some(u32::MAX);
+        assert_eq!(group.full_fcnt(0), None);
+    }
+}
@@ -0,0 +1,1038 @@
+use embedded_storage::Storage;
+
+use super::{crc::crc32_update, kv::ERASED_SECTOR};
+
+/// Label of the ESP-IDF NVS partition.
+pub const NVS_PARTITION: &str = "nvs";
+/// Size of an NVS page.
+pub const PAGE_SIZE: usize = 4096;
+/// Maximum length of a key or namespace name, without the NUL terminator.
+pub const MAX_KEY_LEN: usize = 15;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const HEADER_SIZE: usize = 32;
+const BITMAP_OFFSET: usize = 32;
+const FIRST_ENTRY_OFFSET: usize = 64;
+const PAGE_VERSION: u8 = 0xFE;
+
+const PAGE_EMPTY: u32 = 0xFFFF_FFFF;
+const PAGE_ACTIVE: u32 = 0xFFFF_FFFE;
+const PAGE_FULL: u32 = 0xFFFF_FFFC;
+const PAGE_FREEING: u32 = 0xFFFF_FFF8;
+
+const ENTRY_EMPTY: u8 = 0b11;
+const ENTRY_WRITTEN: u8 = 0b10;
+const ENTRY_ERASED: u8 = 0b00;
+
+/// Namespace index of the entries that define namespaces.
+const NS_INDEX: u8 = 0;
+/// Chunk index of every entry that is not a blob data chunk.
+const CHUNK_ANY: u8 = 0xFF;
+/// Blob chunks alternate between these two ranges so the previous version
+/// stays readable until the new one is complete.
+const CHUNK_START_0: u8 = 0;
+const CHUNK_START_1: u8 = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ItemType {
+    U8 = 0x01,
+    I8 = 0x11,
+    U16 = 0x02,
+    I16 = 0x12,
+    U32 = 0x04,
+    I32 = 0x14,
+    U64 = 0x08,
+    I64 = 0x18,
+    Str = 0x21,
+    Blob = 0x41,
+    BlobData = 0x42,
+    BlobIndex = 0x48,
+}
+
+impl ItemType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0x01 => ItemType::U8,
+            0x11 => ItemType::I8,
+            0x02 => ItemType::U16,
+            0x12 => ItemType::I16,
+            0x04 => ItemType::U32,
+            0x14 => ItemType::I32,
+            0x08 => ItemType::U64,
+            0x18 => ItemType::I64,
+            0x21 => ItemType::Str,
+            0x41 => ItemType::Blob,
+            0x42 => ItemType::BlobData,
+            0x48 => ItemType::BlobIndex,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum NvsError {
+    Storage,
+    NoPages,
+    KeyTooLong,
+    TypeMismatch,
+    BufferTooSmall,
+    InvalidString,
+    DataCrc,
+    NoNamespaceSlot,
+    Full,
+    MissingChunk(u8),
+    BlobSize { expected: usize, found: usize },
+}
+
+impl core::fmt::Display for NvsError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            NvsError::Storage => write!(f, "Failed to access storage"),
+            NvsError::NoPages => write!(f, "Partition is smaller than an NVS page"),
+            NvsError::KeyTooLong => write!(f, "Key is longer than 15 bytes"),
+            NvsError::TypeMismatch => write!(f, "Stored value has a different type"),
+            NvsError::BufferTooSmall => write!(f, "Buffer too small for value"),
+            NvsError::InvalidString => write!(f, "Stored string is not valid UTF-8"),
+            NvsError::DataCrc => write!(f, "Stored data CRC mismatch"),
+            NvsError::NoNamespaceSlot => write!(f, "No free namespace index"),
+            NvsError::Full => write!(f, "No free NVS page"),
+            NvsError::MissingChunk(chunk) => write!(f, "Blob chunk {} is missing", chunk),
+            NvsError::BlobSize { expected, found } => write!(
+                f,
+                "Blob chunks hold {} bytes instead of {}",
+                found, expected
+            ),
+        }
+    }
+}
+
+/// Integer types that can be stored as primitive NVS entries.
+pub trait NvsInt: Sized {
+    const TYPE: ItemType;
+    fn to_data(&self) -> [u8; 8];
+    fn from_data(data: &[u8; 8]) -> Self;
+}
+
+macro_rules! impl_nvs_int {
+    ($($t:ty => $kind:ident),*) => {
+        $(
+            impl NvsInt for $t {
+                const TYPE: ItemType = ItemType::$kind;
+
+                fn to_data(&self) -> [u8; 8] {
+                    let mut data = [0xFF; 8];
+                    let bytes = self.to_le_bytes();
+                    data[..bytes.len()].copy_from_slice(&bytes);
+                    data
+                }
+
+                fn from_data(data: &[u8; 8]) -> Self {
+                    let mut bytes = [0u8; core::mem::size_of::<$t>()];
+                    bytes.copy_from_slice(&data[..core::mem::size_of::<$t>()]);
+                    <$t>::from_le_bytes(bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_nvs_int!(
+    u8 => U8, i8 => I8, u16 => U16, i16 => I16,
+    u32 => U32, i32 => I32, u64 => U64, i64 => I64
+);
+
+/// A single 32-byte NVS entry.
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 1    | Namespace index                         |
+/// | 1      | 1    | Type                                    |
+/// | 2      | 1    | Span (entries used, including this one) |
+/// | 3      | 1    | Chunk index                             |
+/// | 4      | 4    | CRC-32 of bytes 0..4 and 8..32          |
+/// | 8      | 16   | Key, NUL-terminated                     |
+/// | 24     | 8    | Data                                    |
+#[derive(Clone)]
+struct Entry([u8; ENTRY_SIZE]);
+
+impl Entry {
+    fn new(ns: u8, kind: ItemType, span: u8, chunk: u8, key: &str, data: [u8; 8]) -> Self {
+        let mut raw = [0xFFu8; ENTRY_SIZE];
+        raw[0] = ns;
+        raw[1] = kind as u8;
+        raw[2] = span;
+        raw[3] = chunk;
+        raw[8..24].fill(0);
+        raw[8..8 + key.len()].copy_from_slice(key.as_bytes());
+        raw[24..32].copy_from_slice(&data);
+        let crc = Self::crc(&raw);
+        raw[4..8].copy_from_slice(&crc.to_le_bytes());
+        Self(raw)
+    }
+
+    fn crc(raw: &[u8; ENTRY_SIZE]) -> u32 {
+        crc32_update(crc32_update(0xFFFF_FFFF, &raw[0..4]), &raw[8..32])
+    }
+
+    fn is_valid(&self) -> bool {
+        let stored = u32::from_le_bytes([self.0[4], self.0[5], self.0[6], self.0[7]]);
+        stored == Self::crc(&self.0)
+    }
+
+    fn ns(&self) -> u8 {
+        self.0[0]
+    }
+
+    fn kind(&self) -> Option<ItemType> {
+        ItemType::from_u8(self.0[1])
+    }
+
+    fn span(&self) -> usize {
+        (self.0[2] as usize).max(1)
+    }
+
+    fn chunk(&self) -> u8 {
+        self.0[3]
+    }
+
+    fn key_matches(&self, key: &str) -> bool {
+        let stored = &self.0[8..24];
+        let len = stored.iter().position(|b| *b == 0).unwrap_or(16);
+        &stored[..len] == key.as_bytes()
+    }
+
+    fn data(&self) -> [u8; 8] {
+        let mut data = [0u8; 8];
+        data.copy_from_slice(&self.0[24..32]);
+        data
+    }
+
+    /// Size of a variable-length value (string, blob or blob data).
+    fn var_size(&self) -> usize {
+        u16::from_le_bytes([self.0[24], self.0[25]]) as usize
+    }
+
+    /// CRC of a variable-length value.
+    fn var_crc(&self) -> u32 {
+        u32::from_le_bytes([self.0[28], self.0[29], self.0[30], self.0[31]])
+    }
+}
+
+/// Location of an entry in the partition.
+#[derive(Clone, Copy)]
+struct Slot {
+    page: u32,
+    index: usize,
+}
+
+/// Summary of the page states of the partition.
+struct PageScan {
+    active: Option<u32>,
+    freeing: Option<u32>,
+    empty: Option<u32>,
+    empty_count: usize,
+    next_sequence: u32,
+}
+
+/// Reader and writer for the ESP-IDF non-volatile storage format.
+///
+/// Values written by `nvs_partition_gen.py` or by ESP-IDF firmware can be read,
+/// and values written here are readable by ESP-IDF. New entries are appended to
+/// the active page and the previous entry of the key is marked erased, so only
+/// `1 -> 0` bit transitions are needed until a page has to be reclaimed.
+pub struct Nvs<S> {
+    storage: S,
+    pages: u32,
+}
+
+impl<S: Storage> Nvs<S> {
+    pub fn new(storage: S) -> Result<Self, NvsError> {
+        let pages = (storage.capacity() / PAGE_SIZE) as u32;
+        if pages == 0 {
+            return Err(NvsError::NoPages);
+        }
+        Ok(Self { storage, pages })
+    }
+
+    /// Releases the underlying storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    /// Reads an integer value.
+    pub fn get_int<T: NvsInt>(
+        &mut self,
+        namespace: &str,
+        key: &str,
+    ) -> Result<Option<T>, NvsError> {
+        let Some(ns) = self.namespace(namespace)? else {
+            return Ok(None);
+        };
+        match self.find(ns, key, None, CHUNK_ANY)? {
+            Some((_, entry)) if entry.kind() == Some(T::TYPE) => {
+                Ok(Some(T::from_data(&entry.data())))
+            }
+            Some(_) => Err(NvsError::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a string value into `buffer`, returning the string slice.
+    pub fn get_str<'b>(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        buffer: &'b mut [u8],
+    ) -> Result<Option<&'b str>, NvsError> {
+        let Some(ns) = self.namespace(namespace)? else {
+            return Ok(None);
+        };
+        let Some((slot, entry)) = self.find(ns, key, None, CHUNK_ANY)? else {
+            return Ok(None);
+        };
+        if entry.kind() != Some(ItemType::Str) {
+            return Err(NvsError::TypeMismatch);
+        }
+        let len = self.read_var(slot, &entry, buffer)?;
+        // Strings are stored with their NUL terminator.
+        let len = buffer[..len].iter().position(|b| *b == 0).unwrap_or(len);
+        core::str::from_utf8(&buffer[..len])
+            .map(Some)
+            .map_err(|_| NvsError::InvalidString)
+    }
+
+    /// Reads a blob value into `buffer`, returning its length.
+    ///
+    /// Both the single-entry blobs of NVS version 1 and the chunked blobs of
+    /// version 2 are supported.
+    ///
+    /// # Errors
+    ///
+    /// * `BufferTooSmall` - If the blob does not fit in `buffer`.
+    /// * `MissingChunk` - If a chunk listed by the blob index is not stored.
+    /// * `BlobSize` - If the chunks do not add up to the size of the index.
+    /// * `DataCrc` - If a chunk fails its checksum.
+    pub fn get_blob(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>, NvsError> {
+        let Some(ns) = self.namespace(namespace)? else {
+            return Ok(None);
+        };
+        if let Some((_, index)) = self.find(ns, key, Some(ItemType::BlobIndex), CHUNK_ANY)? {
+            let data = index.data();
+            let size = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let (count, start) = (data[4], data[5]);
+            if size > buffer.len() {
+                return Err(NvsError::BufferTooSmall);
+            }
+            let mut offset = 0;
+            for chunk in start..start.saturating_add(count) {
+                let Some((slot, entry)) = self.find(ns, key, Some(ItemType::BlobData), chunk)?
+                else {
+                    return Err(NvsError::MissingChunk(chunk));
+                };
+                offset += self.read_var(slot, &entry, &mut buffer[offset..size])?;
+            }
+            if offset != size {
+                return Err(NvsError::BlobSize {
+                    expected: size,
+                    found: offset,
+                });
+            }
+            return Ok(Some(offset));
+        }
+        match self.find(ns, key, Some(ItemType::Blob), CHUNK_ANY)? {
+            Some((slot, entry)) => self.read_var(slot, &entry, buffer).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes an integer value.
+    pub fn set_int<T: NvsInt>(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        value: T,
+    ) -> Result<(), NvsError> {
+        check_key(key)?;
+        let ns = self.create_namespace(namespace)?;
+        let old = self.find(ns, key, None, CHUNK_ANY)?;
+        self.append(
+            Entry::new(ns, T::TYPE, 1, CHUNK_ANY, key, value.to_data()),
+            &[],
+            &[],
+        )?;
+        self.erase_found(old)
+    }
+
+    /// Writes a string value.
+    pub fn set_str(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), NvsError> {
+        check_key(key)?;
+        let ns = self.create_namespace(namespace)?;
+        let old = self.find(ns, key, None, CHUNK_ANY)?;
+        self.append_var(ns, ItemType::Str, CHUNK_ANY, key, value.as_bytes(), true)?;
+        self.erase_found(old)
+    }
+
+    /// Writes a blob value as a single version 2 chunk.
+    pub fn set_blob(&mut self, namespace: &str, key: &str, value: &[u8]) -> Result<(), NvsError> {
+        check_key(key)?;
+        let ns = self.create_namespace(namespace)?;
+        let old = self.find(ns, key, Some(ItemType::BlobIndex), CHUNK_ANY)?;
+        let start = match &old {
+            Some((_, entry)) if entry.data()[5] == CHUNK_START_0 => CHUNK_START_1,
+            _ => CHUNK_START_0,
+        };
+
+        self.append_var(ns, ItemType::BlobData, start, key, value, false)?;
+        let mut data = [0xFFu8; 8];
+        data[0..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        data[4] = 1;
+        data[5] = start;
+        self.append(
+            Entry::new(ns, ItemType::BlobIndex, 1, CHUNK_ANY, key, data),
+            &[],
+            &[],
+        )?;
+
+        if let Some((_, entry)) = &old {
+            let data = entry.data();
+            for chunk in data[5]..data[5].saturating_add(data[4]) {
+                let found = self.find(ns, key, Some(ItemType::BlobData), chunk)?;
+                self.erase_found(found)?;
+            }
+        }
+        self.erase_found(old)?;
+        // A blob written in the version 1 format is superseded as well.
+        let legacy = self.find(ns, key, Some(ItemType::Blob), CHUNK_ANY)?;
+        self.erase_found(legacy)
+    }
+
+    /// Erases a key of any type.
+    pub fn erase_key(&mut self, namespace: &str, key: &str) -> Result<(), NvsError> {
+        let Some(ns) = self.namespace(namespace)? else {
+            return Ok(());
+        };
+        while let Some(found) = self.find(ns, key, None, CHUNK_ANY)? {
+            self.erase_found(Some(found))?;
+        }
+        Ok(())
+    }
+
+    /// Looks up the index of a namespace.
+    fn namespace(&mut self, name: &str) -> Result<Option<u8>, NvsError> {
+        Ok(self
+            .find(NS_INDEX, name, Some(ItemType::U8), CHUNK_ANY)?
+            .map(|(_, entry)| entry.data()[0]))
+    }
+
+    /// Looks up the index of a namespace, creating it if needed.
+    fn create_namespace(&mut self, name: &str) -> Result<u8, NvsError> {
+        check_key(name)?;
+        if let Some(ns) = self.namespace(name)? {
+            return Ok(ns);
+        }
+
+        let mut highest = 0u8;
+        self.for_each_entry(|_, entry| {
+            if entry.ns() == NS_INDEX && entry.kind() == Some(ItemType::U8) {
+                highest = highest.max(entry.data()[0]);
+            }
+            false
+        })?;
+        if highest == 0xFE {
+            return Err(NvsError::NoNamespaceSlot);
+        }
+
+        let ns = highest + 1;
+        let mut data = [0xFFu8; 8];
+        data[0] = ns;
+        self.append(
+            Entry::new(NS_INDEX, ItemType::U8, 1, CHUNK_ANY, name, data),
+            &[],
+            &[],
+        )?;
+        Ok(ns)
+    }
+
+    /// Finds the latest valid entry matching the namespace, key, type and chunk.
+    ///
+    /// Passing `None` as the type matches any value entry except blob data.
+    fn find(
+        &mut self,
+        ns: u8,
+        key: &str,
+        kind: Option<ItemType>,
+        chunk: u8,
+    ) -> Result<Option<(Slot, Entry)>, NvsError> {
+        let mut found = None;
+        self.for_each_entry(|slot, entry| {
+            let kind_matches = match kind {
+                Some(kind) => entry.kind() == Some(kind),
+                None => entry.kind().is_some() && entry.kind() != Some(ItemType::BlobData),
+            };
+            if entry.ns() == ns && kind_matches && entry.chunk() == chunk && entry.key_matches(key)
+            {
+                found = Some((slot, entry.clone()));
+            }
+            false
+        })?;
+        Ok(found)
+    }
+
+    /// Visits every written, CRC-valid item header, in page sequence order.
+    ///
+    /// The visitor returns `true` to stop the iteration early.
+    fn for_each_entry<F>(&mut self, mut visit: F) -> Result<(), NvsError>
+    where
+        F: FnMut(Slot, &Entry) -> bool,
+    {
+        let mut last_sequence = None;
+        while let Some((page, sequence)) = self.next_page(last_sequence)? {
+            last_sequence = Some(sequence);
+            let bitmap = self.read_bitmap(page)?;
+            let mut index = 0;
+            while index < ENTRIES_PER_PAGE {
+                if entry_state(&bitmap, index) != ENTRY_WRITTEN {
+                    index += 1;
+                    continue;
+                }
+                let entry = self.read_entry(page, index)?;
+                if !entry.is_valid() {
+                    index += 1;
+                    continue;
+                }
+                if visit(Slot { page, index }, &entry) {
+                    return Ok(());
+                }
+                index += entry.span();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the in-use page with the lowest sequence number above `after`.
+    fn next_page(&mut self, after: Option<u32>) -> Result<Option<(u32, u32)>, NvsError> {
+        let mut next: Option<(u32, u32)> = None;
+        for page in 0..self.pages {
+            let Some((state, sequence)) = self.read_page_header(page)? else {
+                continue;
+            };
+            if !matches!(state, PAGE_ACTIVE | PAGE_FULL | PAGE_FREEING) {
+                continue;
+            }
+            if after.is_some_and(|after| sequence <= after) {
+                continue;
+            }
+            if next.is_none_or(|(_, best)| sequence < best) {
+                next = Some((page, sequence));
+            }
+        }
+        Ok(next)
+    }
+
+    /// Reads the state and sequence number of a page with a valid header.
+    fn read_page_header(&mut self, page: u32) -> Result<Option<(u32, u32)>, NvsError> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.read(page, 0, &mut header)?;
+        let state = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        if state == PAGE_EMPTY {
+            return Ok(Some((state, 0)));
+        }
+        let stored = u32::from_le_bytes([header[28], header[29], header[30], header[31]]);
+        if crc32_update(0xFFFF_FFFF, &header[4..28]) != stored {
+            return Ok(None);
+        }
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok(Some((state, sequence)))
+    }
+
+    /// Reads the value of a string or blob entry and checks its CRC.
+    fn read_var(
+        &mut self,
+        slot: Slot,
+        entry: &Entry,
+        buffer: &mut [u8],
+    ) -> Result<usize, NvsError> {
+        let size = entry.var_size();
+        let buffer = buffer.get_mut(..size).ok_or(NvsError::BufferTooSmall)?;
+        let mut offset = 0;
+        let mut index = slot.index + 1;
+        while offset < size {
+            let data = self.read_entry(slot.page, index)?;
+            let len = (size - offset).min(ENTRY_SIZE);
+            buffer[offset..offset + len].copy_from_slice(&data.0[..len]);
+            offset += len;
+            index += 1;
+        }
+        if crc32_update(0xFFFF_FFFF, buffer) != entry.var_crc() {
+            return Err(NvsError::DataCrc);
+        }
+        Ok(size)
+    }
+
+    /// Appends a string or blob data entry followed by its data entries.
+    fn append_var(
+        &mut self,
+        ns: u8,
+        kind: ItemType,
+        chunk: u8,
+        key: &str,
+        value: &[u8],
+        nul_terminated: bool,
+    ) -> Result<(), NvsError> {
+        let terminator: &[u8] = if nul_terminated { &[0] } else { &[] };
+        let size = value.len() + terminator.len();
+        if 1 + size.div_ceil(ENTRY_SIZE) > ENTRIES_PER_PAGE {
+            return Err(NvsError::Full);
+        }
+
+        let mut header = [0xFFu8; 8];
+        header[0..2].copy_from_slice(&(size as u16).to_le_bytes());
+        let crc = crc32_update(crc32_update(0xFFFF_FFFF, value), terminator);
+        header[4..8].copy_from_slice(&crc.to_le_bytes());
+        let span = 1 + size.div_ceil(ENTRY_SIZE);
+        let entry = Entry::new(ns, kind, span as u8, chunk, key, header);
+        self.append(entry, value, terminator)
+    }
+
+    /// Appends an item header followed by `data` and `tail`, padded to whole
+    /// entries.
+    fn append(&mut self, entry: Entry, data: &[u8], tail: &[u8]) -> Result<(), NvsError> {
+        let size = data.len() + tail.len();
+        let span = 1 + size.div_ceil(ENTRY_SIZE);
+        let slot = self.free_slot(span)?;
+
+        self.write(slot.page, entry_offset(slot.index), &entry.0)?;
+        for i in 0..span - 1 {
+            let mut raw = [0xFFu8; ENTRY_SIZE];
+            for (j, byte) in raw.iter_mut().enumerate() {
+                let at = i * ENTRY_SIZE + j;
+                if at < data.len() {
+                    *byte = data[at];
+                } else if at < size {
+                    *byte = tail[at - data.len()];
+                }
+            }
+            self.write(slot.page, entry_offset(slot.index + 1 + i), &raw)?;
+        }
+        for index in slot.index..slot.index + span {
+            self.set_entry_state(slot.page, index, ENTRY_WRITTEN)?;
+        }
+        Ok(())
+    }
+
+    /// Finds room for `span` consecutive entries, moving to a new page if the
+    /// active one is full.
+    ///
+    /// One empty page is kept in reserve: when it is the last one left, the
+    /// full page with the most erased entries is reclaimed into it instead.
+    fn free_slot(&mut self, span: usize) -> Result<Slot, NvsError> {
+        loop {
+            let pages = self.scan_pages()?;
+            if let Some(page) = pages.freeing {
+                self.reclaim(page)?;
+                continue;
+            }
+
+            if let Some(page) = pages.active {
+                let index = self.next_free_index(page)?;
+                if index + span <= ENTRIES_PER_PAGE {
+                    return Ok(Slot { page, index });
+                }
+                self.write(page, 0, &PAGE_FULL.to_le_bytes())?;
+            }
+
+            let victim = match pages.empty_count {
+                0 => return Err(NvsError::Full),
+                1 => self.reclaim_candidate()?,
+                _ => None,
+            };
+            match victim {
+                Some(page) => self.write(page, 0, &PAGE_FREEING.to_le_bytes())?,
+                None => {
+                    let page = pages.empty.ok_or(NvsError::Full)?;
+                    self.open_page(page, pages.next_sequence)?;
+                    return Ok(Slot { page, index: 0 });
+                }
+            }
+        }
+    }
+
+    fn scan_pages(&mut self) -> Result<PageScan, NvsError> {
+        let mut scan = PageScan {
+            active: None,
+            freeing: None,
+            empty: None,
+            empty_count: 0,
+            next_sequence: 0,
+        };
+        let mut highest = None;
+        for page in 0..self.pages {
+            match self.read_page_header(page)? {
+                // A page whose header failed its CRC holds nothing readable,
+                // typically a page opened by a write cut short, so it is
+                // reused like an empty one.
+                Some((PAGE_EMPTY, _)) | None => {
+                    scan.empty.get_or_insert(page);
+                    scan.empty_count += 1;
+                }
+                Some((state, sequence)) => {
+                    if state == PAGE_FREEING {
+                        scan.freeing = Some(page);
+                    }
+                    if highest.is_none_or(|h| sequence > h) {
+                        highest = Some(sequence);
+                        scan.active = (state == PAGE_ACTIVE).then_some(page);
+                    }
+                }
+            }
+        }
+        scan.next_sequence = highest.map_or(0, |h| h.wrapping_add(1));
+        Ok(scan)
+    }
+
+    /// Erases `page` and initializes it as the active page.
+    fn open_page(&mut self, page: u32, sequence: u32) -> Result<(), NvsError> {
+        self.write(page, 0, &ERASED_SECTOR[..PAGE_SIZE])?;
+        let mut header = [0xFFu8; HEADER_SIZE];
+        header[0..4].copy_from_slice(&PAGE_ACTIVE.to_le_bytes());
+        header[4..8].copy_from_slice(&sequence.to_le_bytes());
+        header[8] = PAGE_VERSION;
+        let crc = crc32_update(0xFFFF_FFFF, &header[4..28]);
+        header[28..32].copy_from_slice(&crc.to_le_bytes());
+        self.write(page, 0, &header)
+    }
+
+    /// Full page with the most erased entries, if any has erased entries.
+    fn reclaim_candidate(&mut self) -> Result<Option<u32>, NvsError> {
+        let mut best = None;
+        let mut best_erased = 0;
+        for page in 0..self.pages {
+            if !matches!(self.read_page_header(page)?, Some((PAGE_FULL, _))) {
+                continue;
+            }
+            let bitmap = self.read_bitmap(page)?;
+            let erased = (0..ENTRIES_PER_PAGE)
+                .filter(|index| entry_state(&bitmap, *index) == ENTRY_ERASED)
+                .count();
+            if erased > best_erased {
+                best = Some(page);
+                best_erased = erased;
+            }
+        }
+        Ok(best)
+    }
+
+    /// Moves the live entries of a page marked as freeing to the active page
+    /// and erases it.
+    ///
+    /// Entries already copied by an interrupted reclaim are superseded by
+    /// their copies and skipped, so this can safely be resumed after a reset.
+    fn reclaim(&mut self, victim: u32) -> Result<(), NvsError> {
+        let pages = self.scan_pages()?;
+        let target = match pages.active {
+            Some(page) if page != victim => page,
+            _ => {
+                let page = pages.empty.ok_or(NvsError::Full)?;
+                self.open_page(page, pages.next_sequence)?;
+                page
+            }
+        };
+
+        let bitmap = self.read_bitmap(victim)?;
+        let mut index = 0;
+        let mut key = [0u8; MAX_KEY_LEN];
+        while index < ENTRIES_PER_PAGE {
+            let entry = self.read_entry(victim, index)?;
+            if entry_state(&bitmap, index) != ENTRY_WRITTEN || !entry.is_valid() {
+                index += 1;
+                continue;
+            }
+            let span = entry.span();
+            let key_len = entry.0[8..8 + MAX_KEY_LEN]
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(MAX_KEY_LEN);
+            key[..key_len].copy_from_slice(&entry.0[8..8 + key_len]);
+            let key = core::str::from_utf8(&key[..key_len]).map_err(|_| NvsError::InvalidString)?;
+
+            let latest = self.find(entry.ns(), key, entry.kind(), entry.chunk())?;
+            if latest.is_some_and(|(slot, _)| slot.page == victim && slot.index == index) {
+                let slot = Slot {
+                    page: target,
+                    index: self.next_free_index(target)?,
+                };
+                if slot.index + span > ENTRIES_PER_PAGE {
+                    return Err(NvsError::Full);
+                }
+                for i in 0..span {
+                    let raw = self.read_entry(victim, index + i)?;
+                    self.write(target, entry_offset(slot.index + i), &raw.0)?;
+                }
+                for i in 0..span {
+                    self.set_entry_state(target, slot.index + i, ENTRY_WRITTEN)?;
+                }
+            }
+            index += span;
+        }
+
+        self.write(victim, 0, &ERASED_SECTOR[..PAGE_SIZE])
+    }
+
+    /// Index after the last used entry of a page.
+    ///
+    /// Entries programmed by a write that was interrupted before its state
+    /// was updated are counted as used, so they are never overwritten.
+    fn next_free_index(&mut self, page: u32) -> Result<usize, NvsError> {
+        let bitmap = self.read_bitmap(page)?;
+        let mut next = 0;
+        for index in 0..ENTRIES_PER_PAGE {
+            let used = entry_state(&bitmap, index) != ENTRY_EMPTY
+                || self.read_entry(page, index)?.0.iter().any(|b| *b != 0xFF);
+            if used {
+                next = index + 1;
+            }
+        }
+        Ok(next)
+    }
+
+    /// Marks a previously found entry and its data entries as erased.
+    fn erase_found(&mut self, found: Option<(Slot, Entry)>) -> Result<(), NvsError> {
+        let Some((slot, entry)) = found else {
+            return Ok(());
+        };
+        for index in slot.index..(slot.index + entry.span()).min(ENTRIES_PER_PAGE) {
+            self.set_entry_state(slot.page, index, ENTRY_ERASED)?;
+        }
+        Ok(())
+    }
+
+    fn read_bitmap(&mut self, page: u32) -> Result<[u8; 32], NvsError> {
+        let mut bitmap = [0u8; 32];
+        self.read(page, BITMAP_OFFSET, &mut bitmap)?;
+        Ok(bitmap)
+    }
+
+    /// Updates the two state bits of an entry, rewriting only the word that
+    /// holds them.
+    fn set_entry_state(&mut self, page: u32, index: usize, state: u8) -> Result<(), NvsError> {
+        let offset = BITMAP_OFFSET + (index / 16) * 4;
+        let mut word = [0u8; 4];
+        self.read(page, offset, &mut word)?;
+        let shift = (index % 16) * 2;
+        let value = u32::from_le_bytes(word) & !(0b11 << shift) | ((state as u32) << shift);
+        self.write(page, offset, &value.to_le_bytes())
+    }
+
+    fn read_entry(&mut self, page: u32, index: usize) -> Result<Entry, NvsError> {
+        let mut raw = [0u8; ENTRY_SIZE];
+        self.read(page, entry_offset(index), &mut raw)?;
+        Ok(Entry(raw))
+    }
+
+    fn read(&mut self, page: u32, offset: usize, bytes: &mut [u8]) -> Result<(), NvsError> {
+        let address = page * PAGE_SIZE as u32 + offset as u32;
+        self.storage
+            .read(address, bytes)
+            .map_err(|_| NvsError::Storage)
+    }
+
+    fn write(&mut self, page: u32, offset: usize, bytes: &[u8]) -> Result<(), NvsError> {
+        let address = page * PAGE_SIZE as u32 + offset as u32;
+        self.storage
+            .write(address, bytes)
+            .map_err(|_| NvsError::Storage)
+    }
+}
+
+fn check_key(key: &str) -> Result<(), NvsError> {
+    if key.len() > MAX_KEY_LEN {
+        return Err(NvsError::KeyTooLong);
+    }
+    Ok(())
+}
+
+fn entry_offset(index: usize) -> usize {
+    FIRST_ENTRY_OFFSET + index * ENTRY_SIZE
+}
+
+fn entry_state(bitmap: &[u8; 32], index: usize) -> u8 {
+    (bitmap[index / 4] >> ((index % 4) * 2)) & 0b11
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_storage::MemStorage;
+
+    /// Image of the values in `testdata/nvs.csv`, built by hand in the
+    /// version 2 page layout over three pages; it was not written by
+    /// `nvs_partition_gen`. The "ca" blob is split into chunks of 48 and 32
+    /// bytes on the same page to exercise reassembly, where the tool only
+    /// splits blobs at page boundaries.
+    const NVS_BIN: &[u8] = include_bytes!("testdata/nvs.bin");
+    const PASSWORD: &str = "a-long-password-that-spans-more-than-one-entry!!";
+
+    fn fixture() -> Nvs<MemStorage> {
+        Nvs::new(MemStorage::from_image(NVS_BIN.to_vec())).unwrap()
+    }
+
+    /// Marks the blob data entry `chunk` of the image as erased.
+    fn erase_chunk(image: &mut [u8], chunk: u8) {
+        let index = (0..ENTRIES_PER_PAGE)
+            .find(|i| {
+                let entry = &image[FIRST_ENTRY_OFFSET + i * ENTRY_SIZE..];
+                entry[1] == ItemType::BlobData as u8 && entry[3] == chunk
+            })
+            .unwrap();
+        let bit = index * 2;
+        image[BITMAP_OFFSET + bit / 8] &= !(0b11 << (bit % 8));
+    }
+
+    #[test]
+    fn reads_fixture_image() {
+        let mut nvs = fixture();
+        let mut buffer = [0u8; 128];
+        assert_eq!(
+            nvs.get_str("wifi", "ssid", &mut buffer),
+            Ok(Some("FieldNet"))
+        );
+        assert_eq!(
+            nvs.get_str("wifi", "password", &mut buffer),
+            Ok(Some(PASSWORD))
+        );
+        assert_eq!(nvs.get_blob("lorawan", "appkey", &mut buffer), Ok(Some(16)));
+        assert_eq!(&buffer[..16], &(0..16).collect::<std::vec::Vec<u8>>()[..]);
+        assert_eq!(nvs.get_int::<u32>("lorawan", "port"), Ok(Some(7)));
+        assert_eq!(nvs.get_int::<i8>("lorawan", "offset"), Ok(Some(-3)));
+        assert_eq!(nvs.get_blob("certs", "ca", &mut buffer), Ok(Some(80)));
+        assert_eq!(&buffer[..80], &(0..80).collect::<std::vec::Vec<u8>>()[..]);
+    }
+
+    #[test]
+    fn lookup_errors() {
+        let mut nvs = fixture();
+        let mut buffer = [0u8; 8];
+        assert_eq!(
+            nvs.get_int::<u8>("lorawan", "port"),
+            Err(NvsError::TypeMismatch)
+        );
+        assert_eq!(nvs.get_int::<u32>("missing", "port"), Ok(None));
+        assert_eq!(nvs.get_int::<u32>("wifi", "port"), Ok(None));
+        assert_eq!(
+            nvs.get_str("wifi", "password", &mut buffer),
+            Err(NvsError::BufferTooSmall)
+        );
+        assert_eq!(
+            nvs.get_blob("certs", "ca", &mut buffer),
+            Err(NvsError::BufferTooSmall)
+        );
+        assert_eq!(
+            nvs.set_int("wifi", "a-key-that-is-too-long", 1u8),
+            Err(NvsError::KeyTooLong)
+        );
+    }
+
+    #[test]
+    fn missing_chunk_is_an_error() {
+        let mut image = NVS_BIN.to_vec();
+        erase_chunk(&mut image, 1);
+        let mut nvs = Nvs::new(MemStorage::from_image(image)).unwrap();
+        let mut buffer = [0u8; 128];
+        assert_eq!(
+            nvs.get_blob("certs", "ca", &mut buffer),
+            Err(NvsError::MissingChunk(1))
+        );
+        // The other blobs are unaffected.
+        assert_eq!(nvs.get_blob("lorawan", "appkey", &mut buffer), Ok(Some(16)));
+    }
+
+    #[test]
+    fn corrupted_data_is_an_error() {
+        let mut image = NVS_BIN.to_vec();
+        let at = image
+            .windows(PASSWORD.len())
+            .position(|w| w == PASSWORD.as_bytes())
+            .unwrap();
+        image[at] ^= 0x01;
+        let mut nvs = Nvs::new(MemStorage::from_image(image)).unwrap();
+        let mut buffer = [0u8; 128];
+        assert_eq!(
+            nvs.get_str("wifi", "password", &mut buffer),
+            Err(NvsError::DataCrc)
+        );
+    }
+
+    #[test]
+    fn writes_over_fixture_image() {
+        let mut nvs = fixture();
+        let mut buffer = [0u8; 128];
+        nvs.set_str("wifi", "ssid", "Other").unwrap();
+        nvs.set_blob("lorawan", "appkey", &[9; 40]).unwrap();
+        nvs.set_blob("certs", "ca", &[3; 10]).unwrap();
+        for i in 0..3000u32 {
+            nvs.set_int("cfg", "n", i).unwrap();
+        }
+        nvs.erase_key("lorawan", "port").unwrap();
+
+        let mut nvs = Nvs::new(nvs.into_inner()).unwrap();
+        assert_eq!(nvs.get_str("wifi", "ssid", &mut buffer), Ok(Some("Other")));
+        assert_eq!(
+            nvs.get_str("wifi", "password", &mut buffer),
+            Ok(Some(PASSWORD))
+        );
+        assert_eq!(nvs.get_blob("lorawan", "appkey", &mut buffer), Ok(Some(40)));
+        assert_eq!(&buffer[..40], &[9; 40]);
+        assert_eq!(nvs.get_blob("certs", "ca", &mut buffer), Ok(Some(10)));
+        assert_eq!(nvs.get_int::<u32>("cfg", "n"), Ok(Some(2999)));
+        assert_eq!(nvs.get_int::<u32>("lorawan", "port"), Ok(None));
+        assert_eq!(nvs.get_int::<i8>("lorawan", "offset"), Ok(Some(-3)));
+    }
+
+    #[test]
+    fn power_cut_keeps_old_or_new_value() {
+        for cut in (0..10 * PAGE_SIZE).step_by(53) {
+            let mut nvs = Nvs::new(MemStorage::new(3 * PAGE_SIZE)).unwrap();
+            nvs.set_str("a", "keep", "value").unwrap();
+            let mut storage = nvs.into_inner();
+            storage.cut_after(cut);
+            let mut nvs = Nvs::new(storage).unwrap();
+            let mut last = None;
+            for i in 0..400u32 {
+                if nvs.set_int("a", "n", i).is_err() {
+                    break;
+                }
+                last = Some(i);
+            }
+
+            let mut storage = nvs.into_inner();
+            storage.restore();
+            let mut nvs = Nvs::new(storage).unwrap();
+            let mut buffer = [0u8; 16];
+            assert_eq!(
+                nvs.get_str("a", "keep", &mut buffer),
+                Ok(Some("value")),
+                "cut at {}",
+                cut
+            );
+            let value = nvs.get_int::<u32>("a", "n").unwrap();
+            if let Some(last) = last {
+                assert!(
+                    value == Some(last) || value == Some(last + 1),
+                    "cut at {}: {:?} after {}",
+                    cut,
+                    value,
+                    last
+                );
+            }
+            nvs.set_int("a", "n", 4242u32).unwrap();
+            assert_eq!(nvs.get_int::<u32>("a", "n"), Ok(Some(4242)));
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use embedded_storage::{ReadStorage, Storage};
+use sha2::{Digest, Sha256};
+
+use super::{
+    crc::crc32_update,
+    fragmentation::{FragSessionSetup, Fragmentation},
+};
+
+/// Label of the partition selecting the OTA slot the bootloader starts.
+pub const OTADATA_PARTITION: &str = "otadata";
+/// Labels of the application slots, in bootloader order.
+pub const OTA_SLOTS: [&str; 2] = ["ota_0", "ota_1"];
+/// Size of the trailer that follows the image in an update data block.
+pub const IMAGE_TRAILER_LEN: usize = 104;
+/// Ed25519 key that updates must be signed with, given in hex in the
+/// `FIRMWARE_UPDATE_KEY` environment variable at build time. Firmware built
+/// without it refuses all updates.
+pub const UPDATE_PUBLIC_KEY: Option<[u8; 32]> = match option_env!("FIRMWARE_UPDATE_KEY") {
+    Some(hex) => Some(parse_key(hex)),
+    None => None,
+};
+
+/// The two copies of the selection entry live in separate flash sectors.
+const OTADATA_SECTOR: u32 = 0x1000;
+const ENTRY_LEN: usize = 32;
+/// `ota_state` of an entry written without rollback support, which the
+/// bootloader does not check.
+const OTA_STATE_UNDEFINED: u32 = 0xFFFF_FFFF;
+const ESP_IMAGE_MAGIC: u8 = 0xE9;
+const TRAILER_MAGIC: [u8; 4] = *b"FWUP";
+
+const fn nibble(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        b'A'..=b'F' => c - b'A' + 10,
+        _ => panic!("FIRMWARE_UPDATE_KEY is not hex"),
+    }
+}
+
+/// Parses a 64-digit hex key, failing the build when it is malformed.
+const fn parse_key(hex: &str) -> [u8; 32] {
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 64, "FIRMWARE_UPDATE_KEY must be 32 bytes");
+    let mut key = [0u8; 32];
+    let mut i = 0;
+    while i < key.len() {
+        key[i] = nibble(hex[2 * i]) << 4 | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    key
+}
+
+#[derive(Debug, PartialEq)]
+pub enum OtaError {
+    Storage,
+    InvalidSlot(usize),
+    MissingTrailer,
+    Length(u32),
+    NotAnImage,
+    HashMismatch,
+    NoPublicKey,
+    BadSignature,
+}
+
+impl core::fmt::Display for OtaError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OtaError::Storage => write!(f, "Failed to access storage"),
+            OtaError::InvalidSlot(slot) => write!(f, "No OTA slot {}", slot),
+            OtaError::MissingTrailer => write!(f, "Update has no image trailer"),
+            OtaError::Length(len) => write!(f, "Image length {} does not match the update", len),
+            OtaError::NotAnImage => write!(f, "Update is not an ESP32 application image"),
+            OtaError::HashMismatch => write!(f, "Image SHA-256 does not match"),
+            OtaError::NoPublicKey => write!(f, "No update signing key built in"),
+            OtaError::BadSignature => write!(f, "Image signature does not verify"),
+        }
+    }
+}
+
+/// Selection entry of the ESP-IDF bootloader (`esp_ota_select_entry_t`).
+///
+/// | Offset | Size | Field                                  |
+/// |--------|------|----------------------------------------|
+/// | 0      | 4    | Sequence number (LE)                   |
+/// | 4      | 20   | Label, unused                          |
+/// | 24     | 4    | OTA state (LE)                         |
+/// | 28     | 4    | CRC-32 of the sequence number (LE)     |
+///
+/// The bootloader starts slot `(seq - 1) % 2` of the valid entry with the
+/// highest sequence number, and the first slot when neither is valid.
+fn entry_seq(entry: &[u8; ENTRY_LEN]) -> Option<u32> {
+    let seq = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let crc = u32::from_le_bytes([entry[28], entry[29], entry[30], entry[31]]);
+    (seq != u32::MAX && crc == crc32_update(u32::MAX, &entry[0..4])).then_some(seq)
+}
+
+fn encode_entry(seq: u32) -> [u8; ENTRY_LEN] {
+    let mut entry = [0xFF; ENTRY_LEN];
+    entry[0..4].copy_from_slice(&seq.to_le_bytes());
+    entry[24..28].copy_from_slice(&OTA_STATE_UNDEFINED.to_le_bytes());
+    let crc = crc32_update(u32::MAX, &entry[0..4]);
+    entry[28..32].copy_from_slice(&crc.to_le_bytes());
+    entry
+}
+
+/// The `otadata` partition, which tells the bootloader which OTA slot to
+/// start.
+pub struct OtaData<S> {
+    storage: S,
+}
+
+impl<S: Storage> OtaData<S> {
+    pub fn new(storage: S) -> Self {
+        Self { storage }
+    }
+
+    fn entries(&mut self) -> Result<[Option<u32>; 2], OtaError> {
+        let mut entries = [None; 2];
+        for (sector, seq) in entries.iter_mut().enumerate() {
+            let mut entry = [0u8; ENTRY_LEN];
+            self.storage
+                .read(sector as u32 * OTADATA_SECTOR, &mut entry)
+                .map_err(|_| OtaError::Storage)?;
+            *seq = entry_seq(&entry);
+        }
+        Ok(entries)
+    }
+
+    /// Slot the bootloader starts, which is the running one.
+    pub fn boot_slot(&mut self) -> Result<usize, OtaError> {
+        let seq = self.entries()?.into_iter().flatten().max();
+        Ok(seq.map_or(0, |seq| (seq as usize - 1) % OTA_SLOTS.len()))
+    }
+
+    /// Makes the bootloader start `slot` from the next reset.
+    ///
+    /// The new entry goes to the sector that does not hold the current one,
+    /// so a power loss while writing leaves the previous selection intact.
+    pub fn set_boot_slot(&mut self, slot: usize) -> Result<(), OtaError> {
+        if slot >= OTA_SLOTS.len() {
+            return Err(OtaError::InvalidSlot(slot));
+        }
+        let entries = self.entries()?;
+        let (sector, current) = match entries {
+            [Some(a), Some(b)] if a >= b => (1, a),
+            [Some(_), Some(b)] => (0, b),
+            [Some(a), None] => (1, a),
+            [None, Some(b)] => (0, b),
+            [None, None] => (0, 0),
+        };
+        let count = OTA_SLOTS.len() as u32;
+        let mut seq = current + 1;
+        while (seq - 1) % count != slot as u32 {
+            seq += 1;
+        }
+        self.storage
+            .write(sector * OTADATA_SECTOR, &encode_entry(seq))
+            .map_err(|_| OtaError::Storage)
+    }
+}
+
+/// Checks the firmware update of `len` bytes at the start of `storage`
+/// against the signing key `public_key`, returning the length of the
+/// application image.
+///
+/// The image is followed by a trailer:
+///
+/// | Offset | Size | Field                                  |
+/// |--------|------|----------------------------------------|
+/// | 0      | 4    | Magic `FWUP`                           |
+/// | 4      | 4    | Image length (LE)                      |
+/// | 8      | 32   | SHA-256 of the image                   |
+/// | 40     | 64   | Ed25519 signature of the SHA-256       |
+///
+/// The signature is over the 32 digest bytes, as made by
+/// `openssl pkeyutl -sign -rawin -inkey key.pem -in digest.bin`.
+///
+/// # Errors
+///
+/// * `MissingTrailer` - If the update is too short or the magic is wrong.
+/// * `Length` - If the image length does not match the update length.
+/// * `NotAnImage` - If the image does not start with the ESP image magic.
+/// * `HashMismatch` - If the image does not hash to the trailer's SHA-256.
+/// * `BadSignature` - If the key is invalid or did not sign the SHA-256.
+pub fn verify_image<S: ReadStorage>(
+    storage: &mut S,
+    len: usize,
+    public_key: &[u8; 32],
+) -> Result<u32, OtaError> {
+    let image_len = len
+        .checked_sub(IMAGE_TRAILER_LEN)
+        .ok_or(OtaError::MissingTrailer)?;
+    let mut trailer = [0u8; IMAGE_TRAILER_LEN];
+    storage
+        .read(image_len as u32, &mut trailer)
+        .map_err(|_| OtaError::Storage)?;
+    if trailer[0..4] != TRAILER_MAGIC {
+        return Err(OtaError::MissingTrailer);
+    }
+    let declared = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if declared as usize != image_len {
+        return Err(OtaError::Length(declared));
+    }
+    if image_len == 0 {
+        return Err(OtaError::NotAnImage);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut chunk = [0u8; 256];
+    let mut offset = 0;
+    while offset < image_len {
+        let n = chunk.len().min(image_len - offset);
+        storage
+            .read(offset as u32, &mut chunk[..n])
+            .map_err(|_| OtaError::Storage)?;
+        if offset == 0 && chunk[0] != ESP_IMAGE_MAGIC {
+            return Err(OtaError::NotAnImage);
+        }
+        hasher.update(&chunk[..n]);
+        offset += n;
+    }
+    let digest = hasher.finalize();
+    if digest[..] != trailer[8..40] {
+        return Err(OtaError::HashMismatch);
+    }
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| OtaError::BadSignature)?;
+    let mut signature = [0u8; 64];
+    signature.copy_from_slice(&trailer[40..104]);
+    key.verify_strict(&digest, &Signature::from_bytes(&signature))
+        .map_err(|_| OtaError::BadSignature)?;
+    Ok(declared)
+}
+
+/// A firmware update, reassembled into the OTA slot that is not running.
+pub struct FirmwareUpdate<S, D> {
+    pub fragmentation: Fragmentation<S>,
+    otadata: OtaData<D>,
+    target: usize,
+    public_key: [u8; 32],
+}
+
+impl<S: Storage, D: Storage> FirmwareUpdate<S, D> {
+    /// Receives updates signed with `public_key` into `slot`, the storage of
+    /// OTA slot `target`.
+    pub fn new(slot: S, otadata: OtaData<D>, target: usize, public_key: [u8; 32]) -> Self {
+        Self {
+            fragmentation: Fragmentation::new(slot),
+            otadata,
+            target,
+            public_key,
+        }
+    }
+
+    /// Verifies the data block of a completed session and has the bootloader
+    /// start it from the next reset, returning the image length.
+    ///
+    /// The boot selection is only changed once the whole image and its
+    /// signature checked out.
+    pub fn install(&mut self, session: &FragSessionSetup) -> Result<u32, OtaError> {
+        let storage = self.fragmentation.storage();
+        let image_len = verify_image(storage, session.data_len(), &self.public_key)?;
+        self.otadata.set_boot_slot(self.target)?;
+        Ok(image_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_storage::MemStorage;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const SIGNING_KEY: [u8; 32] = [0x42; 32];
+
+    /// An application image of `len` bytes followed by its signed trailer.
+    fn update(len: usize, key: &[u8; 32]) -> std::vec::Vec<u8> {
+        let mut data: std::vec::Vec<u8> = (0..len as u32).map(|i| (i * 31) as u8).collect();
+        data[0] = ESP_IMAGE_MAGIC;
+        let digest = Sha256::digest(&data);
+        let signature = SigningKey::from_bytes(key).sign(&digest);
+        data.extend_from_slice(&TRAILER_MAGIC);
+        data.extend_from_slice(&(len as u32).to_le_bytes());
+        data.extend_from_slice(&digest);
+        data.extend_from_slice(&signature.to_bytes());
+        data
+    }
+
+    fn public_key() -> [u8; 32] {
+        SigningKey::from_bytes(&SIGNING_KEY)
+            .verifying_key()
+            .to_bytes()
+    }
+
+    #[test]
+    fn parses_build_keys() {
+        let hex = "00112233445566778899aabbccddeeff00112233445566778899AABBCCDDEEFF";
+        let key = parse_key(hex);
+        assert_eq!(key[..4], [0x00, 0x11, 0x22, 0x33]);
+        assert_eq!(key[28..], [0xCC, 0xDD, 0xEE, 0xFF]);
+    }
+
+    #[test]
+    fn selects_boot_slots() {
+        let mut otadata = OtaData::new(MemStorage::new(2 * OTADATA_SECTOR as usize));
+        assert_eq!(otadata.boot_slot(), Ok(0));
+        for slot in [1, 0, 1] {
+            otadata.set_boot_slot(slot).unwrap();
+            assert_eq!(otadata.boot_slot(), Ok(slot));
+        }
+        assert_eq!(otadata.set_boot_slot(2), Err(OtaError::InvalidSlot(2)));
+    }
+
+    #[test]
+    fn verifies_signed_images() {
+        let data = update(5000, &SIGNING_KEY);
+        let len = data.len();
+        let mut storage = MemStorage::from_image(data);
+        let key = public_key();
+        assert_eq!(verify_image(&mut storage, len, &key), Ok(5000));
+        assert_eq!(
+            verify_image(&mut storage, len - 1, &key),
+            Err(OtaError::MissingTrailer)
+        );
+
+        storage.data[100] ^= 1;
+        assert_eq!(
+            verify_image(&mut storage, len, &key),
+            Err(OtaError::HashMismatch)
+        );
+        storage.data[100] ^= 1;
+        storage.data[0] = 0;
+        assert_eq!(
+            verify_image(&mut storage, len, &key),
+            Err(OtaError::NotAnImage)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_signatures() {
+        let key = public_key();
+        let data = update(900, &[0x24; 32]);
+        let len = data.len();
+        let mut storage = MemStorage::from_image(data);
+        assert_eq!(
+            verify_image(&mut storage, len, &key),
+            Err(OtaError::BadSignature)
+        );
+
+        let mut storage = MemStorage::from_image(update(900, &SIGNING_KEY));
+        storage.data[len - 1] ^= 1;
+        assert_eq!(
+            verify_image(&mut storage, len, &key),
+            Err(OtaError::BadSignature)
+        );
+    }
+
+    /// Sends `data` in fragments of `frag_size` bytes and installs it.
+    fn install(data: &[u8], frag_size: usize) -> (Result<u32, OtaError>, usize) {
+        let nb_frag = data.len().div_ceil(frag_size);
+        let padding = nb_frag * frag_size - data.len();
+        let mut block = data.to_vec();
+        block.resize(nb_frag * frag_size, 0);
+
+        let otadata = OtaData::new(MemStorage::new(2 * OTADATA_SECTOR as usize));
+        let slot = MemStorage::new(4096);
+        let mut firmware =
+            std::boxed::Box::new(FirmwareUpdate::new(slot, otadata, 1, public_key()));
+        let mut answer = crate::devices::downlink::Answer::new();
+        let setup = [
+            0x02,
+            0,
+            nb_frag as u8,
+            0,
+            frag_size as u8,
+            0,
+            padding as u8,
+            0,
+            0,
+            0,
+            0,
+        ];
+        firmware.fragmentation.handle(&setup, &mut answer);
+        let mut completed = None;
+        for (i, fragment) in block.chunks(frag_size).enumerate() {
+            let header = (i as u16 + 1).to_le_bytes();
+            let mut bytes = std::vec![0x08, header[0], header[1]];
+            bytes.extend_from_slice(fragment);
+            completed = completed.or(firmware.fragmentation.handle(&bytes, &mut answer));
+        }
+        let result = firmware.install(&completed.unwrap());
+        (result, firmware.otadata.boot_slot().unwrap())
+    }
+
+    #[test]
+    fn installs_only_signed_updates() {
+        assert_eq!(install(&update(900, &SIGNING_KEY), 50), (Ok(900), 1));
+        let forged = update(900, &[0x24; 32]);
+        assert_eq!(install(&forged, 50), (Err(OtaError::BadSignature), 0));
+    }
+}
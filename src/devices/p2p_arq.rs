@@ -0,0 +1,660 @@
+use embassy_time::{Duration, Instant};
+
+use super::p2p_frame::{
+    flags, Frame, FrameError, Header, MessageType, NodeId, BROADCAST, MAX_FRAME_LEN,
+};
+
+/// Peers whose sequence numbers are tracked; the oldest is forgotten first.
+pub const MAX_PEERS: usize = 16;
+/// Sequence numbers behind the latest one of a peer that are checked for
+/// duplicates. Older ones are taken as a restart of the peer.
+pub const DUPLICATE_WINDOW: u16 = 32;
+
+/// Radio the P2P link runs on, implemented over the LoRa radio and by
+/// simulated radios on the host.
+#[allow(async_fn_in_trait)]
+pub trait P2pRadio {
+    type Error: core::fmt::Debug;
+
+    /// Transmits a frame.
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), Self::Error>;
+
+    /// Listens for a frame for up to `timeout`, returning its length, or
+    /// `None` if nothing was received.
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, Self::Error>;
+
+    /// Signal strength of the last frame received, in dBm, if the radio
+    /// measures it.
+    fn rssi(&self) -> Option<i16> {
+        None
+    }
+
+    /// Signal-to-noise ratio of the last frame received, in dB, if the radio
+    /// measures it.
+    fn snr(&self) -> Option<i16> {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ArqError<E> {
+    /// No acknowledgement after every attempt.
+    Timeout {
+        attempts: u8,
+    },
+    /// Broadcast frames cannot be acknowledged.
+    Broadcast,
+    Frame(FrameError),
+    Radio(E),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for ArqError<E> {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ArqError::Timeout { attempts } => {
+                write!(f, "No acknowledgement after {} attempts", attempts)
+            }
+            ArqError::Broadcast => write!(f, "Broadcast frames cannot be acknowledged"),
+            ArqError::Frame(e) => write!(f, "{}", e),
+            ArqError::Radio(e) => write!(f, "Radio error: {:?}", e),
+        }
+    }
+}
+
+/// Retransmission settings of [`Arq::send_reliable`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArqConfig {
+    /// Transmissions after the first one.
+    pub retries: u8,
+    /// Time to wait for the acknowledgement of a transmission.
+    pub ack_timeout: Duration,
+    /// Upper bound of the random delay added before a retransmission, so
+    /// two nodes that collided do not collide again.
+    pub max_backoff: Duration,
+}
+
+impl Default for ArqConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            ack_timeout: Duration::from_millis(1500),
+            max_backoff: Duration::from_millis(1000),
+        }
+    }
+}
+
+/// A frame acknowledged by its destination.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delivered {
+    pub seq: u16,
+    /// Transmissions it took, 1 if the first one was acknowledged.
+    pub attempts: u8,
+}
+
+/// A frame received for this node.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Received {
+    pub kind: MessageType,
+    pub source: NodeId,
+    pub seq: u16,
+    pub flags: u8,
+    /// Bytes of the payload copied to the caller's buffer.
+    pub len: usize,
+}
+
+impl Received {
+    fn new(header: &Header, len: usize) -> Self {
+        Self {
+            kind: header.kind,
+            source: header.source,
+            seq: header.seq,
+            flags: header.flags,
+            len,
+        }
+    }
+}
+
+/// Sequence numbers received from a sender: the latest one, with a bit for
+/// each of the [`DUPLICATE_WINDOW`] before it that was received too.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SequenceWindow {
+    latest: Option<u16>,
+    window: u32,
+}
+
+impl SequenceWindow {
+    /// Records a received sequence number, returning `false` if it was
+    /// received before.
+    pub fn accept(&mut self, seq: u16) -> bool {
+        let Some(latest) = self.latest else {
+            self.latest = Some(seq);
+            self.window = 0;
+            return true;
+        };
+        let ahead = seq.wrapping_sub(latest);
+        let behind = latest.wrapping_sub(seq);
+        if ahead == 0 {
+            false
+        } else if ahead < 0x8000 {
+            self.window = match ahead {
+                1..=31 => (self.window << ahead) | (1 << (ahead - 1)),
+                32 => 1 << 31,
+                _ => 0,
+            };
+            self.latest = Some(seq);
+            true
+        } else if behind <= DUPLICATE_WINDOW {
+            let bit = 1 << (behind - 1);
+            let seen = self.window & bit != 0;
+            self.window |= bit;
+            !seen
+        } else {
+            // Far behind: the sender restarted its sequence numbers.
+            self.latest = Some(seq);
+            self.window = 0;
+            true
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    node: NodeId,
+    /// Last sequence number sent to the peer.
+    tx_seq: u16,
+    rx: SequenceWindow,
+}
+
+impl Peer {
+    fn new(node: NodeId) -> Self {
+        Self {
+            node,
+            tx_seq: 0,
+            rx: SequenceWindow::default(),
+        }
+    }
+}
+
+/// Acknowledged delivery of P2P frames (stop-and-wait ARQ).
+///
+/// Frames sent with [`Arq::send_reliable`] request an acknowledgement and are
+/// retransmitted until one arrives or the retries run out. Received frames
+/// that request one are acknowledged, duplicates included, but only delivered
+/// once.
+pub struct Arq<R> {
+    radio: R,
+    node: NodeId,
+    config: ArqConfig,
+    peers: heapless::Vec<Peer, MAX_PEERS>,
+    /// Sequence number of frames to broadcast.
+    broadcast_seq: u16,
+    random: u32,
+    frame: [u8; MAX_FRAME_LEN],
+}
+
+impl<R: P2pRadio> Arq<R> {
+    /// Sends and receives as `node`; `seed` starts the random backoff and
+    /// must not be 0.
+    pub fn new(radio: R, node: NodeId, config: ArqConfig, seed: u32) -> Self {
+        Self {
+            radio,
+            node,
+            config,
+            peers: heapless::Vec::new(),
+            broadcast_seq: 0,
+            random: seed.max(1),
+            frame: [0; MAX_FRAME_LEN],
+        }
+    }
+
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    pub fn radio(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    fn peer(&mut self, node: NodeId) -> &mut Peer {
+        let index = match self.peers.iter().position(|peer| peer.node == node) {
+            Some(index) => index,
+            None => {
+                if self.peers.is_full() {
+                    self.peers.remove(0);
+                }
+                // Cannot fail, a slot was freed above.
+                let _ = self.peers.push(Peer::new(node));
+                self.peers.len() - 1
+            }
+        };
+        &mut self.peers[index]
+    }
+
+    fn next_seq(&mut self, destination: NodeId) -> u16 {
+        let seq = if destination == BROADCAST {
+            &mut self.broadcast_seq
+        } else {
+            &mut self.peer(destination).tx_seq
+        };
+        *seq = seq.wrapping_add(1);
+        *seq
+    }
+
+    /// Random delay of up to `max_backoff` (xorshift32).
+    fn backoff(&mut self) -> Duration {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        let max_ms = self.config.max_backoff.as_millis() + 1;
+        Duration::from_millis(self.random as u64 % max_ms)
+    }
+
+    async fn transmit(&mut self, header: Header, payload: &[u8]) -> Result<(), ArqError<R::Error>> {
+        let len = Frame::new(header, payload)
+            .encode(&mut self.frame)
+            .map_err(ArqError::Frame)?;
+        self.radio
+            .transmit(&self.frame[..len])
+            .await
+            .map_err(ArqError::Radio)
+    }
+
+    /// Sends `payload` once without waiting for an acknowledgement,
+    /// returning its sequence number.
+    pub async fn send(
+        &mut self,
+        destination: NodeId,
+        payload: &[u8],
+    ) -> Result<u16, ArqError<R::Error>> {
+        self.send_frame(MessageType::Data, destination, payload, 0)
+            .await
+    }
+
+    /// Sends a frame of any kind once, returning its sequence number, for
+    /// the layers built on the link.
+    pub async fn send_frame(
+        &mut self,
+        kind: MessageType,
+        destination: NodeId,
+        payload: &[u8],
+        flags: u8,
+    ) -> Result<u16, ArqError<R::Error>> {
+        let header = Header {
+            kind,
+            source: self.node,
+            destination,
+            seq: self.next_seq(destination),
+            flags,
+        };
+        self.transmit(header, payload).await?;
+        Ok(header.seq)
+    }
+
+    /// Sends `payload` to `destination` until it is acknowledged.
+    ///
+    /// Frames from other nodes received while waiting are dropped without an
+    /// acknowledgement, so their senders retransmit them.
+    ///
+    /// # Errors
+    ///
+    /// * `Broadcast` - If `destination` is [`BROADCAST`].
+    /// * `Timeout` - If no acknowledgement came after all the retries.
+    /// * `Frame` - If the payload does not fit in a frame.
+    /// * `Radio` - If the radio failed.
+    pub async fn send_reliable(
+        &mut self,
+        destination: NodeId,
+        payload: &[u8],
+    ) -> Result<Delivered, ArqError<R::Error>> {
+        self.send_reliable_frame(MessageType::Data, destination, payload)
+            .await
+    }
+
+    /// Like [`Arq::send_reliable`], for the kinds of frames [`Arq::receive`]
+    /// acknowledges: `Data` and `Unicast`.
+    pub async fn send_reliable_frame(
+        &mut self,
+        kind: MessageType,
+        destination: NodeId,
+        payload: &[u8],
+    ) -> Result<Delivered, ArqError<R::Error>> {
+        if destination == BROADCAST {
+            return Err(ArqError::Broadcast);
+        }
+        let header = Header {
+            kind,
+            source: self.node,
+            destination,
+            seq: self.next_seq(destination),
+            flags: flags::ACK_REQUEST,
+        };
+        let attempts = self.config.retries.saturating_add(1);
+        for attempt in 1..=attempts {
+            self.transmit(header, payload).await?;
+            // Keep listening during the backoff, a late acknowledgement is
+            // as good as a timely one.
+            let wait = self.config.ack_timeout + self.backoff();
+            if self.wait_for_ack(destination, header.seq, wait).await? {
+                return Ok(Delivered {
+                    seq: header.seq,
+                    attempts: attempt,
+                });
+            }
+        }
+        Err(ArqError::Timeout { attempts })
+    }
+
+    async fn wait_for_ack(
+        &mut self,
+        destination: NodeId,
+        seq: u16,
+        wait: Duration,
+    ) -> Result<bool, ArqError<R::Error>> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let Some(len) = self
+                .radio
+                .receive(&mut self.frame, timeout)
+                .await
+                .map_err(ArqError::Radio)?
+            else {
+                return Ok(false);
+            };
+            if let Ok(frame) = Frame::decode(&self.frame[..len]) {
+                let header = &frame.header;
+                if header.kind == MessageType::Ack
+                    && header.source == destination
+                    && header.destination == self.node
+                    && header.seq == seq
+                {
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Listens for up to `timeout` for a frame for this node, copying its
+    /// payload to `payload`.
+    ///
+    /// `Data` and `Unicast` frames that request it are acknowledged;
+    /// duplicates, `Ack` frames and frames for other nodes are skipped. Other
+    /// kinds are passed on as they are, for the layers built on the link.
+    ///
+    /// # Errors
+    ///
+    /// * `Frame` - If a payload does not fit in `payload`.
+    /// * `Radio` - If the radio failed.
+    pub async fn receive(
+        &mut self,
+        payload: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<Received>, ArqError<R::Error>> {
+        self.receive_matching(payload, timeout, |_| true).await
+    }
+
+    /// Like [`Arq::receive`], but frames `wanted` rejects are dropped without
+    /// an acknowledgement, so their senders retransmit them.
+    pub async fn receive_matching(
+        &mut self,
+        payload: &mut [u8],
+        timeout: Duration,
+        wanted: impl Fn(&Header) -> bool,
+    ) -> Result<Option<Received>, ArqError<R::Error>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let Some(len) = self
+                .radio
+                .receive(&mut self.frame, timeout)
+                .await
+                .map_err(ArqError::Radio)?
+            else {
+                return Ok(None);
+            };
+            let (header, copied) = match Frame::decode(&self.frame[..len]) {
+                Ok(frame) if frame.header.kind != MessageType::Ack => {
+                    if !frame.header.is_for(self.node) || !wanted(&frame.header) {
+                        continue;
+                    }
+                    // Left unacknowledged the frame is sent again later.
+                    let data = payload
+                        .get_mut(..frame.payload.len())
+                        .ok_or(ArqError::Frame(FrameError::BufferTooSmall(
+                            frame.payload.len(),
+                        )))?;
+                    data.copy_from_slice(frame.payload);
+                    (frame.header, data.len())
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    esp_println::println!("[LoRa P2P] Dropped frame: {}", e);
+                    continue;
+                }
+            };
+            if !matches!(header.kind, MessageType::Data | MessageType::Unicast) {
+                return Ok(Some(Received::new(&header, copied)));
+            }
+            // Broadcasts are numbered apart from the frames sent to this
+            // node and never retransmitted, so only the latter are checked.
+            let direct = header.destination == self.node;
+            let fresh = !direct || self.peer(header.source).rx.accept(header.seq);
+            if header.ack_requested() && direct {
+                let ack = Header {
+                    kind: MessageType::Ack,
+                    source: self.node,
+                    destination: header.source,
+                    seq: header.seq,
+                    flags: 0,
+                };
+                self.transmit(ack, &[]).await?;
+            }
+            if !fresh {
+                continue;
+            }
+            return Ok(Some(Received::new(&header, copied)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, rc::Rc, vec::Vec};
+
+    use embassy_futures::{block_on, join::join};
+
+    use crate::devices::test_util::{Channel, LossyRadio};
+
+    fn arq(channel: &Rc<RefCell<Channel>>, side: usize, config: ArqConfig) -> Arq<LossyRadio> {
+        Arq::new(
+            LossyRadio::new(channel, side),
+            side as NodeId + 1,
+            config,
+            7,
+        )
+    }
+
+    /// Queues a `Data` frame from node 1 for node 2.
+    fn inject(channel: &Rc<RefCell<Channel>>, seq: u16, flags: u8, destination: NodeId) {
+        let header = Header {
+            kind: MessageType::Data,
+            source: 1,
+            destination,
+            seq,
+            flags,
+        };
+        let mut frame = [0u8; 16];
+        let len = Frame::new(header, b"p").encode(&mut frame).unwrap();
+        channel.borrow_mut().inboxes[1].push_back(frame[..len].to_vec());
+    }
+
+    /// Sequence numbers of the frames node 2 delivers until it times out.
+    fn delivered(receiver: &mut Arq<LossyRadio>) -> Vec<u16> {
+        let mut payload = [0u8; 4];
+        let mut seqs = Vec::new();
+        while let Some(received) =
+            block_on(receiver.receive(&mut payload, Duration::from_secs(1))).unwrap()
+        {
+            seqs.push(received.seq);
+        }
+        seqs
+    }
+
+    #[test]
+    fn delivers_in_order_over_a_lossy_channel() {
+        let channel = Channel::new(30);
+        let config = ArqConfig {
+            retries: 12,
+            ..Default::default()
+        };
+        let mut sender = arq(&channel, 0, config);
+        let mut receiver = arq(&channel, 1, config);
+        let done = RefCell::new(false);
+        let send = async {
+            let mut retransmitted = 0;
+            for i in 0..300u16 {
+                let delivered = sender.send_reliable(2, &i.to_le_bytes()).await.unwrap();
+                assert_eq!(delivered.seq, i + 1);
+                retransmitted += delivered.attempts as usize - 1;
+            }
+            *done.borrow_mut() = true;
+            retransmitted
+        };
+        let receive = async {
+            let mut payload = [0u8; 4];
+            let mut messages = Vec::new();
+            while !*done.borrow() {
+                let received = receiver
+                    .receive(&mut payload, Duration::from_secs(1))
+                    .await
+                    .unwrap();
+                if let Some(received) = received {
+                    assert_eq!((received.source, received.len), (1, 2));
+                    messages.push(u16::from_le_bytes([payload[0], payload[1]]));
+                }
+            }
+            messages
+        };
+        let (retransmitted, messages) = block_on(join(send, receive));
+        // Each message once and in order, though lost acks made the sender
+        // retransmit some the receiver already had.
+        assert_eq!(messages, (0..300).collect::<Vec<_>>());
+        assert!(retransmitted > 100);
+    }
+
+    #[test]
+    fn retries_until_acknowledged() {
+        let channel = Channel::new(0);
+        let mut sender = arq(&channel, 0, ArqConfig::default());
+        let mut receiver = arq(&channel, 1, ArqConfig::default());
+        // The first frame is lost, the ack of the second one too.
+        channel.borrow_mut().script.extend([true, false, true]);
+        let done = RefCell::new(false);
+        let send = async {
+            let delivered = sender.send_reliable(2, b"hi").await;
+            *done.borrow_mut() = true;
+            delivered
+        };
+        let receive = async {
+            let mut payload = [0u8; 4];
+            let mut count = 0;
+            while !*done.borrow() {
+                let received = receiver
+                    .receive(&mut payload, Duration::from_secs(1))
+                    .await
+                    .unwrap();
+                if let Some(received) = received {
+                    assert_eq!(&payload[..received.len], b"hi");
+                    count += 1;
+                }
+            }
+            count
+        };
+        let (delivered, count) = block_on(join(send, receive));
+        assert_eq!(
+            delivered,
+            Ok(Delivered {
+                seq: 1,
+                attempts: 3
+            })
+        );
+        assert_eq!(count, 1);
+        // Three frames and the acks of the last two.
+        assert_eq!(channel.borrow().sent, 5);
+    }
+
+    #[test]
+    fn times_out_after_every_retry() {
+        let channel = Channel::new(100);
+        let mut sender = arq(&channel, 0, ArqConfig::default());
+        let result = block_on(sender.send_reliable(2, b"hi"));
+        assert_eq!(result, Err(ArqError::Timeout { attempts: 4 }));
+        assert_eq!(channel.borrow().sent, 4);
+        let result = block_on(sender.send_reliable(BROADCAST, b"hi"));
+        assert_eq!(result, Err(ArqError::Broadcast));
+    }
+
+    #[test]
+    fn acknowledges_duplicates_but_delivers_them_once() {
+        let channel = Channel::new(0);
+        let mut receiver = arq(&channel, 1, ArqConfig::default());
+        for seq in [100, 100, 99, 101, 99] {
+            inject(&channel, seq, flags::ACK_REQUEST, 2);
+        }
+        // Broadcasts are not checked for duplicates, frames for other nodes
+        // are skipped.
+        inject(&channel, 5, 0, BROADCAST);
+        inject(&channel, 5, 0, BROADCAST);
+        inject(&channel, 9, flags::ACK_REQUEST, 3);
+        assert_eq!(delivered(&mut receiver), [100, 99, 101, 5, 5]);
+        assert_eq!(channel.borrow().inboxes[0].len(), 5);
+    }
+
+    #[test]
+    fn follows_restarted_senders() {
+        let channel = Channel::new(0);
+        let mut receiver = arq(&channel, 1, ArqConfig::default());
+        for seq in [500, 501, 1, 2, 2] {
+            inject(&channel, seq, 0, 2);
+        }
+        assert_eq!(delivered(&mut receiver), [500, 501, 1, 2]);
+    }
+
+    #[test]
+    fn windows_wrap_around() {
+        let mut window = SequenceWindow::default();
+        for seq in [0xFFFE, 0xFFFF, 0, 1] {
+            assert!(window.accept(seq));
+        }
+        assert!(!window.accept(0xFFFF));
+        assert!(!window.accept(0));
+        // Missed before the wrap and received late.
+        assert!(window.accept(0xFFFD));
+        assert!(!window.accept(0xFFFD));
+    }
+
+    #[test]
+    fn windows_track_the_last_sequence_numbers() {
+        let mut window = SequenceWindow::default();
+        assert!(window.accept(100));
+        assert!(window.accept(100 + DUPLICATE_WINDOW));
+        // At the edge of the window.
+        assert!(window.accept(100 + DUPLICATE_WINDOW + 1));
+        assert!(window.accept(101));
+        assert!(!window.accept(101));
+        assert!(!window.accept(100 + DUPLICATE_WINDOW + 1));
+        // A jump forward forgets the window.
+        assert!(window.accept(1000));
+        assert!(window.accept(999));
+        // Far behind is a restart of the sender.
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        assert!(window.accept(11));
+    }
+}
@@ -0,0 +1,295 @@
+/// What [`ListenBeforeTalk`] does with a frame once the channel was busy at
+/// every attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusyPolicy {
+    /// Drops the frame, failing its transmission.
+    Drop,
+    /// Transmits it anyway, as without listen-before-talk.
+    Transmit,
+}
+
+/// Settings of [`ListenBeforeTalk`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CadConfig {
+    /// Channel activity detections made for a frame, the first included.
+    pub max_attempts: u8,
+    /// Upper bound of the delay after the first busy detection, doubled
+    /// after each further one up to `max_backoff_ms`. The delay is random
+    /// between half of it and all of it, so nodes waiting for the same
+    /// frame to end do not all transmit when it does.
+    pub backoff_ms: u32,
+    pub max_backoff_ms: u32,
+    pub on_busy: BusyPolicy,
+}
+
+impl Default for CadConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            backoff_ms: 250,
+            max_backoff_ms: 4_000,
+            on_busy: BusyPolicy::Drop,
+        }
+    }
+}
+
+/// What follows a channel activity detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CadDecision {
+    Transmit,
+    /// Detect again after this many milliseconds.
+    Backoff(u32),
+    /// The channel stayed busy, the frame is not sent.
+    Drop,
+}
+
+/// Channel activity detections of a [`ListenBeforeTalk`], by outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CadStats {
+    /// Detections that found the channel clear.
+    pub clear: u32,
+    pub busy: u32,
+    /// Detections that failed; the frame is sent without one.
+    pub failed: u32,
+    /// Detections whose interrupt did not fire, the radio's flags were
+    /// polled instead.
+    pub polled: u32,
+    pub backoffs: u32,
+    /// Total time spent backing off, in milliseconds.
+    pub backoff_ms: u64,
+    /// Frames the channel stayed busy for, dropped or sent anyway depending
+    /// on [`CadConfig::on_busy`].
+    pub gave_up: u32,
+}
+
+impl CadStats {
+    pub fn detections(&self) -> u32 {
+        self.clear + self.busy + self.failed
+    }
+}
+
+/// Listen-before-talk: senses the channel with the radio's channel activity
+/// detection (CAD) before each frame and backs off while it is busy.
+///
+/// A CAD looks for a LoRa preamble for a couple of symbols, so it finds the
+/// frames of other nodes of the network that are being sent, which a plain
+/// RSSI check misses below the noise floor. The delays grow exponentially
+/// with random jitter, and after [`CadConfig::max_attempts`] detections the
+/// frame is dropped or sent anyway per [`CadConfig::on_busy`].
+pub struct ListenBeforeTalk {
+    config: CadConfig,
+    random: u32,
+    attempts: u8,
+    stats: CadStats,
+}
+
+impl ListenBeforeTalk {
+    /// `seed` starts the random backoff, so nodes differ.
+    pub fn new(config: CadConfig, seed: u32) -> Self {
+        Self {
+            config,
+            random: seed.max(1),
+            attempts: 0,
+            stats: CadStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &CadConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> CadStats {
+        self.stats
+    }
+
+    /// Starts sensing for a new frame.
+    pub fn start(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Upper bound of the delay after busy detection number `attempt`,
+    /// counted from 1.
+    pub fn window_ms(&self, attempt: u8) -> u32 {
+        self.config
+            .backoff_ms
+            .saturating_mul(1 << attempt.saturating_sub(1).min(16))
+            .min(self.config.max_backoff_ms)
+    }
+
+    /// Decides what follows a detection that found the channel `busy` or
+    /// not; `polled` when its interrupt did not fire.
+    pub fn detected(&mut self, busy: bool, polled: bool) -> CadDecision {
+        if polled {
+            self.stats.polled += 1;
+        }
+        if !busy {
+            self.stats.clear += 1;
+            return CadDecision::Transmit;
+        }
+        self.stats.busy += 1;
+        self.attempts = self.attempts.saturating_add(1);
+        if self.attempts >= self.config.max_attempts {
+            self.stats.gave_up += 1;
+            return match self.config.on_busy {
+                BusyPolicy::Drop => CadDecision::Drop,
+                BusyPolicy::Transmit => CadDecision::Transmit,
+            };
+        }
+        let delay_ms = self.backoff(self.window_ms(self.attempts));
+        self.stats.backoffs += 1;
+        self.stats.backoff_ms += delay_ms as u64;
+        CadDecision::Backoff(delay_ms)
+    }
+
+    /// Records a detection that failed; the frame is sent, listening first
+    /// is best effort.
+    pub fn failed(&mut self) -> CadDecision {
+        self.stats.failed += 1;
+        CadDecision::Transmit
+    }
+
+    /// Random delay between half of `window_ms` and all of it (xorshift32).
+    fn backoff(&mut self, window_ms: u32) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        window_ms - window_ms / 2 + self.random % (window_ms / 2 + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn delay(decision: CadDecision) -> u32 {
+        match decision {
+            CadDecision::Backoff(ms) => ms,
+            decision => panic!("backoff expected, got {:?}", decision),
+        }
+    }
+
+    #[test]
+    fn transmits_when_clear() {
+        let mut lbt = ListenBeforeTalk::new(CadConfig::default(), 7);
+        lbt.start();
+        assert_eq!(lbt.detected(false, false), CadDecision::Transmit);
+        assert_eq!(
+            lbt.stats(),
+            CadStats {
+                clear: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_its_cap() {
+        let lbt = ListenBeforeTalk::new(CadConfig::default(), 1);
+        let windows: std::vec::Vec<u32> = (1..=7).map(|attempt| lbt.window_ms(attempt)).collect();
+        assert_eq!(windows, [250, 500, 1_000, 2_000, 4_000, 4_000, 4_000]);
+        // Large attempts neither shift out of range nor overflow.
+        assert_eq!(lbt.window_ms(0), 250);
+        assert_eq!(lbt.window_ms(u8::MAX), 4_000);
+        let unbounded = ListenBeforeTalk::new(
+            CadConfig {
+                max_backoff_ms: u32::MAX,
+                ..Default::default()
+            },
+            1,
+        );
+        assert_eq!(unbounded.window_ms(u8::MAX), 250 << 16);
+    }
+
+    #[test]
+    fn jitters_within_the_window() {
+        let config = CadConfig {
+            max_attempts: u8::MAX,
+            ..Default::default()
+        };
+        for seed in [0, 1, 99, 0xDEAD_BEEF] {
+            let mut lbt = ListenBeforeTalk::new(config, seed);
+            let mut delays = std::vec::Vec::new();
+            for _ in 0..200 {
+                lbt.start();
+                for attempt in 1..=6 {
+                    let window = lbt.window_ms(attempt);
+                    let ms = delay(lbt.detected(true, false));
+                    assert!((window / 2..=window).contains(&ms), "{} of {}", ms, window);
+                    if attempt == 1 {
+                        delays.push(ms);
+                    }
+                }
+            }
+            // Nodes waiting on the same frame spread over the window.
+            delays.sort();
+            delays.dedup();
+            assert!(delays.len() > 50, "{} distinct delays", delays.len());
+        }
+    }
+
+    #[test]
+    fn drops_after_max_attempts() {
+        let mut lbt = ListenBeforeTalk::new(CadConfig::default(), 3);
+        lbt.start();
+        let mut total_ms = 0;
+        for _ in 1..5 {
+            total_ms += delay(lbt.detected(true, false)) as u64;
+        }
+        assert_eq!(lbt.detected(true, true), CadDecision::Drop);
+        assert_eq!(
+            lbt.stats(),
+            CadStats {
+                busy: 5,
+                polled: 1,
+                backoffs: 4,
+                backoff_ms: total_ms,
+                gave_up: 1,
+                ..Default::default()
+            }
+        );
+
+        // The next frame starts over from the shortest backoff.
+        lbt.start();
+        assert!(delay(lbt.detected(true, false)) <= 250);
+        assert_eq!(lbt.detected(false, false), CadDecision::Transmit);
+    }
+
+    #[test]
+    fn transmits_anyway_after_max_attempts() {
+        let config = CadConfig {
+            max_attempts: 2,
+            on_busy: BusyPolicy::Transmit,
+            ..Default::default()
+        };
+        let mut lbt = ListenBeforeTalk::new(config, 3);
+        lbt.start();
+        delay(lbt.detected(true, false));
+        assert_eq!(lbt.detected(true, false), CadDecision::Transmit);
+        assert_eq!(lbt.stats().gave_up, 1);
+
+        // A single attempt gives up at the first busy detection.
+        let mut lbt = ListenBeforeTalk::new(
+            CadConfig {
+                max_attempts: 1,
+                ..config
+            },
+            3,
+        );
+        lbt.start();
+        assert_eq!(lbt.detected(true, false), CadDecision::Transmit);
+        assert_eq!(lbt.stats().backoffs, 0);
+    }
+
+    #[test]
+    fn transmits_when_detection_fails() {
+        let mut lbt = ListenBeforeTalk::new(CadConfig::default(), 3);
+        lbt.start();
+        assert_eq!(lbt.failed(), CadDecision::Transmit);
+        delay(lbt.detected(true, true));
+        assert_eq!(lbt.detected(false, true), CadDecision::Transmit);
+        let stats = lbt.stats();
+        assert_eq!((stats.clear, stats.busy, stats.failed), (1, 1, 1));
+        assert_eq!((stats.polled, stats.backoffs, stats.gave_up), (2, 1, 0));
+        assert_eq!(stats.detections(), 3);
+    }
+}
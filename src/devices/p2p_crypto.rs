@@ -0,0 +1,1148 @@
+use aes::Aes128;
+use ccm::{
+    aead::generic_array::GenericArray,
+    consts::{U13, U8},
+    AeadInPlace, Ccm, KeyInit,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Instant};
+use embedded_storage::Storage;
+use heapless::String;
+
+use super::{
+    crc::crc16_ccitt,
+    kv::{KvError, KvStore},
+    nvs::{Nvs, NvsError},
+    p2p_arq::{P2pRadio, MAX_PEERS},
+    p2p_frame::{
+        self, flags, Frame, FrameError, Header, MessageType, NodeId, BROADCAST, CRC_LEN,
+        HEADER_LEN, MAX_FRAME_LEN, MAX_PAYLOAD_LEN,
+    },
+};
+
+pub const P2P_NAMESPACE: &str = "p2p";
+/// NVS key of the 16-byte key shared by the nodes of the network, a blob in
+/// the [`P2P_NAMESPACE`] of the factory NVS partition.
+pub const NETWORK_KEY_KEY: &str = "netkey";
+/// Prefix of the settings keys of the link keys agreed with paired nodes,
+/// followed by the node ID in hex.
+pub const LINK_KEY_PREFIX: &str = "p2p.link.";
+/// Settings key of the IDs of the paired nodes, oldest first, 2 bytes each
+/// (LE).
+pub const PAIRED_KEY: &str = "p2p.paired";
+/// Nodes paired at a time; pairing another forgets the oldest.
+pub const MAX_PAIRED: usize = MAX_PEERS;
+/// Settings key of the first frame counter this node has not reserved yet.
+pub const COUNTER_KEY: &str = "p2p.counter";
+/// Prefix of the settings keys of the lowest counter accepted from each
+/// sender, followed by the node ID in hex.
+pub const REPLAY_KEY_PREFIX: &str = "p2p.replay.";
+pub const COUNTER_LEN: usize = 4;
+/// Length of the sender's factory MAC address, which the node ID is only
+/// the last bytes of.
+pub const SENDER_MAC_LEN: usize = 6;
+pub const MIC_LEN: usize = 8;
+/// Bytes a secured frame carries after the encrypted payload.
+pub const SECURITY_LEN: usize = COUNTER_LEN + SENDER_MAC_LEN + MIC_LEN;
+/// Frame counters reserved in flash at a time.
+///
+/// The counter is stored before the first frame of a block is sent, so a
+/// reboot skips the rest of the block instead of reusing a counter, and the
+/// flash is written once every this many frames.
+pub const COUNTER_BLOCK: u32 = 256;
+
+/// AES-128-CCM with an 8-byte MIC and a 13-byte nonce.
+type FrameCipher = Ccm<Aes128, U8, U13>;
+
+#[derive(Debug, PartialEq)]
+pub enum SecureError<E> {
+    Storage,
+    /// Every frame counter was used, the network key must be changed.
+    CounterExhausted,
+    Frame(FrameError),
+    Radio(E),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for SecureError<E> {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SecureError::Storage => write!(f, "Failed to store the frame counter"),
+            SecureError::CounterExhausted => write!(f, "Frame counter exhausted"),
+            SecureError::Frame(e) => write!(f, "{}", e),
+            SecureError::Radio(e) => write!(f, "Radio error: {:?}", e),
+        }
+    }
+}
+
+/// Why a received frame was rejected.
+#[derive(Debug, PartialEq)]
+pub enum OpenError {
+    Frame(FrameError),
+    /// The frame is not secured.
+    Unsecured,
+    /// The payload is shorter than the counter, sender MAC and MIC.
+    Truncated(usize),
+    /// The sender MAC does not belong to the source of the frame.
+    Spoofed(NodeId),
+    /// The MIC does not match: the frame was forged, altered or secured with
+    /// another network key.
+    Forged,
+    /// The counter is not above the last one received from the sender.
+    Replayed {
+        counter: u32,
+        latest: u32,
+    },
+}
+
+impl core::fmt::Display for OpenError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            OpenError::Frame(e) => write!(f, "{}", e),
+            OpenError::Unsecured => write!(f, "Frame is not secured"),
+            OpenError::Truncated(len) => {
+                write!(f, "Secured payload of {} bytes is truncated", len)
+            }
+            OpenError::Forged => write!(f, "MIC mismatch"),
+            OpenError::Spoofed(source) => {
+                write!(f, "Sender MAC does not match node {:04X}", source)
+            }
+            OpenError::Replayed { counter, latest } => write!(
+                f,
+                "Replayed frame counter {}, latest is {}",
+                counter, latest
+            ),
+        }
+    }
+}
+
+/// Reads the provisioned network key from the `p2p` NVS namespace, `None`
+/// if there is none.
+///
+/// # Errors
+///
+/// * `BlobSize` - If the stored key is shorter than 16 bytes.
+/// * `BufferTooSmall` - If it is longer.
+pub fn read_network_key<S: Storage>(nvs: &mut Nvs<S>) -> Result<Option<[u8; 16]>, NvsError> {
+    let mut key = [0u8; 16];
+    match nvs.get_blob(P2P_NAMESPACE, NETWORK_KEY_KEY, &mut key) {
+        Ok(Some(16)) => Ok(Some(key)),
+        Ok(Some(len)) => Err(NvsError::BlobSize {
+            expected: 16,
+            found: len,
+        }),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Provisions the network key. Nodes only understand each other when they
+/// share it; it is usually flashed with the rest of the factory NVS
+/// partition, as a `netkey,data,hex2bin,...` line under a `p2p` namespace
+/// line of the `nvs_partition_gen` CSV.
+pub fn write_network_key<S: Storage>(nvs: &mut Nvs<S>, key: &[u8; 16]) -> Result<(), NvsError> {
+    nvs.set_blob(P2P_NAMESPACE, NETWORK_KEY_KEY, key)
+}
+
+/// Settings key of `prefix` followed by the ID of `peer`.
+fn peer_key_name(prefix: &str, peer: NodeId) -> String<16> {
+    let mut name = String::new();
+    let _ = core::fmt::write(&mut name, core::format_args!("{}{:04X}", prefix, peer));
+    name
+}
+
+fn link_key_name(peer: NodeId) -> String<16> {
+    peer_key_name(LINK_KEY_PREFIX, peer)
+}
+
+/// Reads the link key agreed with `peer`, `None` if it was never paired.
+pub fn read_link_key<S: Storage>(
+    settings: &mut KvStore<S>,
+    peer: NodeId,
+) -> Result<Option<[u8; 16]>, KvError> {
+    settings.get(&link_key_name(peer))
+}
+
+/// Stores the link key agreed with `peer`, replacing the previous one.
+///
+/// Returns the node forgotten to make room for `peer`, if
+/// [`MAX_PAIRED`] nodes were paired already.
+pub fn write_link_key<S: Storage>(
+    settings: &mut KvStore<S>,
+    peer: NodeId,
+    key: &[u8; 16],
+) -> Result<Option<NodeId>, KvError> {
+    let mut paired = read_paired(settings)?;
+    paired.retain(|node| *node != peer);
+    let forgotten = if paired.is_full() {
+        let oldest = paired.remove(0);
+        settings.remove(&link_key_name(oldest))?;
+        Some(oldest)
+    } else {
+        None
+    };
+    settings.set(&link_key_name(peer), key)?;
+    // Cannot fail, a slot was freed above.
+    let _ = paired.push(peer);
+    write_paired(settings, &paired)?;
+    Ok(forgotten)
+}
+
+/// Removes the link key agreed with `peer`, so the frames exchanged with it
+/// are secured with the network key again.
+pub fn remove_link_key<S: Storage>(settings: &mut KvStore<S>, peer: NodeId) -> Result<(), KvError> {
+    let mut paired = read_paired(settings)?;
+    paired.retain(|node| *node != peer);
+    settings.remove(&link_key_name(peer))?;
+    write_paired(settings, &paired)
+}
+
+/// Nodes this node is paired with, oldest first.
+pub fn read_paired<S: Storage>(
+    settings: &mut KvStore<S>,
+) -> Result<heapless::Vec<NodeId, MAX_PAIRED>, KvError> {
+    let stored: Option<heapless::Vec<u8, { 2 * MAX_PAIRED }>> = settings.get(PAIRED_KEY)?;
+    Ok(stored
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|id| NodeId::from_le_bytes([id[0], id[1]]))
+        .collect())
+}
+
+fn write_paired<S: Storage>(settings: &mut KvStore<S>, paired: &[NodeId]) -> Result<(), KvError> {
+    let stored: heapless::Vec<u8, { 2 * MAX_PAIRED }> =
+        paired.iter().flat_map(|node| node.to_le_bytes()).collect();
+    settings.set(PAIRED_KEY, &stored)
+}
+
+/// The provisioned network key, `None` if none is usable: the node must not
+/// transmit then, as a key shared by every build would secure nothing.
+pub fn load_network_key<S: Storage>(nvs: &mut Nvs<S>) -> Option<[u8; 16]> {
+    match read_network_key(nvs) {
+        Ok(Some(key)) if key != [0; 16] => Some(key),
+        Ok(Some(_)) => {
+            esp_println::println!("[LoRa P2P] The provisioned network key is all zeros");
+            None
+        }
+        Ok(None) => {
+            esp_println::println!(
+                "[LoRa P2P] No network key provisioned in NVS {}/{}",
+                P2P_NAMESPACE,
+                NETWORK_KEY_KEY
+            );
+            None
+        }
+        Err(e) => {
+            esp_println::println!("[LoRa P2P] Failed to read the network key: {}", e);
+            None
+        }
+    }
+}
+
+/// CCM nonce of a frame: the sender's MAC address and the frame counter.
+///
+/// Node IDs are only the last two bytes of the MAC address and may be
+/// shared by two nodes, which must not use the same nonce with the same key;
+/// the header is authenticated anyway.
+///
+/// | Offset | Size | Field                       |
+/// |--------|------|-----------------------------|
+/// | 0      | 6    | Sender MAC                  |
+/// | 6      | 4    | Frame counter (LE)          |
+/// | 10     | 3    | Zero                        |
+fn nonce(mac: &[u8; SENDER_MAC_LEN], counter: u32) -> [u8; 13] {
+    let mut nonce = [0u8; 13];
+    nonce[..SENDER_MAC_LEN].copy_from_slice(mac);
+    nonce[SENDER_MAC_LEN..SENDER_MAC_LEN + COUNTER_LEN].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+/// Secures the encoded frame `plain` with `counter` for the node with MAC
+/// address `mac`, writing the frame to send into `out` and returning its
+/// length.
+///
+/// The header, with [`flags::SECURED`] set and the length of the secured
+/// payload, is authenticated; the payload is encrypted and followed by:
+///
+/// | Offset | Size | Field                       |
+/// |--------|------|-----------------------------|
+/// | n      | 4    | Frame counter (LE)          |
+/// | n + 4  | 6    | Sender MAC                  |
+/// | n + 10 | 8    | MIC                         |
+///
+/// The CRC of the frame covers them too.
+///
+/// # Errors
+///
+/// * `Frame` - If `plain` is not a valid frame or the secured one does not fit in `out`.
+pub fn seal(
+    key: &[u8; 16],
+    mac: &[u8; SENDER_MAC_LEN],
+    counter: u32,
+    plain: &[u8],
+    out: &mut [u8],
+) -> Result<usize, FrameError> {
+    let frame = Frame::decode(plain)?;
+    if frame.payload.len() > MAX_PAYLOAD_LEN {
+        return Err(FrameError::PayloadTooLong(frame.payload.len()));
+    }
+    let len = frame.encoded_len() + SECURITY_LEN;
+    let secured = out.get_mut(..len).ok_or(FrameError::BufferTooSmall(len))?;
+    let (header, rest) = secured.split_at_mut(HEADER_LEN);
+    header.copy_from_slice(&plain[..HEADER_LEN]);
+    header[7] |= flags::SECURED;
+    header[8] = (frame.payload.len() + SECURITY_LEN) as u8;
+
+    let (payload, trailer) = rest.split_at_mut(frame.payload.len());
+    payload.copy_from_slice(frame.payload);
+    let nonce = nonce(mac, counter);
+    let mic = FrameCipher::new(GenericArray::from_slice(key))
+        .encrypt_in_place_detached(GenericArray::from_slice(&nonce), header, payload)
+        .map_err(|_| FrameError::PayloadTooLong(frame.payload.len()))?;
+    let (counter_bytes, trailer) = trailer.split_at_mut(COUNTER_LEN);
+    counter_bytes.copy_from_slice(&counter.to_le_bytes());
+    trailer[..SENDER_MAC_LEN].copy_from_slice(mac);
+    trailer[SENDER_MAC_LEN..SENDER_MAC_LEN + MIC_LEN].copy_from_slice(&mic);
+    let crc = crc16_ccitt(&secured[..len - CRC_LEN]);
+    secured[len - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+    Ok(len)
+}
+
+/// Checks and decrypts a frame secured by [`seal`], writing the plain frame
+/// into `out`.
+///
+/// Returns the length of the plain frame, its source and its counter; the
+/// counter is not checked against replays.
+///
+/// # Errors
+///
+/// * `Frame` - If `secured` is not a valid frame or the plain one does not fit in `out`.
+/// * `Unsecured` - If the frame is not secured.
+/// * `Truncated` - If the payload cannot hold the counter, sender MAC and MIC.
+/// * `Spoofed` - If the sender MAC is not that of the source node.
+/// * `Forged` - If the MIC does not match.
+pub fn open(
+    key: &[u8; 16],
+    secured: &[u8],
+    out: &mut [u8],
+) -> Result<(usize, NodeId, u32), OpenError> {
+    let frame = Frame::decode(secured).map_err(OpenError::Frame)?;
+    if frame.header.flags & flags::SECURED == 0 {
+        return Err(OpenError::Unsecured);
+    }
+    let Some(data_len) = frame.payload.len().checked_sub(SECURITY_LEN) else {
+        return Err(OpenError::Truncated(frame.payload.len()));
+    };
+    let (ciphertext, trailer) = frame.payload.split_at(data_len);
+    let counter = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let mut mac = [0u8; SENDER_MAC_LEN];
+    mac.copy_from_slice(&trailer[COUNTER_LEN..COUNTER_LEN + SENDER_MAC_LEN]);
+    let mic = &trailer[COUNTER_LEN + SENDER_MAC_LEN..];
+    if p2p_frame::node_id(&mac) != frame.header.source {
+        return Err(OpenError::Spoofed(frame.header.source));
+    }
+
+    let mut payload = [0u8; MAX_PAYLOAD_LEN];
+    let payload = payload
+        .get_mut(..data_len)
+        .ok_or(OpenError::Frame(FrameError::PayloadTooLong(data_len)))?;
+    payload.copy_from_slice(ciphertext);
+    let header = &secured[..HEADER_LEN];
+    FrameCipher::new(GenericArray::from_slice(key))
+        .decrypt_in_place_detached(
+            GenericArray::from_slice(&nonce(&mac, counter)),
+            header,
+            payload,
+            GenericArray::from_slice(mic),
+        )
+        .map_err(|_| OpenError::Forged)?;
+
+    let mut plain = frame.header;
+    plain.flags &= !flags::SECURED;
+    let len = Frame::new(plain, payload)
+        .encode(out)
+        .map_err(OpenError::Frame)?;
+    Ok((len, plain.source, counter))
+}
+
+/// Counter of the frames this node secures, persisted in blocks of
+/// [`COUNTER_BLOCK`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TxCounter {
+    next: u32,
+    /// First counter not covered by the stored reservation.
+    reserved: u32,
+}
+
+impl TxCounter {
+    /// Resumes counting from the stored reservation, `None` for a node that
+    /// never sent a secured frame.
+    pub fn resume(stored: Option<u32>) -> Self {
+        let next = stored.unwrap_or(0);
+        Self {
+            next,
+            reserved: next,
+        }
+    }
+
+    /// Returns the counter of the next frame, with the reservation to store
+    /// before the frame is sent when a new block starts.
+    pub fn take(&mut self) -> Option<(u32, Option<u32>)> {
+        let counter = self.next;
+        if counter == u32::MAX {
+            return None;
+        }
+        let reservation = (counter >= self.reserved).then(|| {
+            self.reserved = counter.saturating_add(COUNTER_BLOCK);
+            self.reserved
+        });
+        self.next += 1;
+        Some((counter, reservation))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sender {
+    node: NodeId,
+    /// Lowest counter accepted from it.
+    next: u32,
+    /// Lowest counter accepted after a reboot, as stored.
+    stored: u32,
+}
+
+/// Frame counters received from each sender.
+///
+/// Only frames whose MIC matched are recorded. Like the [`TxCounter`], a
+/// sender's counter is persisted once per [`COUNTER_BLOCK`]: the start of the
+/// block of its latest counter is stored, so after a reboot, or once the
+/// sender was forgotten to make room for another, only the frames of that
+/// block can be replayed. A node whose settings were erased counts from zero
+/// again, and is refused until the network key changes.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    senders: heapless::Vec<Sender, MAX_PEERS>,
+}
+
+impl ReplayGuard {
+    /// Whether the counters of `source` are known, otherwise they must be
+    /// resumed before the next one is accepted.
+    pub fn knows(&self, source: NodeId) -> bool {
+        self.senders.iter().any(|sender| sender.node == source)
+    }
+
+    /// Resumes the counters of `source` from the stored lowest counter, `None`
+    /// for a sender never heard.
+    pub fn resume(&mut self, source: NodeId, stored: Option<u32>) {
+        self.senders.retain(|sender| sender.node != source);
+        if self.senders.is_full() {
+            self.senders.remove(0);
+        }
+        let stored = stored.unwrap_or(0);
+        // Cannot fail, a slot was freed above.
+        let _ = self.senders.push(Sender {
+            node: source,
+            next: stored,
+            stored,
+        });
+    }
+
+    /// Records `counter` from `source`, returning the lowest counter to store
+    /// for it when a new block starts.
+    ///
+    /// # Errors
+    ///
+    /// * `Replayed` - If `counter` is not above the last one from `source`.
+    pub fn accept(&mut self, source: NodeId, counter: u32) -> Result<Option<u32>, OpenError> {
+        if !self.knows(source) {
+            self.resume(source, None);
+        }
+        let Some(sender) = self.senders.iter_mut().find(|sender| sender.node == source) else {
+            return Ok(None);
+        };
+        if counter < sender.next {
+            return Err(OpenError::Replayed {
+                counter,
+                latest: sender.next - 1,
+            });
+        }
+        sender.next = counter.saturating_add(1);
+        let block = counter - counter % COUNTER_BLOCK;
+        Ok((block > sender.stored).then(|| {
+            sender.stored = block;
+            block
+        }))
+    }
+}
+
+/// Frames received by a [`SecureRadio`], by outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SecurityStats {
+    pub accepted: u32,
+    /// Frames that could not be decoded.
+    pub malformed: u32,
+    pub unsecured: u32,
+    pub forged: u32,
+    pub replayed: u32,
+}
+
+impl SecurityStats {
+    /// Frames rejected for any reason.
+    pub fn rejected(&self) -> u32 {
+        self.malformed + self.unsecured + self.forged + self.replayed
+    }
+
+    fn count<T>(&mut self, result: &Result<T, OpenError>) {
+        let counter = match result {
+            Ok(_) => &mut self.accepted,
+            Err(OpenError::Frame(_)) | Err(OpenError::Truncated(_)) => &mut self.malformed,
+            Err(OpenError::Unsecured) => &mut self.unsecured,
+            Err(OpenError::Forged) | Err(OpenError::Spoofed(_)) => &mut self.forged,
+            Err(OpenError::Replayed { .. }) => &mut self.replayed,
+        };
+        *counter = counter.wrapping_add(1);
+    }
+}
+
+/// Radio that secures the frames of the P2P link of `node`.
+///
+/// Frames exchanged with a paired node are secured with the link key agreed
+/// with it, the other frames with the network key; `Pairing` frames always
+/// use the network key, so nodes can pair again. Every transmitted frame is
+/// sealed with a new counter.
+///
+/// Received frames are only passed on when they are secured with the right
+/// key and carry a counter above the last one of their sender; the others
+/// are dropped and counted in the [`SecurityStats`]. Frames for other nodes
+/// are dropped without being checked.
+pub struct SecureRadio<'a, R, S> {
+    radio: R,
+    mac: [u8; SENDER_MAC_LEN],
+    node: NodeId,
+    key: [u8; 16],
+    /// Link keys read from the settings, `None` for nodes never paired.
+    links: heapless::Vec<(NodeId, Option<[u8; 16]>), MAX_PEERS>,
+    counter: TxCounter,
+    settings: &'a Mutex<CriticalSectionRawMutex, KvStore<S>>,
+    replay: ReplayGuard,
+    stats: SecurityStats,
+    frame: [u8; MAX_FRAME_LEN],
+}
+
+impl<'a, R: P2pRadio, S: Storage> SecureRadio<'a, R, S> {
+    /// Secures `radio` of the node with MAC address `mac` with the network
+    /// `key`, resuming the frame counter stored in `settings`.
+    pub async fn new(
+        radio: R,
+        mac: [u8; SENDER_MAC_LEN],
+        key: [u8; 16],
+        settings: &'a Mutex<CriticalSectionRawMutex, KvStore<S>>,
+    ) -> Result<Self, SecureError<R::Error>> {
+        let stored = settings
+            .lock()
+            .await
+            .get(COUNTER_KEY)
+            .map_err(|_| SecureError::Storage)?;
+        Ok(Self {
+            radio,
+            mac,
+            node: p2p_frame::node_id(&mac),
+            key,
+            links: heapless::Vec::new(),
+            counter: TxCounter::resume(stored),
+            settings,
+            replay: ReplayGuard::default(),
+            stats: SecurityStats::default(),
+            frame: [0; MAX_FRAME_LEN],
+        })
+    }
+
+    pub fn stats(&self) -> SecurityStats {
+        self.stats
+    }
+
+    pub fn radio(&mut self) -> &mut R {
+        &mut self.radio
+    }
+
+    /// Stores the link key agreed with `peer` and secures the frames
+    /// exchanged with it from now on.
+    pub async fn store_link_key(
+        &mut self,
+        peer: NodeId,
+        key: [u8; 16],
+    ) -> Result<(), SecureError<R::Error>> {
+        let forgotten = write_link_key(&mut *self.settings.lock().await, peer, &key)
+            .map_err(|_| SecureError::Storage)?;
+        if let Some(forgotten) = forgotten {
+            self.cache_link(forgotten, None);
+        }
+        self.cache_link(peer, Some(key));
+        Ok(())
+    }
+
+    /// Forgets the link key agreed with `peer` and secures the frames
+    /// exchanged with it with the network key from now on.
+    pub async fn forget_link_key(&mut self, peer: NodeId) -> Result<(), SecureError<R::Error>> {
+        remove_link_key(&mut *self.settings.lock().await, peer)
+            .map_err(|_| SecureError::Storage)?;
+        self.cache_link(peer, None);
+        Ok(())
+    }
+
+    /// Nodes this node is paired with, oldest first.
+    pub async fn paired(
+        &mut self,
+    ) -> Result<heapless::Vec<NodeId, MAX_PAIRED>, SecureError<R::Error>> {
+        read_paired(&mut *self.settings.lock().await).map_err(|_| SecureError::Storage)
+    }
+
+    /// The link key agreed with `peer`, `None` if it is not paired.
+    pub async fn link_key(
+        &mut self,
+        peer: NodeId,
+    ) -> Result<Option<[u8; 16]>, SecureError<R::Error>> {
+        if let Some((_, link)) = self.links.iter().find(|(node, _)| *node == peer) {
+            return Ok(*link);
+        }
+        let link = read_link_key(&mut *self.settings.lock().await, peer)
+            .map_err(|_| SecureError::Storage)?;
+        self.cache_link(peer, link);
+        Ok(link)
+    }
+
+    fn cache_link(&mut self, peer: NodeId, key: Option<[u8; 16]>) {
+        match self.links.iter_mut().find(|(node, _)| *node == peer) {
+            Some(link) => link.1 = key,
+            None => {
+                if self.links.is_full() {
+                    self.links.remove(0);
+                }
+                // Cannot fail, a slot was freed above.
+                let _ = self.links.push((peer, key));
+            }
+        }
+    }
+
+    /// Key of a frame sent or received by this node.
+    async fn key_for(&mut self, header: &Header) -> Result<[u8; 16], SecureError<R::Error>> {
+        if header.kind == MessageType::Pairing || header.destination == BROADCAST {
+            return Ok(self.key);
+        }
+        let peer = if header.source == self.node {
+            header.destination
+        } else {
+            header.source
+        };
+        Ok(self.link_key(peer).await?.unwrap_or(self.key))
+    }
+
+    /// Checks `counter` from `source` against replays, reading its stored
+    /// counter when the sender is not known and storing a new block.
+    async fn accept(
+        &mut self,
+        source: NodeId,
+        counter: u32,
+    ) -> Result<Result<(), OpenError>, SecureError<R::Error>> {
+        let name = peer_key_name(REPLAY_KEY_PREFIX, source);
+        if !self.replay.knows(source) {
+            let stored = self
+                .settings
+                .lock()
+                .await
+                .get(&name)
+                .map_err(|_| SecureError::Storage)?;
+            self.replay.resume(source, stored);
+        }
+        let stored = match self.replay.accept(source, counter) {
+            Ok(Some(stored)) => stored,
+            Ok(None) => return Ok(Ok(())),
+            Err(e) => return Ok(Err(e)),
+        };
+        self.settings
+            .lock()
+            .await
+            .set(&name, &stored)
+            .map_err(|_| SecureError::Storage)?;
+        Ok(Ok(()))
+    }
+}
+
+impl<R: P2pRadio, S: Storage> P2pRadio for SecureRadio<'_, R, S> {
+    type Error = SecureError<R::Error>;
+
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), Self::Error> {
+        let header = Frame::decode(frame).map_err(SecureError::Frame)?.header;
+        let key = self.key_for(&header).await?;
+        let (counter, reservation) = self.counter.take().ok_or(SecureError::CounterExhausted)?;
+        if let Some(reserved) = reservation {
+            self.settings
+                .lock()
+                .await
+                .set(COUNTER_KEY, &reserved)
+                .map_err(|_| SecureError::Storage)?;
+        }
+        let len =
+            seal(&key, &self.mac, counter, frame, &mut self.frame).map_err(SecureError::Frame)?;
+        self.radio
+            .transmit(&self.frame[..len])
+            .await
+            .map_err(SecureError::Radio)
+    }
+
+    async fn receive(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, Self::Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            let Some(len) = self
+                .radio
+                .receive(&mut self.frame, timeout)
+                .await
+                .map_err(SecureError::Radio)?
+            else {
+                return Ok(None);
+            };
+            let key = match Frame::decode(&self.frame[..len]).map(|frame| frame.header) {
+                Ok(header) if !header.is_for(self.node) => continue,
+                Ok(header) => self.key_for(&header).await?,
+                // Rejected as malformed by `open`.
+                Err(_) => self.key,
+            };
+            let result = match open(&key, &self.frame[..len], buffer) {
+                Ok((len, source, counter)) => self.accept(source, counter).await?.map(|()| len),
+                Err(e) => Err(e),
+            };
+            self.stats.count(&result);
+            match result {
+                Ok(len) => return Ok(Some(len)),
+                Err(e) => esp_println::println!("[LoRa P2P] Dropped frame: {}", e),
+            }
+        }
+    }
+
+    fn rssi(&self) -> Option<i16> {
+        self.radio.rssi()
+    }
+
+    fn snr(&self) -> Option<i16> {
+        self.radio.snr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+    use embassy_futures::block_on;
+
+    use crate::devices::{kv::SECTOR_SIZE, nvs::PAGE_SIZE, test_storage::MemStorage};
+
+    const KEY: [u8; 16] = [
+        0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4A, 0x4B, 0x4C, 0x4D, 0x4E,
+        0x4F,
+    ];
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn frame(source: NodeId, destination: NodeId, seq: u16, payload: &[u8]) -> Vec<u8> {
+        let header = Header {
+            kind: MessageType::Data,
+            source,
+            destination,
+            seq,
+            flags: 0,
+        };
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = Frame::new(header, payload).encode(&mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    /// MAC address of the node `node`.
+    fn mac(node: NodeId) -> [u8; SENDER_MAC_LEN] {
+        let [high, low] = node.to_be_bytes();
+        [0x24, 0x0A, 0xC4, 0x00, high, low]
+    }
+
+    fn sealed(key: &[u8; 16], counter: u32, plain: &[u8]) -> Vec<u8> {
+        let source = Frame::decode(plain).unwrap().header.source;
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = seal(key, &mac(source), counter, plain, &mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    /// Replaces the CRC of `frame`, so only the MIC can reject a change.
+    fn fix_crc(frame: &mut [u8]) {
+        let end = frame.len() - CRC_LEN;
+        let crc = crc16_ccitt(&frame[..end]);
+        frame[end..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn encrypts_rfc_3610_vectors() {
+        // Packet vectors #1 and #2: 8-byte MIC, 13-byte nonce.
+        let vectors = [
+            (
+                "00000003020100A0A1A2A3A4A5",
+                "08090A0B0C0D0E0F101112131415161718191A1B1C1D1E",
+                "588C979A61C663D2F066D0C2C0F989806D5F6B61DAC384",
+                "17E8D12CFDF926E0",
+            ),
+            (
+                "00000004030201A0A1A2A3A4A5",
+                "08090A0B0C0D0E0F101112131415161718191A1B1C1D1E1F",
+                "72C91A36E135F8CF291CA894085C87E3CC15C439C9E43A3B",
+                "A091D56E10400916",
+            ),
+        ];
+        let key = hex("C0C1C2C3C4C5C6C7C8C9CACBCCCDCECF");
+        let cipher = FrameCipher::new(GenericArray::from_slice(&key));
+        for (nonce, plain, encrypted, mic) in vectors {
+            let mut data = hex(plain);
+            let tag = cipher
+                .encrypt_in_place_detached(
+                    GenericArray::from_slice(&hex(nonce)),
+                    &hex("0001020304050607"),
+                    &mut data,
+                )
+                .unwrap();
+            assert_eq!(data, hex(encrypted));
+            assert_eq!(tag.to_vec(), hex(mic));
+        }
+    }
+
+    #[test]
+    fn seals_and_opens_frames() {
+        let header = Header {
+            kind: MessageType::Data,
+            source: 0x1234,
+            destination: BROADCAST,
+            seq: 7,
+            flags: flags::ACK_REQUEST,
+        };
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = Frame::new(header, b"hello p2p")
+            .encode(&mut buffer)
+            .unwrap();
+        let plain = &buffer[..len];
+        let secured = sealed(&KEY, 0x0102_0304, plain);
+        // Checked against the AES-CCM of the Python cryptography package.
+        let expected =
+            hex("103412ffff0700031b2e4fe30cb5fa3a0ec904030201240ac40012349b70e78da5f46250");
+        assert_eq!(&secured[..expected.len()], &expected[..]);
+        assert!(Frame::decode(&secured).is_ok());
+
+        let mut opened = [0u8; MAX_FRAME_LEN];
+        let result = open(&KEY, &secured, &mut opened);
+        assert_eq!(result, Ok((plain.len(), 0x1234, 0x0102_0304)));
+        assert_eq!(&opened[..plain.len()], plain);
+
+        // The largest payload still fits in a radio frame.
+        let plain = frame(1, 2, 1, &[9; MAX_PAYLOAD_LEN]);
+        let secured = sealed(&KEY, 0, &plain);
+        assert_eq!(secured.len(), MAX_FRAME_LEN);
+        assert_eq!(open(&KEY, &secured, &mut opened), Ok((plain.len(), 1, 0)));
+    }
+
+    #[test]
+    fn rejects_forged_frames() {
+        let secured = sealed(&KEY, 42, &frame(1, 2, 3, b"payload"));
+        let mut opened = [0u8; MAX_FRAME_LEN];
+        // The version, flags and length bytes fail to decode instead. The
+        // source and the node ID in the sender MAC must agree.
+        let node_id_at = secured.len() - CRC_LEN - MIC_LEN - 2;
+        for i in (1..7).chain(HEADER_LEN..secured.len() - CRC_LEN) {
+            for bit in 0..8 {
+                let mut forged = secured.clone();
+                forged[i] ^= 1 << bit;
+                fix_crc(&mut forged);
+                let result = open(&KEY, &forged, &mut opened);
+                let expected = if (1..3).contains(&i) || (node_id_at..node_id_at + 2).contains(&i) {
+                    OpenError::Spoofed(Frame::decode(&forged).unwrap().header.source)
+                } else {
+                    OpenError::Forged
+                };
+                assert_eq!(result, Err(expected), "byte {} bit {}", i, bit);
+            }
+        }
+        let mut other = KEY;
+        other[0] ^= 1;
+        assert_eq!(open(&other, &secured, &mut opened), Err(OpenError::Forged));
+    }
+
+    #[test]
+    fn nodes_sharing_an_id_use_their_own_nonces() {
+        let plain = frame(0x1234, 2, 3, b"payload");
+        let mut first = [0u8; MAX_FRAME_LEN];
+        let len = seal(&KEY, &mac(0x1234), 42, &plain, &mut first).unwrap();
+        let mut other = mac(0x1234);
+        other[3] = 0x01;
+        let mut second = [0u8; MAX_FRAME_LEN];
+        assert_eq!(seal(&KEY, &other, 42, &plain, &mut second), Ok(len));
+        // Same header, counter and key, but another keystream.
+        assert_ne!(first[HEADER_LEN..len], second[HEADER_LEN..len]);
+
+        let mut opened = [0u8; MAX_FRAME_LEN];
+        let result = open(&KEY, &second[..len], &mut opened);
+        assert_eq!(result, Ok((plain.len(), 0x1234, 42)));
+
+        // A MAC of another node cannot be claimed.
+        let mut spoofed = [0u8; MAX_FRAME_LEN];
+        let len = seal(&KEY, &mac(0x4321), 42, &plain, &mut spoofed).unwrap();
+        let result = open(&KEY, &spoofed[..len], &mut opened);
+        assert_eq!(result, Err(OpenError::Spoofed(0x1234)));
+    }
+
+    #[test]
+    fn rejects_truncated_and_unsecured_frames() {
+        let mut opened = [0u8; MAX_FRAME_LEN];
+        let plain = frame(1, 2, 3, b"payload");
+        assert_eq!(open(&KEY, &plain, &mut opened), Err(OpenError::Unsecured));
+
+        let secured = sealed(&KEY, 42, &plain);
+        let result = open(&KEY, &secured[..secured.len() - 1], &mut opened);
+        assert!(matches!(result, Err(OpenError::Frame(_))));
+
+        let header = Header {
+            kind: MessageType::Data,
+            source: 1,
+            destination: 2,
+            seq: 3,
+            flags: flags::SECURED,
+        };
+        let mut short = [0u8; MAX_FRAME_LEN];
+        let len = Frame::new(header, &[0; SECURITY_LEN - 1])
+            .encode(&mut short)
+            .unwrap();
+        let result = open(&KEY, &short[..len], &mut opened);
+        assert_eq!(result, Err(OpenError::Truncated(SECURITY_LEN - 1)));
+    }
+
+    #[test]
+    fn reserves_counter_blocks() {
+        let mut counter = TxCounter::resume(None);
+        assert_eq!(counter.take(), Some((0, Some(COUNTER_BLOCK))));
+        for i in 1..COUNTER_BLOCK {
+            assert_eq!(counter.take(), Some((i, None)));
+        }
+        let next = Some((COUNTER_BLOCK, Some(2 * COUNTER_BLOCK)));
+        assert_eq!(counter.take(), next);
+
+        let mut counter = TxCounter::resume(Some(512));
+        assert_eq!(counter.take(), Some((512, Some(768))));
+        let mut counter = TxCounter::resume(Some(u32::MAX - 1));
+        assert_eq!(counter.take(), Some((u32::MAX - 1, Some(u32::MAX))));
+        assert_eq!(counter.take(), None);
+    }
+
+    #[test]
+    fn rejects_replayed_counters() {
+        let mut guard = ReplayGuard::default();
+        assert_eq!(guard.accept(1, 5), Ok(None));
+        let replayed = OpenError::Replayed {
+            counter: 5,
+            latest: 5,
+        };
+        assert_eq!(guard.accept(1, 5), Err(replayed));
+        let replayed = OpenError::Replayed {
+            counter: 3,
+            latest: 5,
+        };
+        assert_eq!(guard.accept(1, 3), Err(replayed));
+        assert_eq!(guard.accept(2, 0), Ok(None));
+        assert_eq!(guard.accept(1, 9), Ok(None));
+        // A new block is stored once.
+        assert_eq!(guard.accept(1, 300), Ok(Some(COUNTER_BLOCK)));
+        assert_eq!(guard.accept(1, 301), Ok(None));
+
+        // After a reboot the stored block is the limit.
+        let mut guard = ReplayGuard::default();
+        assert!(!guard.knows(1));
+        guard.resume(1, Some(COUNTER_BLOCK));
+        assert!(guard.knows(1));
+        let replayed = OpenError::Replayed {
+            counter: 9,
+            latest: COUNTER_BLOCK - 1,
+        };
+        assert_eq!(guard.accept(1, 9), Err(replayed));
+        assert_eq!(guard.accept(1, 290), Ok(None));
+    }
+
+    #[test]
+    fn reads_provisioned_network_keys() {
+        let mut nvs = Nvs::new(MemStorage::new(3 * PAGE_SIZE)).unwrap();
+        assert_eq!(read_network_key(&mut nvs), Ok(None));
+        assert_eq!(load_network_key(&mut nvs), None);
+        write_network_key(&mut nvs, &KEY).unwrap();
+        assert_eq!(read_network_key(&mut nvs), Ok(Some(KEY)));
+        assert_eq!(load_network_key(&mut nvs), Some(KEY));
+
+        write_network_key(&mut nvs, &[0; 16]).unwrap();
+        assert_eq!(load_network_key(&mut nvs), None);
+        nvs.set_blob(P2P_NAMESPACE, NETWORK_KEY_KEY, &[1; 8])
+            .unwrap();
+        let short = NvsError::BlobSize {
+            expected: 16,
+            found: 8,
+        };
+        assert_eq!(read_network_key(&mut nvs), Err(short));
+    }
+
+    /// Radio whose frames go out into `sent` and come in from `inbox`.
+    struct Loopback {
+        sent: Rc<RefCell<Vec<Vec<u8>>>>,
+        inbox: Rc<RefCell<VecDeque<Vec<u8>>>>,
+    }
+
+    impl P2pRadio for Loopback {
+        type Error = ();
+
+        async fn transmit(&mut self, frame: &[u8]) -> Result<(), ()> {
+            self.sent.borrow_mut().push(frame.to_vec());
+            Ok(())
+        }
+
+        async fn receive(&mut self, buffer: &mut [u8], _: Duration) -> Result<Option<usize>, ()> {
+            let Some(frame) = self.inbox.borrow_mut().pop_front() else {
+                return Ok(None);
+            };
+            buffer[..frame.len()].copy_from_slice(&frame);
+            Ok(Some(frame.len()))
+        }
+    }
+
+    type Settings = Mutex<CriticalSectionRawMutex, KvStore<MemStorage>>;
+
+    fn settings() -> Settings {
+        Mutex::new(KvStore::mount(MemStorage::new(2 * SECTOR_SIZE)).unwrap())
+    }
+
+    #[test]
+    fn resumes_counters_after_a_reboot() {
+        let (sender_settings, receiver_settings) = (settings(), settings());
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let inbox = Rc::new(RefCell::new(VecDeque::new()));
+        let loopback = || Loopback {
+            sent: sent.clone(),
+            inbox: inbox.clone(),
+        };
+        block_on(async {
+            let mut sender = SecureRadio::new(loopback(), mac(1), KEY, &sender_settings)
+                .await
+                .unwrap();
+            for seq in 0..300 {
+                sender.transmit(&frame(1, 2, seq, b"data")).await.unwrap();
+            }
+            let stored = sender_settings.lock().await.get::<u32>(COUNTER_KEY);
+            assert_eq!(stored, Ok(Some(2 * COUNTER_BLOCK)));
+
+            // A rebooted sender skips the rest of its block.
+            let mut sender = SecureRadio::new(loopback(), mac(1), KEY, &sender_settings)
+                .await
+                .unwrap();
+            sender.transmit(&frame(1, 2, 300, b"data")).await.unwrap();
+            let frames = sent.borrow().clone();
+            let last = open(&KEY, frames.last().unwrap(), &mut [0; MAX_FRAME_LEN]);
+            assert_eq!(last.map(|(_, _, counter)| counter), Ok(2 * COUNTER_BLOCK));
+
+            let mut receiver = SecureRadio::new(loopback(), mac(2), KEY, &receiver_settings)
+                .await
+                .unwrap();
+            inbox.borrow_mut().extend(frames.iter().cloned());
+            let mut buffer = [0u8; MAX_FRAME_LEN];
+            while let Ok(Some(_)) = receiver.receive(&mut buffer, Duration::from_secs(1)).await {}
+            assert_eq!(receiver.stats().accepted, 301);
+            let name = peer_key_name(REPLAY_KEY_PREFIX, 1);
+            let stored = receiver_settings.lock().await.get::<u32>(&name);
+            assert_eq!(stored, Ok(Some(2 * COUNTER_BLOCK)));
+
+            // A rebooted receiver still refuses the frames before the last
+            // stored block.
+            let mut receiver = SecureRadio::new(loopback(), mac(2), KEY, &receiver_settings)
+                .await
+                .unwrap();
+            inbox.borrow_mut().extend(frames[..300].iter().cloned());
+            let received = receiver.receive(&mut buffer, Duration::from_secs(1)).await;
+            assert_eq!(received, Ok(None));
+            assert_eq!(receiver.stats().replayed, 300);
+        });
+    }
+
+    #[test]
+    fn keeps_frames_to_paired_nodes_apart() {
+        let (first, second) = (settings(), settings());
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let inbox = Rc::new(RefCell::new(VecDeque::new()));
+        let loopback = || Loopback {
+            sent: sent.clone(),
+            inbox: inbox.clone(),
+        };
+        block_on(async {
+            let mut sender = SecureRadio::new(loopback(), mac(1), KEY, &first)
+                .await
+                .unwrap();
+            let mut receiver = SecureRadio::new(loopback(), mac(2), KEY, &second)
+                .await
+                .unwrap();
+            sender.store_link_key(2, [0x55; 16]).await.unwrap();
+            sender.transmit(&frame(1, 2, 1, b"x")).await.unwrap();
+            sender
+                .transmit(&frame(1, BROADCAST, 2, b"y"))
+                .await
+                .unwrap();
+            inbox.borrow_mut().extend(sent.borrow_mut().drain(..));
+            let mut buffer = [0u8; MAX_FRAME_LEN];
+            let received = receiver.receive(&mut buffer, Duration::from_secs(1)).await;
+            // The unicast frame needs the link key, the broadcast does not.
+            let len = received.unwrap().unwrap();
+            assert_eq!(Frame::decode(&buffer[..len]).unwrap().payload, b"y");
+            assert_eq!(receiver.stats().forged, 1);
+
+            receiver.store_link_key(1, [0x55; 16]).await.unwrap();
+            sender.transmit(&frame(1, 2, 3, b"z")).await.unwrap();
+            inbox.borrow_mut().extend(sent.borrow_mut().drain(..));
+            let received = receiver.receive(&mut buffer, Duration::from_secs(1)).await;
+            let len = received.unwrap().unwrap();
+            assert_eq!(Frame::decode(&buffer[..len]).unwrap().payload, b"z");
+            assert_eq!(
+                read_link_key(&mut *second.lock().await, 1),
+                Ok(Some([0x55; 16]))
+            );
+        });
+    }
+
+    #[test]
+    fn forgets_the_oldest_paired_node() {
+        let settings = settings();
+        block_on(async {
+            let mut store = settings.lock().await;
+            for peer in 0..MAX_PAIRED as NodeId {
+                assert_eq!(
+                    write_link_key(&mut store, peer, &[peer as u8; 16]),
+                    Ok(None)
+                );
+            }
+            // Pairing again moves a node to the back.
+            assert_eq!(write_link_key(&mut store, 0, &[0x77; 16]), Ok(None));
+            let evicted = write_link_key(&mut store, 100, &[100; 16]);
+            assert_eq!(evicted, Ok(Some(1)));
+            assert_eq!(read_link_key(&mut store, 1), Ok(None));
+            assert_eq!(read_link_key(&mut store, 0), Ok(Some([0x77; 16])));
+
+            remove_link_key(&mut store, 0).unwrap();
+            assert_eq!(read_link_key(&mut store, 0), Ok(None));
+            let paired = read_paired(&mut store).unwrap();
+            let expected: Vec<NodeId> = (2..MAX_PAIRED as NodeId).chain([100]).collect();
+            assert_eq!(paired[..], expected[..]);
+        });
+    }
+}
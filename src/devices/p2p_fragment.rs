@@ -0,0 +1,656 @@
+use embassy_time::Duration;
+
+use super::{
+    p2p_arq::{Arq, ArqError, P2pRadio, Received},
+    p2p_frame::{flags, Header, MessageType, NodeId, BROADCAST, MAX_PAYLOAD_LEN},
+};
+
+pub const FRAGMENT_HEADER_LEN: usize = 4;
+/// Message bytes carried by every fragment but the last.
+pub const FRAGMENT_DATA_LEN: usize = MAX_PAYLOAD_LEN - FRAGMENT_HEADER_LEN;
+/// Largest number of fragments of a message, the range of the index.
+pub const MAX_FRAGMENTS: usize = 255;
+/// Largest message the fragment header can describe.
+pub const MAX_MESSAGE_LEN: usize = MAX_FRAGMENTS * FRAGMENT_DATA_LEN;
+/// Time after the last fragment of a message before its buffer is freed;
+/// past it, a fragment with the same ID starts a new message.
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
+
+const BITMAP_LEN: usize = MAX_FRAGMENTS.div_ceil(8);
+
+#[derive(Debug, PartialEq)]
+pub enum FragmentError<E> {
+    TooLong(usize),
+    Empty,
+    Truncated(usize),
+    /// The header does not describe a fragment of a valid message.
+    Invalid,
+    /// Every reassembly buffer holds an incomplete message.
+    Busy,
+    /// The receiver still missed fragments after every round.
+    Timeout {
+        missing: usize,
+    },
+    Arq(ArqError<E>),
+}
+
+impl<E: core::fmt::Debug> core::fmt::Display for FragmentError<E> {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FragmentError::TooLong(len) => write!(f, "Message of {} bytes is too long", len),
+            FragmentError::Empty => write!(f, "Message is empty"),
+            FragmentError::Truncated(len) => write!(f, "Fragment of {} bytes is truncated", len),
+            FragmentError::Invalid => write!(f, "Invalid fragment header"),
+            FragmentError::Busy => write!(f, "No free reassembly buffer"),
+            FragmentError::Timeout { missing } => {
+                write!(f, "Receiver still misses {} fragments", missing)
+            }
+            FragmentError::Arq(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl<E> From<ArqError<E>> for FragmentError<E> {
+    fn from(e: ArqError<E>) -> Self {
+        FragmentError::Arq(e)
+    }
+}
+
+/// Number of fragments of a message of `len` bytes.
+pub fn fragment_count(len: usize) -> usize {
+    len.div_ceil(FRAGMENT_DATA_LEN)
+}
+
+/// Header of a `Fragment` frame's payload, followed by the message bytes of
+/// the fragment.
+///
+/// | Offset | Size | Field                   |
+/// |--------|------|-------------------------|
+/// | 0      | 1    | Message ID              |
+/// | 1      | 1    | Fragment index          |
+/// | 2      | 2    | Message length (LE)     |
+///
+/// Fragment `i` holds the message bytes from `i * FRAGMENT_DATA_LEN`; every
+/// fragment but the last is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentHeader {
+    pub message_id: u8,
+    pub index: u8,
+    pub message_len: u16,
+}
+
+impl FragmentHeader {
+    pub fn encode(&self) -> [u8; FRAGMENT_HEADER_LEN] {
+        let len = self.message_len.to_le_bytes();
+        [self.message_id, self.index, len[0], len[1]]
+    }
+
+    /// Splits a fragment into its header and message bytes, checking they
+    /// are consistent.
+    ///
+    /// # Errors
+    ///
+    /// * `Truncated` - If `payload` is shorter than the header.
+    /// * `Invalid` - If the message is empty or too long, the index is past
+    ///   the last fragment or the fragment has the wrong length.
+    pub fn decode<E>(payload: &[u8]) -> Result<(Self, &[u8]), FragmentError<E>> {
+        if payload.len() < FRAGMENT_HEADER_LEN {
+            return Err(FragmentError::Truncated(payload.len()));
+        }
+        let (header, data) = payload.split_at(FRAGMENT_HEADER_LEN);
+        let header = Self {
+            message_id: header[0],
+            index: header[1],
+            message_len: u16::from_le_bytes([header[2], header[3]]),
+        };
+        let range = header.range().ok_or(FragmentError::Invalid)?;
+        if data.len() != range.len() {
+            return Err(FragmentError::Invalid);
+        }
+        Ok((header, data))
+    }
+
+    /// Bytes of the message the fragment holds.
+    fn range(&self) -> Option<core::ops::Range<usize>> {
+        let len = self.message_len as usize;
+        let start = self.index as usize * FRAGMENT_DATA_LEN;
+        (len > 0 && len <= MAX_MESSAGE_LEN && start < len)
+            .then(|| start..len.min(start + FRAGMENT_DATA_LEN))
+    }
+}
+
+/// Set of fragment indexes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentSet([u8; BITMAP_LEN]);
+
+impl FragmentSet {
+    pub fn empty() -> Self {
+        Self([0; BITMAP_LEN])
+    }
+
+    /// Fragments `0..count`.
+    pub fn first(count: usize) -> Self {
+        let mut set = Self::empty();
+        for index in 0..count.min(MAX_FRAGMENTS) {
+            set.insert(index as u8);
+        }
+        set
+    }
+
+    pub fn insert(&mut self, index: u8) {
+        self.0[index as usize / 8] |= 1 << (index % 8);
+    }
+
+    pub fn contains(&self, index: u8) -> bool {
+        self.0[index as usize / 8] & (1 << (index % 8)) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|byte| *byte == 0)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..MAX_FRAGMENTS as u8).filter(|index| self.contains(*index))
+    }
+
+    /// Set of the indexes below `count` not in this set.
+    fn complement(&self, count: usize) -> Self {
+        let mut set = Self::empty();
+        for index in (0..count.min(MAX_FRAGMENTS) as u8).filter(|i| !self.contains(*i)) {
+            set.insert(index);
+        }
+        set
+    }
+}
+
+/// Payload of a `FragmentStatus` frame: the message ID followed by the
+/// bitmap of missing fragments, bit `i % 8` of byte `i / 8` for fragment
+/// `i`. Trailing zero bytes are left out, so a message received in full has
+/// an empty bitmap.
+pub fn encode_status(message_id: u8, missing: &FragmentSet, buffer: &mut [u8]) -> usize {
+    let used = missing
+        .0
+        .iter()
+        .rposition(|byte| *byte != 0)
+        .map_or(0, |last| last + 1);
+    buffer[0] = message_id;
+    buffer[1..1 + used].copy_from_slice(&missing.0[..used]);
+    1 + used
+}
+
+/// Decodes a `FragmentStatus` payload into the message ID and the missing
+/// fragments.
+pub fn decode_status(payload: &[u8]) -> Option<(u8, FragmentSet)> {
+    let (&message_id, bitmap) = payload.split_first()?;
+    let mut missing = FragmentSet::empty();
+    missing.0.get_mut(..bitmap.len())?.copy_from_slice(bitmap);
+    Some((message_id, missing))
+}
+
+struct Session<const N: usize> {
+    source: NodeId,
+    message_id: u8,
+    message_len: u16,
+    received: FragmentSet,
+    last_ms: u64,
+    complete: bool,
+    data: [u8; N],
+}
+
+impl<const N: usize> Session<N> {
+    fn timed_out(&self, now_ms: u64) -> bool {
+        now_ms.saturating_sub(self.last_ms) >= REASSEMBLY_TIMEOUT_MS
+    }
+}
+
+/// Reassembles messages of up to `N` bytes from fragments arriving in any
+/// order, `S` messages at a time.
+///
+/// A complete message keeps its buffer, so repeated status requests for it
+/// are answered, until the buffer is needed for another message or
+/// [`REASSEMBLY_TIMEOUT_MS`] passed without a fragment of it; senders reuse
+/// message IDs after that.
+pub struct Reassembler<const N: usize, const S: usize> {
+    sessions: [Option<Session<N>>; S],
+}
+
+impl<const N: usize, const S: usize> Default for Reassembler<N, S> {
+    fn default() -> Self {
+        Self {
+            sessions: core::array::from_fn(|_| None),
+        }
+    }
+}
+
+impl<const N: usize, const S: usize> Reassembler<N, S> {
+    fn find(&self, source: NodeId, message_id: u8) -> Option<usize> {
+        self.sessions.iter().position(|session| {
+            matches!(session, Some(s) if s.source == source && s.message_id == message_id)
+        })
+    }
+
+    /// Stores a fragment received from `source` at `now_ms`, returning the
+    /// message length once the fragment completed it.
+    ///
+    /// # Errors
+    ///
+    /// * `Truncated`, `Invalid` - If the fragment is malformed.
+    /// * `TooLong` - If the message does not fit in a buffer.
+    /// * `Busy` - If every buffer holds an incomplete message that has not
+    ///   timed out.
+    pub fn push<E>(
+        &mut self,
+        source: NodeId,
+        payload: &[u8],
+        now_ms: u64,
+    ) -> Result<Option<usize>, FragmentError<E>> {
+        let (header, data) = FragmentHeader::decode(payload)?;
+        let len = header.message_len as usize;
+        if len > N {
+            return Err(FragmentError::TooLong(len));
+        }
+        let index = match self.find(source, header.message_id) {
+            // A reused message ID with another length, or once the
+            // message timed out, is a new message.
+            Some(index)
+                if self.sessions[index].as_ref().is_some_and(|s| {
+                    s.message_len == header.message_len && !s.timed_out(now_ms)
+                }) =>
+            {
+                index
+            }
+            found => {
+                let index = found.or_else(|| self.free_slot(now_ms));
+                let index = index.ok_or(FragmentError::Busy)?;
+                self.sessions[index] = Some(Session {
+                    source,
+                    message_id: header.message_id,
+                    message_len: header.message_len,
+                    received: FragmentSet::empty(),
+                    last_ms: now_ms,
+                    complete: false,
+                    data: [0; N],
+                });
+                index
+            }
+        };
+        let Some(session) = self.sessions[index].as_mut() else {
+            return Ok(None);
+        };
+        session.last_ms = now_ms;
+        // A repeated fragment of a message already delivered.
+        if session.complete {
+            return Ok(None);
+        }
+        if let Some(range) = header.range() {
+            session.data[range].copy_from_slice(data);
+        }
+        session.received.insert(header.index);
+        session.complete = session.received.len() == fragment_count(len);
+        Ok(session.complete.then_some(len))
+    }
+
+    /// A free buffer, a complete message's or one that timed out otherwise.
+    fn free_slot(&self, now_ms: u64) -> Option<usize> {
+        let expired = |s: &Session<N>| s.complete || s.timed_out(now_ms);
+        self.sessions.iter().position(Option::is_none).or_else(|| {
+            self.sessions
+                .iter()
+                .position(|session| session.as_ref().is_some_and(expired))
+        })
+    }
+
+    /// The complete message `message_id` from `source`.
+    pub fn message(&self, source: NodeId, message_id: u8) -> Option<&[u8]> {
+        let session = self.sessions[self.find(source, message_id)?].as_ref()?;
+        session
+            .complete
+            .then(|| &session.data[..session.message_len as usize])
+    }
+
+    /// Fragments of message `message_id` from `source` still missing, `None`
+    /// if it is not being reassembled.
+    pub fn missing(&self, source: NodeId, message_id: u8) -> Option<FragmentSet> {
+        let session = self.sessions[self.find(source, message_id)?].as_ref()?;
+        let count = fragment_count(session.message_len as usize);
+        Some(session.received.complement(count))
+    }
+}
+
+/// Rounds of [`send_message`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FragmentConfig {
+    /// Rounds of retransmissions after the first burst.
+    pub retries: u8,
+    /// Time to wait for the receiver's status after a burst.
+    pub status_timeout: Duration,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        Self {
+            retries: 5,
+            status_timeout: Duration::from_millis(3000),
+        }
+    }
+}
+
+/// Sends `data` to `destination` as message `message_id`, in fragments.
+///
+/// All fragments go out in a burst whose last fragment requests a
+/// `FragmentStatus`; then only the fragments the receiver reports missing
+/// are sent again. When no status arrives, the last missing fragment alone is
+/// sent again to ask for it.
+///
+/// # Errors
+///
+/// * `Empty`, `TooLong` - If `data` cannot be sent as a message.
+/// * `Arq` - With `Broadcast` if `destination` is [`BROADCAST`], or when the
+///   radio failed.
+/// * `Timeout` - If fragments were still missing after every round.
+pub async fn send_message<R: P2pRadio>(
+    arq: &mut Arq<R>,
+    destination: NodeId,
+    message_id: u8,
+    data: &[u8],
+    config: &FragmentConfig,
+) -> Result<(), FragmentError<R::Error>> {
+    if destination == BROADCAST {
+        return Err(FragmentError::Arq(ArqError::Broadcast));
+    }
+    if data.is_empty() {
+        return Err(FragmentError::Empty);
+    }
+    if data.len() > MAX_MESSAGE_LEN {
+        return Err(FragmentError::TooLong(data.len()));
+    }
+    let mut missing = FragmentSet::first(fragment_count(data.len()));
+    let mut burst = missing;
+    let mut frame = [0u8; MAX_PAYLOAD_LEN];
+    for _ in 0..=config.retries {
+        let last = burst.iter().last();
+        for index in burst.iter() {
+            let header = FragmentHeader {
+                message_id,
+                index,
+                message_len: data.len() as u16,
+            };
+            let Some(range) = header.range() else {
+                continue;
+            };
+            let len = FRAGMENT_HEADER_LEN + range.len();
+            frame[..FRAGMENT_HEADER_LEN].copy_from_slice(&header.encode());
+            frame[FRAGMENT_HEADER_LEN..len].copy_from_slice(&data[range]);
+            let flags = if Some(index) == last {
+                flags::ACK_REQUEST
+            } else {
+                0
+            };
+            arq.send_frame(MessageType::Fragment, destination, &frame[..len], flags)
+                .await?;
+        }
+
+        let is_status = |header: &Header| {
+            header.kind == MessageType::FragmentStatus && header.source == destination
+        };
+        let status = arq
+            .receive_matching(&mut frame, config.status_timeout, is_status)
+            .await?;
+        let reported = status
+            .and_then(|received| decode_status(&frame[..received.len]))
+            .filter(|(id, _)| *id == message_id);
+        burst = match reported {
+            Some((_, reported)) if reported.is_empty() => return Ok(()),
+            Some((_, reported)) => {
+                missing = reported;
+                missing
+            }
+            // Ask again with the fragment most likely still missing.
+            None => {
+                let mut poll = FragmentSet::empty();
+                if let Some(index) = missing.iter().last() {
+                    poll.insert(index);
+                }
+                poll
+            }
+        };
+    }
+    Err(FragmentError::Timeout {
+        missing: missing.len(),
+    })
+}
+
+/// Stores a `Fragment` frame received by `arq` and answers its status
+/// request, returning the message length once it is complete; read it with
+/// [`Reassembler::message`].
+pub async fn receive_fragment<R: P2pRadio, const N: usize, const S: usize>(
+    arq: &mut Arq<R>,
+    reassembler: &mut Reassembler<N, S>,
+    received: &Received,
+    payload: &[u8],
+    now_ms: u64,
+) -> Result<Option<(u8, usize)>, FragmentError<R::Error>> {
+    let (header, _) = FragmentHeader::decode(payload)?;
+    let message_id = header.message_id;
+    let pushed = reassembler.push(received.source, payload, now_ms);
+    if received.flags & flags::ACK_REQUEST != 0 {
+        if let Some(missing) = reassembler.missing(received.source, message_id) {
+            let mut status = [0u8; 1 + BITMAP_LEN];
+            let len = encode_status(message_id, &missing, &mut status);
+            arq.send_frame(
+                MessageType::FragmentStatus,
+                received.source,
+                &status[..len],
+                0,
+            )
+            .await?;
+        }
+    }
+    Ok(pushed?.map(|len| (message_id, len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, vec::Vec};
+
+    use embassy_futures::{block_on, join::join};
+
+    use crate::devices::{
+        p2p_arq::ArqConfig,
+        test_util::{Channel, LossyRadio},
+    };
+
+    /// Fragment `index` of `data`, sent as message `message_id`.
+    fn fragment(message_id: u8, index: u8, data: &[u8]) -> Vec<u8> {
+        let header = FragmentHeader {
+            message_id,
+            index,
+            message_len: data.len() as u16,
+        };
+        let mut payload = header.encode().to_vec();
+        payload.extend_from_slice(&data[header.range().unwrap()]);
+        payload
+    }
+
+    fn message(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    #[test]
+    fn reassembles_fragments_in_any_order() {
+        let data = message(1000, 0);
+        assert_eq!(fragment_count(data.len()), 5);
+        let mut reassembler = Reassembler::<1024, 2>::default();
+        for index in [4, 0, 2, 2, 1] {
+            let pushed = reassembler.push::<()>(5, &fragment(9, index, &data), 0);
+            assert_eq!(pushed, Ok(None));
+        }
+        let missing = reassembler.missing(5, 9).unwrap();
+        assert_eq!(missing.iter().collect::<Vec<_>>(), [3]);
+        assert_eq!(reassembler.message(5, 9), None);
+
+        let pushed = reassembler.push::<()>(5, &fragment(9, 3, &data), 10);
+        assert_eq!(pushed, Ok(Some(1000)));
+        assert_eq!(reassembler.message(5, 9), Some(&data[..]));
+        assert!(reassembler.missing(5, 9).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_bad_fragments() {
+        let data = message(1000, 0);
+        let mut reassembler = Reassembler::<1024, 2>::default();
+        let long = message(1025, 0);
+        let pushed = reassembler.push::<()>(5, &fragment(1, 0, &long), 0);
+        assert_eq!(pushed, Err(FragmentError::TooLong(1025)));
+        let mut short = fragment(1, 0, &data);
+        short.pop();
+        let pushed = reassembler.push::<()>(5, &short, 0);
+        assert_eq!(pushed, Err(FragmentError::Invalid));
+        let pushed = reassembler.push::<()>(5, &fragment(1, 0, &data)[..3], 0);
+        assert_eq!(pushed, Err(FragmentError::Truncated(3)));
+        let mut past_the_end = fragment(1, 4, &data);
+        past_the_end[1] = 5;
+        let pushed = reassembler.push::<()>(5, &past_the_end, 0);
+        assert_eq!(pushed, Err(FragmentError::Invalid));
+    }
+
+    #[test]
+    fn reuses_message_ids_once_timed_out() {
+        let first = message(300, 1);
+        let second = message(300, 2);
+        let mut reassembler = Reassembler::<1024, 2>::default();
+        assert_eq!(
+            reassembler.push::<()>(5, &fragment(9, 1, &first), 0),
+            Ok(None)
+        );
+        let pushed = reassembler.push::<()>(5, &fragment(9, 0, &first), 100);
+        assert_eq!(pushed, Ok(Some(300)));
+
+        // Repeats keep the delivered message, however late they come.
+        let late = REASSEMBLY_TIMEOUT_MS - 1;
+        assert_eq!(
+            reassembler.push::<()>(5, &fragment(9, 0, &first), late),
+            Ok(None)
+        );
+        let later = 2 * late;
+        assert_eq!(
+            reassembler.push::<()>(5, &fragment(9, 1, &first), later),
+            Ok(None)
+        );
+        assert_eq!(reassembler.message(5, 9), Some(&first[..]));
+
+        // The sender reused the ID once the message timed out.
+        let reused = later + REASSEMBLY_TIMEOUT_MS;
+        let pushed = reassembler.push::<()>(5, &fragment(9, 0, &second), reused);
+        assert_eq!(pushed, Ok(None));
+        assert_eq!(reassembler.message(5, 9), None);
+        let pushed = reassembler.push::<()>(5, &fragment(9, 1, &second), reused);
+        assert_eq!(pushed, Ok(Some(300)));
+        assert_eq!(reassembler.message(5, 9), Some(&second[..]));
+    }
+
+    #[test]
+    fn frees_buffers_of_complete_and_timed_out_messages() {
+        let data = message(1000, 0);
+        let mut reassembler = Reassembler::<1024, 2>::default();
+        let short = message(10, 0);
+        assert_eq!(
+            reassembler.push::<()>(5, &fragment(9, 0, &short), 0),
+            Ok(Some(10))
+        );
+        assert_eq!(
+            reassembler.push::<()>(6, &fragment(1, 0, &data), 100),
+            Ok(None)
+        );
+        assert_eq!(reassembler.message(5, 9), Some(&short[..]));
+        assert_eq!(
+            reassembler.push::<()>(7, &fragment(1, 0, &data), 100),
+            Ok(None)
+        );
+        assert_eq!(reassembler.message(5, 9), None);
+
+        let pushed = reassembler.push::<()>(8, &fragment(1, 0, &data), 200);
+        assert_eq!(pushed, Err(FragmentError::Busy));
+        let timed_out = 100 + REASSEMBLY_TIMEOUT_MS;
+        let pushed = reassembler.push::<()>(8, &fragment(1, 0, &data), timed_out);
+        assert_eq!(pushed, Ok(None));
+    }
+
+    #[test]
+    fn encodes_the_missing_fragments() {
+        let mut missing = FragmentSet::empty();
+        missing.insert(3);
+        missing.insert(17);
+        let mut buffer = [0u8; 1 + BITMAP_LEN];
+        let len = encode_status(4, &missing, &mut buffer);
+        assert_eq!(&buffer[..len], &[4, 0x08, 0x00, 0x02]);
+        assert_eq!(decode_status(&buffer[..len]), Some((4, missing)));
+        assert_eq!(encode_status(4, &FragmentSet::empty(), &mut buffer), 1);
+        assert_eq!(decode_status(&[1; 2 + BITMAP_LEN]), None);
+    }
+
+    #[test]
+    fn sends_messages_over_a_lossy_channel() {
+        for loss_percent in [0, 20, 40] {
+            let channel = Channel::new(loss_percent);
+            let radio = |side| LossyRadio::new(&channel, side);
+            let mut sender = Arq::new(radio(0), 1, ArqConfig::default(), 7);
+            let mut receiver = Arq::new(radio(1), 2, ArqConfig::default(), 9);
+            let config = FragmentConfig {
+                retries: 30,
+                ..Default::default()
+            };
+            let messages: Vec<Vec<u8>> = (0..6).map(|i| message(300 + 340 * i, i as u8)).collect();
+            let done = RefCell::new(false);
+            let send = async {
+                for (message_id, data) in messages.iter().enumerate() {
+                    send_message(&mut sender, 2, message_id as u8, data, &config)
+                        .await
+                        .unwrap();
+                }
+                let empty = send_message(&mut sender, 2, 0, &[], &config).await;
+                assert_eq!(empty, Err(FragmentError::Empty));
+                *done.borrow_mut() = true;
+            };
+            let receive = async {
+                let mut reassembler = Reassembler::<2048, 2>::default();
+                let mut payload = [0u8; MAX_PAYLOAD_LEN];
+                let mut delivered = Vec::new();
+                while !*done.borrow() {
+                    let received = receiver
+                        .receive(&mut payload, Duration::from_secs(1))
+                        .await
+                        .unwrap();
+                    let Some(received) = received else {
+                        continue;
+                    };
+                    assert_eq!(received.kind, MessageType::Fragment);
+                    let fragment = payload;
+                    let complete = receive_fragment(
+                        &mut receiver,
+                        &mut reassembler,
+                        &received,
+                        &fragment[..received.len],
+                        0,
+                    )
+                    .await
+                    .unwrap();
+                    if let Some((message_id, len)) = complete {
+                        let data = reassembler.message(1, message_id).unwrap();
+                        assert_eq!(data.len(), len);
+                        delivered.push(data.to_vec());
+                    }
+                }
+                delivered
+            };
+            let ((), delivered) = block_on(join(send, receive));
+            assert_eq!(delivered, messages, "{}% lost", loss_percent);
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use super::{crc::crc16_ccitt, p2p_crypto::SECURITY_LEN};
+
+/// Version of the frame format, the high nibble of the first byte.
+pub const FRAME_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 9;
+pub const CRC_LEN: usize = 2;
+/// Largest frame the radio sends.
+pub const MAX_FRAME_LEN: usize = 255;
+/// Largest payload of a frame, leaving room for the counter and MIC of a
+/// secured frame.
+pub const MAX_PAYLOAD_LEN: usize = MAX_FRAME_LEN - HEADER_LEN - SECURITY_LEN - CRC_LEN;
+
+/// Address of a node on the P2P channel.
+pub type NodeId = u16;
+/// Destination of frames for every node.
+pub const BROADCAST: NodeId = 0xFFFF;
+
+/// Bits of the flags byte.
+pub mod flags {
+    /// The sender waits for an `Ack` frame, or a `FragmentStatus` frame when
+    /// set on a `Fragment`.
+    pub const ACK_REQUEST: u8 = 0x01;
+    /// The payload is encrypted and followed by a counter and MIC, see
+    /// `p2p_crypto`.
+    pub const SECURED: u8 = 0x02;
+    /// Flags a receiver of this version understands, the others must be 0.
+    pub const KNOWN: u8 = ACK_REQUEST | SECURED;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FrameError {
+    BufferTooSmall(usize),
+    PayloadTooLong(usize),
+    Truncated(usize),
+    UnsupportedVersion(u8),
+    UnknownType(u8),
+    ReservedFlags(u8),
+    Length { declared: usize, actual: usize },
+    Crc { expected: u16, actual: u16 },
+}
+
+impl core::fmt::Display for FrameError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FrameError::BufferTooSmall(len) => {
+                write!(f, "Buffer too small for a frame of {} bytes", len)
+            }
+            FrameError::PayloadTooLong(len) => write!(f, "Payload of {} bytes is too long", len),
+            FrameError::Truncated(len) => write!(f, "Frame of {} bytes is truncated", len),
+            FrameError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported frame version {}", version)
+            }
+            FrameError::UnknownType(kind) => write!(f, "Unknown message type {}", kind),
+            FrameError::ReservedFlags(flags) => write!(f, "Reserved flags set: {:#04x}", flags),
+            FrameError::Length { declared, actual } => write!(
+                f,
+                "Payload length {} does not match the {} bytes received",
+                declared, actual
+            ),
+            FrameError::Crc { expected, actual } => write!(
+                f,
+                "CRC mismatch: expected {:#06x}, got {:#06x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+/// Kind of a frame, the low nibble of the first byte.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Data = 0,
+    Ack = 1,
+    /// Part of a message too large for one frame, see `p2p_fragment`.
+    Fragment = 2,
+    /// Fragments of a message the receiver is missing.
+    FragmentStatus = 3,
+    /// Key exchange between nodes in pairing mode, see `p2p_pairing`.
+    Pairing = 4,
+    /// Message flooded through the mesh, see `p2p_mesh`.
+    Mesh = 5,
+    /// Announcement of a node to the ones in range, see `p2p_neighbors`.
+    Beacon = 6,
+    /// Routes known by a node, see `p2p_routing`.
+    Routes = 7,
+    /// Message forwarded hop by hop along the routes to its destination.
+    Unicast = 8,
+    /// Time beacon and slot table of a TDMA coordinator, see `p2p_tdma`.
+    Tdma = 9,
+}
+
+impl MessageType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => MessageType::Data,
+            1 => MessageType::Ack,
+            2 => MessageType::Fragment,
+            3 => MessageType::FragmentStatus,
+            4 => MessageType::Pairing,
+            5 => MessageType::Mesh,
+            6 => MessageType::Beacon,
+            7 => MessageType::Routes,
+            8 => MessageType::Unicast,
+            9 => MessageType::Tdma,
+            _ => return None,
+        })
+    }
+}
+
+/// Header of a P2P frame.
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 1    | Version (7:4), message type (3:0)       |
+/// | 1      | 2    | Source node (LE)                        |
+/// | 3      | 2    | Destination node (LE)                   |
+/// | 5      | 2    | Sequence number (LE)                    |
+/// | 7      | 1    | Flags                                   |
+/// | 8      | 1    | Payload length                          |
+/// | 9      | n    | Payload                                 |
+/// | 9 + n  | 2    | CRC-16/CCITT of the preceding bytes (LE)|
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Header {
+    pub kind: MessageType,
+    pub source: NodeId,
+    pub destination: NodeId,
+    pub seq: u16,
+    pub flags: u8,
+}
+
+impl Header {
+    /// Whether a node should process the frame rather than ignore it.
+    pub fn is_for(&self, node: NodeId) -> bool {
+        self.source != node && (self.destination == node || self.destination == BROADCAST)
+    }
+
+    pub fn ack_requested(&self) -> bool {
+        self.flags & flags::ACK_REQUEST != 0
+    }
+}
+
+/// A decoded P2P frame, borrowing its payload from the received bytes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame<'a> {
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    pub fn new(header: Header, payload: &'a [u8]) -> Self {
+        Self { header, payload }
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        HEADER_LEN + self.payload.len() + CRC_LEN
+    }
+
+    /// Encodes the frame into `buffer`, returning the number of bytes used.
+    ///
+    /// # Errors
+    ///
+    /// * `PayloadTooLong` - If the payload exceeds [`MAX_PAYLOAD_LEN`].
+    /// * `ReservedFlags` - If flags this version does not define are set.
+    /// * `BufferTooSmall` - If the frame does not fit in `buffer`.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, FrameError> {
+        let header = &self.header;
+        if self.payload.len() > MAX_PAYLOAD_LEN {
+            return Err(FrameError::PayloadTooLong(self.payload.len()));
+        }
+        if header.flags & !flags::KNOWN != 0 {
+            return Err(FrameError::ReservedFlags(header.flags & !flags::KNOWN));
+        }
+        let len = self.encoded_len();
+        let frame = buffer
+            .get_mut(..len)
+            .ok_or(FrameError::BufferTooSmall(len))?;
+        frame[0] = (FRAME_VERSION << 4) | header.kind as u8;
+        frame[1..3].copy_from_slice(&header.source.to_le_bytes());
+        frame[3..5].copy_from_slice(&header.destination.to_le_bytes());
+        frame[5..7].copy_from_slice(&header.seq.to_le_bytes());
+        frame[7] = header.flags;
+        frame[8] = self.payload.len() as u8;
+        frame[HEADER_LEN..len - CRC_LEN].copy_from_slice(self.payload);
+        let crc = crc16_ccitt(&frame[..len - CRC_LEN]);
+        frame[len - CRC_LEN..].copy_from_slice(&crc.to_le_bytes());
+        Ok(len)
+    }
+
+    /// Decodes a received frame, which must span all of `bytes`.
+    ///
+    /// # Errors
+    ///
+    /// * `Truncated` - If `bytes` is shorter than an empty frame.
+    /// * `Crc` - If the frame is corrupted.
+    /// * `UnsupportedVersion` - If the frame is of another format version.
+    /// * `UnknownType` - If the message type is not defined.
+    /// * `ReservedFlags` - If flags this version does not define are set.
+    /// * `Length` - If the payload length does not match the frame length.
+    pub fn decode(bytes: &'a [u8]) -> Result<Self, FrameError> {
+        if bytes.len() < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::Truncated(bytes.len()));
+        }
+        let (body, crc) = bytes.split_at(bytes.len() - CRC_LEN);
+        let expected = u16::from_le_bytes([crc[0], crc[1]]);
+        let actual = crc16_ccitt(body);
+        if expected != actual {
+            return Err(FrameError::Crc { expected, actual });
+        }
+        let version = bytes[0] >> 4;
+        if version != FRAME_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let kind = MessageType::from_u8(bytes[0] & 0x0F)
+            .ok_or(FrameError::UnknownType(bytes[0] & 0x0F))?;
+        let raw_flags = bytes[7];
+        if raw_flags & !flags::KNOWN != 0 {
+            return Err(FrameError::ReservedFlags(raw_flags & !flags::KNOWN));
+        }
+        let payload = &body[HEADER_LEN..];
+        let declared = bytes[8] as usize;
+        if declared != payload.len() {
+            return Err(FrameError::Length {
+                declared,
+                actual: payload.len(),
+            });
+        }
+        Ok(Self {
+            header: Header {
+                kind,
+                source: u16::from_le_bytes([bytes[1], bytes[2]]),
+                destination: u16::from_le_bytes([bytes[3], bytes[4]]),
+                seq: u16::from_le_bytes([bytes[5], bytes[6]]),
+                flags: raw_flags,
+            },
+            payload,
+        })
+    }
+}
+
+/// Node address of a device, from the last bytes of its factory MAC address.
+pub fn node_id(mac: &[u8; 6]) -> NodeId {
+    match u16::from_be_bytes([mac[4], mac[5]]) {
+        BROADCAST => BROADCAST - 1,
+        id => id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_util::randoms;
+
+    const HEADER: Header = Header {
+        kind: MessageType::Data,
+        source: 0x1234,
+        destination: BROADCAST,
+        seq: 7,
+        flags: flags::ACK_REQUEST,
+    };
+
+    /// Encodes `HEADER` with payload "hi", changes it with `mutate` and
+    /// decodes it with a CRC that matches again.
+    fn decode_altered(mutate: impl FnOnce(&mut [u8; 13])) -> Result<(), FrameError> {
+        let mut bytes = [0u8; 13];
+        Frame::new(HEADER, b"hi").encode(&mut bytes).unwrap();
+        mutate(&mut bytes);
+        let crc = crc16_ccitt(&bytes[..11]);
+        bytes[11..].copy_from_slice(&crc.to_le_bytes());
+        Frame::decode(&bytes).map(|_| ())
+    }
+
+    #[test]
+    fn encodes_the_header() {
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = Frame::new(HEADER, b"hi").encode(&mut buffer).unwrap();
+        assert_eq!(len, 13);
+        assert_eq!(
+            &buffer[..11],
+            &[0x10, 0x34, 0x12, 0xFF, 0xFF, 7, 0, 0x01, 2, b'h', b'i']
+        );
+        let frame = Frame::decode(&buffer[..len]).unwrap();
+        assert_eq!(frame, Frame::new(HEADER, b"hi"));
+        assert!(frame.header.is_for(0x0001));
+        assert!(!frame.header.is_for(0x1234));
+        assert!(frame.header.ack_requested());
+    }
+
+    #[test]
+    fn refuses_to_encode_invalid_frames() {
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let payload = [0u8; MAX_PAYLOAD_LEN + 1];
+        assert_eq!(
+            Frame::new(HEADER, &payload).encode(&mut buffer),
+            Err(FrameError::PayloadTooLong(MAX_PAYLOAD_LEN + 1))
+        );
+        assert!(Frame::new(HEADER, &payload[..MAX_PAYLOAD_LEN])
+            .encode(&mut buffer)
+            .is_ok());
+        assert_eq!(
+            Frame::new(HEADER, b"hi").encode(&mut [0; 12]),
+            Err(FrameError::BufferTooSmall(13))
+        );
+        let header = Header {
+            flags: 0x80 | flags::SECURED,
+            ..HEADER
+        };
+        assert_eq!(
+            Frame::new(header, b"hi").encode(&mut buffer),
+            Err(FrameError::ReservedFlags(0x80))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_crc() {
+        let mut bytes = [0u8; 13];
+        Frame::new(HEADER, b"hi").encode(&mut bytes).unwrap();
+        bytes[9] ^= 0x01;
+        let expected = u16::from_le_bytes([bytes[11], bytes[12]]);
+        let actual = crc16_ccitt(&bytes[..11]);
+        assert_eq!(
+            Frame::decode(&bytes),
+            Err(FrameError::Crc { expected, actual })
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let result = decode_altered(|bytes| bytes[0] = 0x20);
+        assert_eq!(result, Err(FrameError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        let result = decode_altered(|bytes| bytes[0] = 0x1F);
+        assert_eq!(result, Err(FrameError::UnknownType(15)));
+    }
+
+    #[test]
+    fn rejects_reserved_flags() {
+        let result = decode_altered(|bytes| bytes[7] = 0x81);
+        assert_eq!(result, Err(FrameError::ReservedFlags(0x80)));
+    }
+
+    #[test]
+    fn rejects_wrong_lengths() {
+        let result = decode_altered(|bytes| bytes[8] = 3);
+        let error = FrameError::Length {
+            declared: 3,
+            actual: 2,
+        };
+        assert_eq!(result, Err(error));
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let mut bytes = [0u8; 13];
+        Frame::new(HEADER, b"hi").encode(&mut bytes).unwrap();
+        for len in 0..HEADER_LEN + CRC_LEN {
+            assert_eq!(
+                Frame::decode(&bytes[..len]),
+                Err(FrameError::Truncated(len))
+            );
+        }
+        // A cut frame fails its CRC before its length is checked.
+        assert!(matches!(
+            Frame::decode(&bytes[..12]),
+            Err(FrameError::Crc { .. })
+        ));
+    }
+
+    #[test]
+    fn round_trips_random_frames() {
+        let mut randoms = randoms(0x1234_5678);
+        let mut next = || randoms.next().unwrap();
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        for _ in 0..10_000 {
+            let len = next() as usize % (MAX_PAYLOAD_LEN + 1);
+            payload[..len]
+                .iter_mut()
+                .for_each(|byte| *byte = next() as u8);
+            let header = Header {
+                kind: MessageType::from_u8((next() % 10) as u8).unwrap(),
+                source: next() as u16,
+                destination: next() as u16,
+                seq: next() as u16,
+                flags: next() as u8 & flags::KNOWN,
+            };
+            let frame = Frame::new(header, &payload[..len]);
+            let encoded = frame.encode(&mut buffer).unwrap();
+            assert_eq!(encoded, frame.encoded_len());
+            assert_eq!(Frame::decode(&buffer[..encoded]), Ok(frame));
+
+            // The CRC catches any single bit flip.
+            let bit = next() as usize % (encoded * 8);
+            buffer[bit / 8] ^= 1 << (bit % 8);
+            assert!(Frame::decode(&buffer[..encoded]).is_err());
+
+            // Random bytes are rejected without panicking.
+            let len = next() as usize % (MAX_FRAME_LEN + 1);
+            buffer[..len]
+                .iter_mut()
+                .for_each(|byte| *byte = next() as u8);
+            let _ = Frame::decode(&buffer[..len]);
+        }
+    }
+
+    #[test]
+    fn derives_node_ids() {
+        assert_eq!(node_id(&[0x24, 0x0A, 0xC4, 0x00, 0x12, 0x34]), 0x1234);
+        assert_eq!(node_id(&[0, 0, 0, 0, 0xFF, 0xFF]), BROADCAST - 1);
+    }
+}
@@ -0,0 +1,396 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::Storage;
+
+use super::{
+    kv::{KvError, KvStore},
+    p2p_arq::SequenceWindow,
+    p2p_frame::{NodeId, BROADCAST, MAX_PAYLOAD_LEN},
+};
+
+/// Settings key of the role of the node, `true` for a relay.
+pub const RELAY_KEY: &str = "p2p.relay";
+/// Settings key of the hop limit of the messages this node sends.
+pub const HOP_LIMIT_KEY: &str = "p2p.hops";
+pub const MESH_HEADER_LEN: usize = 8;
+/// Largest message carried by a `Mesh` frame.
+pub const MAX_MESSAGE_LEN: usize = MAX_PAYLOAD_LEN - MESH_HEADER_LEN;
+/// Origins whose message IDs are tracked; the least recently heard is
+/// forgotten first.
+pub const MAX_ORIGINS: usize = 16;
+/// Rebroadcasts waiting for their delay at a time.
+pub const MAX_PENDING: usize = 4;
+/// SNR range the rebroadcast delay spreads over, in dB: a frame heard at
+/// the lower bound is rebroadcast after the shortest delay.
+pub const MIN_SNR_DB: i16 = -20;
+pub const MAX_SNR_DB: i16 = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum MeshError {
+    Malformed(usize),
+    MessageTooLong(usize),
+}
+
+impl core::fmt::Display for MeshError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            MeshError::Malformed(len) => write!(f, "Malformed mesh frame of {} bytes", len),
+            MeshError::MessageTooLong(len) => {
+                write!(f, "Message of {} bytes is too long for the mesh", len)
+            }
+        }
+    }
+}
+
+/// Header of a message flooded through the mesh, at the start of the
+/// payload of a `Mesh` frame.
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 2    | Origin node (LE)                        |
+/// | 2      | 2    | Destination node (LE)                   |
+/// | 4      | 2    | Message ID, per origin (LE)             |
+/// | 6      | 1    | Hops left                               |
+/// | 7      | 1    | Hops taken                              |
+/// | 8      | n    | Message                                 |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshHeader {
+    pub origin: NodeId,
+    pub destination: NodeId,
+    pub id: u16,
+    /// Rebroadcasts the message may still take; 0 stops it at the nodes
+    /// that hear this copy.
+    pub hops_left: u8,
+    /// Rebroadcasts the message took, 0 when heard from its origin.
+    pub hops: u8,
+}
+
+impl MeshHeader {
+    pub fn encode(&self, buffer: &mut [u8; MESH_HEADER_LEN]) {
+        buffer[0..2].copy_from_slice(&self.origin.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.destination.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.id.to_le_bytes());
+        buffer[6] = self.hops_left;
+        buffer[7] = self.hops;
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, MeshError> {
+        if bytes.len() < MESH_HEADER_LEN {
+            return Err(MeshError::Malformed(bytes.len()));
+        }
+        Ok(Self {
+            origin: u16::from_le_bytes([bytes[0], bytes[1]]),
+            destination: u16::from_le_bytes([bytes[2], bytes[3]]),
+            id: u16::from_le_bytes([bytes[4], bytes[5]]),
+            hops_left: bytes[6],
+            hops: bytes[7],
+        })
+    }
+}
+
+/// What a node does with the mesh.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MeshRole {
+    /// Sends, receives and rebroadcasts messages.
+    Node,
+    /// Only rebroadcasts messages, to extend the range of the others.
+    Relay,
+}
+
+/// Settings of [`Flood`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshConfig {
+    pub role: MeshRole,
+    /// Rebroadcasts a message sent by this node may take.
+    pub hop_limit: u8,
+    /// Delay before rebroadcasting a frame heard at [`MIN_SNR_DB`] or
+    /// below, growing with the SNR up to `max_delay_ms` at [`MAX_SNR_DB`].
+    ///
+    /// Distant nodes hear frames weaker, so they rebroadcast first and
+    /// carry messages furthest; the nodes close to the sender usually hear
+    /// them before their own delay ends and stay quiet.
+    pub min_delay_ms: u32,
+    pub max_delay_ms: u32,
+    /// Upper bound of the random delay added, so nodes hearing a frame
+    /// equally well do not rebroadcast at the same time.
+    pub jitter_ms: u32,
+    /// Copies of a message heard while its rebroadcast waits that cancel
+    /// it, 0 to always rebroadcast.
+    pub suppress_after: u8,
+}
+
+impl Default for MeshConfig {
+    fn default() -> Self {
+        Self {
+            role: MeshRole::Node,
+            hop_limit: 3,
+            min_delay_ms: 100,
+            max_delay_ms: 2_000,
+            jitter_ms: 2_000,
+            suppress_after: 1,
+        }
+    }
+}
+
+/// Reads the role and hop limit from the settings, keeping the defaults of
+/// the ones not set.
+pub fn read_config<S: Storage>(settings: &mut KvStore<S>) -> Result<MeshConfig, KvError> {
+    let mut config = MeshConfig::default();
+    if settings.get::<bool>(RELAY_KEY)? == Some(true) {
+        config.role = MeshRole::Relay;
+    }
+    if let Some(hop_limit) = settings.get::<u8>(HOP_LIMIT_KEY)? {
+        config.hop_limit = hop_limit;
+    }
+    Ok(config)
+}
+
+/// The mesh settings, or the defaults if they cannot be read.
+pub async fn load_config<S: Storage>(
+    settings: &Mutex<CriticalSectionRawMutex, KvStore<S>>,
+) -> MeshConfig {
+    match read_config(&mut *settings.lock().await) {
+        Ok(config) => config,
+        Err(e) => {
+            esp_println::println!("[LoRa P2P] Failed to read the mesh settings: {}", e);
+            MeshConfig::default()
+        }
+    }
+}
+
+/// Counters of the messages a node handled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MeshStats {
+    pub sent: u32,
+    pub delivered: u32,
+    pub duplicates: u32,
+    pub rebroadcasts: u32,
+    /// Rebroadcasts cancelled as enough neighbours rebroadcast the message.
+    pub suppressed: u32,
+    /// Rebroadcasts dropped as every slot was waiting.
+    pub overflows: u32,
+}
+
+#[derive(Debug, Clone)]
+struct Pending {
+    origin: NodeId,
+    id: u16,
+    due_ms: u64,
+    /// Copies heard since the rebroadcast was scheduled.
+    heard: u8,
+    frame: heapless::Vec<u8, MAX_PAYLOAD_LEN>,
+}
+
+/// Managed flooding of messages through the mesh.
+///
+/// Every node rebroadcasts the messages it hears for the first time, after a
+/// delay growing with the SNR they were heard at, until their hop limit is
+/// reached. Messages are recognised by their origin and ID, so every node
+/// delivers and rebroadcasts a message once; a rebroadcast is cancelled when
+/// neighbours rebroadcast the message first.
+///
+/// The state machine does no I/O: the caller sends the frames returned by
+/// [`Flood::originate`] and [`Flood::due`] as `Mesh` broadcasts and passes
+/// the ones it receives to [`Flood::handle`].
+pub struct Flood {
+    node: NodeId,
+    config: MeshConfig,
+    next_id: u16,
+    seen: heapless::Vec<(NodeId, SequenceWindow), MAX_ORIGINS>,
+    pending: heapless::Vec<Pending, MAX_PENDING>,
+    stats: MeshStats,
+}
+
+impl Flood {
+    /// Floods as `node`; message IDs start from `random`, so the messages
+    /// sent after a reboot are not taken as the ones sent before.
+    pub fn new(node: NodeId, config: MeshConfig, random: u32) -> Self {
+        Self {
+            node,
+            config,
+            next_id: random as u16,
+            seen: heapless::Vec::new(),
+            pending: heapless::Vec::new(),
+            stats: MeshStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> &MeshConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> MeshStats {
+        self.stats
+    }
+
+    /// Writes a `Mesh` payload carrying `message` to `destination` into
+    /// `buffer`, returning its length.
+    ///
+    /// # Errors
+    ///
+    /// * `MessageTooLong` - If `message` exceeds [`MAX_MESSAGE_LEN`].
+    pub fn originate(
+        &mut self,
+        destination: NodeId,
+        message: &[u8],
+        buffer: &mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Result<usize, MeshError> {
+        if message.len() > MAX_MESSAGE_LEN {
+            return Err(MeshError::MessageTooLong(message.len()));
+        }
+        self.next_id = self.next_id.wrapping_add(1);
+        let header = MeshHeader {
+            origin: self.node,
+            destination,
+            id: self.next_id,
+            hops_left: self.config.hop_limit,
+            hops: 0,
+        };
+        let (head, body) = buffer.split_at_mut(MESH_HEADER_LEN);
+        // Cannot fail, the split is the header's length.
+        header.encode(head.try_into().unwrap());
+        body[..message.len()].copy_from_slice(message);
+        self.stats.sent += 1;
+        Ok(MESH_HEADER_LEN + message.len())
+    }
+
+    /// Handles the payload of a `Mesh` frame heard at `snr` dB, if the radio
+    /// measured it, scheduling its rebroadcast. `random` is any random value,
+    /// reduced to the jitter of the delay.
+    ///
+    /// Returns the header of a message to deliver to this node, which
+    /// follows the header in `payload`, or `None` for duplicates, messages
+    /// for other nodes and the messages a relay hears.
+    ///
+    /// # Errors
+    ///
+    /// * `Malformed` - If the payload is shorter than a header.
+    pub fn handle(
+        &mut self,
+        payload: &[u8],
+        snr: Option<i16>,
+        now_ms: u64,
+        random: u32,
+    ) -> Result<Option<MeshHeader>, MeshError> {
+        let header = MeshHeader::decode(payload)?;
+        if header.origin == self.node || !self.first_heard(header.origin, header.id) {
+            self.stats.duplicates += 1;
+            self.heard_again(header.origin, header.id);
+            return Ok(None);
+        }
+        let for_node = header.destination == self.node;
+        if !for_node && header.hops_left > 0 {
+            self.schedule(&header, payload, snr, now_ms, random);
+        }
+        if self.config.role == MeshRole::Relay || !(for_node || header.destination == BROADCAST) {
+            return Ok(None);
+        }
+        self.stats.delivered += 1;
+        Ok(Some(header))
+    }
+
+    /// Records a message, returning `false` if it was heard before.
+    fn first_heard(&mut self, origin: NodeId, id: u16) -> bool {
+        let index = match self.seen.iter().position(|(node, _)| *node == origin) {
+            Some(index) => index,
+            None => {
+                if self.seen.is_full() {
+                    self.seen.remove(0);
+                }
+                // Cannot fail, a slot was freed above.
+                let _ = self.seen.push((origin, SequenceWindow::default()));
+                self.seen.len() - 1
+            }
+        };
+        // Keep the origins in the order they were last heard.
+        let (node, mut window) = self.seen.remove(index);
+        let fresh = window.accept(id);
+        // Cannot fail, the entry was just removed.
+        let _ = self.seen.push((node, window));
+        fresh
+    }
+
+    fn heard_again(&mut self, origin: NodeId, id: u16) {
+        let suppress_after = self.config.suppress_after;
+        let Some(index) = self
+            .pending
+            .iter()
+            .position(|pending| pending.origin == origin && pending.id == id)
+        else {
+            return;
+        };
+        self.pending[index].heard += 1;
+        if suppress_after > 0 && self.pending[index].heard >= suppress_after {
+            self.pending.remove(index);
+            self.stats.suppressed += 1;
+        }
+    }
+
+    fn schedule(
+        &mut self,
+        header: &MeshHeader,
+        payload: &[u8],
+        snr: Option<i16>,
+        now_ms: u64,
+        random: u32,
+    ) {
+        let mut frame = heapless::Vec::new();
+        let forwarded = MeshHeader {
+            hops_left: header.hops_left - 1,
+            hops: header.hops.saturating_add(1),
+            ..*header
+        };
+        let mut head = [0u8; MESH_HEADER_LEN];
+        forwarded.encode(&mut head);
+        if frame.extend_from_slice(&head).is_err()
+            || frame
+                .extend_from_slice(&payload[MESH_HEADER_LEN..])
+                .is_err()
+        {
+            return;
+        }
+        let pending = Pending {
+            origin: header.origin,
+            id: header.id,
+            due_ms: now_ms + self.delay_ms(snr, random) as u64,
+            heard: 0,
+            frame,
+        };
+        if self.pending.push(pending).is_err() {
+            self.stats.overflows += 1;
+        }
+    }
+
+    /// Delay before rebroadcasting a frame heard at `snr`, in the middle of
+    /// the range when the SNR is unknown.
+    pub fn delay_ms(&self, snr: Option<i16>, random: u32) -> u32 {
+        let config = &self.config;
+        let range = (MAX_SNR_DB - MIN_SNR_DB) as u32;
+        let above = match snr {
+            Some(snr) => snr.clamp(MIN_SNR_DB, MAX_SNR_DB).abs_diff(MIN_SNR_DB) as u32,
+            None => range / 2,
+        };
+        let spread = config.max_delay_ms.saturating_sub(config.min_delay_ms);
+        config.min_delay_ms + spread * above / range + random % (config.jitter_ms + 1)
+    }
+
+    /// Time the next rebroadcast is due, if one waits.
+    pub fn next_due_ms(&self) -> Option<u64> {
+        self.pending.iter().map(|pending| pending.due_ms).min()
+    }
+
+    /// Takes the rebroadcast due first by `now_ms`, copying its payload to
+    /// `buffer` and returning its length.
+    pub fn due(&mut self, now_ms: u64, buffer: &mut [u8; MAX_PAYLOAD_LEN]) -> Option<usize> {
+        let (index, _) = self
+            .pending
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| pending.due_ms <= now_ms)
+            .min_by_key(|(_, pending)| pending.due_ms)?;
+        let pending = self.pending.remove(index);
+        buffer[..pending.frame.len()].copy_from_slice(&pending.frame);
+        self.stats.rebroadcasts += 1;
+        Some(pending.frame.len())
+    }
+}
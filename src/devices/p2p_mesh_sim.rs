@@ -0,0 +1,689 @@
+use super::{
+    airtime::LoRaParams,
+    p2p_crypto::SECURITY_LEN,
+    p2p_frame::{NodeId, BROADCAST, CRC_LEN, HEADER_LEN, MAX_PAYLOAD_LEN},
+    p2p_mesh::{Flood, MeshConfig, MeshRole, MAX_MESSAGE_LEN, MESH_HEADER_LEN},
+    p2p_routing::Router,
+    payload::COMPACT_FIX_LEN,
+};
+
+/// Transmissions on the air at a time; more are dropped.
+pub const MAX_IN_FLIGHT: usize = 32;
+/// Bytes at the start of a simulated message holding the time it was sent.
+const TIMESTAMP_LEN: usize = 8;
+
+/// Quality of the link from one node to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkQuality {
+    pub snr_db: i16,
+    /// Frames lost on the link besides collisions, in percent.
+    pub loss_percent: u8,
+}
+
+/// Which nodes of a network of `N` hear each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Topology<const N: usize> {
+    links: [[Option<LinkQuality>; N]; N],
+}
+
+impl<const N: usize> Default for Topology<N> {
+    fn default() -> Self {
+        Self {
+            links: [[None; N]; N],
+        }
+    }
+}
+
+impl<const N: usize> Topology<N> {
+    /// Nodes in a row, each hearing the ones beside it.
+    pub fn line(quality: LinkQuality) -> Self {
+        let mut topology = Self::default();
+        for node in 1..N {
+            topology.connect(node - 1, node, quality);
+        }
+        topology
+    }
+
+    /// Nodes in rows of `width`, each hearing the ones beside, above and
+    /// below it.
+    pub fn grid(width: usize, quality: LinkQuality) -> Self {
+        let mut topology = Self::default();
+        for node in 0..N {
+            if node % width + 1 < width && node + 1 < N {
+                topology.connect(node, node + 1, quality);
+            }
+            if node + width < N {
+                topology.connect(node, node + width, quality);
+            }
+        }
+        topology
+    }
+
+    /// Every node hearing every other.
+    pub fn full(quality: LinkQuality) -> Self {
+        let mut topology = Self::default();
+        for a in 0..N {
+            for b in a + 1..N {
+                topology.connect(a, b, quality);
+            }
+        }
+        topology
+    }
+
+    /// Lets `a` and `b` hear each other.
+    pub fn connect(&mut self, a: usize, b: usize, quality: LinkQuality) {
+        self.links[a][b] = Some(quality);
+        self.links[b][a] = Some(quality);
+    }
+
+    /// Stops `a` and `b` hearing each other.
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        self.links[a][b] = None;
+        self.links[b][a] = None;
+    }
+
+    /// Whether `to` can be reached from `from`, over any number of hops.
+    pub fn reachable(&self, from: usize, to: usize) -> bool {
+        let mut reached = [false; N];
+        let mut stack = heapless::Vec::<usize, N>::new();
+        reached[from] = true;
+        // Cannot fail, each node is pushed once.
+        let _ = stack.push(from);
+        while let Some(node) = stack.pop() {
+            for (next, reached) in reached.iter_mut().enumerate() {
+                if self.links[node][next].is_some() && !*reached {
+                    *reached = true;
+                    let _ = stack.push(next);
+                }
+            }
+        }
+        reached[to]
+    }
+
+    pub fn link(&self, from: usize, to: usize) -> Option<LinkQuality> {
+        self.links[from][to]
+    }
+}
+
+/// Settings of a simulation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimConfig {
+    /// Settings of every node, but for its role.
+    pub mesh: MeshConfig,
+    pub params: LoRaParams,
+    /// Messages each node that is not a relay broadcasts.
+    pub messages: u16,
+    /// Time between two messages of a node; the nodes take turns evenly
+    /// over it.
+    pub interval_ms: u64,
+    /// Bytes of each message, at least 8.
+    pub message_len: usize,
+    /// Seed of the random losses and delays, not 0.
+    pub seed: u32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            mesh: MeshConfig::default(),
+            params: LoRaParams::p2p(),
+            messages: 10,
+            interval_ms: 60_000,
+            message_len: COMPACT_FIX_LEN.max(TIMESTAMP_LEN),
+            seed: 1,
+        }
+    }
+}
+
+/// Outcome of a simulation.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SimReport {
+    pub sent: u32,
+    /// Deliveries of every message to every other node that is not a relay.
+    pub expected: u32,
+    pub delivered: u32,
+    /// Frames sent, the first transmission of each message included.
+    pub transmissions: u32,
+    /// Frames lost as the receiver was transmitting or heard another frame
+    /// at the same time.
+    pub collisions: u32,
+    /// Time frames were on the air, summed over every transmission.
+    pub airtime_ms: u64,
+    pub max_hops: u8,
+    pub max_latency_ms: u64,
+}
+
+impl SimReport {
+    /// Share of the expected deliveries that happened, from 0 to 1.
+    pub fn delivery_ratio(&self) -> f32 {
+        match self.expected {
+            0 => 1.0,
+            expected => self.delivered as f32 / expected as f32,
+        }
+    }
+
+    /// Airtime the network spent on each message sent.
+    pub fn airtime_per_message_ms(&self) -> u64 {
+        self.airtime_ms / self.sent.max(1) as u64
+    }
+}
+
+struct Transmission<const N: usize> {
+    sender: usize,
+    end_ms: u64,
+    /// Receivers that cannot decode the frame, as they were transmitting or
+    /// heard another frame at the same time.
+    lost: [bool; N],
+    payload: heapless::Vec<u8, MAX_PAYLOAD_LEN>,
+}
+
+/// Network of simulated nodes flooding messages over a shared channel.
+struct Network<'a, const N: usize> {
+    topology: &'a Topology<N>,
+    config: &'a SimConfig,
+    nodes: [Flood; N],
+    busy_until_ms: [u64; N],
+    on_air: heapless::Vec<Transmission<N>, MAX_IN_FLIGHT>,
+    random: u32,
+    report: SimReport,
+}
+
+impl<const N: usize> Network<'_, N> {
+    /// xorshift32
+    fn random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    /// Puts a frame from `sender` on the air, deafening the nodes that hear
+    /// it to the frames already on the air and the other way round.
+    fn transmit(&mut self, sender: usize, payload: &[u8], now_ms: u64) {
+        let frame_len = HEADER_LEN + payload.len() + SECURITY_LEN + CRC_LEN;
+        let airtime_ms = self.config.params.time_on_air_ms(frame_len) as u64;
+        let mut transmission = Transmission {
+            sender,
+            end_ms: now_ms + airtime_ms,
+            lost: [false; N],
+            payload: heapless::Vec::new(),
+        };
+        // Cannot fail, the payload came from a buffer of this size.
+        let _ = transmission.payload.extend_from_slice(payload);
+        for other in self.on_air.iter_mut() {
+            other.lost[sender] = true;
+            transmission.lost[other.sender] = true;
+            for receiver in 0..N {
+                if self.topology.link(sender, receiver).is_some()
+                    && self.topology.link(other.sender, receiver).is_some()
+                {
+                    other.lost[receiver] = true;
+                    transmission.lost[receiver] = true;
+                }
+            }
+        }
+        self.busy_until_ms[sender] = transmission.end_ms;
+        self.report.transmissions += 1;
+        self.report.airtime_ms += airtime_ms;
+        if self.on_air.push(transmission).is_err() {
+            esp_println::println!("[Mesh sim] Too many frames on the air");
+        }
+    }
+
+    /// Passes a frame that left the air to the nodes that heard it.
+    fn receive(&mut self, transmission: Transmission<N>) {
+        for receiver in 0..N {
+            let Some(link) = self.topology.link(transmission.sender, receiver) else {
+                continue;
+            };
+            if transmission.lost[receiver] {
+                self.report.collisions += 1;
+                continue;
+            }
+            if self.random() % 100 < link.loss_percent as u32 {
+                continue;
+            }
+            let random = self.random();
+            let heard = self.nodes[receiver].handle(
+                &transmission.payload,
+                Some(link.snr_db),
+                transmission.end_ms,
+                random,
+            );
+            if let Ok(Some(header)) = heard {
+                let message = &transmission.payload[MESH_HEADER_LEN..];
+                let mut sent_ms = [0u8; TIMESTAMP_LEN];
+                sent_ms.copy_from_slice(&message[..TIMESTAMP_LEN]);
+                let latency_ms = transmission.end_ms - u64::from_le_bytes(sent_ms);
+                self.report.delivered += 1;
+                self.report.max_hops = self.report.max_hops.max(header.hops);
+                self.report.max_latency_ms = self.report.max_latency_ms.max(latency_ms);
+            }
+        }
+    }
+}
+
+/// Simulates `config.messages` broadcasts from each node of `topology` that
+/// is not a relay, flooded by nodes of the given `roles`.
+///
+/// Frames are lost on the links at random, and at the nodes that were
+/// transmitting or heard two frames at once; nodes do not sense the channel
+/// before transmitting.
+pub fn simulate<const N: usize>(
+    topology: &Topology<N>,
+    roles: &[MeshRole; N],
+    config: &SimConfig,
+) -> SimReport {
+    let mut random = config.seed.max(1);
+    let nodes = core::array::from_fn(|node| {
+        random = random.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let mesh = MeshConfig {
+            role: roles[node],
+            ..config.mesh
+        };
+        Flood::new(node as NodeId + 1, mesh, random)
+    });
+    let mut network = Network {
+        topology,
+        config,
+        nodes,
+        busy_until_ms: [0; N],
+        on_air: heapless::Vec::new(),
+        random: config.seed.max(1),
+        report: SimReport::default(),
+    };
+    let senders = roles.iter().filter(|role| **role == MeshRole::Node).count() as u32;
+    network.report.expected = senders * senders.saturating_sub(1) * config.messages as u32;
+
+    let total = N * config.messages as usize;
+    // Time of the n-th message of the schedule and the node sending it.
+    let schedule = |n: usize| {
+        let (round, node) = (n / N, n % N);
+        let at_ms = round as u64 * config.interval_ms + node as u64 * config.interval_ms / N as u64;
+        (at_ms, node)
+    };
+    let mut next = 0;
+    let mut message = [0u8; MAX_PAYLOAD_LEN];
+    let mut buffer = [0u8; MAX_PAYLOAD_LEN];
+    loop {
+        while next < total && roles[schedule(next).1] == MeshRole::Relay {
+            next += 1;
+        }
+        let origination = (next < total).then(|| {
+            let (at_ms, node) = schedule(next);
+            at_ms.max(network.busy_until_ms[node])
+        });
+        let end = network.on_air.iter().map(|t| t.end_ms).min();
+        let due = (0..N)
+            .filter_map(|node| {
+                let due_ms = network.nodes[node].next_due_ms()?;
+                Some(due_ms.max(network.busy_until_ms[node]))
+            })
+            .min();
+        let Some(now_ms) = [origination, end, due].into_iter().flatten().min() else {
+            break;
+        };
+
+        while let Some(index) = network
+            .on_air
+            .iter()
+            .position(|transmission| transmission.end_ms <= now_ms)
+        {
+            let transmission = network.on_air.swap_remove(index);
+            network.receive(transmission);
+        }
+        if origination == Some(now_ms) {
+            let node = schedule(next).1;
+            let len = config.message_len.clamp(TIMESTAMP_LEN, MAX_MESSAGE_LEN);
+            message[..TIMESTAMP_LEN].copy_from_slice(&now_ms.to_le_bytes());
+            if let Ok(len) = network.nodes[node].originate(BROADCAST, &message[..len], &mut buffer)
+            {
+                network.report.sent += 1;
+                network.transmit(node, &buffer[..len], now_ms);
+            }
+            next += 1;
+        }
+        for node in 0..N {
+            if network.busy_until_ms[node] > now_ms {
+                continue;
+            }
+            if let Some(len) = network.nodes[node].due(now_ms, &mut buffer) {
+                network.transmit(node, &buffer[..len], now_ms);
+            }
+        }
+    }
+    network.report
+}
+
+/// Why a route could not be followed from one node to another.
+#[derive(Debug, PartialEq)]
+pub enum PathError {
+    /// The node has no route to the destination.
+    NoRoute(NodeId),
+    /// The node routes through a node it no longer hears.
+    LinkDown(NodeId),
+    /// The route comes back to the node.
+    Loop(NodeId),
+}
+
+/// Network of simulated nodes exchanging route advertisements, to check the
+/// routes converge, and stay free of loops as links come and go.
+///
+/// Advertisements are lost on the links at random but never collide, and
+/// arrive as soon as sent. Nodes are given by their index, their ID is the
+/// index plus one.
+pub struct RoutingSim<const N: usize> {
+    topology: Topology<N>,
+    routers: [Router; N],
+    now_ms: u64,
+    random: u32,
+}
+
+impl<const N: usize> RoutingSim<N> {
+    /// Starts the nodes of `topology`, with `seed` for the random losses
+    /// and delays, not 0.
+    pub fn new(topology: Topology<N>, seed: u32) -> Self {
+        let mut random = seed.max(1);
+        let routers = core::array::from_fn(|node| {
+            random = random.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            Router::new(node as NodeId + 1, random, (random % 1_000) as u64)
+        });
+        Self {
+            topology,
+            routers,
+            now_ms: 0,
+            random: seed.max(1),
+        }
+    }
+
+    /// xorshift32
+    fn random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn router(&self, node: usize) -> &Router {
+        &self.routers[node]
+    }
+
+    pub fn topology(&self) -> &Topology<N> {
+        &self.topology
+    }
+
+    /// Lets `a` and `b` hear each other.
+    pub fn connect(&mut self, a: usize, b: usize, quality: LinkQuality) {
+        self.topology.connect(a, b, quality);
+    }
+
+    /// Stops `a` and `b` hearing each other. When `detected`, both notice at
+    /// once, as when a unicast frame to the other is not acknowledged;
+    /// otherwise their routes through the other only expire.
+    pub fn disconnect(&mut self, a: usize, b: usize, detected: bool) {
+        self.topology.disconnect(a, b);
+        if detected {
+            self.routers[a].link_lost(b as NodeId + 1, self.now_ms);
+            self.routers[b].link_lost(a as NodeId + 1, self.now_ms);
+        }
+    }
+
+    /// Restarts `node`, which forgets its routes and numbers its
+    /// advertisements from `seq`.
+    pub fn reboot(&mut self, node: usize, seq: u16) {
+        self.routers[node] = Router::new(node as NodeId + 1, seq as u32, self.now_ms);
+    }
+
+    /// Runs the network for `duration_ms`, returning how many times the
+    /// routes had a loop after an advertisement was received.
+    pub fn run(&mut self, duration_ms: u64) -> u32 {
+        let end_ms = self.now_ms + duration_ms;
+        let mut loops = 0;
+        let mut buffer = [0u8; MAX_PAYLOAD_LEN];
+        while let Some((sender, due_ms)) = self
+            .routers
+            .iter()
+            .map(Router::next_advert_ms)
+            .enumerate()
+            .min_by_key(|(_, due_ms)| *due_ms)
+        {
+            if due_ms > end_ms {
+                break;
+            }
+            self.now_ms = self.now_ms.max(due_ms);
+            for router in self.routers.iter_mut() {
+                router.expire(self.now_ms);
+            }
+            let random = self.random();
+            let Some(len) = self.routers[sender].advertise(self.now_ms, random, &mut buffer) else {
+                continue;
+            };
+            for receiver in 0..N {
+                let Some(link) = self.topology.link(sender, receiver) else {
+                    continue;
+                };
+                if self.random() % 100 < link.loss_percent as u32 {
+                    continue;
+                }
+                // Cannot fail, the advertisement is a list of entries.
+                let _ = self.routers[receiver].handle(
+                    sender as NodeId + 1,
+                    Some(link.snr_db),
+                    &buffer[..len],
+                    self.now_ms,
+                );
+                if !self.loop_free() {
+                    loops += 1;
+                }
+            }
+        }
+        self.now_ms = end_ms;
+        loops
+    }
+
+    /// The nodes a message from `from` to `to` goes through, `to` included,
+    /// following the routes of each node.
+    pub fn path(&self, from: usize, to: usize) -> Result<heapless::Vec<NodeId, N>, PathError> {
+        let destination = to as NodeId + 1;
+        let mut path = heapless::Vec::new();
+        let mut node = from;
+        while node != to {
+            let id = node as NodeId + 1;
+            let next_hop = self.routers[node]
+                .next_hop(destination)
+                .ok_or(PathError::NoRoute(id))?;
+            let next = next_hop as usize - 1;
+            if self.topology.link(node, next).is_none() {
+                return Err(PathError::LinkDown(id));
+            }
+            if next == from || path.contains(&next_hop) {
+                return Err(PathError::Loop(next_hop));
+            }
+            path.push(next_hop).map_err(|_| PathError::Loop(next_hop))?;
+            node = next;
+        }
+        Ok(path)
+    }
+
+    /// Whether every node has a working route to each node it can reach,
+    /// and none to the others.
+    pub fn converged(&self) -> bool {
+        (0..N).all(|from| {
+            (0..N)
+                .filter(|to| *to != from)
+                .all(|to| self.path(from, to).is_ok() == self.topology.reachable(from, to))
+        })
+    }
+
+    /// Whether no route of any node comes back to it.
+    pub fn loop_free(&self) -> bool {
+        (0..N).all(|from| {
+            (0..N)
+                .filter(|to| *to != from)
+                .all(|to| !matches!(self.path(from, to), Err(PathError::Loop(_))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MeshRole::{Node, Relay};
+
+    const GOOD: LinkQuality = LinkQuality {
+        snr_db: 8,
+        loss_percent: 0,
+    };
+    const FAIR: LinkQuality = LinkQuality {
+        snr_db: 8,
+        loss_percent: 5,
+    };
+
+    /// Messages far enough apart for the floods not to overlap.
+    fn quiet() -> SimConfig {
+        SimConfig {
+            interval_ms: 240_000,
+            ..Default::default()
+        }
+    }
+
+    /// Airtime of one frame of a simulated message.
+    fn frame_ms(config: &SimConfig) -> u64 {
+        let len = HEADER_LEN + MESH_HEADER_LEN + config.message_len + SECURITY_LEN + CRC_LEN;
+        config.params.time_on_air_ms(len) as u64
+    }
+
+    #[test]
+    fn floods_a_line_through_relays() {
+        let config = quiet();
+        let report = simulate(&Topology::<5>::line(GOOD), &[Node; 5], &config);
+        assert_eq!(report.delivery_ratio(), 1.0);
+        assert_eq!(report.max_hops, 3);
+        // Only the ends hear a single copy, every node repeats each message.
+        let frames = report.airtime_per_message_ms() / frame_ms(&config);
+        assert!((4..=5).contains(&frames), "{report:?}");
+
+        let roles = [Node, Relay, Node, Relay, Node];
+        let report = simulate(&Topology::<5>::line(GOOD), &roles, &config);
+        assert_eq!((report.sent, report.expected), (30, 60));
+        assert_eq!(report.delivery_ratio(), 1.0);
+        assert!(report.airtime_per_message_ms() <= 5 * frame_ms(&config));
+
+        let config = SimConfig {
+            mesh: MeshConfig {
+                hop_limit: 1,
+                ..Default::default()
+            },
+            ..quiet()
+        };
+        let report = simulate(&Topology::<5>::line(GOOD), &roles, &config);
+        assert!(report.delivery_ratio() < 1.0, "{report:?}");
+    }
+
+    #[test]
+    fn floods_a_grid_through_relays() {
+        let config = quiet();
+        let mut roles = [Node; 9];
+        roles[4] = Relay;
+        let report = simulate(&Topology::<9>::grid(3, GOOD), &roles, &config);
+        assert_eq!(report.expected, 8 * 7 * 10);
+        assert!(report.delivery_ratio() > 0.95, "{report:?}");
+        assert!(report.max_hops <= config.mesh.hop_limit);
+        assert!(report.airtime_per_message_ms() <= 9 * frame_ms(&config));
+
+        let lossy = LinkQuality {
+            snr_db: -5,
+            loss_percent: 10,
+        };
+        let report = simulate(&Topology::<9>::grid(3, lossy), &[Node; 9], &config);
+        assert!(report.delivery_ratio() > 0.9, "{report:?}");
+    }
+
+    #[test]
+    fn suppresses_repeats_in_a_full_network() {
+        let config = quiet();
+        let roles = [Node, Node, Node, Node, Relay, Relay];
+        let report = simulate(&Topology::<6>::full(GOOD), &roles, &config);
+        assert_eq!(report.delivery_ratio(), 1.0);
+        assert_eq!(report.max_hops, 0);
+        assert!(report.airtime_per_message_ms() < 6 * frame_ms(&config));
+
+        let config = SimConfig {
+            mesh: MeshConfig {
+                suppress_after: 0,
+                ..Default::default()
+            },
+            ..quiet()
+        };
+        let unmanaged = simulate(&Topology::<6>::full(GOOD), &roles, &config);
+        assert_eq!(unmanaged.delivery_ratio(), 1.0);
+        assert_eq!(unmanaged.airtime_per_message_ms(), 6 * frame_ms(&config));
+        assert!(report.airtime_ms < unmanaged.airtime_ms);
+    }
+
+    /// Runs `sim` for `duration_ms`, checking the routes never loop and
+    /// converge by the end.
+    fn settle<const N: usize>(sim: &mut RoutingSim<N>, duration_ms: u64) {
+        assert_eq!(sim.run(duration_ms), 0, "loops by {} ms", sim.now_ms());
+        assert!(sim.loop_free());
+        assert!(sim.converged(), "not converged by {} ms", sim.now_ms());
+    }
+
+    #[test]
+    fn routes_around_lost_links() {
+        for seed in 1..10 {
+            let mut sim = RoutingSim::<9>::new(Topology::grid(3, FAIR), seed);
+            settle(&mut sim, 300_000);
+            assert_eq!(sim.path(0, 8).map(|path| path.len()), Ok(4));
+            // Both ends notice the first loss, the routes over the others
+            // only expire.
+            sim.disconnect(4, 5, true);
+            sim.disconnect(0, 1, false);
+            sim.disconnect(7, 8, false);
+            settle(&mut sim, 600_000);
+            assert_eq!(sim.path(0, 8).map(|path| path.len()), Ok(6));
+            // Node 2 is cut off from the others.
+            sim.disconnect(1, 2, true);
+            sim.disconnect(2, 5, false);
+            settle(&mut sim, 600_000);
+            assert_eq!(sim.path(0, 2), Err(PathError::NoRoute(1)));
+        }
+    }
+
+    #[test]
+    fn uses_recovered_links() {
+        for seed in 1..10 {
+            let mut sim = RoutingSim::<6>::new(Topology::line(FAIR), seed);
+            settle(&mut sim, 300_000);
+            sim.disconnect(2, 3, true);
+            settle(&mut sim, 600_000);
+            assert_eq!(sim.path(0, 5), Err(PathError::NoRoute(1)));
+            sim.connect(2, 3, FAIR);
+            settle(&mut sim, 300_000);
+            assert_eq!(sim.path(0, 5).map(|path| path.len()), Ok(5));
+            // A shortcut is taken once heard.
+            sim.connect(0, 5, FAIR);
+            settle(&mut sim, 300_000);
+            assert_eq!(sim.path(0, 5).unwrap()[..], [6]);
+        }
+    }
+
+    #[test]
+    fn renews_the_routes_of_rebooted_nodes() {
+        // The node restarts its sequence numbers at random, below or above
+        // the ones the others know.
+        for (seed, offset) in (1..10).zip([1_000u16, 64_536].into_iter().cycle()) {
+            let mut sim = RoutingSim::<9>::new(Topology::grid(3, FAIR), seed);
+            settle(&mut sim, 300_000);
+            let seq = sim.router(0).route(5).map(|route| route.seq).unwrap();
+            sim.reboot(4, seq.wrapping_sub(offset));
+            settle(&mut sim, 300_000);
+            let renewed = sim.router(0).route(5).map(|route| route.seq).unwrap();
+            assert!(renewed.wrapping_sub(seq) as i16 > 0, "seed {seed}");
+        }
+    }
+}
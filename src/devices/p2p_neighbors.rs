@@ -0,0 +1,446 @@
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use super::p2p_frame::NodeId;
+
+/// Neighbours tracked; the least recently heard is forgotten first.
+pub const MAX_NEIGHBORS: usize = 16;
+/// Time between two beacons of a node.
+pub const BEACON_INTERVAL_MS: u64 = 60_000;
+/// Upper bound of the random delay added to the beacon interval, so nodes
+/// started together drift apart.
+pub const BEACON_JITTER_MS: u64 = 10_000;
+/// Neighbours not heard for this long are dropped, three beacons missed.
+pub const NEIGHBOR_EXPIRY_MS: u64 = 3 * BEACON_INTERVAL_MS;
+pub const BEACON_LEN: usize = 2;
+/// Weight of a new sample in the moving averages, 1/2^n.
+const AVERAGE_SHIFT: u32 = 3;
+/// Battery level of a beacon when it is not measured.
+const BATTERY_UNKNOWN: u8 = 0xFF;
+/// Voltages of an empty and of a full LiPo cell, in mV.
+const EMPTY_MV: u32 = 3300;
+const FULL_MV: u32 = 4200;
+
+/// Bits of the capabilities of a node.
+pub mod capabilities {
+    /// The node only relays messages, see `p2p_mesh`.
+    pub const RELAY: u8 = 0x01;
+    /// The node has a GPS fix and sends its position.
+    pub const GPS: u8 = 0x02;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BeaconError {
+    Malformed(usize),
+}
+
+impl core::fmt::Display for BeaconError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BeaconError::Malformed(len) => write!(f, "Malformed beacon of {} bytes", len),
+        }
+    }
+}
+
+/// Announcement a node broadcasts to the nodes in range, the payload of a
+/// `Beacon` frame; the node ID is the source of the frame.
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 1    | Capabilities, see [`capabilities`]      |
+/// | 1      | 1    | Battery level in percent, 0xFF: unknown |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Beacon {
+    pub capabilities: u8,
+    pub battery_percent: Option<u8>,
+}
+
+impl Beacon {
+    pub fn encode(&self) -> [u8; BEACON_LEN] {
+        [
+            self.capabilities,
+            self.battery_percent.unwrap_or(BATTERY_UNKNOWN),
+        ]
+    }
+
+    /// Decodes a beacon; bytes after the known fields are ignored, for the
+    /// fields later versions add.
+    pub fn decode(bytes: &[u8]) -> Result<Self, BeaconError> {
+        let [capabilities, battery, ..] = *bytes else {
+            return Err(BeaconError::Malformed(bytes.len()));
+        };
+        Ok(Self {
+            capabilities,
+            battery_percent: match battery {
+                BATTERY_UNKNOWN => None,
+                percent => Some(percent.min(100)),
+            },
+        })
+    }
+}
+
+/// A node heard directly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Neighbor {
+    pub node: NodeId,
+    /// From its last beacon, `None` until one is heard.
+    pub capabilities: Option<u8>,
+    pub battery_percent: Option<u8>,
+    pub last_heard_ms: u64,
+    /// Frames heard from it, beacons included.
+    pub frames: u32,
+    /// Moving averages of the signal of its frames, in 1/16 dB.
+    rssi_x16: Option<i32>,
+    snr_x16: Option<i32>,
+}
+
+impl Neighbor {
+    fn new(node: NodeId) -> Self {
+        Self {
+            node,
+            capabilities: None,
+            battery_percent: None,
+            last_heard_ms: 0,
+            frames: 0,
+            rssi_x16: None,
+            snr_x16: None,
+        }
+    }
+
+    /// Moving average of the signal strength, in dBm.
+    pub fn rssi_dbm(&self) -> Option<i16> {
+        self.rssi_x16.map(|rssi| ((rssi + 8) >> 4) as i16)
+    }
+
+    /// Moving average of the signal-to-noise ratio, in dB.
+    pub fn snr_db(&self) -> Option<i16> {
+        self.snr_x16.map(|snr| ((snr + 8) >> 4) as i16)
+    }
+
+    pub fn age_ms(&self, now_ms: u64) -> u64 {
+        now_ms.saturating_sub(self.last_heard_ms)
+    }
+}
+
+/// Adds a sample to an exponential moving average, starting it from the
+/// first one.
+fn average(average: &mut Option<i32>, sample: Option<i16>) {
+    let Some(sample) = sample else {
+        return;
+    };
+    let sample = (sample as i32) << 4;
+    *average = Some(match *average {
+        Some(value) => value + ((sample - value) >> AVERAGE_SHIFT),
+        None => sample,
+    });
+}
+
+/// The nodes in range, learnt from the frames they send.
+#[derive(Debug, Default)]
+pub struct NeighborTable {
+    neighbors: heapless::Vec<Neighbor, MAX_NEIGHBORS>,
+}
+
+impl NeighborTable {
+    pub const fn new() -> Self {
+        Self {
+            neighbors: heapless::Vec::new(),
+        }
+    }
+
+    /// Records a frame heard from `node` at the given signal.
+    pub fn heard(&mut self, node: NodeId, rssi: Option<i16>, snr: Option<i16>, now_ms: u64) {
+        let index = match self.neighbors.iter().position(|n| n.node == node) {
+            Some(index) => index,
+            None => {
+                if self.neighbors.is_full() {
+                    let oldest = self
+                        .neighbors
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, n)| n.last_heard_ms)
+                        .map_or(0, |(index, _)| index);
+                    self.neighbors.swap_remove(oldest);
+                }
+                // Cannot fail, a slot was freed above.
+                let _ = self.neighbors.push(Neighbor::new(node));
+                self.neighbors.len() - 1
+            }
+        };
+        let neighbor = &mut self.neighbors[index];
+        neighbor.last_heard_ms = now_ms;
+        neighbor.frames = neighbor.frames.saturating_add(1);
+        average(&mut neighbor.rssi_x16, rssi);
+        average(&mut neighbor.snr_x16, snr);
+    }
+
+    /// Records the capabilities and battery level `node` announced, once its
+    /// beacon was recorded with [`NeighborTable::heard`].
+    pub fn beacon(&mut self, node: NodeId, beacon: &Beacon) {
+        if let Some(neighbor) = self.neighbors.iter_mut().find(|n| n.node == node) {
+            neighbor.capabilities = Some(beacon.capabilities);
+            neighbor.battery_percent = beacon.battery_percent;
+        }
+    }
+
+    /// Drops the neighbours not heard for [`NEIGHBOR_EXPIRY_MS`], calling
+    /// `expired` with each.
+    pub fn expire(&mut self, now_ms: u64, mut expired: impl FnMut(&Neighbor)) {
+        self.neighbors.retain(|neighbor| {
+            let keep = neighbor.age_ms(now_ms) < NEIGHBOR_EXPIRY_MS;
+            if !keep {
+                expired(neighbor);
+            }
+            keep
+        });
+    }
+
+    pub fn get(&self, node: NodeId) -> Option<&Neighbor> {
+        self.neighbors.iter().find(|n| n.node == node)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Neighbor> {
+        self.neighbors.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.neighbors.is_empty()
+    }
+
+    /// Writes the table as a JSON array for HTTP reports, e.g.
+    /// `[{"node":"1A2B","age_s":12,"rssi":-87,"snr":6,"battery":80,"capabilities":2}]`,
+    /// with `null` for the values not known.
+    pub fn write_json(&self, out: &mut impl core::fmt::Write, now_ms: u64) -> core::fmt::Result {
+        struct Value<T>(Option<T>);
+        impl<T: core::fmt::Display> core::fmt::Display for Value<T> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                match &self.0 {
+                    Some(value) => write!(f, "{}", value),
+                    None => write!(f, "null"),
+                }
+            }
+        }
+
+        out.write_char('[')?;
+        for (i, neighbor) in self.neighbors.iter().enumerate() {
+            if i > 0 {
+                out.write_char(',')?;
+            }
+            write!(
+                out,
+                "{{\"node\":\"{:04X}\",\"age_s\":{},\"rssi\":{},\"snr\":{},\"battery\":{},\"capabilities\":{}}}",
+                neighbor.node,
+                neighbor.age_ms(now_ms) / 1000,
+                Value(neighbor.rssi_dbm()),
+                Value(neighbor.snr_db()),
+                Value(neighbor.battery_percent),
+                Value(neighbor.capabilities)
+            )?;
+        }
+        out.write_char(']')
+    }
+}
+
+/// Neighbours of this node, shared with routing, the display and HTTP
+/// reports.
+static NEIGHBORS: Mutex<CriticalSectionRawMutex, RefCell<NeighborTable>> =
+    Mutex::new(RefCell::new(NeighborTable::new()));
+
+/// Charge left in a battery at `mv`, from 0 to 100 percent, on a straight
+/// line between [`EMPTY_MV`] and [`FULL_MV`].
+pub fn percent_from_mv(mv: u32) -> u8 {
+    ((mv.clamp(EMPTY_MV, FULL_MV) - EMPTY_MV) * 100 / (FULL_MV - EMPTY_MV)) as u8
+}
+
+/// Battery level this node reports in its beacons.
+static BATTERY_PERCENT: Mutex<CriticalSectionRawMutex, Cell<Option<u8>>> =
+    Mutex::new(Cell::new(None));
+
+/// Runs `f` with the neighbour table of this node.
+pub fn with_neighbors<T>(f: impl FnOnce(&mut NeighborTable) -> T) -> T {
+    NEIGHBORS.lock(|table| f(&mut table.borrow_mut()))
+}
+
+/// A copy of the neighbours of this node.
+pub fn neighbors() -> heapless::Vec<Neighbor, MAX_NEIGHBORS> {
+    with_neighbors(|table| table.iter().copied().collect())
+}
+
+pub fn neighbor(node: NodeId) -> Option<Neighbor> {
+    with_neighbors(|table| table.get(node).copied())
+}
+
+pub fn battery_percent() -> Option<u8> {
+    BATTERY_PERCENT.lock(|battery| battery.get())
+}
+
+/// Sets the battery level reported in beacons, see
+/// [`task_battery`](crate::devices::battery::task_battery).
+pub fn set_battery_percent(percent: Option<u8>) {
+    BATTERY_PERCENT.lock(|battery| battery.set(percent.map(|percent| percent.min(100))));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_beacons() {
+        let beacon = Beacon {
+            capabilities: capabilities::GPS,
+            battery_percent: Some(80),
+        };
+        assert_eq!(beacon.encode(), [0x02, 80]);
+        assert_eq!(Beacon::decode(&beacon.encode()), Ok(beacon));
+
+        let unknown = Beacon {
+            capabilities: capabilities::RELAY | capabilities::GPS,
+            battery_percent: None,
+        };
+        assert_eq!(unknown.encode(), [0x03, 0xFF]);
+        assert_eq!(Beacon::decode(&unknown.encode()), Ok(unknown));
+        // Later fields are ignored and levels above 100 are capped.
+        assert_eq!(
+            Beacon::decode(&[0x01, 200, 9, 9]).unwrap().battery_percent,
+            Some(100)
+        );
+        assert_eq!(Beacon::decode(&[0x01]), Err(BeaconError::Malformed(1)));
+        assert_eq!(Beacon::decode(&[]), Err(BeaconError::Malformed(0)));
+    }
+
+    #[test]
+    fn averages_the_signal() {
+        let mut table = NeighborTable::new();
+        table.heard(5, Some(-100), Some(-10), 1_000);
+        let neighbor = table.get(5).unwrap();
+        assert_eq!(
+            (neighbor.rssi_dbm(), neighbor.snr_db()),
+            (Some(-100), Some(-10))
+        );
+        assert_eq!((neighbor.frames, neighbor.capabilities), (1, None));
+
+        // Each sample moves the average an eighth of the way.
+        table.heard(5, Some(-60), Some(6), 2_000);
+        let neighbor = table.get(5).unwrap();
+        assert_eq!(
+            (neighbor.rssi_dbm(), neighbor.snr_db()),
+            (Some(-95), Some(-8))
+        );
+        for i in 0..60 {
+            table.heard(5, Some(-60), Some(6), 3_000 + i);
+        }
+        let neighbor = table.get(5).unwrap();
+        assert_eq!(
+            (neighbor.rssi_dbm(), neighbor.snr_db()),
+            (Some(-60), Some(6))
+        );
+        assert_eq!(neighbor.frames, 62);
+        assert_eq!(neighbor.last_heard_ms, 3_059);
+
+        // A frame without a measure leaves the averages as they are.
+        table.heard(5, None, None, 4_000);
+        let neighbor = table.get(5).unwrap();
+        assert_eq!(
+            (neighbor.rssi_dbm(), neighbor.snr_db()),
+            (Some(-60), Some(6))
+        );
+        assert_eq!(neighbor.age_ms(5_500), 1_500);
+    }
+
+    #[test]
+    fn records_beacons_of_known_nodes() {
+        let mut table = NeighborTable::new();
+        let beacon = Beacon {
+            capabilities: capabilities::RELAY,
+            battery_percent: Some(50),
+        };
+        table.heard(5, None, None, 0);
+        table.beacon(5, &beacon);
+        table.beacon(6, &beacon);
+        assert_eq!(
+            table.get(5).unwrap().capabilities,
+            Some(capabilities::RELAY)
+        );
+        assert_eq!(table.get(5).unwrap().battery_percent, Some(50));
+        assert!(table.get(6).is_none());
+    }
+
+    #[test]
+    fn evicts_the_least_recently_heard() {
+        let mut table = NeighborTable::new();
+        for node in 0..MAX_NEIGHBORS as NodeId {
+            table.heard(node, None, None, 100 + node as u64);
+        }
+        // Node 0 is heard again, node 1 is then the oldest.
+        table.heard(0, None, None, 1_000);
+        table.heard(99, None, None, 1_001);
+        assert_eq!(table.len(), MAX_NEIGHBORS);
+        assert!(table.get(1).is_none());
+        assert!(table.get(0).is_some());
+        assert_eq!(table.get(99).unwrap().frames, 1);
+    }
+
+    #[test]
+    fn expires_silent_neighbors() {
+        let mut table = NeighborTable::new();
+        table.heard(1, None, None, 1_000);
+        table.heard(2, None, None, 5_000);
+        let mut expired = std::vec::Vec::new();
+        table.expire(1_000 + NEIGHBOR_EXPIRY_MS - 1, |n| expired.push(n.node));
+        assert!(expired.is_empty());
+        table.expire(1_000 + NEIGHBOR_EXPIRY_MS, |n| expired.push(n.node));
+        assert_eq!(expired, [1]);
+        assert_eq!(
+            table.iter().map(|n| n.node).collect::<std::vec::Vec<_>>(),
+            [2]
+        );
+        table.expire(u64::MAX, |n| expired.push(n.node));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn writes_json() {
+        let mut table = NeighborTable::new();
+        let mut json = heapless::String::<256>::new();
+        table.write_json(&mut json, 0).unwrap();
+        assert_eq!(json.as_str(), "[]");
+
+        table.heard(0x1A2B, Some(-87), Some(6), 1_000);
+        table.beacon(
+            0x1A2B,
+            &Beacon {
+                capabilities: capabilities::GPS,
+                battery_percent: Some(80),
+            },
+        );
+        table.heard(7, None, None, 3_000);
+        json.clear();
+        table.write_json(&mut json, 13_500).unwrap();
+        assert_eq!(
+            json.as_str(),
+            concat!(
+                r#"[{"node":"1A2B","age_s":12,"rssi":-87,"snr":6,"battery":80,"capabilities":2},"#,
+                r#"{"node":"0007","age_s":10,"rssi":null,"snr":null,"battery":null,"capabilities":null}]"#
+            )
+        );
+        // A buffer too small fails instead of cutting the array.
+        let mut short = heapless::String::<16>::new();
+        assert!(table.write_json(&mut short, 13_500).is_err());
+    }
+
+    #[test]
+    fn converts_battery_voltage() {
+        assert_eq!(percent_from_mv(0), 0);
+        assert_eq!(percent_from_mv(EMPTY_MV), 0);
+        assert_eq!(percent_from_mv(3_750), 50);
+        assert_eq!(percent_from_mv(4_191), 99);
+        assert_eq!(percent_from_mv(FULL_MV), 100);
+        assert_eq!(percent_from_mv(5_000), 100);
+    }
+}
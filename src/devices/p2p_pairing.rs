@@ -0,0 +1,833 @@
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use super::{
+    button::LONG_PRESS_SIGNAL,
+    p2p_arq::{Arq, ArqError, P2pRadio},
+    p2p_frame::{MessageType, NodeId, BROADCAST, MAX_PAYLOAD_LEN},
+};
+
+/// Time a node stays in pairing mode, verification included.
+pub const PAIRING_TIMEOUT_MS: u64 = 60_000;
+/// Time between two transmissions of the messages of the current step.
+pub const RESEND_INTERVAL_MS: u64 = 2_000;
+/// Bytes of a pairing message, its type then a key, commitment or MAC.
+pub const MESSAGE_LEN: usize = 33;
+/// Time a paired node keeps answering the peer's confirmations, long
+/// enough for two of its resends.
+pub const CLOSING_MS: u64 = 3 * RESEND_INTERVAL_MS;
+/// Verification codes are below this, shown as 6 digits.
+pub const CODE_MODULUS: u32 = 1_000_000;
+
+mod kind {
+    pub const COMMIT: u8 = 0x01;
+    pub const REVEAL: u8 = 0x02;
+    pub const CONFIRM: u8 = 0x03;
+    pub const UNPAIR: u8 = 0x04;
+}
+
+const CONFIRM_LABEL: &[u8] = b"LoRa P2P confirm";
+const UNPAIR_LABEL: &[u8] = b"LoRa P2P unpair";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum PairingError {
+    Malformed(usize),
+    /// The revealed public key does not match the peer's commitment.
+    CommitmentMismatch,
+    /// The public key is of low order, the shared secret would be known.
+    WeakKey,
+    /// The peer's confirmation does not verify: it derived another link key.
+    BadConfirmation,
+}
+
+impl core::fmt::Display for PairingError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PairingError::Malformed(len) => write!(f, "Malformed pairing message of {} bytes", len),
+            PairingError::CommitmentMismatch => {
+                write!(f, "Public key does not match the commitment")
+            }
+            PairingError::WeakKey => write!(f, "Weak public key"),
+            PairingError::BadConfirmation => write!(f, "Confirmation does not verify"),
+        }
+    }
+}
+
+/// Message of the pairing handshake, the payload of a `Pairing` frame.
+///
+/// | Offset | Size | Field                                            |
+/// |--------|------|--------------------------------------------------|
+/// | 0      | 1    | Type: 1 = commit, 2 = reveal, 3 = confirm,       |
+/// |        |      | 4 = unpair                                       |
+/// | 1      | 32   | SHA-256 of the public key, the key, or a MAC     |
+///
+/// The MAC of a confirm or unpair message is the HMAC-SHA256, under the
+/// link key, of a label and the IDs of the sender then the receiver (LE).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    Commit([u8; 32]),
+    Reveal([u8; 32]),
+    /// The sender's user accepted the code, proving the sender derived the
+    /// same link key.
+    Confirm([u8; 32]),
+    /// The sender forgot the link key agreed with the receiver.
+    Unpair([u8; 32]),
+}
+
+impl Message {
+    pub fn encode(&self) -> [u8; MESSAGE_LEN] {
+        let (kind, value) = match self {
+            Message::Commit(hash) => (kind::COMMIT, hash),
+            Message::Reveal(key) => (kind::REVEAL, key),
+            Message::Confirm(mac) => (kind::CONFIRM, mac),
+            Message::Unpair(mac) => (kind::UNPAIR, mac),
+        };
+        let mut buffer = [0u8; MESSAGE_LEN];
+        buffer[0] = kind;
+        buffer[1..].copy_from_slice(value);
+        buffer
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, PairingError> {
+        let (&kind, value) = bytes
+            .split_first()
+            .ok_or(PairingError::Malformed(bytes.len()))?;
+        let value: [u8; 32] = value
+            .try_into()
+            .map_err(|_| PairingError::Malformed(bytes.len()))?;
+        match kind {
+            kind::COMMIT => Ok(Message::Commit(value)),
+            kind::REVEAL => Ok(Message::Reveal(value)),
+            kind::CONFIRM => Ok(Message::Confirm(value)),
+            kind::UNPAIR => Ok(Message::Unpair(value)),
+            _ => Err(PairingError::Malformed(bytes.len())),
+        }
+    }
+}
+
+/// Progress of pairing, shared with the display.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PairingStatus {
+    Idle,
+    /// Announcing the commitment, waiting for a node in pairing mode.
+    Searching,
+    /// Exchanging public keys with `peer`.
+    Exchanging {
+        peer: NodeId,
+    },
+    /// Waiting for the user to compare `code` with the one `peer` shows.
+    Verifying {
+        peer: NodeId,
+        code: u32,
+    },
+    /// The code was accepted, waiting for the user of `peer` to accept it.
+    Confirming {
+        peer: NodeId,
+    },
+    Paired {
+        peer: NodeId,
+    },
+    TimedOut,
+    Cancelled,
+}
+
+static PAIRING_STATUS: Mutex<CriticalSectionRawMutex, Cell<PairingStatus>> =
+    Mutex::new(Cell::new(PairingStatus::Idle));
+
+pub fn pairing_status() -> PairingStatus {
+    PAIRING_STATUS.lock(|status| status.get())
+}
+
+fn set_status(value: PairingStatus) {
+    PAIRING_STATUS.lock(|status| status.set(value));
+}
+
+/// Returns the status to idle once pairing ended, hiding its outcome.
+pub fn clear_status() {
+    PAIRING_STATUS.lock(|status| {
+        if matches!(
+            status.get(),
+            PairingStatus::Paired { .. } | PairingStatus::TimedOut | PairingStatus::Cancelled
+        ) {
+            status.set(PairingStatus::Idle);
+        }
+    });
+}
+
+/// Messages to send, with their destination.
+pub type Outgoing = heapless::Vec<(NodeId, Message), 2>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Searching,
+    Exchanging {
+        peer: NodeId,
+        commitment: [u8; 32],
+    },
+    Verifying {
+        peer: NodeId,
+        key: [u8; 16],
+        code: u32,
+        /// The peer's confirmation arrived already.
+        confirmed: bool,
+    },
+    /// The user accepted the code, the peer did not yet.
+    Confirming {
+        peer: NodeId,
+        key: [u8; 16],
+    },
+    /// Both sides confirmed; the peer's confirmations are answered until
+    /// `until_ms`, in case it missed this node's.
+    Closing {
+        peer: NodeId,
+        key: [u8; 16],
+        until_ms: u64,
+    },
+    Paired {
+        peer: NodeId,
+        key: [u8; 16],
+    },
+    TimedOut,
+    Cancelled,
+}
+
+/// MAC of `label` sent by `source` to `destination`, under the link `key`.
+fn mac(key: &[u8; 16], label: &[u8], source: NodeId, destination: NodeId) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(label);
+    mac.update(&source.to_le_bytes());
+    mac.update(&destination.to_le_bytes());
+    mac
+}
+
+/// Message telling `peer` that `node` forgot the link `key` agreed with it.
+pub fn unpair_message(node: NodeId, peer: NodeId, key: &[u8; 16]) -> Message {
+    Message::Unpair(
+        mac(key, UNPAIR_LABEL, node, peer)
+            .finalize()
+            .into_bytes()
+            .into(),
+    )
+}
+
+/// Whether `message` from `source` tells `node` to forget the link `key`
+/// agreed with `source`.
+pub fn is_unpair(message: &Message, source: NodeId, node: NodeId, key: &[u8; 16]) -> bool {
+    match message {
+        Message::Unpair(tag) => mac(key, UNPAIR_LABEL, source, node)
+            .verify_slice(tag)
+            .is_ok(),
+        _ => false,
+    }
+}
+
+/// X25519 key agreement between two nodes in pairing mode, confirmed by the
+/// users comparing a verification code (sans-IO).
+///
+/// 1. Both nodes broadcast a commitment to their public key.
+/// 2. A node that receives a commitment sends its own and its public key to
+///    that peer, which answers the same way.
+/// 3. Each node checks the peer's key against its commitment and derives the
+///    link key and verification code from the shared secret.
+/// 4. The users compare the codes and accept them; each node then sends a
+///    confirmation MAC'd with the link key, until the peer's arrives.
+/// 5. A node is paired once its user accepted and the peer's confirmation
+///    verified, so neither side stores a key the other did not derive and
+///    accept.
+///
+/// As a node only learns the peer's key after committing to its own, a node
+/// in the middle cannot choose keys that give both sides the same code, it
+/// has a one in [`CODE_MODULUS`] chance of going unnoticed.
+pub struct Pairing {
+    node: NodeId,
+    secret: StaticSecret,
+    public: PublicKey,
+    state: State,
+    deadline_ms: u64,
+    next_send_ms: u64,
+}
+
+impl Pairing {
+    /// Starts pairing `node` with a fresh random `secret`.
+    pub fn new(node: NodeId, secret: [u8; 32], now_ms: u64) -> Self {
+        let secret = StaticSecret::from(secret);
+        Self {
+            node,
+            public: PublicKey::from(&secret),
+            secret,
+            state: State::Searching,
+            deadline_ms: now_ms + PAIRING_TIMEOUT_MS,
+            next_send_ms: now_ms,
+        }
+    }
+
+    pub fn status(&self) -> PairingStatus {
+        match self.state {
+            State::Searching => PairingStatus::Searching,
+            State::Exchanging { peer, .. } => PairingStatus::Exchanging { peer },
+            State::Verifying { peer, code, .. } => PairingStatus::Verifying { peer, code },
+            State::Confirming { peer, .. } => PairingStatus::Confirming { peer },
+            State::Closing { peer, .. } | State::Paired { peer, .. } => {
+                PairingStatus::Paired { peer }
+            }
+            State::TimedOut => PairingStatus::TimedOut,
+            State::Cancelled => PairingStatus::Cancelled,
+        }
+    }
+
+    /// Whether pairing ended, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self.state,
+            State::Paired { .. } | State::TimedOut | State::Cancelled
+        )
+    }
+
+    /// Time of the next call to [`Pairing::poll`].
+    pub fn next_poll_ms(&self) -> u64 {
+        match self.state {
+            State::Closing { until_ms, .. } => until_ms,
+            _ => self.next_send_ms.min(self.deadline_ms),
+        }
+    }
+
+    /// The peer and link key, once paired.
+    pub fn link(&self) -> Option<(NodeId, [u8; 16])> {
+        match self.state {
+            State::Paired { peer, key } => Some((peer, key)),
+            _ => None,
+        }
+    }
+
+    fn commitment(&self) -> [u8; 32] {
+        Sha256::digest(self.public.as_bytes()).into()
+    }
+
+    fn exchange(&self, peer: NodeId) -> Outgoing {
+        let mut out = Outgoing::new();
+        let _ = out.push((peer, Message::Commit(self.commitment())));
+        let _ = out.push((peer, Message::Reveal(*self.public.as_bytes())));
+        out
+    }
+
+    fn confirmation(&self, peer: NodeId, key: &[u8; 16]) -> Outgoing {
+        let tag = mac(key, CONFIRM_LABEL, self.node, peer).finalize();
+        let mut out = Outgoing::new();
+        let _ = out.push((peer, Message::Confirm(tag.into_bytes().into())));
+        out
+    }
+
+    fn check_confirmation(
+        &self,
+        peer: NodeId,
+        key: &[u8; 16],
+        tag: &[u8; 32],
+    ) -> Result<(), PairingError> {
+        mac(key, CONFIRM_LABEL, peer, self.node)
+            .verify_slice(tag)
+            .map_err(|_| PairingError::BadConfirmation)
+    }
+
+    /// Starts answering the peer's confirmations, once both sides confirmed.
+    fn close(&mut self, peer: NodeId, key: [u8; 16], now_ms: u64) -> Outgoing {
+        self.state = State::Closing {
+            peer,
+            key,
+            until_ms: now_ms + CLOSING_MS,
+        };
+        self.next_send_ms = now_ms + RESEND_INTERVAL_MS;
+        self.confirmation(peer, &key)
+    }
+
+    /// Ends pairing once it timed out, or once it is paired and the peer had
+    /// time to get the confirmation, and returns the messages to send again.
+    pub fn poll(&mut self, now_ms: u64) -> Outgoing {
+        if self.is_finished() {
+            return Outgoing::new();
+        }
+        if let State::Closing {
+            peer,
+            key,
+            until_ms,
+        } = self.state
+        {
+            if now_ms >= until_ms {
+                self.state = State::Paired { peer, key };
+            }
+            return Outgoing::new();
+        }
+        if now_ms >= self.deadline_ms {
+            self.state = State::TimedOut;
+            return Outgoing::new();
+        }
+        if now_ms < self.next_send_ms {
+            return Outgoing::new();
+        }
+        self.next_send_ms = now_ms + RESEND_INTERVAL_MS;
+        match self.state {
+            State::Searching => {
+                let mut out = Outgoing::new();
+                let _ = out.push((BROADCAST, Message::Commit(self.commitment())));
+                out
+            }
+            State::Exchanging { peer, .. } => self.exchange(peer),
+            State::Confirming { peer, key } => self.confirmation(peer, &key),
+            // The peer sends its commitment again until it has our key.
+            _ => Outgoing::new(),
+        }
+    }
+
+    /// Handles a pairing message from `source`, returning the answers.
+    ///
+    /// Messages of other nodes than the peer, and out of turn, are ignored.
+    ///
+    /// # Errors
+    ///
+    /// * `Malformed` - If the payload is not a pairing message.
+    /// * `CommitmentMismatch` - If the peer's key does not match its commitment.
+    /// * `WeakKey` - If the peer's key gives a predictable shared secret.
+    /// * `BadConfirmation` - If the peer's confirmation is not MAC'd with the
+    ///   link key; it is ignored otherwise.
+    pub fn handle(
+        &mut self,
+        source: NodeId,
+        payload: &[u8],
+        now_ms: u64,
+    ) -> Result<Outgoing, PairingError> {
+        let message = Message::decode(payload)?;
+        match (self.state, message) {
+            (State::Searching, Message::Commit(commitment)) => {
+                self.state = State::Exchanging {
+                    peer: source,
+                    commitment,
+                };
+                self.next_send_ms = now_ms + RESEND_INTERVAL_MS;
+                Ok(self.exchange(source))
+            }
+            (State::Exchanging { peer, commitment }, Message::Reveal(key)) if peer == source => {
+                if <[u8; 32]>::from(Sha256::digest(key)) != commitment {
+                    return Err(PairingError::CommitmentMismatch);
+                }
+                let (key, code) = self.derive(source, &PublicKey::from(key))?;
+                self.state = State::Verifying {
+                    peer,
+                    key,
+                    code,
+                    confirmed: false,
+                };
+                Ok(Outgoing::new())
+            }
+            (State::Verifying { peer, .. }, Message::Commit(_)) if peer == source => {
+                let mut out = Outgoing::new();
+                let _ = out.push((peer, Message::Reveal(*self.public.as_bytes())));
+                Ok(out)
+            }
+            (
+                State::Verifying {
+                    peer, key, code, ..
+                },
+                Message::Confirm(tag),
+            ) if peer == source => {
+                self.check_confirmation(peer, &key, &tag)?;
+                self.state = State::Verifying {
+                    peer,
+                    key,
+                    code,
+                    confirmed: true,
+                };
+                Ok(Outgoing::new())
+            }
+            (State::Confirming { peer, key }, Message::Confirm(tag)) if peer == source => {
+                self.check_confirmation(peer, &key, &tag)?;
+                Ok(self.close(peer, key, now_ms))
+            }
+            // The peer confirms again until it gets this node's confirmation;
+            // answered once per interval, so two closing nodes do not
+            // answer each other without end.
+            (State::Closing { peer, key, .. }, Message::Confirm(tag)) if peer == source => {
+                self.check_confirmation(peer, &key, &tag)?;
+                if now_ms < self.next_send_ms {
+                    return Ok(Outgoing::new());
+                }
+                self.next_send_ms = now_ms + RESEND_INTERVAL_MS;
+                Ok(self.confirmation(peer, &key))
+            }
+            _ => Ok(Outgoing::new()),
+        }
+    }
+
+    /// Link key and verification code, from the shared secret and both public
+    /// keys, the key of the lower node ID first.
+    fn derive(&self, peer: NodeId, key: &PublicKey) -> Result<([u8; 16], u32), PairingError> {
+        let shared = self.secret.diffie_hellman(key);
+        if !shared.was_contributory() {
+            return Err(PairingError::WeakKey);
+        }
+        let (first, second) = if self.node < peer {
+            (&self.public, key)
+        } else {
+            (key, &self.public)
+        };
+        let digest = Sha256::new()
+            .chain_update(b"LoRa P2P pairing")
+            .chain_update(first.as_bytes())
+            .chain_update(second.as_bytes())
+            .chain_update(shared.as_bytes())
+            .finalize();
+        let mut link_key = [0u8; 16];
+        link_key.copy_from_slice(&digest[..16]);
+        let code = u32::from_le_bytes([digest[16], digest[17], digest[18], digest[19]]);
+        Ok((link_key, code % CODE_MODULUS))
+    }
+
+    /// Accepts the verification code, returning the confirmation to send,
+    /// or `None` if no code is shown.
+    pub fn confirm(&mut self, now_ms: u64) -> Option<Outgoing> {
+        match self.state {
+            State::Verifying {
+                peer,
+                key,
+                confirmed: true,
+                ..
+            } => Some(self.close(peer, key, now_ms)),
+            State::Verifying { peer, key, .. } => {
+                self.state = State::Confirming { peer, key };
+                self.next_send_ms = now_ms + RESEND_INTERVAL_MS;
+                Some(self.confirmation(peer, &key))
+            }
+            _ => None,
+        }
+    }
+
+    /// Ends pairing, unless both sides confirmed already.
+    pub fn cancel(&mut self) {
+        if !self.is_finished() && !matches!(self.state, State::Closing { .. }) {
+            self.state = State::Cancelled;
+        }
+    }
+}
+
+/// Pairs with another node in pairing mode over `arq`, returning the peer and
+/// link key, or `None` if pairing timed out or was cancelled.
+///
+/// A long press of the button accepts the verification code shown on the
+/// display, or cancels pairing before both sides accepted it. The progress is
+/// published in [`pairing_status`].
+pub async fn pair<R: P2pRadio>(
+    arq: &mut Arq<R>,
+    secret: [u8; 32],
+) -> Result<Option<(NodeId, [u8; 16])>, ArqError<R::Error>> {
+    let mut pairing = Pairing::new(arq.node(), secret, Instant::now().as_millis());
+    // A press before pairing started is not an answer to it.
+    LONG_PRESS_SIGNAL.reset();
+    let result = exchange(arq, &mut pairing).await;
+    if result.is_err() {
+        pairing.cancel();
+    }
+    set_status(pairing.status());
+    result
+}
+
+async fn exchange<R: P2pRadio>(
+    arq: &mut Arq<R>,
+    pairing: &mut Pairing,
+) -> Result<Option<(NodeId, [u8; 16])>, ArqError<R::Error>> {
+    while !pairing.is_finished() {
+        set_status(pairing.status());
+        let now_ms = Instant::now().as_millis();
+        for (destination, message) in pairing.poll(now_ms) {
+            send(arq, destination, &message).await?;
+        }
+        if LONG_PRESS_SIGNAL.try_take().is_some() {
+            // Accepts the code once it is shown, cancels otherwise.
+            match pairing.confirm(now_ms) {
+                Some(confirmation) => {
+                    for (destination, message) in confirmation {
+                        send(arq, destination, &message).await?;
+                    }
+                }
+                None => pairing.cancel(),
+            }
+            continue;
+        }
+
+        let timeout = Duration::from_millis(pairing.next_poll_ms().saturating_sub(now_ms).max(1));
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let received = arq
+            .receive_matching(&mut payload, timeout, |header| {
+                header.kind == MessageType::Pairing
+            })
+            .await?;
+        let Some(received) = received else {
+            continue;
+        };
+        let now_ms = Instant::now().as_millis();
+        match pairing.handle(received.source, &payload[..received.len], now_ms) {
+            Ok(answers) => {
+                for (destination, message) in answers {
+                    send(arq, destination, &message).await?;
+                }
+            }
+            Err(e) => esp_println::println!(
+                "[LoRa P2P] Pairing message from {:04X}: {}",
+                received.source,
+                e
+            ),
+        }
+    }
+    Ok(pairing.link())
+}
+
+/// Tells `peer` that this node forgets the link `key` agreed with it, so it
+/// forgets it too; the message is secured with the network key, as the peer
+/// may have forgotten the link key already.
+pub async fn send_unpair<R: P2pRadio>(
+    arq: &mut Arq<R>,
+    peer: NodeId,
+    key: &[u8; 16],
+) -> Result<(), ArqError<R::Error>> {
+    let message = unpair_message(arq.node(), peer, key);
+    arq.send_reliable_frame(MessageType::Pairing, peer, &message.encode())
+        .await
+        .map(|_| ())
+}
+
+async fn send<R: P2pRadio>(
+    arq: &mut Arq<R>,
+    destination: NodeId,
+    message: &Message,
+) -> Result<(), ArqError<R::Error>> {
+    arq.send_frame(MessageType::Pairing, destination, &message.encode(), 0)
+        .await
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    const IDS: [NodeId; 2] = [0x0A0A, 0x0B0B];
+
+    fn pairings() -> [Pairing; 2] {
+        [
+            Pairing::new(IDS[0], [0x11; 32], 0),
+            Pairing::new(IDS[1], [0x22; 32], 0),
+        ]
+    }
+
+    /// Passes the messages `side` sends to the other side, and the answers
+    /// back, until none are left.
+    fn deliver(pairings: &mut [Pairing; 2], side: usize, messages: Outgoing, now_ms: u64) {
+        let mut queue: VecDeque<_> = messages.into_iter().map(|m| (side, m)).collect();
+        while let Some((from, (destination, message))) = queue.pop_front() {
+            let to = 1 - from;
+            assert!(destination == BROADCAST || destination == IDS[to]);
+            let answers = pairings[to]
+                .handle(IDS[from], &message.encode(), now_ms)
+                .unwrap();
+            queue.extend(answers.into_iter().map(|m| (to, m)));
+        }
+    }
+
+    fn poll(pairings: &mut [Pairing; 2], now_ms: u64) {
+        for side in 0..2 {
+            let messages = pairings[side].poll(now_ms);
+            deliver(pairings, side, messages, now_ms);
+        }
+    }
+
+    /// Runs the key exchange, returning the code both sides show.
+    fn exchange_keys(pairings: &mut [Pairing; 2]) -> u32 {
+        poll(pairings, 0);
+        let (
+            PairingStatus::Verifying { peer: b, code },
+            PairingStatus::Verifying {
+                peer: a,
+                code: other,
+            },
+        ) = (pairings[0].status(), pairings[1].status())
+        else {
+            panic!(
+                "not verifying: {:?}",
+                pairings.each_ref().map(Pairing::status)
+            );
+        };
+        assert_eq!((a, b), (IDS[0], IDS[1]));
+        assert_eq!(code, other);
+        assert!(code < CODE_MODULUS);
+        code
+    }
+
+    fn confirmation(key: &[u8; 16], source: NodeId, destination: NodeId) -> [u8; MESSAGE_LEN] {
+        let tag = mac(key, CONFIRM_LABEL, source, destination).finalize();
+        Message::Confirm(tag.into_bytes().into()).encode()
+    }
+
+    #[test]
+    fn encodes_messages() {
+        let messages = [
+            Message::Commit([1; 32]),
+            Message::Reveal([2; 32]),
+            Message::Confirm([3; 32]),
+            Message::Unpair([4; 32]),
+        ];
+        for (kind, message) in (1..).zip(messages) {
+            let encoded = message.encode();
+            assert_eq!(encoded[0], kind);
+            assert_eq!(Message::decode(&encoded), Ok(message));
+        }
+        assert_eq!(Message::decode(&[1; 32]), Err(PairingError::Malformed(32)));
+        assert_eq!(Message::decode(&[5; 33]), Err(PairingError::Malformed(33)));
+    }
+
+    #[test]
+    fn pairs_once_both_sides_confirm() {
+        let mut pairings = pairings();
+        exchange_keys(&mut pairings);
+        let confirmation = pairings[0].confirm(1000).unwrap();
+        deliver(&mut pairings, 0, confirmation, 1000);
+        let waiting = PairingStatus::Confirming { peer: IDS[1] };
+        assert_eq!(pairings[0].status(), waiting);
+        assert_eq!(pairings.each_ref().map(Pairing::link), [None, None]);
+
+        let confirmation = pairings[1].confirm(2000).unwrap();
+        deliver(&mut pairings, 1, confirmation, 2000);
+        assert_eq!(pairings[0].status(), PairingStatus::Paired { peer: IDS[1] });
+        assert_eq!(pairings[1].status(), PairingStatus::Paired { peer: IDS[0] });
+        // The keys are kept back while the peer may miss the confirmation.
+        poll(&mut pairings, 2000 + CLOSING_MS - 1);
+        assert_eq!(pairings.each_ref().map(Pairing::link), [None, None]);
+        poll(&mut pairings, 2000 + CLOSING_MS);
+        let (Some((b, key)), Some((a, other))) = (pairings[0].link(), pairings[1].link()) else {
+            panic!("not paired");
+        };
+        assert_eq!((a, b), (IDS[0], IDS[1]));
+        assert_eq!(key, other);
+        assert!(pairings.iter().all(Pairing::is_finished));
+    }
+
+    #[test]
+    fn resends_lost_confirmations() {
+        let mut pairings = pairings();
+        exchange_keys(&mut pairings);
+        let confirmation = pairings[0].confirm(1000).unwrap();
+        deliver(&mut pairings, 0, confirmation, 1000);
+        // The confirmation of the second node is lost.
+        pairings[1].confirm(1500).unwrap();
+        assert_eq!(
+            pairings[0].status(),
+            PairingStatus::Confirming { peer: IDS[1] }
+        );
+
+        let mut now_ms = 1500;
+        while pairings[0].status() != (PairingStatus::Paired { peer: IDS[1] }) {
+            now_ms += 100;
+            assert!(now_ms < 1500 + CLOSING_MS, "no confirmation answered");
+            poll(&mut pairings, now_ms);
+        }
+        poll(&mut pairings, now_ms + CLOSING_MS);
+        assert_eq!(
+            pairings[0].link().map(|link| link.1),
+            pairings[1].link().map(|link| link.1)
+        );
+        assert!(pairings[0].link().is_some());
+    }
+
+    #[test]
+    fn rejects_forged_confirmations() {
+        let mut pairings = pairings();
+        exchange_keys(&mut pairings);
+        let forged = confirmation(&[0x55; 16], IDS[1], IDS[0]);
+        let handled = pairings[0].handle(IDS[1], &forged, 1000);
+        assert_eq!(handled, Err(PairingError::BadConfirmation));
+
+        // A node's own confirmation sent back to it does not verify either.
+        let mut reflected = pairings[0].confirm(1000).unwrap();
+        let (_, message) = reflected.pop().unwrap();
+        let handled = pairings[0].handle(IDS[1], &message.encode(), 1000);
+        assert_eq!(handled, Err(PairingError::BadConfirmation));
+        assert_eq!(
+            pairings[0].status(),
+            PairingStatus::Confirming { peer: IDS[1] }
+        );
+        poll(&mut pairings, PAIRING_TIMEOUT_MS);
+        assert_eq!(pairings[0].status(), PairingStatus::TimedOut);
+        assert_eq!(pairings[0].link(), None);
+    }
+
+    #[test]
+    fn rejects_keys_not_matching_the_commitment() {
+        let mut pairing = Pairing::new(IDS[1], [0x22; 32], 0);
+        let commitment = Sha256::digest([0x33; 32]).into();
+        let answers = pairing.handle(IDS[0], &Message::Commit(commitment).encode(), 0);
+        assert_eq!(answers.map(|answers| answers.len()), Ok(2));
+        let reveal = Message::Reveal([0x44; 32]).encode();
+        let handled = pairing.handle(IDS[0], &reveal, 0);
+        assert_eq!(handled, Err(PairingError::CommitmentMismatch));
+        assert_eq!(pairing.status(), PairingStatus::Exchanging { peer: IDS[0] });
+    }
+
+    #[test]
+    fn rejects_weak_keys() {
+        // The identity point makes every shared secret zero.
+        let weak = [0u8; 32];
+        let mut pairing = Pairing::new(IDS[1], [0x22; 32], 0);
+        let commitment = Sha256::digest(weak).into();
+        pairing
+            .handle(IDS[0], &Message::Commit(commitment).encode(), 0)
+            .unwrap();
+        let handled = pairing.handle(IDS[0], &Message::Reveal(weak).encode(), 0);
+        assert_eq!(handled, Err(PairingError::WeakKey));
+        assert_eq!(pairing.status(), PairingStatus::Exchanging { peer: IDS[0] });
+    }
+
+    #[test]
+    fn times_out() {
+        let mut pairing = Pairing::new(IDS[0], [0x11; 32], 0);
+        assert_eq!(pairing.poll(0).len(), 1);
+        assert_eq!(pairing.poll(RESEND_INTERVAL_MS - 1).len(), 0);
+        assert_eq!(pairing.poll(RESEND_INTERVAL_MS).len(), 1);
+        assert_eq!(pairing.next_poll_ms(), 2 * RESEND_INTERVAL_MS);
+        assert!(pairing.poll(PAIRING_TIMEOUT_MS).is_empty());
+        assert_eq!(pairing.status(), PairingStatus::TimedOut);
+        assert!(pairing.is_finished());
+        assert_eq!(pairing.confirm(PAIRING_TIMEOUT_MS), None);
+    }
+
+    #[test]
+    fn cancels_until_both_sides_confirm() {
+        let mut pairings = pairings();
+        exchange_keys(&mut pairings);
+        pairings[0].cancel();
+        assert_eq!(pairings[0].status(), PairingStatus::Cancelled);
+
+        let mut pairings = self::pairings();
+        exchange_keys(&mut pairings);
+        let confirmation = pairings[0].confirm(0).unwrap();
+        deliver(&mut pairings, 0, confirmation, 0);
+        let confirmation = pairings[1].confirm(0).unwrap();
+        deliver(&mut pairings, 1, confirmation, 0);
+        pairings[0].cancel();
+        poll(&mut pairings, CLOSING_MS);
+        assert!(pairings[0].link().is_some());
+    }
+
+    #[test]
+    fn authenticates_unpairing() {
+        let key = [0x66; 16];
+        let message = unpair_message(IDS[0], IDS[1], &key);
+        assert!(is_unpair(&message, IDS[0], IDS[1], &key));
+        assert!(!is_unpair(&message, IDS[1], IDS[0], &key));
+        assert!(!is_unpair(&message, IDS[0], IDS[1], &[0x67; 16]));
+        assert!(!is_unpair(&Message::Commit([0; 32]), IDS[0], IDS[1], &key));
+    }
+}
@@ -0,0 +1,476 @@
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::Storage;
+
+use super::{
+    kv::{KvError, KvStore},
+    p2p_frame::{NodeId, MAX_PAYLOAD_LEN},
+};
+
+/// Settings key of the node the telemetry is sent to, instead of every node.
+pub const SINK_KEY: &str = "p2p.sink";
+/// Routes known at a time, the destination of this node not included.
+pub const MAX_ROUTES: usize = 32;
+/// Time between two periodic advertisements of a node.
+pub const ADVERTISE_INTERVAL_MS: u64 = 30_000;
+/// Upper bound of the random delay added to the advertisement interval.
+pub const ADVERTISE_JITTER_MS: u64 = 5_000;
+/// Shortest time between two advertisements, so a broken link does not set
+/// off a storm of triggered ones.
+pub const TRIGGER_HOLDOFF_MS: u64 = 2_000;
+/// Routes not refreshed for this long are broken, and broken routes are
+/// forgotten after as long again.
+pub const ROUTE_EXPIRY_MS: u64 = 4 * ADVERTISE_INTERVAL_MS;
+/// Metric of a destination that cannot be reached.
+pub const UNREACHABLE: u8 = u8::MAX;
+/// SNR from which a link costs the least, in dB.
+pub const GOOD_SNR_DB: i16 = 5;
+/// Hops a unicast message may take.
+pub const DEFAULT_HOP_LIMIT: u8 = 8;
+pub const ENTRY_LEN: usize = 5;
+pub const ROUTE_HEADER_LEN: usize = 6;
+/// Largest message carried by a `Unicast` frame.
+pub const MAX_MESSAGE_LEN: usize = MAX_PAYLOAD_LEN - ROUTE_HEADER_LEN;
+// An advertisement holds this node and every route.
+const _: () = assert!((MAX_ROUTES + 1) * ENTRY_LEN <= MAX_PAYLOAD_LEN);
+
+#[derive(Debug, PartialEq)]
+pub enum RoutingError {
+    Malformed(usize),
+    MessageTooLong(usize),
+}
+
+impl core::fmt::Display for RoutingError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            RoutingError::Malformed(len) => write!(f, "Malformed routing frame of {} bytes", len),
+            RoutingError::MessageTooLong(len) => {
+                write!(f, "Message of {} bytes is too long to route", len)
+            }
+        }
+    }
+}
+
+/// Reads the node the telemetry is sent to, `None` to send it to every node.
+pub fn read_sink<S: Storage>(settings: &mut KvStore<S>) -> Result<Option<NodeId>, KvError> {
+    settings.get(SINK_KEY)
+}
+
+/// The node the telemetry is sent to, or `None` if there is none or it
+/// cannot be read.
+pub async fn load_sink<S: Storage>(
+    settings: &Mutex<CriticalSectionRawMutex, KvStore<S>>,
+) -> Option<NodeId> {
+    match read_sink(&mut *settings.lock().await) {
+        Ok(sink) => sink,
+        Err(e) => {
+            esp_println::println!("[LoRa P2P] Failed to read the sink node: {}", e);
+            None
+        }
+    }
+}
+
+/// Cost of a hop over a link heard at `snr` dB: 1 from [`GOOD_SNR_DB`], one
+/// more for every 3 dB below, up to 11. A link of unknown SNR costs 6.
+pub fn link_cost(snr: Option<i16>) -> u8 {
+    match snr {
+        Some(snr) => 1 + ((GOOD_SNR_DB - snr).clamp(0, 30) / 3) as u8,
+        None => 6,
+    }
+}
+
+/// Whether sequence number `a` was issued after `b`.
+fn newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+/// Route to a destination in an advertisement, the payload of a `Routes`
+/// frame being a list of them.
+///
+/// | Offset | Size | Field                                      |
+/// |--------|------|--------------------------------------------|
+/// | 0      | 2    | Destination node (LE)                      |
+/// | 2      | 2    | Sequence number (LE)                       |
+/// | 4      | 1    | Metric, 255: unreachable                   |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouteEntry {
+    pub destination: NodeId,
+    pub seq: u16,
+    pub metric: u8,
+}
+
+impl RouteEntry {
+    pub fn encode(&self, buffer: &mut [u8; ENTRY_LEN]) {
+        buffer[0..2].copy_from_slice(&self.destination.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.seq.to_le_bytes());
+        buffer[4] = self.metric;
+    }
+
+    pub fn decode(bytes: &[u8; ENTRY_LEN]) -> Self {
+        Self {
+            destination: u16::from_le_bytes([bytes[0], bytes[1]]),
+            seq: u16::from_le_bytes([bytes[2], bytes[3]]),
+            metric: bytes[4],
+        }
+    }
+}
+
+/// Header of a message routed to a node, at the start of the payload of a
+/// `Unicast` frame.
+///
+/// | Offset | Size | Field                                   |
+/// |--------|------|-----------------------------------------|
+/// | 0      | 2    | Origin node (LE)                        |
+/// | 2      | 2    | Destination node (LE)                   |
+/// | 4      | 1    | Hops left                               |
+/// | 5      | 1    | Hops taken                              |
+/// | 6      | n    | Message                                 |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouteHeader {
+    pub origin: NodeId,
+    pub destination: NodeId,
+    pub hops_left: u8,
+    pub hops: u8,
+}
+
+impl RouteHeader {
+    pub fn encode(&self, buffer: &mut [u8; ROUTE_HEADER_LEN]) {
+        buffer[0..2].copy_from_slice(&self.origin.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.destination.to_le_bytes());
+        buffer[4] = self.hops_left;
+        buffer[5] = self.hops;
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, RoutingError> {
+        if bytes.len() < ROUTE_HEADER_LEN {
+            return Err(RoutingError::Malformed(bytes.len()));
+        }
+        Ok(Self {
+            origin: u16::from_le_bytes([bytes[0], bytes[1]]),
+            destination: u16::from_le_bytes([bytes[2], bytes[3]]),
+            hops_left: bytes[4],
+            hops: bytes[5],
+        })
+    }
+
+    /// The header of the message forwarded one hop further, `None` once it
+    /// has no hops left.
+    pub fn forwarded(&self) -> Option<Self> {
+        Some(Self {
+            hops_left: self.hops_left.checked_sub(1)?,
+            hops: self.hops.saturating_add(1),
+            ..*self
+        })
+    }
+}
+
+/// Writes a `Unicast` payload carrying `message` from `origin` to
+/// `destination` into `buffer`, returning its length.
+///
+/// # Errors
+///
+/// * `MessageTooLong` - If `message` exceeds [`MAX_MESSAGE_LEN`].
+pub fn encode_unicast(
+    header: &RouteHeader,
+    message: &[u8],
+    buffer: &mut [u8; MAX_PAYLOAD_LEN],
+) -> Result<usize, RoutingError> {
+    if message.len() > MAX_MESSAGE_LEN {
+        return Err(RoutingError::MessageTooLong(message.len()));
+    }
+    let (head, body) = buffer.split_at_mut(ROUTE_HEADER_LEN);
+    // Cannot fail, the split is the header's length.
+    header.encode(head.try_into().unwrap());
+    body[..message.len()].copy_from_slice(message);
+    Ok(ROUTE_HEADER_LEN + message.len())
+}
+
+/// Route to a destination through a neighbour.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Route {
+    pub destination: NodeId,
+    pub next_hop: NodeId,
+    /// Sum of the link costs to the destination, [`UNREACHABLE`] once the
+    /// route is broken.
+    pub metric: u8,
+    /// Sequence number the destination issued with the route.
+    pub seq: u16,
+    /// Lowest metric the route had with `seq`: another neighbour is only
+    /// taken as next hop when its own metric is below, so it cannot be
+    /// routing through this node.
+    feasible: u8,
+    updated_ms: u64,
+}
+
+impl Route {
+    pub fn is_broken(&self) -> bool {
+        self.metric == UNREACHABLE
+    }
+}
+
+/// Distance-vector routing table of a node.
+///
+/// Nodes advertise the routes they know to their neighbours, each hop adding
+/// the cost of the link it was heard over, see [`link_cost`]. A node issues
+/// the sequence numbers of the routes to itself: a route with a newer
+/// sequence number replaces the one known, and with the same one a lower
+/// metric does if it is feasible, that is if the neighbour's own metric is
+/// below the lowest this node had, so the neighbour cannot be routing
+/// through this node and the routes stay free of loops.
+///
+/// Every route fits in an advertisement, so a route its next hop leaves out
+/// of one is gone, as when the next hop restarted; a node restarted learns
+/// no routes until it advertised once, and its neighbours dropped the ones
+/// through it.
+///
+/// A route whose next hop stopped answering, or that was not refreshed for
+/// [`ROUTE_EXPIRY_MS`], is broken and advertised as unreachable at once,
+/// until a feasible route or a newer sequence number is heard.
+///
+/// The table does no I/O: the caller broadcasts the advertisements returned
+/// by [`Router::advertise`] as `Routes` frames and passes the ones it
+/// receives to [`Router::handle`].
+pub struct Router {
+    node: NodeId,
+    seq: u16,
+    routes: heapless::Vec<Route, MAX_ROUTES>,
+    next_advert_ms: u64,
+    last_advert_ms: Option<u64>,
+    /// A route broke or appeared since the last advertisement.
+    triggered: bool,
+}
+
+impl Router {
+    /// Routes for `node`, advertising at once; sequence numbers start from
+    /// `random`, so the routes to the node are renewed after a reboot.
+    pub fn new(node: NodeId, random: u32, now_ms: u64) -> Self {
+        Self {
+            node,
+            seq: random as u16,
+            routes: heapless::Vec::new(),
+            next_advert_ms: now_ms,
+            last_advert_ms: None,
+            triggered: false,
+        }
+    }
+
+    pub fn node(&self) -> NodeId {
+        self.node
+    }
+
+    /// Every route known, the broken ones included.
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter()
+    }
+
+    /// The route to `destination`, if one works.
+    pub fn route(&self, destination: NodeId) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.destination == destination && !route.is_broken())
+    }
+
+    pub fn next_hop(&self, destination: NodeId) -> Option<NodeId> {
+        self.route(destination).map(|route| route.next_hop)
+    }
+
+    /// Handles the advertisement `neighbor` sent, heard at `snr` dB.
+    ///
+    /// # Errors
+    ///
+    /// * `Malformed` - If the payload is not a list of entries.
+    pub fn handle(
+        &mut self,
+        neighbor: NodeId,
+        snr: Option<i16>,
+        payload: &[u8],
+        now_ms: u64,
+    ) -> Result<(), RoutingError> {
+        let entries = payload.chunks_exact(ENTRY_LEN);
+        if !entries.remainder().is_empty() {
+            return Err(RoutingError::Malformed(payload.len()));
+        }
+        let cost = link_cost(snr);
+        // Cannot fail, the chunks are an entry long.
+        let entries = entries.map(|bytes| RouteEntry::decode(bytes.try_into().unwrap()));
+        for entry in entries.clone() {
+            self.update(neighbor, cost, &entry, now_ms);
+        }
+        for route in self.routes.iter_mut() {
+            if route.next_hop == neighbor
+                && !route.is_broken()
+                && !entries
+                    .clone()
+                    .any(|entry| entry.destination == route.destination)
+            {
+                Self::break_route(route, now_ms);
+                self.triggered = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, neighbor: NodeId, cost: u8, entry: &RouteEntry, now_ms: u64) {
+        if entry.destination == self.node {
+            // A route to this node newer than its own, broken or left from
+            // before a reboot: supersede it.
+            if newer(entry.seq, self.seq) {
+                self.seq = entry.seq.wrapping_add(1);
+                self.triggered = true;
+            }
+            return;
+        }
+        if self.last_advert_ms.is_none() {
+            // The neighbours may still route through this node from before
+            // it restarted.
+            return;
+        }
+        let metric = entry.metric.saturating_add(cost);
+        let Some(route) = self
+            .routes
+            .iter_mut()
+            .find(|route| route.destination == entry.destination)
+        else {
+            if metric == UNREACHABLE {
+                return;
+            }
+            let route = Route {
+                destination: entry.destination,
+                next_hop: neighbor,
+                metric,
+                seq: entry.seq,
+                feasible: metric,
+                updated_ms: now_ms,
+            };
+            if self.routes.is_full() {
+                // Make room by forgetting a broken route, if there is one.
+                match self.routes.iter().position(Route::is_broken) {
+                    Some(index) => {
+                        self.routes.swap_remove(index);
+                    }
+                    None => return,
+                }
+            }
+            // Cannot fail, there is room.
+            let _ = self.routes.push(route);
+            self.triggered = true;
+            return;
+        };
+        let was_broken = route.is_broken();
+        // A newer sequence number often arrives first over a worse route
+        // with fewer hops: keep the route while its next hop is due to
+        // follow with the same.
+        let settled = route.next_hop == neighbor
+            || was_broken
+            || metric <= route.metric
+            || now_ms.saturating_sub(route.updated_ms)
+                >= ADVERTISE_INTERVAL_MS + ADVERTISE_JITTER_MS;
+        if newer(entry.seq, route.seq) && settled {
+            route.next_hop = neighbor;
+            route.metric = metric;
+            route.seq = entry.seq;
+            route.feasible = metric;
+        } else if entry.seq == route.seq && route.next_hop == neighbor {
+            route.metric = metric;
+            route.feasible = route.feasible.min(metric);
+        } else if entry.seq == route.seq && metric < route.metric && entry.metric < route.feasible {
+            route.next_hop = neighbor;
+            route.metric = metric;
+            route.feasible = metric;
+        } else {
+            return;
+        }
+        route.updated_ms = now_ms;
+        if was_broken != route.is_broken() {
+            self.triggered = true;
+        }
+    }
+
+    /// Breaks the routes through `neighbor`, which stopped answering or
+    /// went out of range.
+    pub fn link_lost(&mut self, neighbor: NodeId, now_ms: u64) {
+        for route in self.routes.iter_mut() {
+            if route.next_hop == neighbor && !route.is_broken() {
+                Self::break_route(route, now_ms);
+                self.triggered = true;
+            }
+        }
+    }
+
+    fn break_route(route: &mut Route, now_ms: u64) {
+        route.metric = UNREACHABLE;
+        route.updated_ms = now_ms;
+    }
+
+    /// Breaks the routes not refreshed for [`ROUTE_EXPIRY_MS`] and forgets
+    /// the ones broken for as long.
+    pub fn expire(&mut self, now_ms: u64) {
+        let mut broke = false;
+        self.routes.retain_mut(|route| {
+            if now_ms.saturating_sub(route.updated_ms) < ROUTE_EXPIRY_MS {
+                return true;
+            }
+            if route.is_broken() {
+                return false;
+            }
+            Self::break_route(route, now_ms);
+            broke = true;
+            true
+        });
+        self.triggered |= broke;
+    }
+
+    /// Time the next advertisement is due.
+    pub fn next_advert_ms(&self) -> u64 {
+        match (self.triggered, self.last_advert_ms) {
+            (true, Some(last_ms)) => self.next_advert_ms.min(last_ms + TRIGGER_HOLDOFF_MS),
+            (true, None) => 0,
+            (false, _) => self.next_advert_ms,
+        }
+    }
+
+    /// Writes the advertisement due by `now_ms` into `buffer`, returning its
+    /// length. `random` is any random value, reduced to the jitter of the
+    /// next periodic one.
+    ///
+    /// This node comes first with a new sequence number when the
+    /// advertisement is periodic, then every route, the broken ones
+    /// included.
+    pub fn advertise(
+        &mut self,
+        now_ms: u64,
+        random: u32,
+        buffer: &mut [u8; MAX_PAYLOAD_LEN],
+    ) -> Option<usize> {
+        if now_ms < self.next_advert_ms() {
+            return None;
+        }
+        if now_ms >= self.next_advert_ms {
+            self.seq = self.seq.wrapping_add(1);
+            self.next_advert_ms =
+                now_ms + ADVERTISE_INTERVAL_MS + random as u64 % ADVERTISE_JITTER_MS;
+        }
+        self.triggered = false;
+        self.last_advert_ms = Some(now_ms);
+        let own = RouteEntry {
+            destination: self.node,
+            seq: self.seq,
+            metric: 0,
+        };
+        let entries = self.routes.iter().map(|route| RouteEntry {
+            destination: route.destination,
+            seq: route.seq,
+            metric: route.metric,
+        });
+        let mut len = 0;
+        for (entry, chunk) in core::iter::once(own)
+            .chain(entries)
+            .zip(buffer.chunks_exact_mut(ENTRY_LEN))
+        {
+            // Cannot fail, the chunks are an entry long.
+            entry.encode(chunk.try_into().unwrap());
+            len += ENTRY_LEN;
+        }
+        Some(len)
+    }
+}
@@ -0,0 +1,668 @@
+use core::cell::RefCell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex as BlockingMutex},
+    mutex::Mutex,
+};
+use embedded_storage::Storage;
+
+use super::{
+    airtime::LoRaParams,
+    kv::{KvError, KvStore},
+    p2p_crypto::SECURITY_LEN,
+    p2p_frame::{NodeId, CRC_LEN, HEADER_LEN, MAX_FRAME_LEN},
+};
+
+/// Settings key of the TDMA role, see [`TdmaRole`].
+pub const TDMA_KEY: &str = "p2p.tdma";
+/// Slots assigned at a time, the coordinator's included.
+pub const MAX_SLOTS: usize = 16;
+/// Frequency error of the two clocks together, in parts per million.
+pub const CLOCK_TOLERANCE_PPM: u64 = 40;
+/// Symbols the start of a received beacon may be misjudged by.
+pub const GUARD_SYMBOLS: u32 = 2;
+/// Time the radio driver and the tasks take to start a transmission, or to
+/// report a reception, on either end.
+pub const PROCESSING_MARGIN_MS: u32 = 5;
+/// Time between the end of a frame and the start of its acknowledgement.
+pub const ACK_TURNAROUND_MS: u32 = 10;
+/// Members that did not hear a beacon for this long are no longer synced
+/// and transmit at any time; the guard times cover the drift until then.
+pub const SYNC_TIMEOUT_MS: u64 = 60_000;
+pub const TDMA_BEACON_HEADER_LEN: usize = 12;
+/// Length of a secured `Ack` frame, which follows a frame in its slot.
+const ACK_FRAME_LEN: usize = HEADER_LEN + SECURITY_LEN + CRC_LEN;
+/// GPS time of a beacon when the coordinator does not know it.
+const TIME_UNKNOWN: u64 = u64::MAX;
+
+#[derive(Debug, PartialEq)]
+pub enum TdmaError {
+    Malformed(usize),
+    TooManySlots(usize),
+}
+
+impl core::fmt::Display for TdmaError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TdmaError::Malformed(len) => write!(f, "Malformed TDMA beacon of {} bytes", len),
+            TdmaError::TooManySlots(slots) => {
+                write!(f, "TDMA beacon of {} slots, at most {}", slots, MAX_SLOTS)
+            }
+        }
+    }
+}
+
+/// Part a node plays in the slotted schedule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TdmaRole {
+    /// Transmits at any time, as without a coordinator.
+    Off = 0,
+    /// Follows the schedule of the coordinator it hears.
+    Member = 1,
+    /// Broadcasts the schedule and assigns the slots.
+    Coordinator = 2,
+}
+
+impl TdmaRole {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => TdmaRole::Off,
+            1 => TdmaRole::Member,
+            2 => TdmaRole::Coordinator,
+            _ => return None,
+        })
+    }
+}
+
+/// Reads the TDMA role from the settings, `Off` if it is not set.
+pub fn read_role<S: Storage>(settings: &mut KvStore<S>) -> Result<TdmaRole, KvError> {
+    match settings.get::<u8>(TDMA_KEY)? {
+        Some(value) => TdmaRole::from_u8(value).ok_or(KvError::InvalidValue),
+        None => Ok(TdmaRole::Off),
+    }
+}
+
+/// The TDMA role, or `Off` if it cannot be read.
+pub async fn load_role<S: Storage>(
+    settings: &Mutex<CriticalSectionRawMutex, KvStore<S>>,
+) -> TdmaRole {
+    match read_role(&mut *settings.lock().await) {
+        Ok(role) => role,
+        Err(e) => {
+            esp_println::println!("[LoRa P2P] Failed to read the TDMA role: {}", e);
+            TdmaRole::Off
+        }
+    }
+}
+
+/// Time kept free at each end of a slot: the error on the start of a beacon,
+/// from its symbol time, the drift of the clocks until the sync times out,
+/// and the processing time.
+pub fn guard_ms(params: &LoRaParams) -> u32 {
+    let sync_us = GUARD_SYMBOLS * params.symbol_us();
+    let drift_us = (SYNC_TIMEOUT_MS * CLOCK_TOLERANCE_PPM / 1000) as u32;
+    (sync_us + drift_us).div_ceil(1000) + PROCESSING_MARGIN_MS
+}
+
+/// Time kept at the end of a slot for the acknowledgement of its frame.
+pub fn ack_ms(params: &LoRaParams) -> u32 {
+    ACK_TURNAROUND_MS + params.time_on_air_ms(ACK_FRAME_LEN)
+}
+
+/// Length of a slot: the largest frame and its acknowledgement, between
+/// two guard times.
+pub fn slot_ms(params: &LoRaParams) -> u32 {
+    2 * guard_ms(params) + params.time_on_air_ms(MAX_FRAME_LEN) + ack_ms(params)
+}
+
+/// Schedule a coordinator broadcasts at the start of each superframe, the
+/// payload of a `Tdma` frame.
+///
+/// A superframe is made of slots of the same length: the beacon's, one for
+/// each node in the table, the coordinator first, then one open to the
+/// nodes without a slot, which take turns at random.
+///
+/// | Offset | Size | Field                                             |
+/// |--------|------|---------------------------------------------------|
+/// | 0      | 2    | Superframe number (LE)                            |
+/// | 2      | 2    | Slot length in ms (LE)                            |
+/// | 4      | 8    | GPS time of the start in ms, all ones: unknown (LE) |
+/// | 12     | 2n   | Nodes of slots 1 to n (LE)                        |
+#[derive(Debug, Clone, PartialEq)]
+pub struct TdmaBeacon {
+    pub superframe: u16,
+    pub slot_ms: u16,
+    pub gps_ms: Option<u64>,
+    pub nodes: heapless::Vec<NodeId, MAX_SLOTS>,
+}
+
+impl TdmaBeacon {
+    /// Writes the beacon into `buffer`, returning its length.
+    pub fn encode(&self, buffer: &mut [u8]) -> usize {
+        buffer[0..2].copy_from_slice(&self.superframe.to_le_bytes());
+        buffer[2..4].copy_from_slice(&self.slot_ms.to_le_bytes());
+        let gps_ms = self.gps_ms.unwrap_or(TIME_UNKNOWN);
+        buffer[4..12].copy_from_slice(&gps_ms.to_le_bytes());
+        let slots = buffer[TDMA_BEACON_HEADER_LEN..].chunks_exact_mut(2);
+        for (node, chunk) in self.nodes.iter().zip(slots) {
+            chunk.copy_from_slice(&node.to_le_bytes());
+        }
+        TDMA_BEACON_HEADER_LEN + 2 * self.nodes.len()
+    }
+
+    /// # Errors
+    ///
+    /// * `Malformed` - If the beacon is truncated or the slot table is of an
+    ///   odd length.
+    /// * `TooManySlots` - If the table has more than [`MAX_SLOTS`] nodes.
+    pub fn decode(bytes: &[u8]) -> Result<Self, TdmaError> {
+        let table = bytes
+            .get(TDMA_BEACON_HEADER_LEN..)
+            .ok_or(TdmaError::Malformed(bytes.len()))?;
+        let slots = table.chunks_exact(2);
+        if !slots.remainder().is_empty() {
+            return Err(TdmaError::Malformed(bytes.len()));
+        }
+        if slots.len() > MAX_SLOTS {
+            return Err(TdmaError::TooManySlots(slots.len()));
+        }
+        let mut gps_ms = [0u8; 8];
+        gps_ms.copy_from_slice(&bytes[4..12]);
+        Ok(Self {
+            superframe: u16::from_le_bytes([bytes[0], bytes[1]]),
+            slot_ms: u16::from_le_bytes([bytes[2], bytes[3]]),
+            gps_ms: match u64::from_le_bytes(gps_ms) {
+                TIME_UNKNOWN => None,
+                gps_ms => Some(gps_ms),
+            },
+            nodes: slots
+                .map(|node| u16::from_le_bytes([node[0], node[1]]))
+                .collect(),
+        })
+    }
+}
+
+/// Superframes as this node knows them, with times in its own clock.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub superframe: u16,
+    /// Start of the last superframe heard or begun.
+    pub start_ms: u64,
+    pub slot_ms: u32,
+    pub nodes: heapless::Vec<NodeId, MAX_SLOTS>,
+}
+
+impl Schedule {
+    /// Slots of a superframe: the beacon's, the nodes' and the open one.
+    pub fn slots(&self) -> usize {
+        self.nodes.len() + 2
+    }
+
+    pub fn superframe_ms(&self) -> u64 {
+        self.slots() as u64 * self.slot_ms as u64
+    }
+
+    /// Slot `node` transmits in: its own, or the open one.
+    pub fn slot_of(&self, node: NodeId) -> usize {
+        match self.nodes.iter().position(|n| *n == node) {
+            Some(index) => index + 1,
+            None => self.nodes.len() + 1,
+        }
+    }
+
+    /// Earliest time from `now_ms` `node` may start a frame lasting
+    /// `airtime_ms`, so it ends with time for its acknowledgement before
+    /// the guard time at the end of the slot. Superframes are assumed to
+    /// repeat unchanged.
+    pub fn transmit_at(
+        &self,
+        node: NodeId,
+        params: &LoRaParams,
+        airtime_ms: u32,
+        now_ms: u64,
+    ) -> u64 {
+        let guard_ms = guard_ms(params) as u64;
+        let period_ms = self.superframe_ms();
+        let offset_ms = self.slot_of(node) as u64 * self.slot_ms as u64;
+        // Room left in the slot after the frame, whatever its length.
+        let tail_ms = guard_ms + ack_ms(params) as u64 + airtime_ms as u64;
+        let elapsed = now_ms.saturating_sub(self.start_ms) / period_ms.max(1);
+        for superframe in elapsed..elapsed + 2 {
+            let slot_start = self.start_ms + superframe * period_ms + offset_ms;
+            let first = slot_start + guard_ms;
+            // A frame longer than a slot may only start at its beginning.
+            let last = (slot_start + self.slot_ms as u64)
+                .saturating_sub(tail_ms)
+                .max(first);
+            if now_ms <= last {
+                return now_ms.max(first);
+            }
+        }
+        // Cannot be reached, the slot of the next superframe is ahead.
+        self.start_ms + (elapsed + 1) * period_ms + offset_ms + guard_ms
+    }
+}
+
+/// Slotted medium access of a node, following its [`TdmaRole`].
+///
+/// The coordinator starts a superframe with each beacon it returns from
+/// [`Tdma::beacon`], and assigns a slot to each node it hears, see
+/// [`Tdma::assign`]. Members sync to the beacons they pass to [`Tdma::handle`],
+/// the start of the superframe being the end of the beacon less its time on
+/// air. Until a member has a slot it transmits in the open slot; while it
+/// is not synced, it transmits at any time.
+///
+/// Times are in milliseconds of the node's clock; the state machine does no
+/// I/O, the caller asks [`Tdma::transmit_at`] when a frame may start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tdma {
+    node: NodeId,
+    role: TdmaRole,
+    params: LoRaParams,
+    schedule: Option<Schedule>,
+    /// Coordinator: nodes of the next superframe, as assigned since the
+    /// last beacon.
+    table: heapless::Vec<NodeId, MAX_SLOTS>,
+    /// Member: when the last beacon was heard. Coordinator: when the next
+    /// superframe starts.
+    mark_ms: u64,
+}
+
+impl Tdma {
+    /// A node transmitting at any time, until configured.
+    pub const fn off() -> Self {
+        Self {
+            node: 0,
+            role: TdmaRole::Off,
+            params: LoRaParams::p2p(),
+            schedule: None,
+            table: heapless::Vec::new(),
+            mark_ms: 0,
+        }
+    }
+
+    /// Takes the `role` of `node` with the modulation `params`; a
+    /// coordinator starts its first superframe at `now_ms`.
+    pub fn new(node: NodeId, role: TdmaRole, params: LoRaParams, now_ms: u64) -> Self {
+        let mut table = heapless::Vec::new();
+        if role == TdmaRole::Coordinator {
+            // Cannot fail, the table is empty.
+            let _ = table.push(node);
+        }
+        let schedule = (role == TdmaRole::Coordinator).then(|| Schedule {
+            superframe: 0,
+            start_ms: now_ms,
+            slot_ms: slot_ms(&params),
+            nodes: table.clone(),
+        });
+        Self {
+            node,
+            role,
+            params,
+            schedule,
+            table,
+            mark_ms: now_ms,
+        }
+    }
+
+    pub fn role(&self) -> TdmaRole {
+        self.role
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    /// Whether the node follows a schedule at `now_ms`.
+    pub fn is_synced(&self, now_ms: u64) -> bool {
+        match self.role {
+            TdmaRole::Off => false,
+            TdmaRole::Member => {
+                self.schedule.is_some() && now_ms.saturating_sub(self.mark_ms) < SYNC_TIMEOUT_MS
+            }
+            TdmaRole::Coordinator => true,
+        }
+    }
+
+    /// Earliest time from `now_ms` a frame lasting `airtime_ms` may start:
+    /// `now_ms` itself when the node is not synced.
+    pub fn transmit_at(&self, airtime_ms: u32, now_ms: u64) -> u64 {
+        match &self.schedule {
+            Some(schedule) if self.is_synced(now_ms) => {
+                schedule.transmit_at(self.node, &self.params, airtime_ms, now_ms)
+            }
+            _ => now_ms,
+        }
+    }
+
+    /// Coordinator: gives `node` a slot from the next superframe, if it has
+    /// none and one is free; returns its slot.
+    pub fn assign(&mut self, node: NodeId) -> Option<usize> {
+        if self.role != TdmaRole::Coordinator {
+            return None;
+        }
+        let index = match self.table.iter().position(|n| *n == node) {
+            Some(index) => index,
+            None => {
+                self.table.push(node).ok()?;
+                esp_println::println!(
+                    "[LoRa P2P] Slot {} assigned to {:04X}",
+                    self.table.len(),
+                    node
+                );
+                self.table.len() - 1
+            }
+        };
+        Some(index + 1)
+    }
+
+    /// Coordinator: frees the slot of `node`, which went out of range; the
+    /// nodes after it move up a slot from the next superframe.
+    pub fn release(&mut self, node: NodeId) {
+        if self.role != TdmaRole::Coordinator || node == self.node {
+            return;
+        }
+        self.table.retain(|n| *n != node);
+    }
+
+    /// Coordinator: when the next superframe starts and its beacon is due.
+    pub fn next_beacon_ms(&self) -> Option<u64> {
+        (self.role == TdmaRole::Coordinator).then_some(self.mark_ms)
+    }
+
+    /// Coordinator: starts a superframe at `now_ms` if one is due, writing
+    /// its beacon into `buffer` and returning its length. `gps_ms` is the
+    /// GPS time at `now_ms`, if known.
+    pub fn beacon(&mut self, now_ms: u64, gps_ms: Option<u64>, buffer: &mut [u8]) -> Option<usize> {
+        if self.role != TdmaRole::Coordinator || now_ms < self.mark_ms {
+            return None;
+        }
+        let schedule = self.schedule.as_mut()?;
+        schedule.superframe = schedule.superframe.wrapping_add(1);
+        schedule.start_ms = now_ms;
+        schedule.nodes.clone_from(&self.table);
+        self.mark_ms = now_ms + schedule.superframe_ms();
+        let beacon = TdmaBeacon {
+            superframe: schedule.superframe,
+            slot_ms: schedule.slot_ms.min(u16::MAX as u32) as u16,
+            gps_ms,
+            nodes: schedule.nodes.clone(),
+        };
+        Some(beacon.encode(buffer))
+    }
+
+    /// Member: syncs to the beacon `payload`, of a frame of `frame_len`
+    /// bytes whose reception ended at `received_ms`.
+    ///
+    /// # Errors
+    ///
+    /// See [`TdmaBeacon::decode`].
+    pub fn handle(
+        &mut self,
+        payload: &[u8],
+        frame_len: usize,
+        received_ms: u64,
+    ) -> Result<TdmaBeacon, TdmaError> {
+        let beacon = TdmaBeacon::decode(payload)?;
+        if self.role == TdmaRole::Member {
+            let airtime_ms = self.params.time_on_air_ms(frame_len) as u64;
+            self.schedule = Some(Schedule {
+                superframe: beacon.superframe,
+                start_ms: received_ms.saturating_sub(airtime_ms),
+                slot_ms: beacon.slot_ms as u32,
+                nodes: beacon.nodes.clone(),
+            });
+            self.mark_ms = received_ms;
+        }
+        Ok(beacon)
+    }
+}
+
+/// Slotted access of this node, shared with the radio, which holds each
+/// frame until it may be sent.
+static TDMA: BlockingMutex<CriticalSectionRawMutex, RefCell<Tdma>> =
+    BlockingMutex::new(RefCell::new(Tdma::off()));
+
+/// Runs `f` with the slotted access of this node.
+pub fn with_tdma<T>(f: impl FnOnce(&mut Tdma) -> T) -> T {
+    TDMA.lock(|tdma| f(&mut tdma.borrow_mut()))
+}
+
+/// Earliest time from `now_ms` this node may start a frame lasting
+/// `airtime_ms`.
+pub fn transmit_at(airtime_ms: u32, now_ms: u64) -> u64 {
+    with_tdma(|tdma| tdma.transmit_at(airtime_ms, now_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Superframe started at 10 s with 1 s slots: the beacon's, the slots
+    /// of nodes 1 and 5, and the open one.
+    fn schedule() -> Schedule {
+        Schedule {
+            superframe: 3,
+            start_ms: 10_000,
+            slot_ms: 1_000,
+            nodes: heapless::Vec::from_slice(&[1, 5]).unwrap(),
+        }
+    }
+
+    fn beacon_of(tdma: &mut Tdma, now_ms: u64) -> TdmaBeacon {
+        let mut buffer = [0; 64];
+        let len = tdma.beacon(now_ms, None, &mut buffer).unwrap();
+        TdmaBeacon::decode(&buffer[..len]).unwrap()
+    }
+
+    #[test]
+    fn sizes_slots() {
+        let params = LoRaParams::p2p();
+        // Two 4.096 ms symbols, 2.4 ms of drift and the processing margin.
+        assert_eq!(guard_ms(&params), 16);
+        assert_eq!(ack_ms(&params), 233);
+        assert_eq!(slot_ms(&params), 16 + 1_062 + 233 + 16);
+        assert_eq!(params.time_on_air_ms(MAX_FRAME_LEN), 1_062);
+        // 32.768 ms symbols.
+        assert_eq!(guard_ms(&LoRaParams::lorawan(12, 125_000)), 73);
+    }
+
+    #[test]
+    fn encodes_and_decodes_beacons() {
+        let beacon = TdmaBeacon {
+            superframe: 0x0102,
+            slot_ms: 1_327,
+            gps_ms: Some(0x1122_3344_5566),
+            nodes: heapless::Vec::from_slice(&[1, 0x1A2B]).unwrap(),
+        };
+        let mut buffer = [0; 64];
+        let len = beacon.encode(&mut buffer);
+        assert_eq!(
+            buffer[..len],
+            [
+                0x02, 0x01, 0x2F, 0x05, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11, 0x00, 0x00, 0x01, 0x00,
+                0x2B, 0x1A
+            ]
+        );
+        assert_eq!(TdmaBeacon::decode(&buffer[..len]), Ok(beacon.clone()));
+
+        let unknown = TdmaBeacon {
+            gps_ms: None,
+            nodes: heapless::Vec::new(),
+            ..beacon
+        };
+        let len = unknown.encode(&mut buffer);
+        assert_eq!(len, TDMA_BEACON_HEADER_LEN);
+        assert_eq!(buffer[4..12], [0xFF; 8]);
+        assert_eq!(TdmaBeacon::decode(&buffer[..len]), Ok(unknown));
+    }
+
+    #[test]
+    fn rejects_malformed_beacons() {
+        let bytes = [0; TDMA_BEACON_HEADER_LEN + 2 * (MAX_SLOTS + 1)];
+        assert_eq!(
+            TdmaBeacon::decode(&bytes[..11]),
+            Err(TdmaError::Malformed(11))
+        );
+        assert_eq!(
+            TdmaBeacon::decode(&bytes[..15]),
+            Err(TdmaError::Malformed(15))
+        );
+        assert!(TdmaBeacon::decode(&bytes[..bytes.len() - 2]).is_ok());
+        assert_eq!(
+            TdmaBeacon::decode(&bytes),
+            Err(TdmaError::TooManySlots(MAX_SLOTS + 1))
+        );
+    }
+
+    #[test]
+    fn finds_the_slot_of_a_node() {
+        let schedule = schedule();
+        assert_eq!(schedule.slots(), 4);
+        assert_eq!(schedule.superframe_ms(), 4_000);
+        assert_eq!(schedule.slot_of(1), 1);
+        assert_eq!(schedule.slot_of(5), 2);
+        assert_eq!(schedule.slot_of(9), 3);
+    }
+
+    #[test]
+    fn transmits_within_the_slot() {
+        let schedule = schedule();
+        let params = LoRaParams::p2p();
+        // The slot of node 5 runs from 12 to 13 s; a 100 ms frame must start
+        // after the guard and end with room for its acknowledgement and the
+        // closing guard.
+        let last = 13_000 - 16 - 233 - 100;
+        assert_eq!(schedule.transmit_at(5, &params, 100, 0), 12_016);
+        assert_eq!(schedule.transmit_at(5, &params, 100, 11_000), 12_016);
+        assert_eq!(schedule.transmit_at(5, &params, 100, 12_016), 12_016);
+        assert_eq!(schedule.transmit_at(5, &params, 100, 12_300), 12_300);
+        // The acknowledgement ends exactly at the closing guard.
+        assert_eq!(schedule.transmit_at(5, &params, 100, last), last);
+        assert_eq!(schedule.transmit_at(5, &params, 100, last + 1), 16_016);
+        // Later superframes repeat the slot.
+        assert_eq!(schedule.transmit_at(5, &params, 100, 40_500), 40_500);
+        assert_eq!(schedule.transmit_at(5, &params, 100, 41_500), 44_016);
+    }
+
+    #[test]
+    fn transmits_in_the_open_slot_without_one() {
+        let schedule = schedule();
+        let params = LoRaParams::p2p();
+        assert_eq!(schedule.transmit_at(9, &params, 100, 11_000), 13_016);
+        assert_eq!(schedule.transmit_at(9, &params, 100, 13_500), 13_500);
+        assert_eq!(schedule.transmit_at(9, &params, 100, 13_700), 17_016);
+    }
+
+    #[test]
+    fn starts_frames_too_long_for_a_slot_at_its_beginning() {
+        let schedule = schedule();
+        let params = LoRaParams::p2p();
+        assert_eq!(schedule.transmit_at(5, &params, 800, 11_000), 12_016);
+        assert_eq!(schedule.transmit_at(5, &params, 800, 12_016), 12_016);
+        assert_eq!(schedule.transmit_at(5, &params, 800, 12_017), 16_016);
+    }
+
+    #[test]
+    fn assigns_and_releases_slots() {
+        let params = LoRaParams::p2p();
+        let mut coordinator = Tdma::new(1, TdmaRole::Coordinator, params, 0);
+        assert_eq!(coordinator.assign(5), Some(2));
+        assert_eq!(coordinator.assign(6), Some(3));
+        assert_eq!(coordinator.assign(5), Some(2));
+        assert_eq!(coordinator.assign(1), Some(1));
+        for node in 7..(MAX_SLOTS as NodeId + 4) {
+            assert_eq!(coordinator.assign(node), Some(node as usize - 3));
+        }
+        assert_eq!(coordinator.assign(100), None);
+
+        // The coordinator keeps its slot and the others move up.
+        coordinator.release(1);
+        coordinator.release(5);
+        assert_eq!(coordinator.assign(6), Some(2));
+        assert_eq!(coordinator.assign(100), Some(MAX_SLOTS));
+
+        for role in [TdmaRole::Off, TdmaRole::Member] {
+            let mut tdma = Tdma::new(1, role, params, 0);
+            assert_eq!(tdma.assign(5), None);
+            assert_eq!(tdma.next_beacon_ms(), None);
+            assert_eq!(tdma.beacon(0, None, &mut [0; 64]), None);
+        }
+    }
+
+    #[test]
+    fn starts_superframes_with_beacons() {
+        let params = LoRaParams::p2p();
+        let slot = slot_ms(&params) as u64;
+        let mut coordinator = Tdma::new(1, TdmaRole::Coordinator, params, 1_000);
+        assert_eq!(coordinator.next_beacon_ms(), Some(1_000));
+        assert_eq!(coordinator.beacon(999, None, &mut [0; 64]), None);
+        coordinator.assign(5);
+
+        let mut buffer = [0; 64];
+        let len = coordinator.beacon(1_000, Some(77), &mut buffer).unwrap();
+        let beacon = TdmaBeacon::decode(&buffer[..len]).unwrap();
+        assert_eq!(beacon.superframe, 1);
+        assert_eq!(beacon.slot_ms as u64, slot);
+        assert_eq!(beacon.gps_ms, Some(77));
+        assert_eq!(beacon.nodes, [1, 5]);
+        // The beacon's slot, two nodes' and the open one.
+        assert_eq!(coordinator.next_beacon_ms(), Some(1_000 + 4 * slot));
+        assert_eq!(
+            coordinator.beacon(1_000 + 4 * slot - 1, None, &mut buffer),
+            None
+        );
+
+        // Changes to the table take effect with the next superframe.
+        coordinator.assign(6);
+        assert_eq!(coordinator.schedule().unwrap().nodes, [1, 5]);
+        let beacon = beacon_of(&mut coordinator, 1_000 + 4 * slot);
+        assert_eq!(beacon.superframe, 2);
+        assert_eq!(beacon.nodes, [1, 5, 6]);
+        assert_eq!(coordinator.schedule().unwrap().start_ms, 1_000 + 4 * slot);
+        assert_eq!(coordinator.next_beacon_ms(), Some(1_000 + 9 * slot));
+    }
+
+    #[test]
+    fn members_sync_to_beacons() {
+        let params = LoRaParams::p2p();
+        let mut coordinator = Tdma::new(1, TdmaRole::Coordinator, params, 0);
+        coordinator.assign(5);
+        let mut buffer = [0; 64];
+        let len = coordinator.beacon(0, None, &mut buffer).unwrap();
+        let frame_len = HEADER_LEN + len + SECURITY_LEN + CRC_LEN;
+        let airtime = params.time_on_air_ms(frame_len) as u64;
+
+        let mut member = Tdma::new(5, TdmaRole::Member, params, 0);
+        assert!(!member.is_synced(0));
+        assert_eq!(member.transmit_at(100, 42), 42);
+        let beacon = member.handle(&buffer[..len], frame_len, 50_000).unwrap();
+        assert_eq!(beacon.nodes, [1, 5]);
+        let schedule = member.schedule().unwrap();
+        assert_eq!(schedule.start_ms, 50_000 - airtime);
+        assert_eq!(schedule.slot_ms, slot_ms(&params));
+        assert_eq!(schedule.nodes, [1, 5]);
+        assert_eq!(schedule.superframe, 1);
+
+        // Slot 2 of the superframe.
+        let slot_start = 50_000 - airtime + 2 * slot_ms(&params) as u64;
+        assert!(member.is_synced(50_000));
+        assert_eq!(member.transmit_at(100, 50_000), slot_start + 16);
+        // Until the sync times out.
+        assert!(member.is_synced(50_000 + SYNC_TIMEOUT_MS - 1));
+        assert!(!member.is_synced(50_000 + SYNC_TIMEOUT_MS));
+        assert_eq!(member.transmit_at(100, 200_000), 200_000);
+
+        // A node without a role decodes the beacon but does not follow it.
+        let mut off = Tdma::new(9, TdmaRole::Off, params, 0);
+        assert!(off.handle(&buffer[..len], frame_len, 50_000).is_ok());
+        assert!(off.schedule().is_none());
+        assert_eq!(off.transmit_at(100, 50_000), 50_000);
+        assert_eq!(
+            member.handle(&buffer[..11], frame_len, 60_000),
+            Err(TdmaError::Malformed(11))
+        );
+        assert!(member.is_synced(60_000));
+    }
+}
@@ -0,0 +1,453 @@
+use embedded_storage::{
+    nor_flash::{
+        ErrorType, MultiwriteNorFlash, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+    },
+    ReadStorage, Storage,
+};
+
+/// Flash offset of the ESP32 partition table.
+pub const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+/// Maximum size of the partition table, in bytes.
+pub const PARTITION_TABLE_SIZE: usize = 0xC00;
+/// Label of the data partition holding the firmware settings.
+pub const CONFIG_PARTITION: &str = "config";
+/// Maximum number of partitions kept by [`PartitionTable`].
+pub const MAX_PARTITIONS: usize = 16;
+
+const ENTRY_LEN: usize = 32;
+const ENTRY_MAGIC: [u8; 2] = [0xAA, 0x50];
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+
+/// Data partition subtypes defined by ESP-IDF.
+pub mod data_subtype {
+    pub const OTA: u8 = 0x00;
+    pub const PHY: u8 = 0x01;
+    pub const NVS: u8 = 0x02;
+    pub const COREDUMP: u8 = 0x03;
+    pub const NVS_KEYS: u8 = 0x04;
+    pub const EFUSE: u8 = 0x05;
+    pub const UNDEFINED: u8 = 0x06;
+    pub const FAT: u8 = 0x81;
+    pub const SPIFFS: u8 = 0x82;
+}
+
+/// App partition subtypes defined by ESP-IDF.
+pub mod app_subtype {
+    pub const FACTORY: u8 = 0x00;
+    pub const OTA_0: u8 = 0x10;
+    pub const OTA_1: u8 = 0x11;
+    pub const TEST: u8 = 0x20;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PartitionError {
+    Storage,
+    BadMagic(usize),
+    TooManyPartitions,
+    NotFound,
+    OutOfBounds,
+}
+
+impl core::fmt::Display for PartitionError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PartitionError::Storage => write!(f, "Failed to access storage"),
+            PartitionError::BadMagic(index) => {
+                write!(f, "Invalid partition table entry {}", index)
+            }
+            PartitionError::TooManyPartitions => write!(f, "Too many partitions"),
+            PartitionError::NotFound => write!(f, "Partition not found"),
+            PartitionError::OutOfBounds => write!(f, "Access outside of partition"),
+        }
+    }
+}
+
+impl NorFlashError for PartitionError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            PartitionError::OutOfBounds => NorFlashErrorKind::OutOfBounds,
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionType {
+    App,
+    Data,
+    Custom(u8),
+}
+
+impl From<u8> for PartitionType {
+    fn from(value: u8) -> Self {
+        match value {
+            0x00 => PartitionType::App,
+            0x01 => PartitionType::Data,
+            other => PartitionType::Custom(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PartitionEntry {
+    pub kind: PartitionType,
+    pub subtype: u8,
+    pub offset: u32,
+    pub size: u32,
+    pub label: [u8; 16],
+    pub flags: u32,
+}
+
+impl PartitionEntry {
+    /// Parses a single 32-byte table entry.
+    ///
+    /// Returns `Ok(None)` at the end of the table, which is marked either by
+    /// erased flash or by the MD5 checksum entry.
+    fn parse(index: usize, bytes: &[u8]) -> Result<Option<Self>, PartitionError> {
+        match [bytes[0], bytes[1]] {
+            ENTRY_MAGIC => (),
+            MD5_MAGIC | [0xFF, 0xFF] => return Ok(None),
+            _ => return Err(PartitionError::BadMagic(index)),
+        }
+
+        let mut label = [0u8; 16];
+        label.copy_from_slice(&bytes[12..28]);
+        Ok(Some(Self {
+            kind: PartitionType::from(bytes[2]),
+            subtype: bytes[3],
+            offset: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            size: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            label,
+            flags: u32::from_le_bytes([bytes[28], bytes[29], bytes[30], bytes[31]]),
+        }))
+    }
+
+    /// Partition label without its NUL padding.
+    pub fn label(&self) -> &str {
+        let len = self
+            .label
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(self.label.len());
+        core::str::from_utf8(&self.label[..len]).unwrap_or("")
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+/// ESP32 partition table, as written by `gen_esp32part.py`.
+#[derive(Debug, Default)]
+pub struct PartitionTable {
+    entries: heapless::Vec<PartitionEntry, MAX_PARTITIONS>,
+}
+
+impl PartitionTable {
+    /// Parses a partition table from its binary image.
+    ///
+    /// # Errors
+    ///
+    /// * `BadMagic` - If an entry does not start with the partition magic.
+    /// * `TooManyPartitions` - If the table holds more than [`MAX_PARTITIONS`] entries.
+    pub fn parse(bytes: &[u8]) -> Result<Self, PartitionError> {
+        let mut table = Self::default();
+        for (index, chunk) in bytes.chunks_exact(ENTRY_LEN).enumerate() {
+            match PartitionEntry::parse(index, chunk)? {
+                Some(entry) => table
+                    .entries
+                    .push(entry)
+                    .map_err(|_| PartitionError::TooManyPartitions)?,
+                None => break,
+            }
+        }
+        Ok(table)
+    }
+
+    /// Reads and parses the partition table from flash, one entry at a time.
+    pub fn read<S: ReadStorage>(storage: &mut S) -> Result<Self, PartitionError> {
+        let mut table = Self::default();
+        let mut chunk = [0u8; ENTRY_LEN];
+        for index in 0..PARTITION_TABLE_SIZE / ENTRY_LEN {
+            let offset = PARTITION_TABLE_OFFSET + (index * ENTRY_LEN) as u32;
+            storage
+                .read(offset, &mut chunk)
+                .map_err(|_| PartitionError::Storage)?;
+            match PartitionEntry::parse(index, &chunk)? {
+                Some(entry) => table
+                    .entries
+                    .push(entry)
+                    .map_err(|_| PartitionError::TooManyPartitions)?,
+                None => break,
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn entries(&self) -> &[PartitionEntry] {
+        &self.entries
+    }
+
+    /// Finds a partition by its label.
+    pub fn find(&self, label: &str) -> Option<&PartitionEntry> {
+        self.entries.iter().find(|entry| entry.label() == label)
+    }
+
+    /// Finds the first partition of the given type and subtype.
+    pub fn find_by_type(&self, kind: PartitionType, subtype: u8) -> Option<&PartitionEntry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.kind == kind && entry.subtype == subtype)
+    }
+}
+
+/// Storage handle bounded to a single partition.
+///
+/// Offsets passed to [`ReadStorage::read`] and [`Storage::write`] are relative
+/// to the start of the partition, and any access that would cross its end is
+/// rejected before touching the flash.
+pub struct Partition<S> {
+    storage: S,
+    offset: u32,
+    size: u32,
+}
+
+impl<S: ReadStorage> Partition<S> {
+    pub fn new(storage: S, entry: &PartitionEntry) -> Self {
+        Self {
+            storage,
+            offset: entry.offset,
+            size: entry.size,
+        }
+    }
+
+    /// Opens the partition labelled `label` from the table stored in `storage`.
+    pub fn open(mut storage: S, label: &str) -> Result<Self, PartitionError> {
+        let table = PartitionTable::read(&mut storage)?;
+        let entry = table.find(label).ok_or(PartitionError::NotFound)?;
+        Ok(Self::new(storage, entry))
+    }
+}
+
+impl<S> Partition<S> {
+    /// Absolute flash offset of the partition.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Releases the underlying storage.
+    pub fn into_inner(self) -> S {
+        self.storage
+    }
+
+    fn absolute(&self, offset: u32, len: usize) -> Result<u32, PartitionError> {
+        let end = offset
+            .checked_add(len as u32)
+            .ok_or(PartitionError::OutOfBounds)?;
+        if end > self.size {
+            return Err(PartitionError::OutOfBounds);
+        }
+        Ok(self.offset + offset)
+    }
+}
+
+impl<S: ReadStorage> ReadStorage for Partition<S> {
+    type Error = PartitionError;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.absolute(offset, bytes.len())?;
+        ReadStorage::read(&mut self.storage, offset, bytes).map_err(|_| PartitionError::Storage)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<S: Storage> Storage for Partition<S> {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.absolute(offset, bytes.len())?;
+        Storage::write(&mut self.storage, offset, bytes).map_err(|_| PartitionError::Storage)
+    }
+}
+
+impl<S> ErrorType for Partition<S> {
+    type Error = PartitionError;
+}
+
+impl<S: ReadNorFlash> ReadNorFlash for Partition<S> {
+    const READ_SIZE: usize = S::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = self.absolute(offset, bytes.len())?;
+        ReadNorFlash::read(&mut self.storage, offset, bytes).map_err(|_| PartitionError::Storage)
+    }
+
+    fn capacity(&self) -> usize {
+        self.size as usize
+    }
+}
+
+impl<S: NorFlash> NorFlash for Partition<S> {
+    const WRITE_SIZE: usize = S::WRITE_SIZE;
+    const ERASE_SIZE: usize = S::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let len = to.checked_sub(from).ok_or(PartitionError::OutOfBounds)?;
+        let start = self.absolute(from, len as usize)?;
+        self.storage
+            .erase(start, start + len)
+            .map_err(|_| PartitionError::Storage)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        let offset = self.absolute(offset, bytes.len())?;
+        NorFlash::write(&mut self.storage, offset, bytes).map_err(|_| PartitionError::Storage)
+    }
+}
+
+impl<S: MultiwriteNorFlash> MultiwriteNorFlash for Partition<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::test_storage::MemStorage;
+
+    /// The table flashed with the firmware, generated from
+    /// `bootloader/partitions.csv`.
+    const PARTITIONS_BIN: &[u8] = include_bytes!("../../bootloader/partitions.bin");
+
+    /// A flash image with the partition table at its offset.
+    fn flash() -> MemStorage {
+        let mut storage = MemStorage::new(0x400000);
+        let start = PARTITION_TABLE_OFFSET as usize;
+        storage.data[start..start + PARTITIONS_BIN.len()].copy_from_slice(PARTITIONS_BIN);
+        storage
+    }
+
+    #[test]
+    fn parses_checked_in_table() {
+        let table = PartitionTable::parse(PARTITIONS_BIN).unwrap();
+        let labels: std::vec::Vec<_> = table.entries().iter().map(|e| e.label()).collect();
+        assert_eq!(
+            labels,
+            ["nvs", "phy_init", "ota_0", "ota_1", "otadata", "config"]
+        );
+
+        let expect = |label: &str, kind, subtype, offset, size| {
+            let entry = table.find(label).unwrap();
+            assert_eq!(
+                (entry.kind, entry.subtype, entry.offset, entry.size),
+                (kind, subtype, offset, size),
+                "{}",
+                label
+            );
+            assert!(!entry.is_encrypted());
+        };
+        expect(
+            "config",
+            PartitionType::Data,
+            data_subtype::UNDEFINED,
+            0x3E0000,
+            0x10000,
+        );
+        expect(
+            "otadata",
+            PartitionType::Data,
+            data_subtype::OTA,
+            0x3D0000,
+            0x2000,
+        );
+        expect(
+            "ota_0",
+            PartitionType::App,
+            app_subtype::OTA_0,
+            0x10000,
+            0x1E0000,
+        );
+        expect(
+            "ota_1",
+            PartitionType::App,
+            app_subtype::OTA_1,
+            0x1F0000,
+            0x1E0000,
+        );
+        expect(
+            "nvs",
+            PartitionType::Data,
+            data_subtype::NVS,
+            0x9000,
+            0x6000,
+        );
+        assert_eq!(
+            table
+                .find_by_type(PartitionType::App, app_subtype::OTA_1)
+                .map(|e| e.label()),
+            Some("ota_1")
+        );
+        assert!(table.find("factory").is_none());
+    }
+
+    #[test]
+    fn reads_table_from_flash() {
+        let mut storage = flash();
+        let table = PartitionTable::read(&mut storage).unwrap();
+        assert_eq!(
+            table.entries(),
+            PartitionTable::parse(PARTITIONS_BIN).unwrap().entries()
+        );
+        assert!(matches!(
+            Partition::open(storage, "missing"),
+            Err(PartitionError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_entries() {
+        let mut bytes = PARTITIONS_BIN[..3 * ENTRY_LEN].to_vec();
+        bytes[2 * ENTRY_LEN] = 0x00;
+        assert!(matches!(
+            PartitionTable::parse(&bytes),
+            Err(PartitionError::BadMagic(2))
+        ));
+
+        let entry = &PARTITIONS_BIN[..ENTRY_LEN];
+        let many: std::vec::Vec<u8> = entry.repeat(MAX_PARTITIONS + 1);
+        assert!(matches!(
+            PartitionTable::parse(&many),
+            Err(PartitionError::TooManyPartitions)
+        ));
+    }
+
+    #[test]
+    fn accesses_stay_in_bounds() {
+        let mut config = Partition::open(flash(), CONFIG_PARTITION).unwrap();
+        assert_eq!(config.offset(), 0x3E0000);
+        assert_eq!(ReadStorage::capacity(&config), 0x10000);
+
+        Storage::write(&mut config, 0x10, b"data").unwrap();
+        let mut bytes = [0u8; 4];
+        ReadStorage::read(&mut config, 0xFFFC, &mut bytes).unwrap();
+        ReadStorage::read(&mut config, 0x10, &mut bytes).unwrap();
+        assert_eq!(&bytes, b"data");
+
+        assert_eq!(
+            ReadStorage::read(&mut config, 0xFFFD, &mut bytes),
+            Err(PartitionError::OutOfBounds)
+        );
+        assert_eq!(
+            Storage::write(&mut config, 0x10000, b"x"),
+            Err(PartitionError::OutOfBounds)
+        );
+        assert_eq!(
+            Storage::write(&mut config, u32::MAX - 1, b"data"),
+            Err(PartitionError::OutOfBounds)
+        );
+
+        // Nothing outside the partition was touched.
+        let storage = config.into_inner();
+        assert_eq!(&storage.data[0x3E0010..0x3E0014], b"data");
+        assert!(storage.data[0x3F0000..].iter().all(|b| *b == 0xFF));
+    }
+}
@@ -0,0 +1,618 @@
+/// Version written at the start of every compact payload.
+pub const COMPACT_VERSION: u8 = 1;
+/// Size of a compact payload carrying a fix, in bytes.
+pub const COMPACT_FIX_LEN: usize = 11;
+/// Size of a compact payload without a fix, in bytes.
+pub const COMPACT_NO_FIX_LEN: usize = 3;
+
+/// Cayenne LPP data types.
+mod lpp {
+    pub const DIGITAL_INPUT: u8 = 0;
+    pub const DIGITAL_OUTPUT: u8 = 1;
+    pub const ANALOG_INPUT: u8 = 2;
+    pub const ANALOG_OUTPUT: u8 = 3;
+    pub const ILLUMINANCE: u8 = 101;
+    pub const PRESENCE: u8 = 102;
+    pub const TEMPERATURE: u8 = 103;
+    pub const HUMIDITY: u8 = 104;
+    pub const BAROMETER: u8 = 115;
+    pub const UNIX_TIME: u8 = 133;
+    pub const GPS: u8 = 136;
+}
+
+/// LPP channels used for the telemetry fields.
+mod channel {
+    pub const GPS: u8 = 1;
+    pub const SATELLITES: u8 = 2;
+    pub const HDOP: u8 = 3;
+    pub const DATA_RATE: u8 = 4;
+    pub const UPTIME: u8 = 5;
+    pub const TIME: u8 = 6;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PayloadError {
+    Full,
+    Truncated,
+    UnsupportedVersion(u8),
+}
+
+impl core::fmt::Display for PayloadError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            PayloadError::Full => write!(f, "Payload exceeds the size budget"),
+            PayloadError::Truncated => write!(f, "Payload is truncated"),
+            PayloadError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported payload version {}", v)
+            }
+        }
+    }
+}
+
+/// Position fix, as reported by a GGA sentence.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    /// Degrees, positive north.
+    pub latitude: f64,
+    /// Degrees, positive east.
+    pub longitude: f64,
+    /// Meters above mean sea level.
+    pub altitude: f32,
+    pub satellites: u8,
+    pub hdop: f32,
+}
+
+/// Device state reported next to the position.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeviceStatus {
+    pub uptime_s: u32,
+    pub data_rate: u8,
+    /// UTC time of the reading, `None` while the clock is not set.
+    pub unix_time: Option<u32>,
+}
+
+/// Contents of a telemetry uplink.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Telemetry {
+    pub fix: Option<Fix>,
+    pub status: DeviceStatus,
+}
+
+/// Encoding of telemetry uplinks.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PayloadFormat {
+    /// Cayenne Low Power Payload, understood by most network servers.
+    #[default]
+    Lpp,
+    /// Bit-packed format, see [`Telemetry::encode_compact`].
+    Compact,
+}
+
+/// Rounds to the nearest integer, saturating at the bounds of `i32`.
+fn round(value: f64) -> i32 {
+    if value >= 0.0 {
+        (value + 0.5) as i32
+    } else {
+        (value - 0.5) as i32
+    }
+}
+
+/// Writer of Cayenne LPP records into a buffer.
+///
+/// The buffer length is the size budget: a record that does not fit is not
+/// written and the payload written so far stays valid.
+pub struct LppEncoder<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> LppEncoder<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Length of the payload written so far.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn add(&mut self, channel: u8, kind: u8, data: &[u8]) -> Result<(), PayloadError> {
+        let end = self.len + 2 + data.len();
+        if end > self.buffer.len() {
+            return Err(PayloadError::Full);
+        }
+        self.buffer[self.len] = channel;
+        self.buffer[self.len + 1] = kind;
+        self.buffer[self.len + 2..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    pub fn add_digital_input(&mut self, channel: u8, value: u8) -> Result<(), PayloadError> {
+        self.add(channel, lpp::DIGITAL_INPUT, &[value])
+    }
+
+    pub fn add_digital_output(&mut self, channel: u8, value: u8) -> Result<(), PayloadError> {
+        self.add(channel, lpp::DIGITAL_OUTPUT, &[value])
+    }
+
+    /// Adds an analog value with 0.01 resolution, saturating at ±327.67.
+    pub fn add_analog_input(&mut self, channel: u8, value: f32) -> Result<(), PayloadError> {
+        let value = round(value as f64 * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.add(channel, lpp::ANALOG_INPUT, &value.to_be_bytes())
+    }
+
+    /// Adds an analog value with 0.01 resolution, saturating at ±327.67.
+    pub fn add_analog_output(&mut self, channel: u8, value: f32) -> Result<(), PayloadError> {
+        let value = round(value as f64 * 100.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.add(channel, lpp::ANALOG_OUTPUT, &value.to_be_bytes())
+    }
+
+    /// Adds an illuminance in lux.
+    pub fn add_illuminance(&mut self, channel: u8, lux: u16) -> Result<(), PayloadError> {
+        self.add(channel, lpp::ILLUMINANCE, &lux.to_be_bytes())
+    }
+
+    pub fn add_presence(&mut self, channel: u8, present: bool) -> Result<(), PayloadError> {
+        self.add(channel, lpp::PRESENCE, &[present as u8])
+    }
+
+    /// Adds a temperature in °C with 0.1 resolution.
+    pub fn add_temperature(&mut self, channel: u8, celsius: f32) -> Result<(), PayloadError> {
+        let value = round(celsius as f64 * 10.0).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        self.add(channel, lpp::TEMPERATURE, &value.to_be_bytes())
+    }
+
+    /// Adds a relative humidity in percent with 0.5 resolution.
+    pub fn add_humidity(&mut self, channel: u8, percent: f32) -> Result<(), PayloadError> {
+        let value = round(percent as f64 * 2.0).clamp(0, 200) as u8;
+        self.add(channel, lpp::HUMIDITY, &[value])
+    }
+
+    /// Adds a barometric pressure in hPa with 0.1 resolution.
+    pub fn add_barometer(&mut self, channel: u8, hpa: f32) -> Result<(), PayloadError> {
+        let value = round(hpa as f64 * 10.0).clamp(0, u16::MAX as i32) as u16;
+        self.add(channel, lpp::BAROMETER, &value.to_be_bytes())
+    }
+
+    /// Adds a UTC time in seconds since the Unix epoch.
+    pub fn add_unix_time(&mut self, channel: u8, unix_s: u32) -> Result<(), PayloadError> {
+        self.add(channel, lpp::UNIX_TIME, &unix_s.to_be_bytes())
+    }
+
+    /// Adds a location: latitude and longitude with 0.0001° resolution and
+    /// altitude in meters with 0.01 resolution.
+    pub fn add_gps(
+        &mut self,
+        channel: u8,
+        latitude: f64,
+        longitude: f64,
+        altitude: f32,
+    ) -> Result<(), PayloadError> {
+        const I24_MAX: i32 = (1 << 23) - 1;
+        let lat = round(latitude * 10_000.0).clamp(-I24_MAX, I24_MAX);
+        let lon = round(longitude * 10_000.0).clamp(-I24_MAX, I24_MAX);
+        let alt = round(altitude as f64 * 100.0).clamp(-I24_MAX, I24_MAX);
+        let mut data = [0u8; 9];
+        data[0..3].copy_from_slice(&lat.to_be_bytes()[1..]);
+        data[3..6].copy_from_slice(&lon.to_be_bytes()[1..]);
+        data[6..9].copy_from_slice(&alt.to_be_bytes()[1..]);
+        self.add(channel, lpp::GPS, &data)
+    }
+}
+
+/// Most-significant-bit-first bit writer.
+struct BitWriter<'a> {
+    buffer: &'a mut [u8],
+    bits: usize,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        buffer.fill(0);
+        Self { buffer, bits: 0 }
+    }
+
+    fn write(&mut self, value: u32, width: usize) {
+        for bit in (0..width).rev() {
+            if (value >> bit) & 1 != 0 {
+                self.buffer[self.bits / 8] |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+}
+
+/// Most-significant-bit-first bit reader.
+struct BitReader<'a> {
+    buffer: &'a [u8],
+    bits: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, width: usize) -> Result<u32, PayloadError> {
+        if self.bits + width > self.buffer.len() * 8 {
+            return Err(PayloadError::Truncated);
+        }
+        let mut value = 0;
+        for _ in 0..width {
+            let bit = (self.buffer[self.bits / 8] >> (7 - self.bits % 8)) & 1;
+            value = (value << 1) | bit as u32;
+            self.bits += 1;
+        }
+        Ok(value)
+    }
+}
+
+/// Maps `value` in `min..=max` onto `0..=2^bits - 1`.
+fn quantize(value: f64, min: f64, max: f64, bits: u32) -> u32 {
+    let steps = ((1u64 << bits) - 1) as f64;
+    let value = value.clamp(min, max);
+    round((value - min) / (max - min) * steps) as u32
+}
+
+fn dequantize(value: u32, min: f64, max: f64, bits: u32) -> f64 {
+    let steps = ((1u64 << bits) - 1) as f64;
+    min + value as f64 / steps * (max - min)
+}
+
+impl Telemetry {
+    /// Encodes the telemetry in `format` into `buffer`, returning the payload
+    /// length.
+    ///
+    /// The length of `buffer` is the size budget. LPP records are added by
+    /// priority, position first, and those that no longer fit are left out.
+    ///
+    /// # Errors
+    ///
+    /// * `Full` - If not even the most important field fits.
+    pub fn encode(&self, format: PayloadFormat, buffer: &mut [u8]) -> Result<usize, PayloadError> {
+        match format {
+            PayloadFormat::Lpp => self.encode_lpp(buffer),
+            PayloadFormat::Compact => self.encode_compact(buffer),
+        }
+    }
+
+    fn encode_lpp(&self, buffer: &mut [u8]) -> Result<usize, PayloadError> {
+        let mut encoder = LppEncoder::new(buffer);
+        if let Some(fix) = &self.fix {
+            encoder.add_gps(channel::GPS, fix.latitude, fix.longitude, fix.altitude)?;
+        }
+        // The remaining fields are optional, so running out of budget for
+        // them still leaves a useful payload.
+        let _ = self.add_lpp_details(&mut encoder);
+        if encoder.is_empty() {
+            return Err(PayloadError::Full);
+        }
+        Ok(encoder.len())
+    }
+
+    /// Adds the optional LPP records, in order of importance.
+    fn add_lpp_details(&self, encoder: &mut LppEncoder) -> Result<(), PayloadError> {
+        if let Some(fix) = &self.fix {
+            encoder.add_digital_input(channel::SATELLITES, fix.satellites)?;
+            encoder.add_analog_input(channel::HDOP, fix.hdop)?;
+        }
+        let uptime_h = self.status.uptime_s as f32 / 3600.0;
+        encoder.add_digital_input(channel::DATA_RATE, self.status.data_rate)?;
+        encoder.add_analog_input(channel::UPTIME, uptime_h)?;
+        match self.status.unix_time {
+            Some(unix_s) => encoder.add_unix_time(channel::TIME, unix_s),
+            None => Ok(()),
+        }
+    }
+
+    /// Encodes the telemetry in the compact format.
+    ///
+    /// Fields are packed most significant bit first:
+    ///
+    /// | Bits | Field                                          |
+    /// |------|------------------------------------------------|
+    /// | 3    | Version ([`COMPACT_VERSION`])                  |
+    /// | 1    | Fix present                                    |
+    /// | 22   | Latitude, -90..90° (only with a fix)           |
+    /// | 23   | Longitude, -180..180° (only with a fix)        |
+    /// | 14   | Altitude + 1000 m, 0..16383 (only with a fix)  |
+    /// | 4    | Satellites, saturating at 15 (only with a fix) |
+    /// | 5    | HDOP × 4, saturating at 31 (only with a fix)   |
+    /// | 12   | Uptime in hours, saturating                    |
+    /// | 4    | Data rate                                      |
+    ///
+    /// The payload is [`COMPACT_FIX_LEN`] bytes with a fix and
+    /// [`COMPACT_NO_FIX_LEN`] bytes without, so it fits every data rate.
+    /// The time of the reading is left out, the receiver timestamps it.
+    pub fn encode_compact(&self, buffer: &mut [u8]) -> Result<usize, PayloadError> {
+        let len = match self.fix {
+            Some(_) => COMPACT_FIX_LEN,
+            None => COMPACT_NO_FIX_LEN,
+        };
+        if buffer.len() < len {
+            return Err(PayloadError::Full);
+        }
+        let mut writer = BitWriter::new(&mut buffer[..len]);
+        writer.write(COMPACT_VERSION as u32, 3);
+        writer.write(self.fix.is_some() as u32, 1);
+        if let Some(fix) = &self.fix {
+            writer.write(quantize(fix.latitude, -90.0, 90.0, 22), 22);
+            writer.write(quantize(fix.longitude, -180.0, 180.0, 23), 23);
+            let altitude = round(fix.altitude as f64 + 1000.0).clamp(0, 16383);
+            writer.write(altitude as u32, 14);
+            writer.write(fix.satellites.min(15) as u32, 4);
+            writer.write(round(fix.hdop as f64 * 4.0).clamp(0, 31) as u32, 5);
+        }
+        writer.write((self.status.uptime_s / 3600).min(4095), 12);
+        writer.write(self.status.data_rate.min(15) as u32, 4);
+        Ok(len)
+    }
+
+    /// Decodes a payload written by [`Telemetry::encode_compact`].
+    ///
+    /// Values come back at the resolution of the format: about 5 m for the
+    /// position, 1 m for the altitude and 1 hour for the uptime.
+    pub fn decode_compact(bytes: &[u8]) -> Result<Self, PayloadError> {
+        let mut reader = BitReader {
+            buffer: bytes,
+            bits: 0,
+        };
+        let version = reader.read(3)? as u8;
+        if version != COMPACT_VERSION {
+            return Err(PayloadError::UnsupportedVersion(version));
+        }
+        let fix = match reader.read(1)? {
+            0 => None,
+            _ => Some(Fix {
+                latitude: dequantize(reader.read(22)?, -90.0, 90.0, 22),
+                longitude: dequantize(reader.read(23)?, -180.0, 180.0, 23),
+                altitude: reader.read(14)? as f32 - 1000.0,
+                satellites: reader.read(4)? as u8,
+                hdop: reader.read(5)? as f32 / 4.0,
+            }),
+        };
+        let status = DeviceStatus {
+            uptime_s: reader.read(12)? * 3600,
+            data_rate: reader.read(4)? as u8,
+            unix_time: None,
+        };
+        Ok(Self { fix, status })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::lorawan_config::Region;
+
+    const FIX: Fix = Fix {
+        latitude: 42.3519,
+        longitude: -87.9094,
+        altitude: 10.0,
+        satellites: 9,
+        hdop: 1.2,
+    };
+
+    fn telemetry(fix: Option<Fix>) -> Telemetry {
+        Telemetry {
+            fix,
+            status: DeviceStatus {
+                uptime_s: 5 * 3600 + 59,
+                data_rate: 3,
+                unix_time: Some(1_700_000_000),
+            },
+        }
+    }
+
+    #[test]
+    fn encodes_lpp_records() {
+        let mut buffer = [0u8; 64];
+        let mut encoder = LppEncoder::new(&mut buffer);
+        encoder.add_digital_input(1, 7).unwrap();
+        encoder.add_digital_output(2, 1).unwrap();
+        encoder.add_analog_input(3, -1.25).unwrap();
+        encoder.add_analog_output(4, 400.0).unwrap();
+        encoder.add_illuminance(5, 0x1234).unwrap();
+        encoder.add_presence(6, true).unwrap();
+        encoder.add_temperature(7, -4.1).unwrap();
+        encoder.add_humidity(8, 55.5).unwrap();
+        encoder.add_barometer(9, 1013.2).unwrap();
+        encoder.add_unix_time(10, 0x5F5E_1000).unwrap();
+        encoder
+            .add_gps(11, FIX.latitude, FIX.longitude, FIX.altitude)
+            .unwrap();
+        let len = encoder.len();
+        assert_eq!(
+            buffer[..len],
+            [
+                1, 0, 7, //
+                2, 1, 1, //
+                3, 2, 0xFF, 0x83, //
+                4, 3, 0x7F, 0xFF, //
+                5, 101, 0x12, 0x34, //
+                6, 102, 1, //
+                7, 103, 0xFF, 0xD7, //
+                8, 104, 111, //
+                9, 115, 0x27, 0x94, //
+                10, 133, 0x5F, 0x5E, 0x10, 0x00, //
+                11, 136, 0x06, 0x76, 0x5F, 0xF2, 0x96, 0x0A, 0x00, 0x03, 0xE8,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn lpp_records_that_do_not_fit_are_left_out() {
+        let mut buffer = [0u8; 5];
+        let mut encoder = LppEncoder::new(&mut buffer);
+        encoder.add_digital_input(1, 7).unwrap();
+        assert_eq!(encoder.add_unix_time(2, 0), Err(PayloadError::Full));
+        assert_eq!(encoder.len(), 3);
+        encoder.add_digital_output(3, 0).unwrap_err();
+        encoder.add_presence(3, false).unwrap_err();
+        assert_eq!(encoder.len(), 3);
+    }
+
+    #[test]
+    fn lpp_telemetry_fits_every_data_rate() {
+        let mut full = [0u8; 64];
+        let full_len = telemetry(Some(FIX))
+            .encode(PayloadFormat::Lpp, &mut full)
+            .unwrap();
+        // Position, satellites, HDOP, data rate, uptime and time.
+        assert_eq!(full_len, 11 + 3 + 4 + 3 + 4 + 6);
+        assert_eq!(&full[11..14], [channel::SATELLITES, lpp::DIGITAL_INPUT, 9]);
+        assert_eq!(&full[14..18], [channel::HDOP, lpp::ANALOG_INPUT, 0, 120]);
+        assert_eq!(&full[18..21], [channel::DATA_RATE, lpp::DIGITAL_INPUT, 3]);
+        assert_eq!(
+            &full[21..25],
+            [channel::UPTIME, lpp::ANALOG_INPUT, 0x01, 0xF6]
+        );
+
+        for region in [Region::US915, Region::AU915, Region::EU868] {
+            for dr in 0..=region.max_data_rate() {
+                let budget = region.max_payload(dr);
+                let mut buffer = [0u8; 256];
+                let len = telemetry(Some(FIX))
+                    .encode(PayloadFormat::Lpp, &mut buffer[..budget])
+                    .unwrap();
+                assert!(len <= budget);
+                // Records are dropped whole, least important first.
+                assert_eq!(buffer[..len], full[..len]);
+            }
+        }
+        // Only the position fits the 11 bytes of US915 DR0.
+        let mut buffer = [0u8; 11];
+        let budget = Region::US915.max_payload(0);
+        assert_eq!(
+            telemetry(Some(FIX)).encode(PayloadFormat::Lpp, &mut buffer[..budget]),
+            Ok(11)
+        );
+        assert_eq!(buffer[..2], [channel::GPS, lpp::GPS]);
+        let len = telemetry(None)
+            .encode(PayloadFormat::Lpp, &mut buffer[..budget])
+            .unwrap();
+        assert_eq!(buffer[..len], [4, 0, 3, 5, 2, 0x01, 0xF6]);
+
+        assert_eq!(
+            telemetry(Some(FIX)).encode(PayloadFormat::Lpp, &mut buffer[..10]),
+            Err(PayloadError::Full)
+        );
+        assert_eq!(
+            telemetry(None).encode(PayloadFormat::Lpp, &mut buffer[..2]),
+            Err(PayloadError::Full)
+        );
+    }
+
+    /// Asserts that `decoded` is `fix` at the resolution of the compact
+    /// format.
+    fn assert_close(decoded: &Fix, fix: &Fix) {
+        assert!((decoded.latitude - fix.latitude).abs() <= 180.0 / ((1 << 22) - 1) as f64);
+        assert!((decoded.longitude - fix.longitude).abs() <= 360.0 / ((1 << 23) - 1) as f64);
+        assert!((decoded.altitude - fix.altitude).abs() <= 0.5);
+        assert_eq!(decoded.satellites, fix.satellites.min(15));
+        assert!((decoded.hdop - fix.hdop).abs() <= 0.125);
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        let mut buffer = [0u8; COMPACT_FIX_LEN];
+        let reading = telemetry(Some(FIX));
+        assert_eq!(
+            reading.encode(PayloadFormat::Compact, &mut buffer),
+            Ok(COMPACT_FIX_LEN)
+        );
+        let decoded = Telemetry::decode_compact(&buffer).unwrap();
+        assert_close(&decoded.fix.unwrap(), &FIX);
+        assert_eq!(
+            decoded.status,
+            DeviceStatus {
+                uptime_s: 5 * 3600,
+                data_rate: 3,
+                unix_time: None,
+            }
+        );
+
+        let reading = telemetry(None);
+        assert_eq!(
+            reading.encode(PayloadFormat::Compact, &mut buffer),
+            Ok(COMPACT_NO_FIX_LEN)
+        );
+        let decoded = Telemetry::decode_compact(&buffer[..COMPACT_NO_FIX_LEN]).unwrap();
+        assert_eq!(decoded.fix, None);
+        assert_eq!(decoded.status.uptime_s, 5 * 3600);
+        assert_eq!(decoded.status.data_rate, 3);
+    }
+
+    #[test]
+    fn compact_round_trip_at_extremes() {
+        let mut buffer = [0u8; COMPACT_FIX_LEN];
+        for (latitude, longitude) in [(-90.0, -180.0), (90.0, 180.0), (-33.8688, -151.2093)] {
+            let fix = Fix {
+                latitude,
+                longitude,
+                altitude: -412.0,
+                satellites: 4,
+                hdop: 0.0,
+            };
+            telemetry(Some(fix)).encode_compact(&mut buffer).unwrap();
+            let decoded = Telemetry::decode_compact(&buffer).unwrap();
+            assert_close(&decoded.fix.unwrap(), &fix);
+        }
+        let decoded = Telemetry::decode_compact(&buffer).unwrap().fix.unwrap();
+        assert!(decoded.latitude < 0.0 && decoded.longitude < 0.0);
+
+        // Values beyond the ranges of the fields saturate.
+        let fix = Fix {
+            latitude: -95.0,
+            longitude: 200.0,
+            altitude: 20_000.0,
+            satellites: 24,
+            hdop: 99.0,
+        };
+        let reading = Telemetry {
+            fix: Some(fix),
+            status: DeviceStatus {
+                uptime_s: u32::MAX,
+                data_rate: 20,
+                unix_time: None,
+            },
+        };
+        reading.encode_compact(&mut buffer).unwrap();
+        let decoded = Telemetry::decode_compact(&buffer).unwrap();
+        let decoded_fix = decoded.fix.unwrap();
+        assert_eq!(decoded_fix.latitude, -90.0);
+        assert_eq!(decoded_fix.longitude, 180.0);
+        assert_eq!(decoded_fix.altitude, 16383.0 - 1000.0);
+        assert_eq!(decoded_fix.satellites, 15);
+        assert_eq!(decoded_fix.hdop, 31.0 / 4.0);
+        assert_eq!(decoded.status.uptime_s, 4095 * 3600);
+        assert_eq!(decoded.status.data_rate, 15);
+    }
+
+    #[test]
+    fn compact_errors() {
+        let mut buffer = [0u8; COMPACT_FIX_LEN];
+        assert_eq!(
+            telemetry(Some(FIX)).encode_compact(&mut buffer[..COMPACT_FIX_LEN - 1]),
+            Err(PayloadError::Full)
+        );
+        assert_eq!(
+            telemetry(None).encode_compact(&mut buffer[..COMPACT_NO_FIX_LEN - 1]),
+            Err(PayloadError::Full)
+        );
+
+        telemetry(Some(FIX)).encode_compact(&mut buffer).unwrap();
+        assert_eq!(
+            Telemetry::decode_compact(&buffer[..COMPACT_FIX_LEN - 1]),
+            Err(PayloadError::Truncated)
+        );
+        assert_eq!(Telemetry::decode_compact(&[]), Err(PayloadError::Truncated));
+        buffer[0] = (buffer[0] & 0x1F) | (2 << 5);
+        assert_eq!(
+            Telemetry::decode_compact(&buffer),
+            Err(PayloadError::UnsupportedVersion(2))
+        );
+    }
+}
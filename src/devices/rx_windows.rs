@@ -0,0 +1,316 @@
+use core::cell::Cell;
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use super::{airtime::LoRaParams, link_status::MacAnswer, lorawan_config::Region};
+
+/// MHDR of a join accept.
+const JOIN_ACCEPT: u8 = 0x20;
+/// Delay of RX1 after an uplink until the network configures another, in
+/// seconds.
+const DEFAULT_RX_DELAY_S: u8 = 1;
+
+mod cid {
+    pub const RX_PARAM_SETUP: u8 = 0x05;
+    pub const RX_TIMING_SETUP: u8 = 0x08;
+}
+
+/// Receive window of class A, opened after each uplink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Rx1,
+    /// Also the window class C listens on between uplinks.
+    Rx2,
+}
+
+/// Receive window settings of a session, as the network configures them in
+/// the join accept and with `RXParamSetupReq` and `RXTimingSetupReq`.
+///
+/// The stack keeps them in its own state, which is lost with a reboot, so
+/// they are stored with the [`SessionRecord`](super::session::SessionRecord)
+/// and applied to the windows of a restored session by
+/// [`MonitoredRadio`](super::link_status::MonitoredRadio).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RxSettings {
+    /// Data rates the RX1 window is below the uplink.
+    pub rx1_dr_offset: u8,
+    pub rx2_data_rate: u8,
+    pub rx2_frequency_hz: u32,
+    /// Delay of RX1 after the uplink, in seconds; RX2 opens a second later.
+    pub rx_delay_s: u8,
+}
+
+impl RxSettings {
+    /// Settings of `region` before the network configures any.
+    pub fn defaults(region: Region) -> Self {
+        let (rx2_frequency_hz, rx2_data_rate) = region.rx2_default();
+        Self {
+            rx1_dr_offset: 0,
+            rx2_data_rate,
+            rx2_frequency_hz,
+            rx_delay_s: DEFAULT_RX_DELAY_S,
+        }
+    }
+
+    /// Reads the settings of a join accept `frame`, encrypted with `appkey`.
+    ///
+    /// The MIC is not checked, `frame` must be a join accept the stack
+    /// accepted.
+    ///
+    /// | Offset | Size | Field (decrypted)                         |
+    /// |--------|------|-------------------------------------------|
+    /// | 0      | 3    | JoinNonce                                 |
+    /// | 3      | 3    | NetID                                     |
+    /// | 6      | 4    | DevAddr                                   |
+    /// | 10     | 1    | DLSettings: RX1 DR offset (bits 6-4), RX2 DR (bits 3-0) |
+    /// | 11     | 1    | RxDelay (bits 3-0, 0 meaning 1 s)         |
+    /// | 12     | 0/16 | CFList                                    |
+    pub fn from_join_accept(region: Region, appkey: &[u8; 16], frame: &[u8]) -> Option<Self> {
+        let (&mhdr, encrypted) = frame.split_first()?;
+        if mhdr != JOIN_ACCEPT || (encrypted.len() != 16 && encrypted.len() != 32) {
+            return None;
+        }
+        // The network encrypts with the AES decrypt operation, so the device
+        // decrypts with the encrypt one.
+        let cipher = Aes128::new(&GenericArray::from(*appkey));
+        let mut block = [0u8; 16];
+        block.copy_from_slice(&encrypted[..16]);
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut block));
+
+        let mut settings = Self::defaults(region);
+        settings.rx1_dr_offset = (block[10] >> 4) & 0x07;
+        settings.rx2_data_rate = block[10] & 0x0F;
+        settings.rx_delay_s = rx_delay(block[11]);
+        Some(settings)
+    }
+
+    /// Applies the `RXParamSetupReq` and `RXTimingSetupReq` among the MAC
+    /// `commands`, returning whether a setting changed.
+    ///
+    /// The stack answers the requests, the network uses the new settings
+    /// once it has the answer.
+    ///
+    /// | CID    | Request          | Arguments                                       |
+    /// |--------|------------------|-------------------------------------------------|
+    /// | `0x05` | RXParamSetupReq  | DLSettings, RX2 frequency (u24, 100 Hz units)   |
+    /// | `0x08` | RXTimingSetupReq | Delay (bits 3-0, 0 meaning 1 s)                 |
+    pub fn apply(&mut self, commands: &[u8]) -> bool {
+        let before = *self;
+        let mut bytes = commands;
+        while let Ok((_, len)) = MacAnswer::parse(bytes) {
+            let (command, rest) = bytes.split_at(len);
+            match command {
+                [cid::RX_PARAM_SETUP, dl_settings, frequency @ ..] => {
+                    self.rx1_dr_offset = (dl_settings >> 4) & 0x07;
+                    self.rx2_data_rate = dl_settings & 0x0F;
+                    let frequency =
+                        u32::from_le_bytes([frequency[0], frequency[1], frequency[2], 0]);
+                    self.rx2_frequency_hz = frequency * 100;
+                }
+                [cid::RX_TIMING_SETUP, delay] => self.rx_delay_s = rx_delay(*delay),
+                _ => {}
+            }
+            bytes = rest;
+        }
+        *self != before
+    }
+
+    /// Frequency and modulation of `window` under these settings, where the
+    /// stack opens it on `frequency_hz` after an uplink at `uplink_dr`.
+    ///
+    /// Returns `None` if the region does not define the data rate.
+    pub fn window(
+        &self,
+        region: Region,
+        window: Window,
+        uplink_dr: u8,
+        frequency_hz: u32,
+    ) -> Option<(u32, LoRaParams)> {
+        match window {
+            Window::Rx1 => {
+                let dr = region.rx1_data_rate(uplink_dr, self.rx1_dr_offset);
+                Some((frequency_hz, region.downlink_params(dr)?))
+            }
+            Window::Rx2 => Some((
+                self.rx2_frequency_hz,
+                region.downlink_params(self.rx2_data_rate)?,
+            )),
+        }
+    }
+
+    /// Time RX1 opens later than the stack's default delay, in milliseconds.
+    pub fn delay_shift_ms(&self) -> i32 {
+        (self.rx_delay_s as i32 - DEFAULT_RX_DELAY_S as i32) * 1000
+    }
+}
+
+fn rx_delay(settings: u8) -> u8 {
+    (settings & 0x0F).max(1)
+}
+
+/// Settings of the restored session, that the stack started with the
+/// defaults of the region.
+static RESTORED: Mutex<CriticalSectionRawMutex, Cell<Option<(Region, RxSettings)>>> =
+    Mutex::new(Cell::new(None));
+
+/// Makes the radio apply `settings` to the windows of a session restored
+/// from flash, the stack only knows the defaults of `region`.
+pub fn restore(region: Region, settings: RxSettings) {
+    RESTORED.lock(|restored| restored.set(Some((region, settings))));
+}
+
+/// Lets the stack's settings stand, as after a join.
+pub fn clear() {
+    RESTORED.lock(|restored| restored.set(None));
+}
+
+/// Settings of the restored session, if there is one.
+pub fn restored() -> Option<(Region, RxSettings)> {
+    RESTORED.lock(|restored| restored.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APPKEY: [u8; 16] = [
+        0x72, 0xad, 0x47, 0x7e, 0xca, 0xe4, 0xa7, 0x80, 0xb5, 0xae, 0x93, 0xbf, 0xac, 0x7a, 0x04,
+        0xbb,
+    ];
+
+    /// Encrypts a join accept the way the network does.
+    fn join_accept(dl_settings: u8, rx_delay: u8, cf_list: bool) -> std::vec::Vec<u8> {
+        use aes::cipher::BlockDecrypt;
+
+        let mut plain = std::vec![0u8; if cf_list { 32 } else { 16 }];
+        plain[0..3].copy_from_slice(&[1, 2, 3]);
+        plain[6..10].copy_from_slice(&[0x34, 0x12, 0x0B, 0x26]);
+        plain[10] = dl_settings;
+        plain[11] = rx_delay;
+        let cipher = Aes128::new(&GenericArray::from(APPKEY));
+        for block in plain.chunks_mut(16) {
+            cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        }
+        let mut frame = std::vec![JOIN_ACCEPT];
+        frame.extend_from_slice(&plain);
+        frame
+    }
+
+    #[test]
+    fn reads_join_accept() {
+        let frame = join_accept(0x23, 5, false);
+        let settings = RxSettings::from_join_accept(Region::EU868, &APPKEY, &frame).unwrap();
+        assert_eq!(
+            settings,
+            RxSettings {
+                rx1_dr_offset: 2,
+                rx2_data_rate: 3,
+                rx2_frequency_hz: 869_525_000,
+                rx_delay_s: 5,
+            }
+        );
+        assert_eq!(settings.delay_shift_ms(), 4000);
+
+        let frame = join_accept(0x08, 0, true);
+        let settings = RxSettings::from_join_accept(Region::AU915, &APPKEY, &frame).unwrap();
+        assert_eq!(settings.rx2_data_rate, 8);
+        assert_eq!(settings.rx_delay_s, 1);
+        assert_eq!(settings.delay_shift_ms(), 0);
+    }
+
+    #[test]
+    fn rejects_other_frames() {
+        let mut frame = join_accept(0, 1, false);
+        frame[0] = 0x60;
+        assert_eq!(
+            RxSettings::from_join_accept(Region::EU868, &APPKEY, &frame),
+            None
+        );
+        let frame = join_accept(0, 1, false);
+        assert_eq!(
+            RxSettings::from_join_accept(Region::EU868, &APPKEY, &frame[..12]),
+            None
+        );
+    }
+
+    #[test]
+    fn applies_mac_commands() {
+        let mut settings = RxSettings::defaults(Region::EU868);
+        // LinkCheckAns, RXParamSetupReq for RX2 at DR3 on 869.525 MHz with
+        // offset 1, RXTimingSetupReq for 3 s.
+        let commands = [0x02, 10, 2, 0x05, 0x13, 0xD2, 0xAD, 0x84, 0x08, 0x03];
+        assert!(settings.apply(&commands));
+        assert_eq!(
+            settings,
+            RxSettings {
+                rx1_dr_offset: 1,
+                rx2_data_rate: 3,
+                rx2_frequency_hz: 869_525_000,
+                rx_delay_s: 3,
+            }
+        );
+        assert!(!settings.apply(&commands));
+
+        // A truncated command ends the parsing, the ones before it count.
+        let mut settings = RxSettings::defaults(Region::EU868);
+        assert!(settings.apply(&[0x08, 0x04, 0x05, 0x13]));
+        assert_eq!(settings.rx_delay_s, 4);
+        assert_eq!(settings.rx2_data_rate, 0);
+        let mut settings = RxSettings::defaults(Region::EU868);
+        assert!(!settings.apply(&[0x05, 0x13]));
+        assert!(!settings.apply(&[0xFF, 0x08, 0x02]));
+    }
+
+    #[test]
+    fn computes_windows() {
+        let settings = RxSettings {
+            rx1_dr_offset: 2,
+            rx2_data_rate: 3,
+            rx2_frequency_hz: 869_525_000,
+            rx_delay_s: 1,
+        };
+        assert_eq!(
+            settings.window(Region::EU868, Window::Rx1, 5, 868_100_000),
+            Some((868_100_000, LoRaParams::lorawan(9, 125_000)))
+        );
+        assert_eq!(
+            settings.window(Region::EU868, Window::Rx2, 5, 868_100_000),
+            Some((869_525_000, LoRaParams::lorawan(9, 125_000)))
+        );
+
+        let settings = RxSettings {
+            rx1_dr_offset: 1,
+            ..RxSettings::defaults(Region::US915)
+        };
+        assert_eq!(
+            settings.window(Region::US915, Window::Rx1, 4, 923_900_000),
+            Some((923_900_000, LoRaParams::lorawan(7, 500_000)))
+        );
+        assert_eq!(
+            settings.window(Region::US915, Window::Rx1, 0, 923_300_000),
+            Some((923_300_000, LoRaParams::lorawan(11, 500_000)))
+        );
+        assert_eq!(
+            settings.window(Region::US915, Window::Rx2, 0, 923_300_000),
+            Some((923_300_000, LoRaParams::lorawan(12, 500_000)))
+        );
+        let settings = RxSettings {
+            rx2_data_rate: 2,
+            ..settings
+        };
+        assert_eq!(settings.window(Region::US915, Window::Rx2, 0, 0), None);
+    }
+
+    #[test]
+    fn restored_settings() {
+        let settings = RxSettings::defaults(Region::EU868);
+        restore(Region::EU868, settings);
+        assert_eq!(restored(), Some((Region::EU868, settings)));
+        clear();
+        assert_eq!(restored(), None);
+    }
+}
@@ -0,0 +1,398 @@
+use embedded_storage::Storage;
+
+use super::{crc::crc32, kv::KvStore, rx_windows::RxSettings};
+
+/// Marker written at the start of every session record.
+pub const SESSION_MAGIC: [u8; 4] = *b"LRWS";
+/// Settings key under which the session record is stored.
+pub const SESSION_KEY: &str = "lora.session";
+/// Current schema version of the session record.
+pub const SESSION_VERSION: u8 = 2;
+/// Uplinks sent between two frame counter checkpoints.
+///
+/// Writing the record after every uplink would wear the flash, so the uplink
+/// counter is only saved every this many frames and skipped ahead by the same
+/// amount when a session is resumed.
+pub const FCNT_CHECKPOINT_INTERVAL: u32 = 32;
+
+const HEADER_LEN: usize = 8;
+const BODY_LEN: usize = 72;
+const CRC_LEN: usize = 4;
+/// Size in bytes of an encoded session record.
+pub const SESSION_RECORD_LEN: usize = HEADER_LEN + BODY_LEN + CRC_LEN;
+
+#[derive(Debug, PartialEq)]
+pub enum SessionError {
+    Storage,
+    Erased,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength(u16),
+    Crc { expected: u32, found: u32 },
+}
+
+impl core::fmt::Display for SessionError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SessionError::Storage => write!(f, "Failed to access storage"),
+            SessionError::Erased => write!(f, "No session record stored"),
+            SessionError::BadMagic => write!(f, "Session record magic mismatch"),
+            SessionError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported session record version {}", v)
+            }
+            SessionError::BadLength(len) => write!(f, "Invalid session body length {}", len),
+            SessionError::Crc { expected, found } => write!(
+                f,
+                "Session record CRC mismatch (expected {:#010x}, found {:#010x})",
+                expected, found
+            ),
+        }
+    }
+}
+
+/// LoRa session state persisted across reboots.
+///
+/// The encoded record is laid out as:
+///
+/// | Offset | Size | Field                               |
+/// |--------|------|-------------------------------------|
+/// | 0      | 4    | Magic (`LRWS`)                      |
+/// | 4      | 1    | Schema version                      |
+/// | 5      | 1    | Reserved (0)                        |
+/// | 6      | 2    | Body length (LE)                    |
+/// | 8      | 72   | Body (see [`SessionRecord::encode`]) |
+/// | 80     | 4    | CRC-32 of bytes 0..80 (LE)          |
+///
+/// Version 2 appended the receive window settings to the body of version 1
+/// (64 bytes).
+///
+/// The DevNonce is the one of the last join request sent; it is saved
+/// before each request so no nonce is used twice, even across reboots.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionRecord {
+    pub device_addr: [u8; 4],
+    pub device_eui: [u8; 8],
+    pub application_eui: [u8; 8],
+    pub application_session_key: [u8; 16],
+    pub network_session_key: [u8; 16],
+    pub device_nonce: u16,
+    pub fcnt_up: u32,
+    pub fcnt_down: u32,
+    /// Receive windows the network configured, `None` until it did.
+    pub rx_settings: Option<RxSettings>,
+}
+
+impl SessionRecord {
+    /// Whether the record holds a network session.
+    pub fn is_joined(&self) -> bool {
+        self.device_addr != [0; 4]
+    }
+
+    /// Prepares a restored session for use after a reboot.
+    ///
+    /// Up to [`FCNT_CHECKPOINT_INTERVAL`] uplinks may have been sent since the
+    /// record was written, so the uplink counter is moved past all of them to
+    /// never reuse a frame counter.
+    pub fn resume(&mut self) {
+        self.fcnt_up = self.fcnt_up.saturating_add(FCNT_CHECKPOINT_INTERVAL);
+    }
+
+    /// Whether the uplink counter has advanced far enough past the stored
+    /// one that a new checkpoint must be written.
+    pub fn needs_checkpoint(&self, fcnt_up: u32) -> bool {
+        fcnt_up.wrapping_sub(self.fcnt_up) >= FCNT_CHECKPOINT_INTERVAL
+    }
+
+    /// Encodes the record, including header and CRC, into `buffer`.
+    ///
+    /// Body fields are only ever appended in newer schema versions, so the
+    /// byte offsets below must never change.
+    pub fn encode(&self, buffer: &mut [u8; SESSION_RECORD_LEN]) {
+        buffer.fill(0);
+        buffer[0..4].copy_from_slice(&SESSION_MAGIC);
+        buffer[4] = SESSION_VERSION;
+        buffer[6..8].copy_from_slice(&(BODY_LEN as u16).to_le_bytes());
+
+        let body = &mut buffer[HEADER_LEN..HEADER_LEN + BODY_LEN];
+        body[0..4].copy_from_slice(&self.device_addr);
+        body[4..12].copy_from_slice(&self.device_eui);
+        body[12..20].copy_from_slice(&self.application_eui);
+        body[20..36].copy_from_slice(&self.application_session_key);
+        body[36..52].copy_from_slice(&self.network_session_key);
+        body[52..54].copy_from_slice(&self.device_nonce.to_le_bytes());
+        body[54..58].copy_from_slice(&self.fcnt_up.to_le_bytes());
+        body[58..62].copy_from_slice(&self.fcnt_down.to_le_bytes());
+        if let Some(rx) = &self.rx_settings {
+            body[62] = 1;
+            body[63] = rx.rx1_dr_offset;
+            body[64] = rx.rx2_data_rate;
+            body[65..69].copy_from_slice(&rx.rx2_frequency_hz.to_le_bytes());
+            body[69] = rx.rx_delay_s;
+        }
+
+        let crc = crc32(&buffer[..HEADER_LEN + BODY_LEN]);
+        buffer[HEADER_LEN + BODY_LEN..].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decodes and validates a record previously written by [`SessionRecord::encode`].
+    ///
+    /// Records written by an older schema carry a shorter body; the fields they
+    /// contain are decoded and the fields added since then keep their defaults.
+    ///
+    /// # Errors
+    ///
+    /// * `Erased` - If the buffer is blank flash (all `0xFF`).
+    /// * `BadMagic` - If the record does not start with [`SESSION_MAGIC`].
+    /// * `UnsupportedVersion` - If the version is 0 or newer than [`SESSION_VERSION`].
+    /// * `BadLength` - If the body length does not fit in the record.
+    /// * `Crc` - If the stored checksum does not match the contents.
+    pub fn decode(buffer: &[u8; SESSION_RECORD_LEN]) -> Result<Self, SessionError> {
+        if buffer.iter().all(|b| *b == 0xFF) {
+            return Err(SessionError::Erased);
+        }
+        if buffer[0..4] != SESSION_MAGIC {
+            return Err(SessionError::BadMagic);
+        }
+
+        let version = buffer[4];
+        if version == 0 || version > SESSION_VERSION {
+            return Err(SessionError::UnsupportedVersion(version));
+        }
+
+        let body_len = u16::from_le_bytes([buffer[6], buffer[7]]);
+        if body_len as usize > BODY_LEN {
+            return Err(SessionError::BadLength(body_len));
+        }
+
+        let crc_offset = HEADER_LEN + body_len as usize;
+        let mut stored = [0u8; CRC_LEN];
+        stored.copy_from_slice(&buffer[crc_offset..crc_offset + CRC_LEN]);
+        let found = u32::from_le_bytes(stored);
+        let expected = crc32(&buffer[..crc_offset]);
+        if expected != found {
+            return Err(SessionError::Crc { expected, found });
+        }
+
+        // Pad the body to the current length so fields missing from older
+        // schema versions decode as zero.
+        let mut body = [0u8; BODY_LEN];
+        body[..body_len as usize].copy_from_slice(&buffer[HEADER_LEN..crc_offset]);
+
+        let mut record = Self::default();
+        record.device_addr.copy_from_slice(&body[0..4]);
+        record.device_eui.copy_from_slice(&body[4..12]);
+        record.application_eui.copy_from_slice(&body[12..20]);
+        record
+            .application_session_key
+            .copy_from_slice(&body[20..36]);
+        record.network_session_key.copy_from_slice(&body[36..52]);
+        record.device_nonce = u16::from_le_bytes([body[52], body[53]]);
+        record.fcnt_up = u32::from_le_bytes([body[54], body[55], body[56], body[57]]);
+        record.fcnt_down = u32::from_le_bytes([body[58], body[59], body[60], body[61]]);
+        record.rx_settings = (body[62] == 1).then(|| RxSettings {
+            rx1_dr_offset: body[63],
+            rx2_data_rate: body[64],
+            rx2_frequency_hz: u32::from_le_bytes([body[65], body[66], body[67], body[68]]),
+            rx_delay_s: body[69],
+        });
+        Ok(record)
+    }
+
+    /// Reads and decodes the record stored in `settings`.
+    pub fn read<S: Storage>(settings: &mut KvStore<S>) -> Result<Self, SessionError> {
+        let mut buffer = [0xFFu8; SESSION_RECORD_LEN];
+        settings
+            .get_raw(SESSION_KEY, &mut buffer)
+            .map_err(|_| SessionError::Storage)?;
+        Self::decode(&buffer)
+    }
+
+    /// Reads the record stored in `settings`, falling back to the defaults
+    /// if there is no valid one, so a corrupted record never leaks partial
+    /// state.
+    pub fn load<S: Storage>(settings: &mut KvStore<S>) -> Self {
+        match Self::read(settings) {
+            Ok(record) => {
+                esp_println::println!("[LoRa] Session restored from flash");
+                record
+            }
+            Err(SessionError::Erased) => {
+                esp_println::println!("[LoRa] No session stored, using defaults");
+                Self::default()
+            }
+            Err(e) => {
+                esp_println::println!("[LoRa] Invalid session in flash: {}", e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Encodes the record and stores it in `settings`.
+    pub fn write<S: Storage>(&self, settings: &mut KvStore<S>) -> Result<(), SessionError> {
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        self.encode(&mut buffer);
+        settings
+            .set_raw(SESSION_KEY, &buffer)
+            .map_err(|_| SessionError::Storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{kv::SECTOR_SIZE, test_storage::MemStorage};
+
+    fn record() -> SessionRecord {
+        SessionRecord {
+            device_addr: [0x26, 0x0B, 0x12, 0x34],
+            device_eui: [1; 8],
+            application_eui: [2; 8],
+            application_session_key: [3; 16],
+            network_session_key: [4; 16],
+            device_nonce: 7,
+            fcnt_up: 1234,
+            fcnt_down: 56,
+            rx_settings: Some(RxSettings {
+                rx1_dr_offset: 1,
+                rx2_data_rate: 3,
+                rx2_frequency_hz: 869_525_000,
+                rx_delay_s: 5,
+            }),
+        }
+    }
+
+    fn settings() -> KvStore<MemStorage> {
+        KvStore::mount(MemStorage::new(2 * SECTOR_SIZE)).unwrap()
+    }
+
+    /// Re-encodes `buffer` as a record of an older schema, whose body ends
+    /// at `body_len`.
+    fn truncate_body(buffer: &mut [u8; SESSION_RECORD_LEN], version: u8, body_len: usize) {
+        buffer[4] = version;
+        buffer[6..8].copy_from_slice(&(body_len as u16).to_le_bytes());
+        buffer[HEADER_LEN + body_len..].fill(0);
+        let crc = crc32(&buffer[..HEADER_LEN + body_len]);
+        buffer[HEADER_LEN + body_len..HEADER_LEN + body_len + CRC_LEN]
+            .copy_from_slice(&crc.to_le_bytes());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        record().encode(&mut buffer);
+        assert_eq!(&buffer[0..4], &SESSION_MAGIC);
+        assert_eq!(SessionRecord::decode(&buffer), Ok(record()));
+
+        let mut settings = settings();
+        record().write(&mut settings).unwrap();
+        let storage = settings.into_inner();
+        let mut settings = KvStore::mount(storage).unwrap();
+        assert_eq!(SessionRecord::read(&mut settings), Ok(record()));
+    }
+
+    #[test]
+    fn bad_crc() {
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        record().encode(&mut buffer);
+        buffer[HEADER_LEN + 20] ^= 0x01;
+        assert!(matches!(
+            SessionRecord::decode(&buffer),
+            Err(SessionError::Crc { .. })
+        ));
+    }
+
+    #[test]
+    fn header_errors() {
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        assert_eq!(
+            SessionRecord::decode(&[0xFF; SESSION_RECORD_LEN]),
+            Err(SessionError::Erased)
+        );
+        record().encode(&mut buffer);
+        buffer[0] = b'X';
+        assert_eq!(SessionRecord::decode(&buffer), Err(SessionError::BadMagic));
+
+        record().encode(&mut buffer);
+        buffer[4] = SESSION_VERSION + 1;
+        assert_eq!(
+            SessionRecord::decode(&buffer),
+            Err(SessionError::UnsupportedVersion(SESSION_VERSION + 1))
+        );
+
+        record().encode(&mut buffer);
+        buffer[6..8].copy_from_slice(&(BODY_LEN as u16 + 1).to_le_bytes());
+        assert_eq!(
+            SessionRecord::decode(&buffer),
+            Err(SessionError::BadLength(BODY_LEN as u16 + 1))
+        );
+    }
+
+    #[test]
+    fn older_body_keeps_defaults() {
+        // A body that ends before the frame counters, as a schema without
+        // them would have written.
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        record().encode(&mut buffer);
+        truncate_body(&mut buffer, 1, 54);
+        let decoded = SessionRecord::decode(&buffer).unwrap();
+        assert_eq!(decoded.device_addr, record().device_addr);
+        assert_eq!(decoded.network_session_key, record().network_session_key);
+        assert_eq!(decoded.device_nonce, 7);
+        assert_eq!((decoded.fcnt_up, decoded.fcnt_down), (0, 0));
+
+        // Version 1 had no receive window settings, and left the bytes
+        // after the frame counters zero.
+        record().encode(&mut buffer);
+        buffer[HEADER_LEN + 62..HEADER_LEN + 64].fill(0);
+        truncate_body(&mut buffer, 1, 64);
+        let decoded = SessionRecord::decode(&buffer).unwrap();
+        assert_eq!(decoded.fcnt_down, 56);
+        assert_eq!(decoded.rx_settings, None);
+    }
+
+    #[test]
+    fn reads_version_1_records() {
+        // Version 1 records are stored shorter than the current ones.
+        let mut settings = settings();
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        record().encode(&mut buffer);
+        buffer[HEADER_LEN + 62..HEADER_LEN + 64].fill(0);
+        truncate_body(&mut buffer, 1, 64);
+        settings
+            .set_raw(SESSION_KEY, &buffer[..HEADER_LEN + 64 + CRC_LEN])
+            .unwrap();
+        let decoded = SessionRecord::read(&mut settings).unwrap();
+        assert_eq!(
+            decoded,
+            SessionRecord {
+                rx_settings: None,
+                ..record()
+            }
+        );
+    }
+
+    #[test]
+    fn load_falls_back_to_defaults() {
+        let mut settings = settings();
+        assert_eq!(SessionRecord::load(&mut settings), SessionRecord::default());
+
+        let mut buffer = [0u8; SESSION_RECORD_LEN];
+        record().encode(&mut buffer);
+        buffer[SESSION_RECORD_LEN - 1] ^= 0xFF;
+        settings.set_raw(SESSION_KEY, &buffer).unwrap();
+        assert_eq!(SessionRecord::load(&mut settings), SessionRecord::default());
+
+        record().write(&mut settings).unwrap();
+        assert_eq!(SessionRecord::load(&mut settings), record());
+    }
+
+    #[test]
+    fn checkpoints() {
+        let mut record = record();
+        assert!(!record.needs_checkpoint(1234 + FCNT_CHECKPOINT_INTERVAL - 1));
+        assert!(record.needs_checkpoint(1234 + FCNT_CHECKPOINT_INTERVAL));
+        record.resume();
+        assert_eq!(record.fcnt_up, 1234 + FCNT_CHECKPOINT_INTERVAL);
+    }
+}
@@ -0,0 +1,65 @@
+use embedded_storage::{ReadStorage, Storage};
+
+/// Flash in RAM for the host tests, starting blank (all `0xFF`).
+///
+/// Writes overwrite bytes as they are, like the read-modify-write storage
+/// the firmware uses over its partitions. A power cut is simulated by
+/// setting `budget`: once that many bytes are written, every write fails
+/// part way, leaving the bytes before the cut programmed.
+pub struct MemStorage {
+    pub data: std::vec::Vec<u8>,
+    pub budget: Option<usize>,
+}
+
+/// The power was cut during a write.
+#[derive(Debug, PartialEq)]
+pub struct PowerCut;
+
+impl MemStorage {
+    pub fn new(len: usize) -> Self {
+        Self::from_image(std::vec![0xFF; len])
+    }
+
+    pub fn from_image(data: std::vec::Vec<u8>) -> Self {
+        Self { data, budget: None }
+    }
+
+    /// Cuts the power after `bytes` more bytes are written.
+    pub fn cut_after(&mut self, bytes: usize) {
+        self.budget = Some(bytes);
+    }
+
+    /// Restores the power.
+    pub fn restore(&mut self) {
+        self.budget = None;
+    }
+}
+
+impl ReadStorage for MemStorage {
+    type Error = PowerCut;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl Storage for MemStorage {
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+        for (i, byte) in bytes.iter().enumerate() {
+            if let Some(budget) = self.budget.as_mut() {
+                if *budget == 0 {
+                    return Err(PowerCut);
+                }
+                *budget -= 1;
+            }
+            self.data[offset as usize + i] = *byte;
+        }
+        Ok(())
+    }
+}
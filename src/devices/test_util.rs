@@ -0,0 +1,89 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc, vec::Vec};
+
+use embassy_futures::yield_now;
+use embassy_time::Duration;
+
+use super::p2p_arq::P2pRadio;
+
+/// Next value of the pseudo-random sequence the host tests draw from, a
+/// linear congruential generator whose low bits are dropped.
+pub fn next_random(state: &mut u32) -> u32 {
+    *state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+    *state >> 8
+}
+
+/// Pseudo-random values starting from `seed`, the same on every run.
+pub fn randoms(mut seed: u32) -> impl Iterator<Item = u32> {
+    core::iter::from_fn(move || Some(next_random(&mut seed)))
+}
+
+/// Channel between two simulated radios, which loses frames in either
+/// direction: first as `script` says, then `loss_percent` of them.
+pub struct Channel {
+    pub inboxes: [VecDeque<Vec<u8>>; 2],
+    /// Whether each of the next frames is lost, ahead of the random losses.
+    pub script: VecDeque<bool>,
+    pub loss_percent: u32,
+    random: u32,
+    /// Frames transmitted by either side, lost or not.
+    pub sent: usize,
+}
+
+impl Channel {
+    pub fn new(loss_percent: u32) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self {
+            inboxes: Default::default(),
+            script: VecDeque::new(),
+            loss_percent,
+            random: 12_345,
+            sent: 0,
+        }))
+    }
+
+    fn lost(&mut self) -> bool {
+        if let Some(lost) = self.script.pop_front() {
+            return lost;
+        }
+        next_random(&mut self.random) % 100 < self.loss_percent
+    }
+}
+
+/// Radio on `side` 0 or 1 of a [`Channel`].
+pub struct LossyRadio {
+    pub channel: Rc<RefCell<Channel>>,
+    pub side: usize,
+}
+
+impl LossyRadio {
+    pub fn new(channel: &Rc<RefCell<Channel>>, side: usize) -> Self {
+        Self {
+            channel: channel.clone(),
+            side,
+        }
+    }
+}
+
+impl P2pRadio for LossyRadio {
+    type Error = ();
+
+    async fn transmit(&mut self, frame: &[u8]) -> Result<(), ()> {
+        let mut channel = self.channel.borrow_mut();
+        channel.sent += 1;
+        if !channel.lost() {
+            channel.inboxes[1 - self.side].push_back(frame.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Gives the other side a few turns to answer before timing out.
+    async fn receive(&mut self, buffer: &mut [u8], _: Duration) -> Result<Option<usize>, ()> {
+        for _ in 0..20 {
+            if let Some(frame) = self.channel.borrow_mut().inboxes[self.side].pop_front() {
+                buffer[..frame.len()].copy_from_slice(&frame);
+                return Ok(Some(frame.len()));
+            }
+            yield_now().await;
+        }
+        Ok(None)
+    }
+}
@@ -1,6 +1,9 @@
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_storage::nor_flash::RmwMultiwriteNorFlashStorage;
 use esp_hal::{gpio::Output, spi::master::SpiDmaBus};
 
+use super::{kv::KvStore, nvs::Nvs, ota::FirmwareUpdate, partition::Partition};
+
 pub type LoRaSpi = SpiDmaBus<'static, esp_hal::Async>;
 pub type BusSpi<'a> = embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice<
     'a,
@@ -10,3 +13,14 @@ pub type BusSpi<'a> = embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice<
 >;
 
 pub type MutexSpi<'a> = Mutex<CriticalSectionRawMutex, LoRaSpi>;
+
+pub type ConfigStorage = Partition<esp_storage::FlashStorage>;
+pub type SettingsStorage = RmwMultiwriteNorFlashStorage<'static, ConfigStorage>;
+pub type Settings = KvStore<SettingsStorage>;
+pub type MutexSettings = Mutex<CriticalSectionRawMutex, Settings>;
+/// The `nvs` partition, provisioned at the factory with `nvs_partition_gen`.
+pub type NvsStorage = RmwMultiwriteNorFlashStorage<'static, Partition<esp_storage::FlashStorage>>;
+pub type FactoryNvs = Nvs<NvsStorage>;
+pub type MutexNvs = Mutex<CriticalSectionRawMutex, FactoryNvs>;
+pub type Firmware =
+    FirmwareUpdate<RmwMultiwriteNorFlashStorage<'static, ConfigStorage>, ConfigStorage>;
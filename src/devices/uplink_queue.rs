@@ -0,0 +1,527 @@
+use core::fmt::Write;
+
+use embedded_storage::Storage;
+
+use super::kv::{KvError, KvStore};
+
+/// Maximum number of uplinks waiting to be sent.
+///
+/// Every queued uplink is a separate settings record when the queue is
+/// persisted, and the live records of the settings store must fit in one
+/// sector, so the queue is kept small enough for 8 full-size uplinks.
+pub const QUEUE_CAPACITY: usize = 8;
+/// Largest application payload of any region and data rate.
+pub const MAX_UPLINK_LEN: usize = 242;
+/// Prefix of the settings keys holding persisted uplinks, followed by the
+/// slot number.
+pub const QUEUE_KEY_PREFIX: &str = "lora.q.";
+/// Transmissions of a confirmed uplink before it is dropped.
+pub const CONFIRMED_ATTEMPTS: u8 = 8;
+/// Transmissions of an unconfirmed uplink before it is dropped. These only
+/// fail when the stack could not transmit at all.
+pub const UNCONFIRMED_ATTEMPTS: u8 = 2;
+
+const RECORD_VERSION: u8 = 1;
+const RECORD_HEADER_LEN: usize = 9;
+const FLAG_CONFIRMED: u8 = 0x01;
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    TooLong(usize),
+    Full,
+    Storage,
+    InvalidRecord(u8),
+}
+
+impl core::fmt::Display for QueueError {
+    /// Format the error as a string, suitable for display to the user.
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            QueueError::TooLong(len) => write!(f, "Uplink of {} bytes is too long", len),
+            QueueError::Full => write!(f, "Uplink queue is full of higher priority uplinks"),
+            QueueError::Storage => write!(f, "Failed to access storage"),
+            QueueError::InvalidRecord(slot) => write!(f, "Invalid queued uplink in slot {}", slot),
+        }
+    }
+}
+
+/// Order in which queued uplinks are sent, and which are dropped first when
+/// the queue overflows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            2 => Priority::High,
+            _ => return None,
+        })
+    }
+}
+
+/// What to drop when an uplink is queued while the queue is full.
+///
+/// Only uplinks of the same or a lower priority than the new one are ever
+/// dropped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest uplink of the lowest priority.
+    DropOldest,
+    /// Replace the oldest uplink on the same port, as the newer reading
+    /// supersedes it, and drop the oldest one otherwise.
+    Coalesce,
+}
+
+/// An uplink waiting in the [`UplinkQueue`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uplink {
+    pub port: u8,
+    pub confirmed: bool,
+    pub priority: Priority,
+    /// Transmissions left before the uplink is dropped.
+    pub attempts_left: u8,
+    pub payload: heapless::Vec<u8, MAX_UPLINK_LEN>,
+    seq: u32,
+    slot: u8,
+}
+
+impl Uplink {
+    /// Creates an uplink with the default number of attempts for its kind.
+    ///
+    /// # Errors
+    ///
+    /// * `TooLong` - If the payload exceeds [`MAX_UPLINK_LEN`].
+    pub fn new(
+        port: u8,
+        priority: Priority,
+        confirmed: bool,
+        payload: &[u8],
+    ) -> Result<Self, QueueError> {
+        Ok(Self {
+            port,
+            confirmed,
+            priority,
+            attempts_left: if confirmed {
+                CONFIRMED_ATTEMPTS
+            } else {
+                UNCONFIRMED_ATTEMPTS
+            },
+            payload: heapless::Vec::from_slice(payload)
+                .map_err(|_| QueueError::TooLong(payload.len()))?,
+            seq: 0,
+            slot: 0,
+        })
+    }
+
+    /// Position of the uplink in the queue order, assigned when it is queued.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Encodes the uplink into `buffer`, returning the number of bytes used.
+    ///
+    /// | Offset | Size | Field                   |
+    /// |--------|------|-------------------------|
+    /// | 0      | 1    | Record version          |
+    /// | 1      | 1    | Port                    |
+    /// | 2      | 1    | Flags (bit 0 confirmed) |
+    /// | 3      | 1    | Priority                |
+    /// | 4      | 1    | Attempts left           |
+    /// | 5      | 4    | Sequence number (LE)    |
+    /// | 9      | ...  | Payload                 |
+    fn encode(&self, buffer: &mut [u8; RECORD_HEADER_LEN + MAX_UPLINK_LEN]) -> usize {
+        buffer[0] = RECORD_VERSION;
+        buffer[1] = self.port;
+        buffer[2] = if self.confirmed { FLAG_CONFIRMED } else { 0 };
+        buffer[3] = self.priority as u8;
+        buffer[4] = self.attempts_left;
+        buffer[5..9].copy_from_slice(&self.seq.to_le_bytes());
+        let len = RECORD_HEADER_LEN + self.payload.len();
+        buffer[RECORD_HEADER_LEN..len].copy_from_slice(&self.payload);
+        len
+    }
+
+    fn decode(bytes: &[u8], slot: u8) -> Option<Self> {
+        if bytes.len() < RECORD_HEADER_LEN {
+            return None;
+        }
+        let (header, payload) = bytes.split_at(RECORD_HEADER_LEN);
+        if header[0] != RECORD_VERSION {
+            return None;
+        }
+        Some(Self {
+            port: header[1],
+            confirmed: header[2] & FLAG_CONFIRMED != 0,
+            priority: Priority::from_u8(header[3])?,
+            attempts_left: header[4],
+            payload: heapless::Vec::from_slice(payload).ok()?,
+            seq: u32::from_le_bytes([header[5], header[6], header[7], header[8]]),
+            slot,
+        })
+    }
+
+    /// Stores the uplink in `settings` so it survives a reboot.
+    pub fn write<S: Storage>(&self, settings: &mut KvStore<S>) -> Result<(), QueueError> {
+        let mut buffer = [0u8; RECORD_HEADER_LEN + MAX_UPLINK_LEN];
+        let len = self.encode(&mut buffer);
+        settings
+            .set_raw(&slot_key(self.slot), &buffer[..len])
+            .map_err(|_| QueueError::Storage)
+    }
+
+    /// Removes the stored copy of the uplink from `settings`.
+    pub fn remove<S: Storage>(&self, settings: &mut KvStore<S>) -> Result<(), QueueError> {
+        settings
+            .remove(&slot_key(self.slot))
+            .map_err(|_| QueueError::Storage)
+    }
+}
+
+fn slot_key(slot: u8) -> heapless::String<16> {
+    let mut key = heapless::String::new();
+    // Cannot fail, the prefix and a u8 fit in the key.
+    let _ = write!(key, "{}{}", QUEUE_KEY_PREFIX, slot);
+    key
+}
+
+/// Bounded priority queue of uplinks waiting for coverage.
+///
+/// Uplinks are sent highest priority first, and in the order they were
+/// queued within a priority. An uplink stays queued until it is delivered or
+/// has used all its attempts.
+///
+/// Only a confirmed uplink can be found undelivered: the stack reports an
+/// unconfirmed one sent as soon as it went on air, so unconfirmed uplinks
+/// only wait while the stack cannot transmit at all.
+///
+/// The queue itself only lives in RAM; callers that persist it store each
+/// uplink with [`Uplink::write`] when it is queued and drop it with
+/// [`Uplink::remove`] when it leaves the queue. The attempts left are only
+/// stored when the uplink is queued, so they start over after a reboot.
+pub struct UplinkQueue {
+    entries: heapless::Vec<Uplink, QUEUE_CAPACITY>,
+    policy: OverflowPolicy,
+    next_seq: u32,
+}
+
+impl UplinkQueue {
+    pub fn new(policy: OverflowPolicy) -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+            policy,
+            next_seq: 0,
+        }
+    }
+
+    /// Restores the uplinks stored in `settings`.
+    ///
+    /// Records that cannot be decoded are removed and reported once all
+    /// valid uplinks are restored.
+    pub fn read<S: Storage>(
+        settings: &mut KvStore<S>,
+        policy: OverflowPolicy,
+    ) -> Result<Self, QueueError> {
+        let mut queue = Self::new(policy);
+        let mut invalid = None;
+        let mut buffer = [0u8; RECORD_HEADER_LEN + MAX_UPLINK_LEN];
+        for slot in 0..QUEUE_CAPACITY as u8 {
+            let key = slot_key(slot);
+            let len = match settings.get_raw(&key, &mut buffer) {
+                Ok(Some(len)) => len,
+                Ok(None) => continue,
+                // A record too long for the buffer is not one of ours.
+                Err(KvError::BufferTooSmall) => 0,
+                Err(_) => return Err(QueueError::Storage),
+            };
+            match Uplink::decode(&buffer[..len], slot) {
+                Some(uplink) => {
+                    queue.next_seq = queue.next_seq.max(uplink.seq + 1);
+                    // Cannot fail, there is one entry per slot.
+                    let _ = queue.entries.push(uplink);
+                }
+                None => {
+                    settings.remove(&key).map_err(|_| QueueError::Storage)?;
+                    invalid = Some(slot);
+                }
+            }
+        }
+        match invalid {
+            Some(slot) => Err(QueueError::InvalidRecord(slot)),
+            None => Ok(queue),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates over the queued uplinks, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Uplink> {
+        self.entries.iter()
+    }
+
+    /// Whether an uplink on `port` is waiting.
+    pub fn contains_port(&self, port: u8) -> bool {
+        self.entries.iter().any(|uplink| uplink.port == port)
+    }
+
+    /// Queues `uplink`, returning the uplink it displaced if the queue was
+    /// full.
+    ///
+    /// # Errors
+    ///
+    /// * `Full` - If the queue only holds uplinks of a higher priority.
+    pub fn push(&mut self, mut uplink: Uplink) -> Result<Option<Uplink>, QueueError> {
+        let dropped = if self.entries.is_full() {
+            let victim = self.victim(&uplink).ok_or(QueueError::Full)?;
+            Some(self.entries.swap_remove(victim))
+        } else {
+            None
+        };
+        uplink.seq = self.next_seq;
+        uplink.slot = (0..QUEUE_CAPACITY as u8)
+            .find(|slot| self.entries.iter().all(|queued| queued.slot != *slot))
+            .unwrap_or_default();
+        self.next_seq += 1;
+        // Cannot fail, a slot was freed above if the queue was full.
+        let _ = self.entries.push(uplink);
+        Ok(dropped)
+    }
+
+    /// Index of the uplink to drop to make room for `uplink`.
+    fn victim(&self, uplink: &Uplink) -> Option<usize> {
+        let coalesce = self.policy == OverflowPolicy::Coalesce;
+        let droppable = |same_port: bool| {
+            self.entries
+                .iter()
+                .enumerate()
+                .filter(|(_, queued)| queued.priority <= uplink.priority)
+                .filter(|(_, queued)| !same_port || queued.port == uplink.port)
+                .min_by_key(|(_, queued)| (queued.priority, queued.seq))
+                .map(|(index, _)| index)
+        };
+        coalesce
+            .then(|| droppable(true))
+            .flatten()
+            .or_else(|| droppable(false))
+    }
+
+    /// The uplink to send next.
+    ///
+    /// Sequence numbers are 32 bits wide and never wrap within the lifetime
+    /// of a device, so they give the order in which uplinks were queued.
+    pub fn peek(&self) -> Option<&Uplink> {
+        self.entries
+            .iter()
+            .min_by_key(|uplink| (core::cmp::Reverse(uplink.priority), uplink.seq))
+    }
+
+    /// Removes the uplink `seq` once it was delivered.
+    pub fn remove(&mut self, seq: u32) -> Option<Uplink> {
+        let index = self.entries.iter().position(|uplink| uplink.seq == seq)?;
+        Some(self.entries.swap_remove(index))
+    }
+
+    /// Accounts for a failed transmission of uplink `seq`, removing and
+    /// returning it once it has no attempts left.
+    pub fn fail(&mut self, seq: u32) -> Option<Uplink> {
+        let index = self.entries.iter().position(|uplink| uplink.seq == seq)?;
+        let uplink = &mut self.entries[index];
+        uplink.attempts_left = uplink.attempts_left.saturating_sub(1);
+        if uplink.attempts_left == 0 {
+            Some(self.entries.swap_remove(index))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{kv::SECTOR_SIZE, test_storage::MemStorage};
+
+    fn uplink(port: u8, priority: Priority) -> Uplink {
+        Uplink::new(port, priority, false, &[port; 4]).unwrap()
+    }
+
+    /// Queue filled with Normal uplinks on ports 2 and 3 in turn.
+    fn full_queue(policy: OverflowPolicy) -> UplinkQueue {
+        let mut queue = UplinkQueue::new(policy);
+        for n in 0..QUEUE_CAPACITY as u8 {
+            queue.push(uplink(2 + n % 2, Priority::Normal)).unwrap();
+        }
+        queue
+    }
+
+    fn settings() -> KvStore<MemStorage> {
+        KvStore::mount(MemStorage::new(2 * SECTOR_SIZE)).unwrap()
+    }
+
+    #[test]
+    fn peeks_by_priority_then_age() {
+        let mut queue = UplinkQueue::new(OverflowPolicy::DropOldest);
+        queue.push(uplink(2, Priority::Normal)).unwrap();
+        queue.push(uplink(0, Priority::Low)).unwrap();
+        queue.push(uplink(3, Priority::Normal)).unwrap();
+        queue.push(uplink(10, Priority::High)).unwrap();
+        queue.push(uplink(11, Priority::High)).unwrap();
+        let mut ports = std::vec::Vec::new();
+        while let Some(next) = queue.peek() {
+            ports.push(next.port);
+            let seq = next.seq();
+            queue.remove(seq).unwrap();
+        }
+        assert_eq!(ports, [10, 11, 2, 3, 0]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.remove(0), None);
+    }
+
+    #[test]
+    fn drop_oldest_overflow() {
+        let mut queue = full_queue(OverflowPolicy::DropOldest);
+        assert_eq!(queue.push(uplink(0, Priority::Low)), Err(QueueError::Full));
+
+        let dropped = queue.push(uplink(3, Priority::Normal)).unwrap().unwrap();
+        assert_eq!((dropped.port, dropped.seq()), (2, 0));
+        assert_eq!(queue.len(), QUEUE_CAPACITY);
+
+        // Lower priorities go first, whatever their age.
+        queue.remove(1).unwrap();
+        queue.push(uplink(0, Priority::Low)).unwrap();
+        let dropped = queue.push(uplink(4, Priority::High)).unwrap().unwrap();
+        assert_eq!((dropped.port, dropped.priority), (0, Priority::Low));
+        let dropped = queue.push(uplink(4, Priority::High)).unwrap().unwrap();
+        assert_eq!(dropped.seq(), 2);
+    }
+
+    #[test]
+    fn coalesce_overflow() {
+        let mut queue = full_queue(OverflowPolicy::Coalesce);
+        // The oldest reading on the same port is replaced.
+        let dropped = queue.push(uplink(3, Priority::Normal)).unwrap().unwrap();
+        assert_eq!((dropped.port, dropped.seq()), (3, 1));
+        assert!(queue.contains_port(2));
+
+        // Without one the oldest uplink goes.
+        let dropped = queue.push(uplink(4, Priority::Normal)).unwrap().unwrap();
+        assert_eq!((dropped.port, dropped.seq()), (2, 0));
+
+        // A higher priority uplink on the same port is kept.
+        let mut queue = UplinkQueue::new(OverflowPolicy::Coalesce);
+        queue.push(uplink(2, Priority::High)).unwrap();
+        for _ in 1..QUEUE_CAPACITY {
+            queue.push(uplink(3, Priority::Low)).unwrap();
+        }
+        let dropped = queue.push(uplink(2, Priority::Low)).unwrap().unwrap();
+        assert_eq!((dropped.port, dropped.seq()), (3, 1));
+        assert_eq!(
+            queue
+                .push(uplink(5, Priority::Normal))
+                .unwrap()
+                .unwrap()
+                .seq(),
+            2
+        );
+    }
+
+    #[test]
+    fn failures_use_up_attempts() {
+        let mut queue = UplinkQueue::new(OverflowPolicy::DropOldest);
+        queue
+            .push(Uplink::new(2, Priority::Normal, true, &[1]).unwrap())
+            .unwrap();
+        queue.push(uplink(3, Priority::Normal)).unwrap();
+        for left in (1..CONFIRMED_ATTEMPTS).rev() {
+            assert_eq!(queue.fail(0), None);
+            assert_eq!(queue.peek().unwrap().attempts_left, left);
+        }
+        assert_eq!(queue.fail(0).unwrap().port, 2);
+        assert_eq!(queue.fail(0), None);
+
+        assert_eq!(queue.fail(1), None);
+        assert_eq!(queue.fail(1).unwrap().attempts_left, 0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn rejects_long_payloads() {
+        assert_eq!(
+            Uplink::new(2, Priority::Normal, false, &[0; MAX_UPLINK_LEN + 1]),
+            Err(QueueError::TooLong(MAX_UPLINK_LEN + 1))
+        );
+        assert!(Uplink::new(2, Priority::Normal, false, &[0; MAX_UPLINK_LEN]).is_ok());
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut settings = settings();
+        let mut queue = UplinkQueue::new(OverflowPolicy::Coalesce);
+        let uplinks = [
+            Uplink::new(2, Priority::Normal, true, &[0xAB; MAX_UPLINK_LEN]).unwrap(),
+            uplink(0, Priority::Low),
+            uplink(10, Priority::High),
+        ];
+        for uplink in uplinks {
+            queue.push(uplink).unwrap();
+        }
+        queue.fail(0);
+        for queued in queue.iter() {
+            queued.write(&mut settings).unwrap();
+        }
+        // A delivered uplink frees its slot.
+        let delivered = queue.remove(1).unwrap();
+        delivered.remove(&mut settings).unwrap();
+
+        let mut restored = UplinkQueue::read(&mut settings, OverflowPolicy::Coalesce).unwrap();
+        let mut expected: std::vec::Vec<_> = queue.iter().cloned().collect();
+        let mut got: std::vec::Vec<_> = restored.iter().cloned().collect();
+        expected.sort_by_key(|uplink| uplink.seq());
+        got.sort_by_key(|uplink| uplink.seq());
+        assert_eq!(got, expected);
+        assert_eq!(got[0].attempts_left, CONFIRMED_ATTEMPTS - 1);
+
+        // Sequence numbers go on after the last one, and free slots are reused.
+        restored.push(uplink(3, Priority::Normal)).unwrap();
+        let pushed = restored.iter().find(|uplink| uplink.port == 3).unwrap();
+        assert_eq!((pushed.seq(), pushed.slot), (3, 1));
+    }
+
+    #[test]
+    fn read_removes_invalid_records() {
+        let mut settings = settings();
+        let mut queue = UplinkQueue::new(OverflowPolicy::Coalesce);
+        queue.push(uplink(2, Priority::Normal)).unwrap();
+        queue.peek().unwrap().write(&mut settings).unwrap();
+        // A record of another version, one with an unknown priority and one
+        // too long for a queued uplink.
+        settings.set_raw(&slot_key(3), &[9; 12]).unwrap();
+        let mut record = [RECORD_VERSION, 2, 0, 7, 1, 0, 0, 0, 0];
+        settings.set_raw(&slot_key(4), &record).unwrap();
+        record[3] = Priority::Normal as u8;
+        let mut long = [0u8; RECORD_HEADER_LEN + MAX_UPLINK_LEN + 1];
+        long[..RECORD_HEADER_LEN].copy_from_slice(&record);
+        settings.set_raw(&slot_key(5), &long).unwrap();
+
+        assert_eq!(
+            UplinkQueue::read(&mut settings, OverflowPolicy::Coalesce).err(),
+            Some(QueueError::InvalidRecord(5))
+        );
+        for slot in 3..=5 {
+            assert_eq!(settings.get_raw(&slot_key(slot), &mut [0; 16]), Ok(None));
+        }
+        let restored = UplinkQueue::read(&mut settings, OverflowPolicy::Coalesce).unwrap();
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored.peek(), queue.peek());
+    }
+}
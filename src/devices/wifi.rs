@@ -1,11 +1,15 @@
-use embassy_futures::join::join;
+use embassy_futures::{
+    join::join,
+    select::{select, Either},
+};
 use embassy_net::{
     dns::DnsSocket,
     tcp::client::{TcpClient, TcpClientState},
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
-use embassy_time::{Duration, Timer};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::{Read, Write};
+use embedded_storage::Storage;
 use esp_wifi::wifi::{
     ClientConfiguration, Configuration, WifiController, WifiDevice, WifiStaDevice, WifiState,
 };
@@ -16,7 +20,11 @@ use reqwless::{
     response::Response,
 };
 
-use crate::devices::display::DISPLAY_SIGNAL;
+use crate::devices::{
+    display::DISPLAY_SIGNAL,
+    nvs::{Nvs, NvsError},
+    p2p_neighbors,
+};
 
 #[derive(PartialEq)]
 enum WifiStatus {
@@ -26,10 +34,73 @@ enum WifiStatus {
 
 static WIFI_SIGNAL_CONNECT: Signal<CriticalSectionRawMutex, WifiStatus> = Signal::new();
 
-const SSID: &str = env!("SSID");
-const PASSWORD: &str = env!("PASSWORD");
+/// NVS namespace holding the factory-provisioned WiFi credentials.
+pub const WIFI_NAMESPACE: &str = "wifi";
+const SSID_KEY: &str = "ssid";
+const PASSWORD_KEY: &str = "password";
+
+// Build-time credentials, used when nothing was provisioned in NVS.
+const SSID: Option<&str> = option_env!("SSID");
+const PASSWORD: Option<&str> = option_env!("PASSWORD");
 const URL: &str = env!("URL");
 const BUFFER_SIZE: usize = 1024;
+/// Time between two reports of the neighbour table while connected.
+const NEIGHBOR_REPORT_INTERVAL: Duration = Duration::from_secs(600);
+/// Room for the JSON of a full neighbour table.
+const NEIGHBOR_REPORT_LEN: usize = 2048;
+
+/// WiFi station credentials.
+#[derive(Debug, Clone, Default)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+}
+
+impl WifiCredentials {
+    /// Reads the credentials from the `wifi` NVS namespace.
+    pub fn read<S: Storage>(nvs: &mut Nvs<S>) -> Result<Option<Self>, NvsError> {
+        let mut credentials = Self::default();
+        let mut buffer = [0u8; 65];
+        let Some(ssid) = nvs.get_str(WIFI_NAMESPACE, SSID_KEY, &mut buffer)? else {
+            return Ok(None);
+        };
+        credentials.ssid = ssid.try_into().map_err(|_| NvsError::BufferTooSmall)?;
+        if let Some(password) = nvs.get_str(WIFI_NAMESPACE, PASSWORD_KEY, &mut buffer)? {
+            credentials.password = password.try_into().map_err(|_| NvsError::BufferTooSmall)?;
+        }
+        Ok(Some(credentials))
+    }
+
+    /// Stores the credentials in the `wifi` NVS namespace.
+    pub fn write<S: Storage>(&self, nvs: &mut Nvs<S>) -> Result<(), NvsError> {
+        nvs.set_str(WIFI_NAMESPACE, SSID_KEY, &self.ssid)?;
+        nvs.set_str(WIFI_NAMESPACE, PASSWORD_KEY, &self.password)
+    }
+
+    /// Loads the provisioned credentials, falling back to the ones given at
+    /// build time through the `SSID` and `PASSWORD` environment variables.
+    pub fn load<S: Storage>(nvs: &mut Nvs<S>) -> Self {
+        match Self::read(nvs) {
+            Ok(Some(credentials)) => {
+                esp_println::println!("[WIFI] Using provisioned credentials");
+                return credentials;
+            }
+            Ok(None) => esp_println::println!("[WIFI] No credentials provisioned"),
+            Err(e) => esp_println::println!("[WIFI] Failed to read credentials: {}", e),
+        }
+
+        let mut credentials = Self::default();
+        if let Some(ssid) = SSID {
+            credentials.ssid = ssid.try_into().expect("[WIFI] ssid larger than 32 bytes");
+        }
+        if let Some(password) = PASSWORD {
+            credentials.password = password
+                .try_into()
+                .expect("[WIFI] password larger than 64 bytes");
+        }
+        credentials
+    }
+}
 
 async fn send_post<'a, C: Read + Write>(
     rx_buffer: &'a mut [u8; BUFFER_SIZE],
@@ -47,6 +118,28 @@ async fn send_post<'a, C: Read + Write>(
     Ok(response)
 }
 
+/// Posts the neighbour table of this node, as JSON.
+async fn send_neighbors<C: Read + Write>(
+    rx_buffer: &mut [u8; BUFFER_SIZE],
+    resource: &mut HttpResource<'_, C>,
+) -> Result<(), reqwless::Error> {
+    let mut report = heapless::String::<NEIGHBOR_REPORT_LEN>::new();
+    let now_ms = Instant::now().as_millis();
+    // Cannot fail, a full table is shorter than the report.
+    let _ = p2p_neighbors::with_neighbors(|table| table.write_json(&mut report, now_ms));
+    resource
+        .post("/api/v1/neighbors")
+        .body(report.as_bytes())
+        .content_type(ContentType::ApplicationJson)
+        .send(rx_buffer)
+        .await?;
+
+    esp_println::println!("[WIFI] Neighbours sent");
+    Ok(())
+}
+
+/// Posts a request once connected, then the neighbour table every
+/// [`NEIGHBOR_REPORT_INTERVAL`] until the connection drops.
 #[embassy_executor::task]
 pub async fn request_http(stack: embassy_net::Stack<'static>) {
     esp_println::println!("[WIFI] Starting http task");
@@ -92,6 +185,22 @@ pub async fn request_http(stack: embassy_net::Stack<'static>) {
             }
         }
         esp_println::println!("[WIFI] Request");
+
+        loop {
+            match http_client.resource(URL).await {
+                Ok(mut resource) => {
+                    if let Err(err) = send_neighbors(&mut rx_buf, &mut resource).await {
+                        esp_println::println!("[WIFI] Failed to send neighbours: {:?}", err);
+                    }
+                }
+                Err(err) => esp_println::println!("[WIFI] 💥 Failed to connect: {:?}", err),
+            }
+            let status = WIFI_SIGNAL_CONNECT.wait();
+            match select(Timer::after(NEIGHBOR_REPORT_INTERVAL), status).await {
+                Either::Second(WifiStatus::Disconnected) => break,
+                Either::First(()) | Either::Second(WifiStatus::Connected) => {}
+            }
+        }
     }
 }
 
@@ -100,6 +209,7 @@ pub async fn network(
     stack: embassy_net::Stack<'static>,
     mut runner: embassy_net::Runner<'static, WifiDevice<'static, WifiStaDevice>>,
     mut controller: WifiController<'static>,
+    credentials: WifiCredentials,
 ) {
     esp_println::println!("[WIFI] Start connection task");
     let wifi = async {
@@ -113,10 +223,8 @@ pub async fn network(
 
             if !matches!(controller.is_started(), Ok(true)) {
                 let client_config = Configuration::Client(ClientConfiguration {
-                    ssid: SSID.try_into().expect("[WIFI] ssid larger than 32 bytes"),
-                    password: PASSWORD
-                        .try_into()
-                        .expect("[WIFI] password larger than 64 bytes"),
+                    ssid: credentials.ssid.clone(),
+                    password: credentials.password.clone(),
                     ..Default::default()
                 });
 
@@ -1,2 +1,2 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 pub mod devices;
\ No newline at end of file
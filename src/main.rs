@@ -1,16 +1,29 @@
 #![no_std]
 #![no_main]
 #[deny(clippy::mem_forget)]
-
 mod devices;
 
 use defmt::println;
-use devices::{lora::LoRaRadio, types::BusSpi, types::MutexSpi};
+use devices::{
+    iv::IrqEvent,
+    kv::{KvStore, SECTOR_SIZE},
+    lora::LoRaRadio,
+    nvs::{Nvs, NVS_PARTITION},
+    partition::{Partition, CONFIG_PARTITION},
+    types::{BusSpi, MutexNvs, MutexSettings, MutexSpi},
+    wifi::WifiCredentials,
+};
+#[cfg(feature = "lorawan")]
+use devices::{
+    lorawan_config::LoRaWanConfig,
+    ota::{FirmwareUpdate, OtaData, OtaError, OTADATA_PARTITION, OTA_SLOTS, UPDATE_PUBLIC_KEY},
+    types::Firmware,
+};
 use embassy_executor::Spawner;
 use embassy_net::StackResources;
 use embassy_time::{Duration, Timer};
 
-use embedded_storage::ReadStorage;
+use embedded_storage::{nor_flash::RmwMultiwriteNorFlashStorage, ReadStorage};
 use esp_backtrace as _;
 use esp_hal::{
     clock::CpuClock,
@@ -33,6 +46,26 @@ macro_rules! mk_static {
     }};
 }
 
+/// Opens the OTA slot that is not running, which receives firmware updates.
+#[cfg(feature = "lorawan")]
+fn open_firmware(buffer: &'static mut [u8; SECTOR_SIZE]) -> Result<Firmware, OtaError> {
+    let public_key = UPDATE_PUBLIC_KEY.ok_or(OtaError::NoPublicKey)?;
+    let flash = esp_storage::FlashStorage::new();
+    let otadata = Partition::open(flash, OTADATA_PARTITION).map_err(|_| OtaError::Storage)?;
+    let mut otadata = OtaData::new(otadata);
+    let running = otadata.boot_slot()?;
+    let target = (running + 1) % OTA_SLOTS.len();
+    let flash = esp_storage::FlashStorage::new();
+    let slot = Partition::open(flash, OTA_SLOTS[target]).map_err(|_| OtaError::Storage)?;
+    esp_println::println!(
+        "[MAIN] Running from {}, updates go to {}",
+        OTA_SLOTS[running],
+        OTA_SLOTS[target]
+    );
+    let slot = RmwMultiwriteNorFlashStorage::new(slot, buffer);
+    Ok(FirmwareUpdate::new(slot, otadata, target, public_key))
+}
+
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) -> ! {
     esp_alloc::heap_allocator!(72 * 1024);
@@ -101,6 +134,7 @@ async fn main(spawner: Spawner) -> ! {
     let mut led =
         devices::led::Led::new(Output::new(peripherals.GPIO25, esp_hal::gpio::Level::Low));
     let button = devices::button::Button::new(Input::new(peripherals.GPIO0, Pull::Up));
+    let battery_ctrl = Output::new(peripherals.GPIO21, esp_hal::gpio::Level::Low);
 
     led.set(devices::led::LedState::Off);
     esp_println::println!("[MAIN] Led initialized");
@@ -145,29 +179,95 @@ async fn main(spawner: Spawner) -> ! {
 
     let flash = esp_storage::FlashStorage::new();
     println!("[MAIN] Flash capacity: {:#x}", flash.capacity());
-    let spi_mutex = mk_static!(MutexSpi, MutexSpi::new(spi));
-    let lora_spi = BusSpi::new(spi_mutex, lora_cs);
-
-    let lora = match LoRaRadio::new(lora_spi, lora_rst, lora_dio0, lora_dio1, flash).await {
-        Ok(lora) => lora,
+    let config_storage = match Partition::open(flash, CONFIG_PARTITION) {
+        Ok(partition) => {
+            esp_println::println!("[MAIN] Config partition at {:#x}", partition.offset());
+            partition
+        }
+        Err(err) => {
+            esp_println::println!("[MAIN] Failed to open config partition: {:?}", err);
+            loop {}
+        }
+    };
+    let merge_buffer = mk_static!([u8; SECTOR_SIZE], [0; SECTOR_SIZE]);
+    let settings_storage = RmwMultiwriteNorFlashStorage::new(config_storage, merge_buffer);
+    let settings = match KvStore::mount(settings_storage) {
+        Ok(settings) => &*mk_static!(MutexSettings, MutexSettings::new(settings)),
+        Err(err) => {
+            esp_println::println!("[MAIN] Failed to mount settings: {:?}", err);
+            loop {}
+        }
+    };
+    let nvs_storage = match Partition::open(esp_storage::FlashStorage::new(), NVS_PARTITION) {
+        Ok(partition) => partition,
         Err(err) => {
-            esp_println::println!("[MAIN] Failed to create lora: {:?}", err);
+            esp_println::println!("[MAIN] Failed to open nvs partition: {:?}", err);
             loop {}
         }
     };
+    let nvs_buffer = mk_static!([u8; SECTOR_SIZE], [0; SECTOR_SIZE]);
+    let nvs = match Nvs::new(RmwMultiwriteNorFlashStorage::new(nvs_storage, nvs_buffer)) {
+        Ok(nvs) => &*mk_static!(MutexNvs, MutexNvs::new(nvs)),
+        Err(err) => {
+            esp_println::println!("[MAIN] Failed to open nvs: {:?}", err);
+            loop {}
+        }
+    };
+    // Updates arrive over LoRaWAN, so P2P builds do not receive any.
+    #[cfg(feature = "lorawan")]
+    let firmware = match open_firmware(mk_static!([u8; SECTOR_SIZE], [0; SECTOR_SIZE])) {
+        Ok(firmware) => Some(mk_static!(Firmware, firmware)),
+        Err(err) => {
+            esp_println::println!("[MAIN] Firmware updates disabled: {}", err);
+            None
+        }
+    };
+    let wifi_credentials = WifiCredentials::load(&mut *nvs.lock().await);
+    #[cfg(feature = "lorawan")]
+    let lorawan_config = LoRaWanConfig::load(&mut *nvs.lock().await);
+
+    let spi_mutex = mk_static!(MutexSpi, MutexSpi::new(spi));
+    let lora_spi = BusSpi::new(spi_mutex, lora_cs);
 
-    esp_hal::
+    let lora_irq = &*mk_static!(IrqEvent, IrqEvent::new());
+    let mut lora =
+        match LoRaRadio::new(lora_spi, lora_rst, lora_dio0, lora_dio1, lora_irq, settings).await {
+            Ok(lora) => lora,
+            Err(err) => {
+                esp_println::println!("[MAIN] Failed to create lora: {:?}", err);
+                loop {}
+            }
+        };
+    lora.load().await;
 
     let tasks = [
         spawner.spawn(devices::gps::uart_reader(uart2_rx)),
         spawner.spawn(devices::gps::uart_writer(uart2_tx)),
-        spawner.spawn(devices::wifi::network(stack, runner, controller)),
+        spawner.spawn(devices::wifi::network(
+            stack,
+            runner,
+            controller,
+            wifi_credentials,
+        )),
         spawner.spawn(devices::wifi::request_http(stack)),
         spawner.spawn(devices::display::display(i2c0, oled_rst)),
         spawner.spawn(devices::led::task_led(led)),
         spawner.spawn(devices::button::task_button(button)),
-        // spawner.spawn(devices::lorawan::task_lorawan(lora, rng)),
-        spawner.spawn(devices::lora_p2p::task_lora_p2p(lora)),
+        spawner.spawn(devices::battery::task_battery(
+            peripherals.ADC1,
+            peripherals.GPIO37,
+            battery_ctrl,
+        )),
+        // The radio runs either the LoRaWAN stack or the P2P link.
+        #[cfg(feature = "lorawan")]
+        spawner.spawn(devices::lorawan::task_lorawan(
+            lora,
+            rng,
+            lorawan_config,
+            firmware,
+        )),
+        #[cfg(not(feature = "lorawan"))]
+        spawner.spawn(devices::lora_p2p::task_lora_p2p(lora, rng, nvs)),
     ];
 
     for task in tasks.iter() {